@@ -49,6 +49,7 @@ run_and_log "${hoard[@]}" init
 run_and_log "${hoard[@]}" db migrate
 run_and_log "${hoard[@]}" db vacuum
 run_and_log "${hoard[@]}" collection add "$COLLECTION"
+run_and_log "${hoard[@]}" collection set "$COLLECTION" --min-copies 1
 run_and_log "${hoard[@]}" collection ls
 run_and_log "${hoard[@]}" location add "$LOCATION"
 run_and_log "${hoard[@]}" location ls
@@ -66,6 +67,8 @@ run_and_log "${hoard[@]}" file ls -c "$COLLECTION" "$VIRT_FILE"
 run_and_log "${hoard[@]}" file find -c "$COLLECTION" "$VIRT_DIR"
 run_and_log "${hoard[@]}" file inspect -c "$COLLECTION" "$VIRT_FILE"
 run_and_log "${hoard[@]}" file path -c "$COLLECTION" "$VIRT_FILE"
+run_and_log "${hoard[@]}" replicate -c "$COLLECTION"
+run_and_log "${hoard[@]}" verify -c "$COLLECTION"
 
 echo -e '\n'
 echo 'The smoke test went happily :)'
@@ -1,71 +1,1796 @@
+use crate::db::types::Timestamp;
+use crate::error::GenericError;
+use crate::fs_utils::TeeReader;
+use crate::hash_utils::{make_hashes, HashAlgorithm};
+use crate::mail_utils::{self, MailHeaders, MboxMessages};
+use bzip2::read::BzDecoder;
+use chrono::{NaiveDate, TimeZone, Utc};
 use flate2::read::GzDecoder;
 use lzma::reader::LzmaReader;
-use std::io::{Read, Seek};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+use rusqlite::ToSql;
+use sevenz_rust::{Password, SevenZArchiveEntry, SevenZReader};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use zip::result::ZipError;
 use zstd::stream::read::Decoder as ZstdDecoder;
 
+/// The offset of the `ustar` magic in a tar header
+const TAR_MAGIC_OFFSET: usize = 257;
+/// ISO 9660 images start with 16 unused sectors followed by the volume descriptors
+const ISO_SECTOR_SIZE: u64 = 2048;
+const ISO_FIRST_DESCRIPTOR: u64 = 16;
+/// Directories bigger than this are assumed to be corrupt rather than read into memory
+const ISO_MAX_DIR_SIZE: u32 = 64 * 1024 * 1024;
+/// The file type bits of a Unix mode and the type of a symlink
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+/// Symlink targets longer than this are assumed to be corrupt
+const MAX_LINK_TARGET: u64 = 4096;
+
+/// Hash values of some content, sorted by algorithm
+pub type Hashes = Vec<(HashAlgorithm, Vec<u8>)>;
+
+/// The format of a file that contains other files, or of a compressed file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContainerType {
+    Tar,
+    TarGzip,
+    TarXz,
+    TarZstd,
+    TarBzip2,
+    Zip,
+    Gzip,
+    Xz,
+    Zstd,
+    Bzip2,
+    SevenZip,
+    Iso9660,
+    /// Email messages one after the other, each starting with a `From ` line
+    Mbox,
+}
+
+impl ContainerType {
+    /// Detect the container type from the leading bytes of the file. Compressed files are
+    /// decompressed far enough to check if they contain a tar archive. The reader is left at the
+    /// start of the file.
+    pub fn detect<R: Read + Seek>(reader: &mut R) -> anyhow::Result<Option<Self>> {
+        reader.seek(SeekFrom::Start(0))?;
+        let header = read_header(&mut *reader)?;
+        reader.seek(SeekFrom::Start(0))?;
+        let compressed = if header.starts_with(&[0x1f, 0x8b]) {
+            Some((Self::Gzip, Self::TarGzip))
+        } else if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some((Self::Xz, Self::TarXz))
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some((Self::Zstd, Self::TarZstd))
+        } else if header.starts_with(b"BZh") {
+            Some((Self::Bzip2, Self::TarBzip2))
+        } else if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") {
+            return Ok(Some(Self::Zip));
+        } else if header.starts_with(&[b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c]) {
+            return Ok(Some(Self::SevenZip));
+        } else if is_tar_header(&header) {
+            return Ok(Some(Self::Tar));
+        } else if mail_utils::is_mbox(&header) {
+            return Ok(Some(Self::Mbox));
+        } else {
+            let is_iso = is_iso_image(&mut *reader)?;
+            reader.seek(SeekFrom::Start(0))?;
+            return Ok(is_iso.then_some(Self::Iso9660));
+        };
+
+        // unwrap ok because the match above returned for everything else
+        let (plain, tar) = compressed.unwrap();
+        // a truncated or corrupt stream is still compressed, even if it can't be read
+        let inner = decompressor(plain, &mut *reader)
+            .and_then(read_header)
+            .unwrap_or_default();
+        reader.seek(SeekFrom::Start(0))?;
+        if is_tar_header(&inner) {
+            Ok(Some(tar))
+        } else {
+            Ok(Some(plain))
+        }
+    }
+
+    /// Whether the members can be listed while reading the archive from start to end. Zip and 7z
+    /// archives keep their directory at the end, and ISO images point to it from the start.
+    pub fn is_streamable(&self) -> bool {
+        !matches!(self, Self::Zip | Self::SevenZip | Self::Iso9660)
+    }
+}
+
+impl fmt::Display for ContainerType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let val = match self {
+            Self::Tar => "tar",
+            Self::TarGzip => "tar+gzip",
+            Self::TarXz => "tar+xz",
+            Self::TarZstd => "tar+zstd",
+            Self::TarBzip2 => "tar+bzip2",
+            Self::Zip => "zip",
+            Self::Gzip => "gzip",
+            Self::Xz => "xz",
+            Self::Zstd => "zstd",
+            Self::Bzip2 => "bzip2",
+            Self::SevenZip => "7z",
+            Self::Iso9660 => "iso9660",
+            Self::Mbox => "mbox",
+        };
+        write!(f, "{}", val)
+    }
+}
+
+impl TryFrom<&str> for ContainerType {
+    type Error = GenericError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "tar" => Ok(Self::Tar),
+            "tar+gzip" => Ok(Self::TarGzip),
+            "tar+xz" => Ok(Self::TarXz),
+            "tar+zstd" => Ok(Self::TarZstd),
+            "tar+bzip2" => Ok(Self::TarBzip2),
+            "zip" => Ok(Self::Zip),
+            "gzip" => Ok(Self::Gzip),
+            "xz" => Ok(Self::Xz),
+            "zstd" => Ok(Self::Zstd),
+            "bzip2" => Ok(Self::Bzip2),
+            "7z" => Ok(Self::SevenZip),
+            "iso9660" => Ok(Self::Iso9660),
+            "mbox" => Ok(Self::Mbox),
+            x => Err(GenericError::new(format!(
+                "Not a known container type: {x}"
+            ))),
+        }
+    }
+}
+
+impl ToSql for ContainerType {
+    #[inline]
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_string()))
+    }
+}
+
+impl FromSql for ContainerType {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Text(bytes) => ::std::str::from_utf8(bytes)
+                .map_err(|e| FromSqlError::Other(Box::new(e)))?
+                .try_into()
+                .map_err(|e| FromSqlError::Other(Box::new(e))),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// The kind of a member of an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EntryType {
+    #[default]
+    File,
+    Directory,
+    Symlink,
+    Hardlink,
+    /// Devices, FIFOs, and the like
+    Other,
+}
+
+impl fmt::Display for EntryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let val = match self {
+            Self::File => "file",
+            Self::Directory => "dir",
+            Self::Symlink => "symlink",
+            Self::Hardlink => "hardlink",
+            Self::Other => "other",
+        };
+        write!(f, "{}", val)
+    }
+}
+
+impl TryFrom<&str> for EntryType {
+    type Error = GenericError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "file" => Ok(Self::File),
+            "dir" => Ok(Self::Directory),
+            "symlink" => Ok(Self::Symlink),
+            "hardlink" => Ok(Self::Hardlink),
+            "other" => Ok(Self::Other),
+            x => Err(GenericError::new(format!("Not a known entry type: {x}"))),
+        }
+    }
+}
+
+impl ToSql for EntryType {
+    #[inline]
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_string()))
+    }
+}
+
+impl FromSql for EntryType {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Text(bytes) => ::std::str::from_utf8(bytes)
+                .map_err(|e| FromSqlError::Other(Box::new(e)))?
+                .try_into()
+                .map_err(|e| FromSqlError::Other(Box::new(e))),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// A member of an archive and what the archive records about it. Formats that don't record
+/// something leave it empty.
+#[derive(Debug, Default, PartialEq)]
+pub struct ArchiveEntry {
+    pub path: String,
+    pub size: u64,
+    pub entry_type: EntryType,
+    /// The target of a symlink or hardlink
+    pub link_target: Option<String>,
+    pub modified: Option<Timestamp>,
+    pub compressed_size: Option<u64>,
+    pub crc32: Option<u32>,
+    pub encrypted: bool,
+    /// Hashes of the contents for each of the configured algorithms
+    pub hashes: Hashes,
+    /// The headers of members that are email messages
+    pub mail: Option<MailHeaders>,
+}
+
+fn unix_time(secs: i64) -> Option<Timestamp> {
+    Utc.timestamp_opt(secs, 0).single().map(Timestamp::from)
+}
+
+/// Zip times have no time zone and are assumed to be UTC.
+fn zip_time(time: zip::DateTime) -> Option<Timestamp> {
+    let date = NaiveDate::from_ymd_opt(time.year().into(), time.month().into(), time.day().into())?;
+    let date_time = date.and_hms_opt(
+        time.hour().into(),
+        time.minute().into(),
+        time.second().into(),
+    )?;
+    Some(Timestamp::from(Utc.from_utc_datetime(&date_time)))
+}
+
+/// Read up to the first 512 bytes (one tar block) of the input.
+fn read_header<R: Read>(reader: R) -> anyhow::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(512);
+    reader.take(512).read_to_end(&mut header)?;
+    Ok(header)
+}
+
+fn is_tar_header(header: &[u8]) -> bool {
+    header.get(TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + 5) == Some(b"ustar")
+}
+
+fn is_iso_image<R: Read + Seek>(reader: &mut R) -> anyhow::Result<bool> {
+    reader.seek(SeekFrom::Start(ISO_FIRST_DESCRIPTOR * ISO_SECTOR_SIZE))?;
+    let mut magic = Vec::with_capacity(6);
+    reader.take(6).read_to_end(&mut magic)?;
+    Ok(magic.get(1..6) == Some(b"CD001"))
+}
+
+fn decompressor<'a, R: Read + 'a>(
+    container_type: ContainerType,
+    reader: R,
+) -> anyhow::Result<Box<dyn Read + 'a>> {
+    Ok(match container_type {
+        ContainerType::Gzip | ContainerType::TarGzip => Box::new(GzDecoder::new(reader)),
+        ContainerType::Xz | ContainerType::TarXz => Box::new(LzmaReader::new_decompressor(reader)?),
+        ContainerType::Zstd | ContainerType::TarZstd => Box::new(ZstdDecoder::new(reader)?),
+        ContainerType::Bzip2 | ContainerType::TarBzip2 => Box::new(BzDecoder::new(reader)),
+        ContainerType::Tar
+        | ContainerType::Zip
+        | ContainerType::SevenZip
+        | ContainerType::Iso9660
+        | ContainerType::Mbox => Box::new(reader),
+    })
+}
+
+/// How far to look into archives inside of archives.
+#[derive(Debug, Clone, Copy)]
+pub struct NestingLimits {
+    /// How many levels of archives inside of the listed archive to list. `0` only lists the
+    /// members of the archive itself.
+    pub max_depth: u32,
+    /// How many bytes can be read into memory and decompressed while listing nested archives.
+    /// Nested archives that don't fit are listed as plain members, and listing stops where the
+    /// budget runs out so that archive bombs can't exhaust memory.
+    pub max_bytes: u64,
+    /// How many bytes a message in an mbox file can have. Bigger messages are skipped.
+    pub max_message_bytes: u64,
+}
+
+/// List the members of an archive of the given type. Leading `./` and `/`
+/// are removed from the paths so that they are relative to the root of the archive. Members
+/// listed more than once are only kept where they are listed last. A compressed
+/// file that isn't an archive has one member: the uncompressed file. Its name is taken from the
+/// gzip header if it's there, or else from `file_name` without the compression extension.
+///
+/// Members that are themselves archives are listed recursively within the `limits`, and the
+/// paths of their members are joined to theirs by `!/` (e.g., `inner.tar.gz!/doc.pdf`). The
+/// containing member is always listed before its members.
+///
+/// The contents of the members are hashed with the `hash_algorithms` while they are read.
 pub fn list_files<R: Read + Seek>(
+    container_type: ContainerType,
     file_name: &str,
     reader: R,
-) -> anyhow::Result<Vec<(String, u64)>> {
-    let split = file_name.split('.').collect::<Vec<_>>();
-    match (split.len(), split[split.len() - 1]) {
-        (len, "gz" | "xz" | "zst" | "zstd") if len < 2 => Ok(Vec::new()),
-        (_, "gz") => match split[split.len() - 2] {
-            "tar" => list_tar_files(GzDecoder::new(reader)),
-            _ => Ok(Vec::new()),
-        },
-        (_, "xz") => match split[split.len() - 2] {
-            "tar" => list_tar_files(LzmaReader::new_decompressor(reader)?),
-            _ => Ok(Vec::new()),
-        },
-        (_, "zst" | "zstd") => match split[split.len() - 2] {
-            "tar" => list_tar_files(ZstdDecoder::new(reader)?),
-            _ => Ok(Vec::new()),
-        },
-        (len, "tgz") if len >= 2 => list_tar_files(GzDecoder::new(reader)),
-        (len, "tar") if len >= 2 => list_tar_files(reader),
-        (len, "zip") if len >= 2 => list_zip_files(reader),
-        _ => Ok(Vec::new()),
-    }
+    limits: &NestingLimits,
+    hash_algorithms: &[HashAlgorithm],
+) -> anyhow::Result<Vec<ArchiveEntry>> {
+    let mut lister = Lister {
+        max_depth: limits.max_depth,
+        budget: Rc::new(Cell::new(limits.max_bytes)),
+        max_message_bytes: limits.max_message_bytes,
+        hash_algorithms,
+        extract: None,
+        extracted: 0,
+        files: Vec::new(),
+    };
+    lister.list(container_type, file_name, reader, "", 0)?;
+    Ok(latest_members(lister.files))
 }
 
-fn list_tar_files<R: Read>(reader: R) -> anyhow::Result<Vec<(String, u64)>> {
-    let mut archive = tar::Archive::new(reader);
-    let entries = match archive.entries() {
-        Ok(entries) => entries,
-        Err(e) => bail!("Could not list entries of archive: {}", e),
+/// List the members of an archive like [`list_files`] while reading it from start to end, so that
+/// the archive can be read for something else (e.g., copying it) at the same time. The archive
+/// must be [streamable](ContainerType::is_streamable). Whatever comes after the end of the
+/// archive isn't read.
+pub fn list_files_stream<R: Read>(
+    container_type: ContainerType,
+    file_name: &str,
+    reader: R,
+    limits: &NestingLimits,
+    hash_algorithms: &[HashAlgorithm],
+) -> anyhow::Result<Vec<ArchiveEntry>> {
+    let mut lister = Lister {
+        max_depth: limits.max_depth,
+        budget: Rc::new(Cell::new(limits.max_bytes)),
+        max_message_bytes: limits.max_message_bytes,
+        hash_algorithms,
+        extract: None,
+        extracted: 0,
+        files: Vec::new(),
     };
+    lister.list_stream(container_type, file_name, reader, "", 0)?;
+    Ok(latest_members(lister.files))
+}
+
+/// Write the contents of the members with the paths that are the keys of `members` to the
+/// local paths that are their values. The archive is read the same way as in [`list_files`], but
+/// only the members asked for and the nested archives that they are in are read, and reading stops
+/// once all of them have been written. The local files must not exist.
+///
+/// Returns the extracted members with the size and hashes of what was written. Members that
+/// weren't found or have no contents (e.g., directories) are left out.
+pub fn extract_files<R: Read + Seek>(
+    container_type: ContainerType,
+    file_name: &str,
+    reader: R,
+    limits: &NestingLimits,
+    hash_algorithms: &[HashAlgorithm],
+    members: &HashMap<String, PathBuf>,
+) -> anyhow::Result<Vec<ArchiveEntry>> {
+    let mut lister = Lister {
+        max_depth: limits.max_depth,
+        budget: Rc::new(Cell::new(limits.max_bytes)),
+        max_message_bytes: limits.max_message_bytes,
+        hash_algorithms,
+        extract: Some(members),
+        extracted: 0,
+        files: Vec::new(),
+    };
+    lister.list(container_type, file_name, reader, "", 0)?;
+    let mut files = lister.files;
+    // nested archives that were read to get to the members are added too
+    files.retain(|f| members.contains_key(&f.path));
+    Ok(files)
+}
+
+/// The file name without the first of the (lowercase) extensions that it ends with
+fn uncompressed_name(file_name: &str, extensions: &[&str]) -> String {
+    let lower = file_name.to_lowercase();
+    match extensions.iter().find(|ext| lower.ends_with(*ext)) {
+        // `.tgz` is short for `.tar.gz`
+        Some(&".tgz") => format!("{}.tar", &file_name[..file_name.len() - 4]),
+        Some(ext) => file_name[..file_name.len() - ext.len()].to_string(),
+        None => file_name.to_string(),
+    }
+}
+
+/// Keep only the last listing of each path, which is what extracting the archive would leave
+/// (e.g., for a tar with both `./a.txt` and `a.txt`, or a file appended to a tar again). The
+/// members of an earlier listing of a nested archive are dropped along with it.
+fn latest_members(files: Vec<ArchiveEntry>) -> Vec<ArchiveEntry> {
+    let mut last = HashMap::new();
+    for (i, entry) in files.iter().enumerate() {
+        last.insert(entry.path.clone(), i);
+    }
+    if last.len() == files.len() {
+        return files;
+    }
+
+    // the listing of each nested archive that the following members are in
+    let mut current = HashMap::new();
+    let mut keep = Vec::with_capacity(files.len());
+    for (i, entry) in files.iter().enumerate() {
+        current.insert(entry.path.as_str(), i);
+        let mut kept = last[&entry.path] == i;
+        let mut end = 0;
+        while let Some(pos) = entry.path[end..].find("!/") {
+            let parent = &entry.path[..end + pos];
+            kept &= current.get(parent) == last.get(parent);
+            end += pos + 2;
+        }
+        keep.push(kept);
+    }
+    files
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(entry, kept)| kept.then_some(entry))
+        .collect()
+}
+
+fn member_path(mut path: &str) -> &str {
+    loop {
+        match path.strip_prefix("./").or_else(|| path.strip_prefix('/')) {
+            Some(stripped) => path = stripped,
+            None if path == "." => return "",
+            None => return path,
+        }
+    }
+}
+
+struct Lister<'a> {
+    max_depth: u32,
+    budget: Rc<Cell<u64>>,
+    max_message_bytes: u64,
+    hash_algorithms: &'a [HashAlgorithm],
+    /// The members to extract and where to, instead of listing all members
+    extract: Option<&'a HashMap<String, PathBuf>>,
+    /// How many of the members to extract have been written
+    extracted: usize,
+    files: Vec<ArchiveEntry>,
+}
+
+impl<'a> Lister<'a> {
+    /// List the members of an archive `depth` levels deep, prefixing their paths with `prefix`.
+    fn list<R: Read + Seek>(
+        &mut self,
+        container_type: ContainerType,
+        file_name: &str,
+        mut reader: R,
+        prefix: &str,
+        depth: u32,
+    ) -> anyhow::Result<()> {
+        match container_type {
+            ContainerType::Zip => {
+                let mut zip = zip::ZipArchive::new(reader)?;
+                for i in 0..zip.len() {
+                    // the metadata of encrypted entries can still be read raw
+                    let encrypted = match zip.by_index(i) {
+                        Ok(_) => false,
+                        Err(ZipError::UnsupportedArchive(e))
+                            if e == ZipError::PASSWORD_REQUIRED =>
+                        {
+                            true
+                        }
+                        Err(e) => return Err(e.into()),
+                    };
+                    let mut file = if encrypted {
+                        zip.by_index_raw(i)?
+                    } else {
+                        zip.by_index(i)?
+                    };
+                    let entry_type = if file.is_dir() {
+                        EntryType::Directory
+                    } else if file.unix_mode().map(|m| m & S_IFMT) == Some(S_IFLNK) {
+                        EntryType::Symlink
+                    } else {
+                        EntryType::File
+                    };
+                    // zip stores the target as the contents of the symlink
+                    let mut link_target = None;
+                    if entry_type == EntryType::Symlink && !encrypted {
+                        let mut target = String::new();
+                        if (&mut file)
+                            .take(MAX_LINK_TARGET)
+                            .read_to_string(&mut target)
+                            .is_ok()
+                        {
+                            link_target = Some(target);
+                        }
+                    }
+                    let entry = ArchiveEntry {
+                        path: file.name().to_string(),
+                        size: file.size(),
+                        entry_type,
+                        link_target,
+                        modified: zip_time(file.last_modified()),
+                        compressed_size: Some(file.compressed_size()),
+                        crc32: Some(file.crc32()),
+                        encrypted,
+                        hashes: Vec::new(),
+                        mail: None,
+                    };
+                    let mut contents = self.budgeted(&mut file, depth);
+                    let contents = (entry_type == EntryType::File && !encrypted)
+                        .then_some(&mut contents as &mut dyn Read);
+                    self.add(prefix, entry, contents, depth)?;
+                    if self.extracted_all() {
+                        break;
+                    }
+                }
+            }
+            ContainerType::SevenZip => {
+                let len = reader.seek(SeekFrom::End(0))?;
+                reader.seek(SeekFrom::Start(0))?;
+                let mut archive = match SevenZReader::new(reader, len, Password::empty()) {
+                    Ok(archive) => archive,
+                    Err(e) => bail!("Could not list entries of 7z archive: {}", e),
+                };
+                if depth < self.max_depth
+                    || !self.hash_algorithms.is_empty()
+                    || self.extract.is_some()
+                {
+                    // the entries have to be decompressed to hash them, look for nested archives,
+                    // or extract them
+                    let res = archive.for_each_entries(|entry, contents| {
+                        if !entry.is_directory() {
+                            let entry = seven_zip_entry(entry);
+                            let mut contents = self.budgeted(contents, depth);
+                            let contents = (entry.entry_type == EntryType::File)
+                                .then_some(&mut contents as &mut dyn Read);
+                            self.add(prefix, entry, contents, depth)
+                                .map_err(|e| sevenz_rust::Error::other(e.to_string()))?;
+                        }
+                        Ok(!self.extracted_all())
+                    });
+                    if let Err(e) = res {
+                        bail!("Could not list entries of 7z archive: {}", e);
+                    }
+                } else {
+                    for entry in archive.archive().files.iter() {
+                        if !entry.is_directory() {
+                            self.add(prefix, seven_zip_entry(entry), None, depth)?;
+                        }
+                    }
+                }
+            }
+            ContainerType::Iso9660 => {
+                for (entry, extent) in list_iso_files(&mut reader)? {
+                    reader.seek(SeekFrom::Start(extent as u64 * ISO_SECTOR_SIZE))?;
+                    let mut contents = self.budgeted((&mut reader).take(entry.size), depth);
+                    self.add(prefix, entry, Some(&mut contents), depth)?;
+                    if self.extracted_all() {
+                        break;
+                    }
+                }
+            }
+            ContainerType::Tar
+            | ContainerType::TarGzip
+            | ContainerType::TarXz
+            | ContainerType::TarZstd
+            | ContainerType::TarBzip2
+            | ContainerType::Mbox
+            | ContainerType::Gzip
+            | ContainerType::Xz
+            | ContainerType::Zstd
+            | ContainerType::Bzip2 => {
+                self.list_stream(container_type, file_name, reader, prefix, depth)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// List the members of an archive that can be read from start to end without seeking.
+    fn list_stream<R: Read>(
+        &mut self,
+        container_type: ContainerType,
+        file_name: &str,
+        reader: R,
+        prefix: &str,
+        depth: u32,
+    ) -> anyhow::Result<()> {
+        match container_type {
+            ContainerType::Tar
+            | ContainerType::TarGzip
+            | ContainerType::TarXz
+            | ContainerType::TarZstd
+            | ContainerType::TarBzip2 => {
+                let reader = self.budgeted(decompressor(container_type, reader)?, depth);
+                self.list_tar(reader, prefix, depth)?;
+            }
+            ContainerType::Mbox => {
+                let reader = io::BufReader::new(self.budgeted(reader, depth));
+                let mut messages = MboxMessages::new(reader, self.max_message_bytes);
+                let mut i = 0;
+                while messages.next_message()? {
+                    i += 1;
+                    let mut head = Vec::new();
+                    (&mut messages)
+                        .take(mail_utils::MAX_HEADER_SIZE)
+                        .read_to_end(&mut head)?;
+                    let mail = mail_utils::parse_headers(&head);
+                    let entry = ArchiveEntry {
+                        // messages have no names, so they are numbered in the order they appear
+                        path: format!("{:06}.eml", i),
+                        modified: mail.as_ref().and_then(|m| m.date.clone()),
+                        mail,
+                        ..Default::default()
+                    };
+                    let files = self.files.len();
+                    self.add_stream(prefix, entry, &mut Cursor::new(head).chain(&mut messages))?;
+                    if messages.truncated() {
+                        log::warn!(
+                            "Skipping message {} of {}: it is more than {} bytes",
+                            i,
+                            prefix.strip_suffix("!/").unwrap_or(file_name),
+                            self.max_message_bytes,
+                        );
+                        self.files.truncate(files);
+                    }
+                }
+            }
+            ContainerType::Gzip => {
+                let mut decoder = GzDecoder::new(reader);
+                // the header is read along with the first byte
+                let mut first = Vec::new();
+                (&mut decoder).take(1).read_to_end(&mut first)?;
+                let header = decoder.header();
+                let name = header
+                    .and_then(|h| h.filename())
+                    .and_then(|name| std::str::from_utf8(name).ok())
+                    .and_then(|name| Path::new(name).file_name())
+                    .and_then(|name| name.to_str())
+                    .map(ToString::to_string)
+                    .unwrap_or_else(|| uncompressed_name(file_name, &[".gz", ".tgz", ".gzip"]));
+                let entry = ArchiveEntry {
+                    path: name,
+                    // `0` means there is no time
+                    modified: header
+                        .map(|h| h.mtime())
+                        .filter(|mtime| *mtime != 0)
+                        .and_then(|mtime| unix_time(mtime.into())),
+                    ..Default::default()
+                };
+                let mut contents = Cursor::new(first).chain(self.budgeted(&mut decoder, depth));
+                self.add_stream(prefix, entry, &mut contents)?;
+            }
+            ContainerType::Xz | ContainerType::Zstd | ContainerType::Bzip2 => {
+                let extensions: &[&str] = match container_type {
+                    ContainerType::Xz => &[".xz"],
+                    ContainerType::Zstd => &[".zst", ".zstd"],
+                    _ => &[".bz2", ".bzip2"],
+                };
+                let mut decoder = self.budgeted(decompressor(container_type, reader)?, depth);
+                let entry = ArchiveEntry {
+                    path: uncompressed_name(file_name, extensions),
+                    ..Default::default()
+                };
+                self.add_stream(prefix, entry, &mut decoder)?;
+            }
+            ContainerType::Zip | ContainerType::SevenZip | ContainerType::Iso9660 => {
+                bail!(
+                    "{} archives can't be listed without seeking",
+                    container_type
+                )
+            }
+        }
+        Ok(())
+    }
+
+    fn list_tar<R: Read>(&mut self, reader: R, prefix: &str, depth: u32) -> anyhow::Result<()> {
+        let mut archive = tar::Archive::new(reader);
+        let entries = match archive.entries() {
+            Ok(entries) => entries,
+            Err(e) => bail!("Could not list entries of archive: {}", e),
+        };
 
-    let mut has_error = false;
-    let mut data = Vec::new();
-    for entry in entries {
-        let entry = entry?;
-        let path = entry.path()?;
-        match path.to_str() {
-            Some(path) => {
-                if !has_error {
-                    data.push((path.to_string(), entry.size()));
+        let mut has_error = false;
+        for entry in entries {
+            let mut entry = entry?;
+            let path = entry.path()?;
+            match path.to_str() {
+                Some(path) => {
+                    if !has_error {
+                        let path = path.to_string();
+                        let header = entry.header();
+                        let entry_type = match header.entry_type() {
+                            tar::EntryType::Regular
+                            | tar::EntryType::Continuous
+                            | tar::EntryType::GNUSparse => EntryType::File,
+                            tar::EntryType::Directory => EntryType::Directory,
+                            tar::EntryType::Symlink => EntryType::Symlink,
+                            tar::EntryType::Link => EntryType::Hardlink,
+                            _ => EntryType::Other,
+                        };
+                        let tar_entry = ArchiveEntry {
+                            path,
+                            size: entry.size(),
+                            entry_type,
+                            link_target: entry
+                                .link_name()?
+                                .and_then(|target| target.to_str().map(ToString::to_string)),
+                            modified: header
+                                .mtime()
+                                .ok()
+                                .and_then(|mtime| mtime.try_into().ok())
+                                .and_then(unix_time),
+                            ..Default::default()
+                        };
+                        let contents =
+                            (entry_type == EntryType::File).then_some(&mut entry as &mut dyn Read);
+                        self.add(prefix, tar_entry, contents, depth)?;
+                    }
                 }
+                None => {
+                    log::error!("Path was not UTF-8: {}", path.to_string_lossy());
+                    has_error = true;
+                }
+            }
+            // the rest of the archive doesn't need to be read
+            if self.extracted_all() {
+                break;
             }
+        }
+
+        if has_error {
+            bail!("Some paths in the tar file were not UTF-8. See logs for details.")
+        }
+        Ok(())
+    }
+
+    /// Add a member. If `contents` are given they are hashed, and the members are added too if
+    /// it's an archive. Members that can't be read are added without hashes.
+    fn add(
+        &mut self,
+        prefix: &str,
+        mut entry: ArchiveEntry,
+        contents: Option<&mut dyn Read>,
+        depth: u32,
+    ) -> anyhow::Result<()> {
+        let path = member_path(&entry.path);
+        if path.is_empty() {
+            return Ok(());
+        }
+        let path = format!("{}{}", prefix, path);
+        entry.path = path.clone();
+        if let Some(extract) = self.extract {
+            if let Some(dest) = extract.get(&path) {
+                if let Some(contents) = contents {
+                    self.extract_contents(entry, contents, dest)?;
+                }
+                return Ok(());
+            }
+            // only the nested archives with members to extract need to be read
+            let nested_prefix = format!("{}!/", path);
+            if !extract.keys().any(|p| p.starts_with(&nested_prefix)) {
+                return Ok(());
+            }
+        }
+        let contents = match contents {
+            Some(contents) => contents,
+            None => {
+                self.files.push(entry);
+                return Ok(());
+            }
+        };
+
+        // enough to find the magic of every container type
+        let mut data = Vec::new();
+        if let Err(e) = contents
+            .take(ISO_FIRST_DESCRIPTOR * ISO_SECTOR_SIZE + 6)
+            .read_to_end(&mut data)
+        {
+            log::warn!("Could not read {}: {}", path, e);
+            self.files.push(entry);
+            return Ok(());
+        }
+
+        let container_type = match self.nested_type(&path, entry.size, &data, depth) {
+            Some(container_type) => container_type,
             None => {
-                log::error!("Path was not UTF-8: {}", path.to_string_lossy());
-                has_error = true;
+                if !self.hash_algorithms.is_empty() {
+                    match self.hash_contents(Cursor::new(data).chain(contents)) {
+                        Ok((_, hashes)) => entry.hashes = hashes,
+                        Err(e) => log::warn!("Could not hash {}: {}", path, e),
+                    }
+                }
+                self.files.push(entry);
+                return Ok(());
             }
+        };
+
+        let budget = self.budget.get();
+        let res = contents
+            .take(entry.size.saturating_sub(data.len() as u64))
+            .read_to_end(&mut data);
+        self.budget.set(budget.saturating_sub(data.len() as u64));
+        if let Err(e) = res {
+            log::warn!("Could not read {}: {}", path, e);
+            self.files.push(entry);
+            return Ok(());
+        }
+        entry.hashes = self.hash_contents(&data[..])?.1;
+        self.files.push(entry);
+
+        let file_name = path.rsplit('/').next().unwrap_or(&path);
+        let prefix = format!("{}!/", path);
+        if let Err(e) = self.list(
+            container_type,
+            file_name,
+            Cursor::new(data),
+            &prefix,
+            depth + 1,
+        ) {
+            // nested archives are best effort, and the members found so far are kept
+            log::warn!("Could not list all members of {}: {}", path, e);
         }
+        Ok(())
     }
 
-    if has_error {
-        bail!("Some paths in the tar file were not UTF-8. See logs for details.")
+    /// Add a member whose size isn't known until its contents have been read, hashing them or
+    /// extracting them.
+    fn add_stream(
+        &mut self,
+        prefix: &str,
+        mut entry: ArchiveEntry,
+        contents: &mut dyn Read,
+    ) -> anyhow::Result<()> {
+        let path = member_path(&entry.path);
+        if path.is_empty() {
+            return Ok(());
+        }
+        entry.path = format!("{}{}", prefix, path);
+        match self.extract {
+            Some(extract) => {
+                if let Some(dest) = extract.get(&entry.path) {
+                    self.extract_contents(entry, contents, dest)?;
+                }
+            }
+            None => {
+                let (size, hashes) = self.hash_contents(contents)?;
+                entry.size = size;
+                entry.hashes = hashes;
+                self.files.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the contents of a member to `dest`, hashing them as they are written
+    fn extract_contents(
+        &mut self,
+        mut entry: ArchiveEntry,
+        contents: &mut dyn Read,
+        dest: &Path,
+    ) -> anyhow::Result<()> {
+        log::info!("Extracting {} to {}", entry.path, dest.to_string_lossy());
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let out = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dest)?;
+        let (size, hashes) = self.hash_contents(TeeReader::new(contents, out))?;
+        entry.size = size;
+        entry.hashes = hashes;
+        self.files.push(entry);
+        self.extracted += 1;
+        Ok(())
+    }
+
+    /// Whether every member to extract has been written
+    fn extracted_all(&self) -> bool {
+        self.extract
+            .is_some_and(|extract| self.extracted >= extract.len())
+    }
+
+    /// The type of a member that should be listed as a nested archive given its first bytes
+    fn nested_type(&self, path: &str, size: u64, data: &[u8], depth: u32) -> Option<ContainerType> {
+        if depth >= self.max_depth {
+            return None;
+        }
+        let container_type = ContainerType::detect(&mut Cursor::new(data)).ok()??;
+        let budget = self.budget.get();
+        if size > budget {
+            log::warn!(
+                "Not listing the members of {}: it is {} bytes but only {} bytes are left to list nested archives",
+                path,
+                size,
+                budget,
+            );
+            return None;
+        }
+        Some(container_type)
+    }
+
+    /// The size and hashes of the contents
+    fn hash_contents<R: Read>(&self, reader: R) -> anyhow::Result<(u64, Hashes)> {
+        let mut reader = CountingReader {
+            reader,
+            bytes_read: 0,
+        };
+        let hashes = make_hashes(&mut reader, self.hash_algorithms)?;
+        // nothing is read when there are no hash algorithms
+        io::copy(&mut reader, &mut io::sink())?;
+        let mut hashes = hashes.into_iter().map(|(a, v)| (*a, v)).collect::<Vec<_>>();
+        hashes.sort_by_key(|(a, _)| a.to_string());
+        Ok((reader.bytes_read, hashes))
+    }
+
+    /// Charge what is read from nested archives to the budget. Every read at `depth > 0` goes
+    /// through this, whether it's the archive stream or the contents of one of its members.
+    fn budgeted<'r, R: Read + 'r>(&self, reader: R, depth: u32) -> Box<dyn Read + 'r> {
+        if depth == 0 {
+            Box::new(reader)
+        } else {
+            Box::new(BudgetReader {
+                reader,
+                budget: Rc::clone(&self.budget),
+            })
+        }
+    }
+}
+
+fn seven_zip_entry(entry: &SevenZArchiveEntry) -> ArchiveEntry {
+    // archives made on Unix keep the mode in the high bits of the attributes
+    let mode = (entry.has_windows_attributes && entry.windows_attributes & 0x8000 != 0)
+        .then_some(entry.windows_attributes >> 16);
+    ArchiveEntry {
+        path: entry.name().to_string(),
+        size: entry.size(),
+        entry_type: if mode.map(|m| m & S_IFMT) == Some(S_IFLNK) {
+            EntryType::Symlink
+        } else {
+            EntryType::File
+        },
+        modified: entry
+            .has_last_modified_date
+            .then(|| unix_time(entry.last_modified_date.to_unix_time()))
+            .flatten(),
+        crc32: entry.has_crc.then_some(entry.crc as u32),
+        ..Default::default()
+    }
+}
+
+/// A reader that counts the bytes read through it.
+struct CountingReader<R> {
+    reader: R,
+    bytes_read: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.reader.read(buf)?;
+        self.bytes_read += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+/// A reader that fails once more than the remaining budget has been read.
+struct BudgetReader<R> {
+    reader: R,
+    budget: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for BudgetReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.reader.read(buf)?;
+        match self.budget.get().checked_sub(bytes_read as u64) {
+            Some(left) => {
+                self.budget.set(left);
+                Ok(bytes_read)
+            }
+            None => Err(io::Error::other(
+                "Ran out of the budget to list nested archives",
+            )),
+        }
     }
-    Ok(data)
 }
 
-fn list_zip_files<R: Read + Seek>(reader: R) -> anyhow::Result<Vec<(String, u64)>> {
-    let mut zip = zip::ZipArchive::new(reader)?;
-    let mut data = Vec::new();
-    for i in 0..zip.len() {
-        let file = zip.by_index(i)?;
-        data.push((file.name().to_string(), file.size()));
+/// List the files in an ISO 9660 image, using the Joliet names when the image has them. Rock
+/// Ridge extensions are ignored.
+/// Returns the files and their first sectors.
+fn list_iso_files<R: Read + Seek>(mut reader: R) -> anyhow::Result<Vec<(ArchiveEntry, u32)>> {
+    let mut root = None;
+    let mut joliet = false;
+    // there are only ever a handful of volume descriptors, but don't trust a corrupt image
+    for sector in ISO_FIRST_DESCRIPTOR..ISO_FIRST_DESCRIPTOR + 32 {
+        let descriptor = read_iso_extent(&mut reader, sector as u32, ISO_SECTOR_SIZE as u32)?;
+        if descriptor.len() < ISO_SECTOR_SIZE as usize || &descriptor[1..6] != b"CD001" {
+            bail!("Invalid ISO 9660 volume descriptor in sector {}", sector);
+        }
+        match descriptor[0] {
+            // primary volume descriptor
+            1 if root.is_none() => root = Some(IsoRecord::parse(&descriptor[156..190], false)?),
+            // supplementary volume descriptor with a Joliet escape sequence
+            2 if matches!(&descriptor[88..91], b"%/@" | b"%/C" | b"%/E") => {
+                root = Some(IsoRecord::parse(&descriptor[156..190], true)?);
+                joliet = true;
+            }
+            // volume descriptor set terminator
+            255 => break,
+            _ => (),
+        }
     }
+    let root = root.ok_or_else(|| anyhow!("ISO 9660 image has no primary volume descriptor"))?;
+
+    let mut files = Vec::<(ArchiveEntry, u32)>::new();
+    let mut visited = HashSet::new();
+    let mut dirs = vec![(String::new(), root)];
+    while let Some((dir_path, dir)) = dirs.pop() {
+        if !visited.insert(dir.extent) {
+            bail!("ISO 9660 directory at sector {} is a loop", dir.extent);
+        }
+        if dir.size > ISO_MAX_DIR_SIZE {
+            bail!("ISO 9660 directory {:?} is too large", dir_path);
+        }
+        let data = read_iso_extent(&mut reader, dir.extent, dir.size)?;
+        let mut pos = 0;
+        while pos < data.len() {
+            let len = data[pos] as usize;
+            if len == 0 {
+                // records don't cross sector boundaries, so the rest of the sector is padding
+                pos = (pos / ISO_SECTOR_SIZE as usize + 1) * ISO_SECTOR_SIZE as usize;
+                continue;
+            }
+            let record = data
+                .get(pos..pos + len)
+                .ok_or_else(|| anyhow!("Truncated ISO 9660 directory {:?}", dir_path))?;
+            pos += len;
+
+            let record = IsoRecord::parse(record, joliet)?;
+            // the first two records are the directory itself and its parent
+            if record.name.is_empty() {
+                continue;
+            }
+            let path = format!("{}/{}", dir_path, record.name);
+            if record.is_dir {
+                dirs.push((path, record));
+            } else {
+                match files.last_mut() {
+                    // files bigger than 4 GiB are split across consecutive records
+                    Some((last, _)) if last.path == path => last.size += record.size as u64,
+                    _ => files.push((
+                        ArchiveEntry {
+                            path,
+                            size: record.size as u64,
+                            modified: record.modified,
+                            ..Default::default()
+                        },
+                        record.extent,
+                    )),
+                }
+            }
+        }
+    }
+    files.sort_by(|(a, _), (b, _)| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+fn read_iso_extent<R: Read + Seek>(
+    reader: &mut R,
+    sector: u32,
+    size: u32,
+) -> anyhow::Result<Vec<u8>> {
+    reader.seek(SeekFrom::Start(sector as u64 * ISO_SECTOR_SIZE))?;
+    let mut data = Vec::with_capacity(size as usize);
+    reader.take(size as u64).read_to_end(&mut data)?;
     Ok(data)
 }
+
+/// A directory record in an ISO 9660 image
+struct IsoRecord {
+    extent: u32,
+    size: u32,
+    is_dir: bool,
+    name: String,
+    modified: Option<Timestamp>,
+}
+
+impl IsoRecord {
+    fn parse(record: &[u8], joliet: bool) -> anyhow::Result<Self> {
+        let name_len = *record.get(32).unwrap_or(&0) as usize;
+        let raw_name = match record.get(33..33 + name_len) {
+            Some(name) if record.len() >= 34 => name,
+            _ => bail!("Invalid ISO 9660 directory record"),
+        };
+        let is_dir = record[25] & 0x02 != 0;
+        let name = match raw_name {
+            [0] | [1] => String::new(),
+            _ if joliet => {
+                let chars = raw_name
+                    .chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect::<Vec<_>>();
+                String::from_utf16(&chars)?
+            }
+            _ => String::from_utf8(raw_name.to_vec())?,
+        };
+        // drop the version number, and the `.` of files without an extension
+        let mut name = name.split(';').next().unwrap_or_default().to_string();
+        if !is_dir && name.ends_with('.') {
+            name.pop();
+        }
+        Ok(Self {
+            // unwraps ok because the record is at least 34 bytes long
+            extent: u32::from_le_bytes(record[2..6].try_into().unwrap()),
+            size: u32::from_le_bytes(record[10..14].try_into().unwrap()),
+            is_dir,
+            name,
+            modified: iso_time(&record[18..25]),
+        })
+    }
+}
+
+/// A directory record's 7 byte recording time: the years since 1900, month, day, hour, minute,
+/// second, and the offset from UTC in 15 minute intervals.
+fn iso_time(time: &[u8]) -> Option<Timestamp> {
+    let date = NaiveDate::from_ymd_opt(1900 + time[0] as i32, time[1].into(), time[2].into())?;
+    let date_time = date.and_hms_opt(time[3].into(), time[4].into(), time[5].into())?;
+    let offset = chrono::Duration::minutes(time[6] as i8 as i64 * 15);
+    Some(Timestamp::from(
+        Utc.from_utc_datetime(&(date_time - offset)),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::fixtures;
+    use bzip2::write::BzEncoder;
+    use flate2::write::GzEncoder;
+    use flate2::{Compression, GzBuilder};
+    use sevenz_rust::{SevenZArchiveEntry, SevenZWriter};
+    use std::io::Write;
+
+    const LIMITS: NestingLimits = NestingLimits {
+        max_depth: 3,
+        max_bytes: 1024 * 1024,
+        max_message_bytes: 1024,
+    };
+
+    fn tar_bytes() -> Vec<u8> {
+        fixtures::tar(&[("./a.txt", b"a"), ("docs/b.txt", b"bb")])
+    }
+
+    #[test]
+    fn container_type_round_trip() {
+        for ct in [
+            ContainerType::TarBzip2,
+            ContainerType::Zip,
+            ContainerType::Gzip,
+        ] {
+            assert_eq!(ContainerType::try_from(&*ct.to_string()).unwrap(), ct);
+        }
+        assert!(ContainerType::try_from("rar").is_err());
+    }
+
+    fn detect_and_list<R: Read + Seek>(
+        file_name: &str,
+        mut reader: R,
+    ) -> (Option<ContainerType>, Vec<(String, u64)>) {
+        let ct = ContainerType::detect(&mut reader).unwrap();
+        let files = match ct {
+            Some(ct) => list_files(ct, file_name, reader, &LIMITS, &[]).unwrap(),
+            None => Vec::new(),
+        };
+        (ct, files.into_iter().map(|e| (e.path, e.size)).collect())
+    }
+
+    #[test]
+    fn detect_by_magic() {
+        let tar = tar_bytes();
+        let (ct, files) = detect_and_list("a.tar", Cursor::new(&tar));
+        assert_eq!(ct, Some(ContainerType::Tar));
+        assert_eq!(
+            files,
+            vec![("a.txt".to_string(), 1), ("docs/b.txt".to_string(), 2)]
+        );
+
+        // the name doesn't matter
+        let mut gz = GzEncoder::new(Vec::new(), Default::default());
+        gz.write_all(&tar).unwrap();
+        let (ct, files) = detect_and_list("a.bin", Cursor::new(gz.finish().unwrap()));
+        assert_eq!(ct, Some(ContainerType::TarGzip));
+        assert_eq!(files.len(), 2);
+
+        let mut bz = BzEncoder::new(Vec::new(), Default::default());
+        bz.write_all(&tar).unwrap();
+        let (ct, files) = detect_and_list("a.tar.bz2", Cursor::new(bz.finish().unwrap()));
+        assert_eq!(ct, Some(ContainerType::TarBzip2));
+        assert_eq!(files.len(), 2);
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("c.txt", Default::default()).unwrap();
+        zip.write_all(b"ccc").unwrap();
+        let (ct, files) = detect_and_list("a.ZIP", zip.finish().unwrap());
+        assert_eq!(ct, Some(ContainerType::Zip));
+        assert_eq!(files, vec![("c.txt".to_string(), 3)]);
+
+        let (ct, files) = detect_and_list("a.zip", Cursor::new(b"not an archive"));
+        assert_eq!(ct, None);
+        assert!(files.is_empty());
+    }
+
+    #[test]
+    fn members_listed_twice() {
+        // the last listing wins, including the members of a nested archive
+        let old_zip = zip_bytes(&[("old.txt", b"old")]);
+        let new_zip = zip_bytes(&[("new.txt", b"new")]);
+        let tar = fixtures::tar(&[
+            ("./a.txt", b"a"),
+            ("c.zip", &old_zip),
+            ("a.txt", b"aa"),
+            ("./c.zip", &new_zip),
+        ]);
+        let (_, files) = detect_and_list("a.tar", Cursor::new(tar));
+        assert_eq!(
+            files,
+            vec![
+                ("a.txt".to_string(), 2),
+                ("c.zip".to_string(), new_zip.len() as u64),
+                ("c.zip!/new.txt".to_string(), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn compressed_files() {
+        // gzip headers can have the original name
+        let mut gz = GzBuilder::new()
+            .filename("dump.sql")
+            .write(Vec::new(), Default::default());
+        gz.write_all(b"just some text").unwrap();
+        let (ct, files) = detect_and_list("renamed.gz", Cursor::new(gz.finish().unwrap()));
+        assert_eq!(ct, Some(ContainerType::Gzip));
+        assert_eq!(files, vec![("dump.sql".to_string(), 14)]);
+
+        let mut gz = GzEncoder::new(Vec::new(), Default::default());
+        gz.write_all(b"just some text").unwrap();
+        let (_, files) = detect_and_list("dump.sql.GZ", Cursor::new(gz.finish().unwrap()));
+        assert_eq!(files, vec![("dump.sql".to_string(), 14)]);
+
+        let mut bz = BzEncoder::new(Vec::new(), Default::default());
+        bz.write_all(b"more text").unwrap();
+        let (ct, files) = detect_and_list("dump.csv.bz2", Cursor::new(bz.finish().unwrap()));
+        assert_eq!(ct, Some(ContainerType::Bzip2));
+        assert_eq!(files, vec![("dump.csv".to_string(), 9)]);
+
+        assert_eq!(uncompressed_name("a.tgz", &[".gz", ".tgz"]), "a.tar");
+        assert_eq!(uncompressed_name("a", &[".xz"]), "a");
+    }
+
+    #[test]
+    fn stream() {
+        // a nested zip is in memory by then, so it can still be listed
+        let zip = zip_bytes(&[("c.txt", b"ccc")]);
+        let tar = fixtures::tar(&[("a.txt", b"a"), ("c.zip", &zip)]);
+        let mut gz = GzEncoder::new(Vec::new(), Default::default());
+        gz.write_all(&tar).unwrap();
+        let tgz = gz.finish().unwrap();
+
+        let seeked = list_files(
+            ContainerType::TarGzip,
+            "a.tgz",
+            Cursor::new(&tgz),
+            &LIMITS,
+            &[HashAlgorithm::Sha256],
+        )
+        .unwrap();
+        let streamed = list_files_stream(
+            ContainerType::TarGzip,
+            "a.tgz",
+            &tgz[..],
+            &LIMITS,
+            &[HashAlgorithm::Sha256],
+        )
+        .unwrap();
+        assert_eq!(streamed, seeked);
+        assert_eq!(
+            streamed.iter().map(|e| e.path.as_str()).collect::<Vec<_>>(),
+            vec!["a.txt", "c.zip", "c.zip!/c.txt"]
+        );
+
+        assert!(!ContainerType::Zip.is_streamable());
+        assert!(ContainerType::Mbox.is_streamable());
+        assert!(list_files_stream(ContainerType::Zip, "c.zip", &zip[..], &LIMITS, &[]).is_err());
+    }
+
+    #[test]
+    fn mbox_messages() {
+        let mut mbox = concat!(
+            "From a@example.com Tue Jul  1 10:52:37 2003\n",
+            "Subject: one\n",
+            "\n",
+            "body\n",
+            "From b@example.com Tue Jul  1 10:52:38 2003\n",
+            "Subject: too big\n",
+            "\n",
+        )
+        .as_bytes()
+        .to_vec();
+        mbox.extend_from_slice(&[b'x'; 2000]);
+        mbox.extend_from_slice(b"\nFrom c@example.com Tue Jul  1 10:52:39 2003\nSubject: three\n");
+
+        let files = list_files_stream(
+            ContainerType::Mbox,
+            "inbox",
+            &mbox[..],
+            &LIMITS,
+            &[HashAlgorithm::Sha256],
+        )
+        .unwrap();
+        assert_eq!(
+            files
+                .iter()
+                .map(|f| (
+                    f.path.as_str(),
+                    f.size,
+                    f.mail.as_ref().and_then(|m| m.subject.as_deref())
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("000001.eml", 19, Some("one")),
+                ("000003.eml", 15, Some("three"))
+            ]
+        );
+        assert_eq!(
+            files[0].hashes,
+            make_hashes(&b"Subject: one\n\nbody\n"[..], &[HashAlgorithm::Sha256])
+                .unwrap()
+                .into_iter()
+                .map(|(a, v)| (*a, v))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn seven_zip() {
+        let mut writer = SevenZWriter::new(Cursor::new(Vec::new())).unwrap();
+        for (path, contents) in [("a.txt", "a"), ("docs/b.txt", "bb")] {
+            let mut entry = SevenZArchiveEntry::new();
+            entry.name = path.to_string();
+            entry.has_stream = true;
+            writer
+                .push_archive_entry(entry, Some(contents.as_bytes()))
+                .unwrap();
+        }
+        let (ct, files) = detect_and_list("a.7z", writer.finish().unwrap());
+        assert_eq!(ct, Some(ContainerType::SevenZip));
+        assert_eq!(
+            files,
+            vec![("a.txt".to_string(), 1), ("docs/b.txt".to_string(), 2)]
+        );
+    }
+
+    /// A directory record pointing at `extent`
+    fn iso_record(name: &[u8], extent: u32, size: u32, is_dir: bool) -> Vec<u8> {
+        let len = 33 + name.len() + (name.len() + 1) % 2;
+        let mut record = vec![0; len];
+        record[0] = len as u8;
+        record[2..6].copy_from_slice(&extent.to_le_bytes());
+        record[6..10].copy_from_slice(&extent.to_be_bytes());
+        record[10..14].copy_from_slice(&size.to_le_bytes());
+        record[14..18].copy_from_slice(&size.to_be_bytes());
+        record[25] = if is_dir { 0x02 } else { 0 };
+        record[32] = name.len() as u8;
+        record[33..33 + name.len()].copy_from_slice(name);
+        record
+    }
+
+    /// A minimal image with `README.TXT` in the root and `A.PDF` in `DOCS`
+    fn iso_bytes() -> Vec<u8> {
+        let sector = ISO_SECTOR_SIZE as usize;
+        let mut image = vec![0; sector * 22];
+
+        let pvd = &mut image[sector * 16..sector * 17];
+        pvd[0] = 1;
+        pvd[1..6].copy_from_slice(b"CD001");
+        pvd[156..190].copy_from_slice(&iso_record(&[0], 18, ISO_SECTOR_SIZE as u32, true));
+        let terminator = &mut image[sector * 17..sector * 18];
+        terminator[0] = 255;
+        terminator[1..6].copy_from_slice(b"CD001");
+
+        let dirs = [
+            (
+                18,
+                vec![
+                    iso_record(&[0], 18, 2048, true),
+                    iso_record(&[1], 18, 2048, true),
+                    iso_record(b"DOCS", 19, 2048, true),
+                    {
+                        // 2001-02-03 04:05:06 at UTC+1
+                        let mut record = iso_record(b"README.TXT;1", 20, 5, false);
+                        record[18..25].copy_from_slice(&[101, 2, 3, 4, 5, 6, 4]);
+                        record
+                    },
+                ],
+            ),
+            (
+                19,
+                vec![
+                    iso_record(&[0], 19, 2048, true),
+                    iso_record(&[1], 18, 2048, true),
+                    iso_record(b"A.PDF;1", 21, 3, false),
+                ],
+            ),
+        ];
+        for (extent, records) in dirs {
+            let records = records.concat();
+            image[sector * extent..sector * extent + records.len()].copy_from_slice(&records);
+        }
+        image
+    }
+
+    #[test]
+    fn iso_image() {
+        let (ct, files) = detect_and_list("a.bin", Cursor::new(iso_bytes()));
+        assert_eq!(ct, Some(ContainerType::Iso9660));
+        assert_eq!(
+            files,
+            vec![("DOCS/A.PDF".to_string(), 3), ("README.TXT".to_string(), 5)]
+        );
+
+        // a directory that contains itself, right after the records of `DOCS`
+        let mut image = iso_bytes();
+        let record = iso_record(b"LOOP", 18, 2048, true);
+        let offset = ISO_SECTOR_SIZE as usize * 19 + 34 + 34 + 40;
+        image[offset..offset + record.len()].copy_from_slice(&record);
+        let mut reader = Cursor::new(image);
+        assert!(list_files(ContainerType::Iso9660, "a.iso", &mut reader, &LIMITS, &[]).is_err());
+    }
+
+    fn zip_bytes(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (path, contents) in files {
+            zip.start_file(*path, Default::default()).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn nested_archives() {
+        // a zip of a tarball of a zip
+        let innermost = zip_bytes(&[("doc.pdf", b"pdf")]);
+        let mut gz = GzEncoder::new(Vec::new(), Default::default());
+        gz.write_all(&fixtures::tar(&[("docs.zip", &innermost)]))
+            .unwrap();
+        let tarball = gz.finish().unwrap();
+        let outer = zip_bytes(&[("inner.tar.gz", &tarball), ("x.txt", b"x")]);
+
+        let (_, files) = detect_and_list("outer.zip", Cursor::new(&outer));
+        assert_eq!(
+            files.iter().map(|(p, _)| p.as_str()).collect::<Vec<_>>(),
+            vec![
+                "inner.tar.gz",
+                "inner.tar.gz!/docs.zip",
+                "inner.tar.gz!/docs.zip!/doc.pdf",
+                "x.txt"
+            ]
+        );
+
+        let limits = NestingLimits {
+            max_depth: 1,
+            ..LIMITS
+        };
+        let files = list_files(
+            ContainerType::Zip,
+            "outer.zip",
+            Cursor::new(&outer),
+            &limits,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(files.len(), 3);
+
+        // nested archives that don't fit in the budget are listed as plain members
+        let limits = NestingLimits {
+            max_bytes: 10,
+            ..LIMITS
+        };
+        let files = list_files(
+            ContainerType::Zip,
+            "outer.zip",
+            Cursor::new(&outer),
+            &limits,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(files.len(), 2);
+    }
+
+    #[test]
+    fn nested_bomb() {
+        // a small zip of a gzip that decompresses to far more than the budget
+        let mut gz = GzEncoder::new(Vec::new(), Compression::best());
+        gz.write_all(&vec![0; 16 * 1024 * 1024]).unwrap();
+        let bomb = zip_bytes(&[("bomb.gz", &gz.finish().unwrap())]);
+        let files = list_files(
+            ContainerType::Zip,
+            "bomb.zip",
+            Cursor::new(&bomb),
+            &LIMITS,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(files.len(), 1);
+    }
+
+    #[test]
+    fn nested_zip_bomb() {
+        // a small zip in a tarball with a member that decompresses to far more than the budget
+        let bomb = zip_bytes(&[("zeros.bin", &vec![0; 16 * 1024 * 1024]), ("x.txt", b"x")]);
+        assert!((bomb.len() as u64) < LIMITS.max_bytes);
+        let tar = fixtures::tar(&[("bomb.zip", &bomb)]);
+        let files = list_files(
+            ContainerType::Tar,
+            "bomb.tar",
+            Cursor::new(&tar),
+            &LIMITS,
+            &[HashAlgorithm::Sha256],
+        )
+        .unwrap();
+        assert_eq!(
+            files
+                .iter()
+                .map(|f| (f.path.as_str(), f.hashes.is_empty()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("bomb.zip", false),
+                ("bomb.zip!/zeros.bin", true),
+                ("bomb.zip!/x.txt", false)
+            ]
+        );
+    }
+
+    #[test]
+    fn member_hashes() {
+        let sha256 = |data: &[u8]| {
+            make_hashes(data, &[HashAlgorithm::Sha256])
+                .unwrap()
+                .remove(&HashAlgorithm::Sha256)
+                .unwrap()
+        };
+        let inner = zip_bytes(&[("doc.pdf", b"pdf")]);
+        let tar = fixtures::tar(&[("docs.zip", &inner), ("a.txt", b"aaa")]);
+        let files = list_files(
+            ContainerType::Tar,
+            "a.tar",
+            Cursor::new(&tar),
+            &LIMITS,
+            &[HashAlgorithm::Sha256],
+        )
+        .unwrap();
+        assert_eq!(
+            files
+                .iter()
+                .map(|f| (f.path.as_str(), f.hashes.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("docs.zip", vec![(HashAlgorithm::Sha256, sha256(&inner))]),
+                (
+                    "docs.zip!/doc.pdf",
+                    vec![(HashAlgorithm::Sha256, sha256(b"pdf"))]
+                ),
+                ("a.txt", vec![(HashAlgorithm::Sha256, sha256(b"aaa"))]),
+            ]
+        );
+
+        let mut gz = GzEncoder::new(Vec::new(), Default::default());
+        gz.write_all(b"aaa").unwrap();
+        let files = list_files(
+            ContainerType::Gzip,
+            "a.txt.gz",
+            Cursor::new(gz.finish().unwrap()),
+            &LIMITS,
+            &[HashAlgorithm::Sha256],
+        )
+        .unwrap();
+        assert_eq!(
+            files[0].hashes,
+            vec![(HashAlgorithm::Sha256, sha256(b"aaa"))]
+        );
+    }
+
+    #[test]
+    fn extract() {
+        let inner = zip_bytes(&[("doc.pdf", b"pdf"), ("other.pdf", b"other")]);
+        let tar = fixtures::tar(&[("docs.zip", &inner), ("a.txt", b"aaa"), ("b.txt", b"b")]);
+        let mut gz = GzEncoder::new(Vec::new(), Default::default());
+        gz.write_all(&tar).unwrap();
+        let tarball = gz.finish().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let members = HashMap::from([
+            ("a.txt".to_string(), dir.path().join("a.txt")),
+            (
+                "docs.zip!/doc.pdf".to_string(),
+                dir.path().join("docs/doc.pdf"),
+            ),
+            ("missing.txt".to_string(), dir.path().join("missing.txt")),
+        ]);
+        let mut files = extract_files(
+            ContainerType::TarGzip,
+            "a.tar.gz",
+            Cursor::new(&tarball),
+            &LIMITS,
+            &[HashAlgorithm::Sha256],
+            &members,
+        )
+        .unwrap();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(
+            files
+                .iter()
+                .map(|f| (f.path.as_str(), f.size, f.hashes.len()))
+                .collect::<Vec<_>>(),
+            vec![("a.txt", 3, 1), ("docs.zip!/doc.pdf", 3, 1)]
+        );
+        assert_eq!(fs::read(dir.path().join("a.txt")).unwrap(), b"aaa");
+        assert_eq!(fs::read(dir.path().join("docs/doc.pdf")).unwrap(), b"pdf");
+        assert!(!dir.path().join("b.txt").exists());
+        assert!(!dir.path().join("missing.txt").exists());
+
+        // existing files are not overwritten
+        assert!(extract_files(
+            ContainerType::TarGzip,
+            "a.tar.gz",
+            Cursor::new(&tarball),
+            &LIMITS,
+            &[],
+            &members,
+        )
+        .is_err());
+
+        let mut gz = GzBuilder::new()
+            .filename("c.txt")
+            .write(Vec::new(), Default::default());
+        gz.write_all(b"ccc").unwrap();
+        let members = HashMap::from([("c.txt".to_string(), dir.path().join("c.txt"))]);
+        let files = extract_files(
+            ContainerType::Gzip,
+            "c.gz",
+            Cursor::new(gz.finish().unwrap()),
+            &LIMITS,
+            &[],
+            &members,
+        )
+        .unwrap();
+        assert_eq!(files[0].size, 3);
+        assert_eq!(fs::read(dir.path().join("c.txt")).unwrap(), b"ccc");
+
+        // the tarball isn't read past the last member to extract
+        let mut tar = fixtures::tar(&[("d.txt", b"ddd"), ("e.txt", b"e")]);
+        // corrupt the checksum of the header of e.txt
+        tar[1024 + 148] = b'x';
+        let members = HashMap::from([("d.txt".to_string(), dir.path().join("d.txt"))]);
+        let files = extract_files(
+            ContainerType::Tar,
+            "d.tar",
+            Cursor::new(&tar),
+            &LIMITS,
+            &[],
+            &members,
+        )
+        .unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(fs::read(dir.path().join("d.txt")).unwrap(), b"ddd");
+        let members = HashMap::from([("e.txt".to_string(), dir.path().join("e.txt"))]);
+        assert!(extract_files(
+            ContainerType::Tar,
+            "d.tar",
+            Cursor::new(&tar),
+            &LIMITS,
+            &[],
+            &members,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn member_metadata() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(3);
+        header.set_mtime(1_600_000_000);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "a.txt", &b"aaa"[..])
+            .unwrap();
+        for (path, target, entry_type) in [
+            ("soft", "a.txt", tar::EntryType::Symlink),
+            ("hard", "a.txt", tar::EntryType::Link),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_size(0);
+            builder.append_link(&mut header, path, target).unwrap();
+        }
+        let tar = builder.into_inner().unwrap();
+        let files =
+            list_files(ContainerType::Tar, "a.tar", Cursor::new(tar), &LIMITS, &[]).unwrap();
+        assert_eq!(
+            files[0],
+            ArchiveEntry {
+                path: "a.txt".to_string(),
+                size: 3,
+                modified: unix_time(1_600_000_000),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            files[0].modified.as_ref().unwrap().to_string(),
+            "2020-09-13T12:26:40.000Z"
+        );
+        assert_eq!(files[1].entry_type, EntryType::Symlink);
+        assert_eq!(files[1].link_target.as_deref(), Some("a.txt"));
+        assert_eq!(files[2].entry_type, EntryType::Hardlink);
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default()
+            .last_modified_time(zip::DateTime::from_date_and_time(2019, 5, 4, 3, 2, 0).unwrap());
+        zip.start_file("secret.txt", options).unwrap();
+        zip.write_all(b"secret secret secret").unwrap();
+        zip.start_file("link", options).unwrap();
+        zip.write_all(b"secret.txt").unwrap();
+        let mut zip = zip.finish().unwrap().into_inner();
+        // the zip writer can't make symlinks, so set the mode of the second entry in its central
+        // directory header
+        let centrals = zip
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| *w == b"PK\x01\x02")
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        zip[centrals[1] + 38..centrals[1] + 42].copy_from_slice(&(0o120777u32 << 16).to_le_bytes());
+        let files =
+            list_files(ContainerType::Zip, "a.zip", Cursor::new(&zip), &LIMITS, &[]).unwrap();
+        assert_eq!(files[0].crc32, Some(0x1557c36c));
+        assert!(files[0].compressed_size.unwrap() > 0);
+        assert_eq!(
+            files[0].modified.as_ref().unwrap().to_string(),
+            "2019-05-04T03:02:00.000Z"
+        );
+        assert!(!files[0].encrypted);
+        assert_eq!(files[1].entry_type, EntryType::Symlink);
+        assert_eq!(files[1].link_target.as_deref(), Some("secret.txt"));
+
+        // and set the encryption flag of the first entry
+        zip[centrals[0] + 8] |= 0x01;
+        let files =
+            list_files(ContainerType::Zip, "a.zip", Cursor::new(&zip), &LIMITS, &[]).unwrap();
+        assert!(files[0].encrypted);
+        assert_eq!(files[0].size, 20);
+
+        let iso = iso_bytes();
+        let files = list_files(
+            ContainerType::Iso9660,
+            "a.iso",
+            Cursor::new(iso),
+            &LIMITS,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            files[1].modified.as_ref().unwrap().to_string(),
+            "2001-02-03T03:05:06.000Z"
+        );
+    }
+}
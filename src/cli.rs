@@ -20,6 +20,11 @@
 //! hoard collection add my-leaks
 //! ```
 //!
+//! Require that every file in the collection be stored on at least two disks.
+//! ```shell
+//! hoard collection set my-leaks --min-copies 2
+//! ```
+//!
 //! Add a DB entry for a location where you store hard disks.
 //! ```shell
 //! hoard location add my-home
@@ -35,11 +40,43 @@
 //! hoard partition add /dev/sdb1
 //! ```
 //!
+//! Use a plain directory (e.g., an NFS share) as a partition of a disk without a block device.
+//! ```shell
+//! hoard disk add --location my-home --label "my NAS" --serial-number nas-01
+//! hoard partition add --disk "my NAS" --dir /mnt/nas/share
+//! ```
+//!
 //! Add a local file to the virtual "file system" of the disk pool.
 //! ```shell
 //! hoard file add --collection my-leaks /local/path/to/my/file.txt /some-dir/file.txt
 //! ```
 //!
+//! Add a whole local directory, skipping some files. Re-running the command after an interruption
+//! skips the files that were already added.
+//! ```shell
+//! hoard file add --collection my-leaks -r --exclude '*.tmp' /local/path/to/dir /some-dir
+//! ```
+//!
+//! Fill one disk before placing files on the next one instead of placing each file on the disk
+//! with the most free space.
+//! ```shell
+//! hoard file add --collection my-leaks --strategy fill-first /local/path/to/file.txt /file.txt
+//! ```
+//!
+//! Keep the files under a directory on as few disks as possible, and see how they are spread
+//! across disks now.
+//! ```shell
+//! hoard collection affinity add --collection my-leaks /some-dir
+//! hoard placement-plan --collection my-leaks /some-dir
+//! ```
+//!
+//! Add a file that may have been released before under another name without storing its content
+//! twice, and list the files with duplicate content.
+//! ```shell
+//! hoard file add --collection my-leaks --dedup /local/path/to/file.txt /other-name.txt
+//! hoard dedup report
+//! ```
+//!
 //! Run an `ls`-like command against the virtual "file system" (works offline).
 //! ```shell
 //! hoard file ls --collection my-leaks /some-dir/
@@ -50,21 +87,72 @@
 //! hoard file find --collection my-leaks /
 //! ```
 //!
+//! Rename a file or directory. Copies on partitions that aren't mounted are renamed the next time
+//! the partition is mounted and files are added, moved, or removed.
+//! ```shell
+//! hoard file mv --collection my-leaks /some-dir/file.txt /some-dir/better-name.txt
+//! ```
+//!
+//! Remove a directory of files and delete their copies. Copies on partitions that aren't mounted
+//! are deleted the next time the partition is mounted and files are added, moved, or removed.
+//! ```shell
+//! hoard file rm --collection my-leaks -r /some-dir
+//! ```
+//!
+//! Apply the queued renames and deletions to the mounted partitions without changing anything
+//! else.
+//! ```shell
+//! hoard apply
+//! ```
+//!
+//! Block files from being removed while a collection is under legal hold.
+//! ```shell
+//! hoard collection set my-leaks --legal-hold true
+//! ```
+//!
+//! Search the members of the archives in a directory, and list the members of one archive.
+//! ```shell
+//! hoard file find --collection my-leaks --in-archives --name '\.eml$' /some-dir/
+//! hoard file inspect --collection my-leaks --members /some-dir/mail.tar.gz
+//! ```
+//!
 //! Inspect a file and get information about where it's located.
 //! ```shell
 //! hoard file inspect --collection my-leaks /some-dir/file.txt
 //! ```
 //!
+//! Print a checklist of the disks to fetch from each location to retrieve a set of files.
+//! ```shell
+//! hoard file plan --collection my-leaks --name '\.pdf$' /some-dir/
+//! ```
+//!
 //! Get the path to the file on the local file system if one of the partition is mounted.
 //! ```shell
 //! hoard file path --collection my-leaks /some-dir/file.txt
 //! ```
+//!
+//! Copy a file out of the pool from any mounted partition that has a good copy of it. If none
+//! are mounted, the disks that have copies are listed.
+//! ```shell
+//! hoard file get --collection my-leaks /some-dir/file.txt /local/path/to/file.txt
+//! ```
+//!
+//! Copy files that have too few copies between the currently mounted partitions.
+//! ```shell
+//! hoard replicate --collection my-leaks
+//! ```
+//!
+//! Re-hash all files on a disk to check for bit rot.
+//! ```shell
+//! hoard verify --disk "my 4TB disk"
+//! ```
 use crate::config::Config;
 use crate::db::init_connection;
-use crate::db::types::{Collection, Location};
-use crate::fs_utils::canonical_path;
-use crate::manager::Manager;
-use clap::Parser;
+use crate::db::types::{Collection, Disk, Location};
+use crate::fs_utils::{canonical_path, GlobFilter, SymlinkMode};
+use crate::manager::{AddDirOptions, AddOptions, Manager, VerifyScope};
+use crate::placement::PlacementStrategy;
+use clap::{ArgGroup, Parser};
 use cli_table::{
     format::{Border, Separator},
     print_stdout, TableStruct, WithTitle,
@@ -72,6 +160,7 @@ use cli_table::{
 use regex::Regex;
 use rusqlite::Connection;
 use simplelog::{ColorChoice, ConfigBuilder, LevelFilter, TermLogger, TerminalMode};
+use std::collections::HashSet;
 use std::path::PathBuf;
 use std::process::exit;
 use uuid::Uuid;
@@ -156,17 +245,70 @@ fn main_inner() -> anyhow::Result<()> {
     }
 
     match cli.command {
+        Command::Apply => manager.apply_pending(),
         Command::Collection(cmd) => cmd.run(&mut manager),
         Command::Database(cmd) => cmd.run(&mut manager),
+        Command::Dedup(cmd) => cmd.run(&mut manager),
         Command::Disk(cmd) => cmd.run(&mut manager),
         Command::Init => Ok(()), // this was already handled
         Command::File(cmd) => cmd.run(&mut manager),
         Command::Location(cmd) => cmd.run(&mut manager),
         Command::Partition(cmd) => cmd.run(&mut manager),
+        Command::PlacementPlan {
+            collection_name,
+            path,
+        } => {
+            let collection = get_collection(manager.conn(), &collection_name)?;
+            let spread = manager.placement_plan(collection.id(), &path)?;
+            let disks = spread
+                .iter()
+                .map(|s| s.disk_label())
+                .collect::<HashSet<_>>()
+                .len();
+            print_table(spread.with_title())?;
+            log::info!(
+                "Files under {} are on {} disk(s)",
+                path.to_string_lossy(),
+                disks
+            );
+            Ok(())
+        }
+        Command::Recover => manager.recover(),
+        Command::Replicate {
+            collection_name,
+            strategy,
+            verify,
+        } => {
+            let collection = get_collection(manager.conn(), &collection_name)?;
+            manager.replicate(collection.id(), strategy, verify)
+        }
         Command::Sync { collection_name } => {
             let collection = get_collection(manager.conn(), &collection_name)?;
             manager.sync_db(collection.id())
         }
+        Command::Verify {
+            collection_name,
+            disk_label,
+            partition_id,
+        } => {
+            let failures = match (collection_name, disk_label, partition_id) {
+                (Some(name), _, _) => {
+                    let collection = get_collection(manager.conn(), &name)?;
+                    manager.verify(VerifyScope::Collection(collection.id()))?
+                }
+                (_, Some(label), _) => {
+                    let disk = get_disk(manager.conn(), &label)?;
+                    manager.verify(VerifyScope::Disk(disk.id()))?
+                }
+                (_, _, Some(id)) => manager.verify(VerifyScope::Partition(&id))?,
+                _ => unreachable!(), // enforced by the arg group
+            };
+            if failures.is_empty() {
+                return Ok(());
+            }
+            print_table(failures.with_title())?;
+            bail!("{} file placements failed verification", failures.len())
+        }
     }
 }
 
@@ -185,6 +327,17 @@ fn parse_uuid(string: &str) -> Result<Uuid, String> {
     Uuid::parse_str(string).map_err(|e| e.to_string())
 }
 
+/// The `file find` options that filter files, which the mail searches don't apply
+const FIND_FILE_FILTERS: &[&str] = &["min-depth", "max-depth", "name", "path", "in-archives"];
+
+fn parse_symlink_mode(string: &str) -> Result<SymlinkMode, String> {
+    SymlinkMode::try_from(string).map_err(|e| e.to_string())
+}
+
+fn parse_placement_strategy(string: &str) -> Result<PlacementStrategy, String> {
+    PlacementStrategy::try_from(string).map_err(|e| e.to_string())
+}
+
 fn parse_regex(string: &str) -> Result<Regex, String> {
     // new line to separate Clap's error line from the nicely formatted
     // helper string for the regex  syntax error
@@ -195,6 +348,10 @@ fn get_location(conn: &Connection, name: &str) -> anyhow::Result<Location> {
     Location::for_name(conn, name)?.ok_or_else(|| anyhow!("Location with name {name} not found"))
 }
 
+fn get_disk(conn: &Connection, label: &str) -> anyhow::Result<Disk> {
+    Disk::for_label(conn, label)?.ok_or_else(|| anyhow!("Disk with label {label} not found"))
+}
+
 fn get_collection(conn: &Connection, name: &str) -> anyhow::Result<Collection> {
     Collection::for_name(conn, name)?
         .ok_or_else(|| anyhow!("Collection with name {name} not found"))
@@ -221,12 +378,17 @@ struct Cli {
 
 #[derive(Debug, Subcommand)]
 enum Command {
+    /// Apply the renames and deletions queued for the mounted partitions
+    Apply,
     /// Manage the collections
     #[clap(subcommand)]
     Collection(CollectionCmd),
     /// Perform operations directly on the database
     #[clap(subcommand, name = "db")]
     Database(DatabaseCmd),
+    /// Find files with the same content
+    #[clap(subcommand)]
+    Dedup(DedupCmd),
     /// Manage physical disks
     #[clap(subcommand)]
     Disk(DiskCmd),
@@ -241,12 +403,49 @@ enum Command {
     /// Manage partitions on physical disks
     #[clap(subcommand)]
     Partition(PartitionCmd),
+    /// Show how the files under a virtual directory are spread across disks
+    PlacementPlan {
+        /// The name of the collection
+        #[clap(long = "collection", short = 'c', value_name = "NAME")]
+        collection_name: String,
+        /// The virtual directory on the hoard disk pool
+        #[clap(parse(try_from_str = canonical_path), value_name = "DIR")]
+        path: PathBuf,
+    },
+    /// Finish or undo adds and replications of files that were interrupted
+    Recover,
+    /// Copy under-replicated files to other mounted partitions
+    Replicate {
+        /// The name of the collection to replicate
+        #[clap(long = "collection", short = 'c', value_name = "NAME")]
+        collection_name: String,
+        /// How to choose partitions for the copies: most-free, fill-first, or round-robin
+        #[clap(long = "strategy", value_name = "STRATEGY", default_value = "most-free", parse(try_from_str = parse_placement_strategy))]
+        strategy: PlacementStrategy,
+        /// Read copies back from the target's disk instead of the page cache when checking them
+        /// against the stored hashes. This is always done if `verify_copies` is set in the config.
+        #[clap(long = "verify")]
+        verify: bool,
+    },
     /// Sync the DB
     Sync {
         /// The name of the collection to sync
         #[clap(long = "collection", short = 'c', value_name = "NAME")]
         collection_name: String,
     },
+    /// Re-hash files on the mounted partitions and record whether they match the DB
+    #[clap(group(ArgGroup::new("scope").required(true)))]
+    Verify {
+        /// The name of the collection to verify
+        #[clap(long = "collection", short = 'c', value_name = "NAME", group = "scope")]
+        collection_name: Option<String>,
+        /// The label of the disk to verify
+        #[clap(long = "disk", value_name = "LABEL", group = "scope")]
+        disk_label: Option<String>,
+        /// The ID of the partition to verify
+        #[clap(long = "partition-id", short = 'p', value_name = "ID", group = "scope", parse(try_from_str = parse_uuid))]
+        partition_id: Option<Uuid>,
+    },
 }
 
 #[derive(Debug, Subcommand)]
@@ -256,17 +455,112 @@ enum CollectionCmd {
     Add {
         /// The collection's name
         name: String,
+        /// The minimum number of disks each file should be stored on
+        #[clap(long = "min-copies", value_name = "INT", default_value = "1")]
+        min_copies: u32,
     },
     /// List the collections
     #[clap(name = "ls")]
     List,
+    /// Change the settings of a collection
+    Set {
+        /// The collection's name
+        name: String,
+        /// The minimum number of disks each file should be stored on
+        #[clap(long = "min-copies", value_name = "INT")]
+        min_copies: Option<u32>,
+        /// Whether to block files from being removed from the collection
+        #[clap(long = "legal-hold", value_name = "BOOL")]
+        legal_hold: Option<bool>,
+    },
+    /// Manage the directories whose files are kept together on as few partitions as possible
+    #[clap(subcommand)]
+    Affinity(AffinityCmd),
 }
 
 impl CollectionCmd {
     fn run(&self, manager: &mut Manager) -> anyhow::Result<()> {
         match self {
-            Self::Add { name } => manager.add_collection(name),
+            Self::Add { name, min_copies } => manager.add_collection(name, *min_copies),
             Self::List => print_table(manager.list_collections()?.with_title()),
+            Self::Set {
+                name,
+                min_copies,
+                legal_hold,
+            } => {
+                let collection = get_collection(manager.conn(), name)?;
+                if min_copies.is_none() && legal_hold.is_none() {
+                    bail!("No settings were given to change")
+                }
+                if let Some(min_copies) = min_copies {
+                    manager.set_collection_min_copies(collection.id(), *min_copies)?;
+                }
+                if let Some(legal_hold) = legal_hold {
+                    manager.set_collection_legal_hold(collection.id(), *legal_hold)?;
+                }
+                Ok(())
+            }
+            Self::Affinity(cmd) => cmd.run(manager),
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+#[clap(disable_help_subcommand = true)]
+enum AffinityCmd {
+    /// Keep the files under a virtual directory together (use `/` for the whole collection)
+    Add {
+        /// The name of the collection
+        #[clap(long = "collection", short = 'c', value_name = "NAME")]
+        collection_name: String,
+        /// The virtual directory on the hoard disk pool
+        #[clap(parse(try_from_str = canonical_path), value_name = "DIR")]
+        path: PathBuf,
+    },
+    /// List the directories that are kept together
+    #[clap(name = "ls")]
+    List {
+        /// The name of the collection
+        #[clap(long = "collection", short = 'c', value_name = "NAME")]
+        collection_name: String,
+    },
+    /// Stop keeping the files under a virtual directory together
+    #[clap(name = "rm")]
+    Remove {
+        /// The name of the collection
+        #[clap(long = "collection", short = 'c', value_name = "NAME")]
+        collection_name: String,
+        /// The virtual directory on the hoard disk pool
+        #[clap(parse(try_from_str = canonical_path), value_name = "DIR")]
+        path: PathBuf,
+    },
+}
+
+impl AffinityCmd {
+    fn run(&self, manager: &mut Manager) -> anyhow::Result<()> {
+        match self {
+            Self::Add {
+                collection_name,
+                path,
+            } => {
+                let collection = get_collection(manager.conn(), collection_name)?;
+                manager.add_placement_affinity(collection.id(), path)
+            }
+            Self::List { collection_name } => {
+                let collection = get_collection(manager.conn(), collection_name)?;
+                print_table(
+                    manager
+                        .list_placement_affinities(collection.id())?
+                        .with_title(),
+                )
+            }
+            Self::Remove {
+                collection_name,
+                path,
+            } => {
+                let collection = get_collection(manager.conn(), collection_name)?;
+                manager.remove_placement_affinity(collection.id(), path)
+            }
         }
     }
 }
@@ -291,16 +585,36 @@ impl DatabaseCmd {
     }
 }
 
+#[derive(Debug, Subcommand)]
+#[clap(disable_help_subcommand = true)]
+enum DedupCmd {
+    /// List files in all collections that have the same content as another file
+    Report,
+}
+
+impl DedupCmd {
+    fn run(&self, manager: &mut Manager) -> anyhow::Result<()> {
+        match self {
+            Self::Report => print_table(manager.dedup_report()?.with_title()),
+        }
+    }
+}
+
 #[derive(Debug, Subcommand)]
 #[clap(disable_help_subcommand = true)]
 enum DiskCmd {
     /// Add a new disk
+    #[clap(group(ArgGroup::new("source").required(true)))]
     Add {
         /// The name of the location of the disk
         #[clap(long = "location", value_name = "NAME")]
         location: String,
         /// The path to the disk (e.g., /dev/sdb)
-        path: String,
+        #[clap(group = "source")]
+        path: Option<String>,
+        /// A serial number to use for disks without a local block device (e.g., a NAS)
+        #[clap(long = "serial-number", value_name = "SERIAL", group = "source")]
+        serial_number: Option<String>,
         /// The physical label on the housing of the disk (e.g., "Secret Data 0161")
         #[clap(long = "label", value_name = "LABEL")]
         label: String,
@@ -316,10 +630,17 @@ impl DiskCmd {
             Self::Add {
                 location,
                 path,
+                serial_number,
                 label,
             } => {
                 let location = get_location(manager.conn(), location)?;
-                manager.add_disk(location.id(), path, label)
+                match (path, serial_number) {
+                    (Some(path), _) => manager.add_disk(location.id(), path, label),
+                    (_, Some(serial_number)) => {
+                        manager.add_disk_with_serial_number(location.id(), serial_number, label)
+                    }
+                    _ => unreachable!(), // enforced by the arg group
+                }
             }
             Self::List => print_table(manager.list_disks()?.with_title()),
         }
@@ -345,6 +666,59 @@ enum FileCmd {
         /// Move the file on to the target partition instead of copying it
         #[clap(long = "move")]
         move_file: bool,
+        /// Add all files in the directory SRC under the virtual directory DEST
+        #[clap(long = "recursive", short = 'r')]
+        recursive: bool,
+        /// Only add files matching the glob (matched against the file name, or against the path
+        /// relative to SRC if the glob contains a `/`)
+        #[clap(
+            long = "include",
+            value_name = "GLOB",
+            multiple_occurrences = true,
+            requires = "recursive"
+        )]
+        include: Vec<String>,
+        /// Skip files and directories matching the glob
+        #[clap(
+            long = "exclude",
+            value_name = "GLOB",
+            multiple_occurrences = true,
+            requires = "recursive"
+        )]
+        exclude: Vec<String>,
+        /// What to do with symlinks: skip, follow, or error
+        #[clap(long = "symlinks", value_name = "MODE", default_value = "skip", parse(try_from_str = parse_symlink_mode))]
+        symlinks: SymlinkMode,
+        /// How to choose a partition for each file: most-free, fill-first, or round-robin
+        #[clap(long = "strategy", value_name = "STRATEGY", default_value = "most-free", parse(try_from_str = parse_placement_strategy))]
+        strategy: PlacementStrategy,
+        /// Don't copy files whose content is already stored, and point them at the stored copy
+        #[clap(long = "dedup")]
+        dedup: bool,
+        /// Read copies back from the target's disk instead of the page cache when checking them
+        /// against the source's hashes. This is always done if `verify_copies` is set in the
+        /// config.
+        #[clap(long = "verify")]
+        verify: bool,
+    },
+    /// Copy members out of an archive on a mounted disk, checking them against the stored hashes
+    Extract {
+        /// The name of the collection the archive belongs to
+        #[clap(long = "collection", short = 'c', value_name = "NAME")]
+        collection_name: String,
+        /// The virtual path of the archive
+        #[clap(value_name = "ARCHIVE")]
+        archive_path: String,
+        /// The paths of the members in the archive
+        #[clap(value_name = "MEMBER", min_values = 1, required = true)]
+        members: Vec<String>,
+        /// The directory on the local system to extract to. Members are written to their paths
+        /// in the archive under it.
+        #[clap(value_name = "DEST")]
+        dest_path: PathBuf,
+        /// Extract members that have no trusted hashes to check them against
+        #[clap(long = "allow-unverified")]
+        allow_unverified: bool,
     },
     /// Find a file meeting certain criteria
     Find {
@@ -363,10 +737,51 @@ enum FileCmd {
         /// Regex for the full path of the file
         #[clap(long = "path", parse(try_from_str = parse_regex), value_name = "REGEX")]
         path: Option<Regex>,
+        /// Search the members of archives instead of files. Members have paths like
+        /// `/archive.tar!/member.txt`. This is implied when searching in an archive. Depths count
+        /// archives as directories.
+        #[clap(long = "in-archives")]
+        in_archives: bool,
+        /// Search email messages by a regex of their `From` header instead of files. Messages are
+        /// files in Maildirs or ending in `.eml`, or members of mbox files. The file filters can't
+        /// be used with it.
+        #[clap(
+            long = "mail-from",
+            parse(try_from_str = parse_regex),
+            value_name = "REGEX",
+            conflicts_with_all = FIND_FILE_FILTERS
+        )]
+        mail_from: Option<Regex>,
+        /// Search email messages by a regex of their `Subject` header instead of files
+        #[clap(
+            long = "subject",
+            parse(try_from_str = parse_regex),
+            value_name = "REGEX",
+            conflicts_with_all = FIND_FILE_FILTERS
+        )]
+        subject: Option<Regex>,
         /// Files and directories to search
         #[clap(value_name = "FILE", min_values = 1)]
         files: Vec<String>,
     },
+    /// Copy a file out of the pool, checking it against the stored hashes
+    Get {
+        /// The name of the collection the file belongs to
+        #[clap(long = "collection", short = 'c', value_name = "NAME")]
+        collection_name: String,
+        /// Copy all files under a directory
+        #[clap(long = "recursive", short = 'r')]
+        recursive: bool,
+        /// The virtual path of the file or directory
+        #[clap(value_name = "FILE")]
+        path: String,
+        /// The path on the local system to copy to
+        #[clap(value_name = "DEST")]
+        dest_path: PathBuf,
+        /// Copy files that have no trusted hashes to check them against
+        #[clap(long = "allow-unverified")]
+        allow_unverified: bool,
+    },
     /// Inspect a file and show metadata
     Inspect {
         /// The name of the collection the file belongs to
@@ -375,6 +790,15 @@ enum FileCmd {
         /// The virtual path on the hoard disk pool
         #[clap(value_name = "FILE", parse(try_from_str = canonical_path))]
         path: PathBuf,
+        /// Show the members of the file if it's an archive
+        #[clap(long = "members")]
+        members: bool,
+    },
+    /// Find files and archive members in any collection with the same content as a local file
+    Match {
+        /// The path of the file on the local system
+        #[clap(value_name = "FILE")]
+        local_path: PathBuf,
     },
     /// List files (similar to `ls`)
     #[clap(name = "ls")]
@@ -390,6 +814,53 @@ enum FileCmd {
         #[clap(long = "all", short = 'a')]
         all: bool,
     },
+    /// Remove a file and delete its copies (similar to `rm`)
+    #[clap(name = "rm")]
+    Remove {
+        /// The name of the collection the files belongs to
+        #[clap(long = "collection", short = 'c', value_name = "NAME")]
+        collection_name: String,
+        /// Remove all files under a directory
+        #[clap(long = "recursive", short = 'r')]
+        recursive: bool,
+        /// The virtual path of the file or directory
+        #[clap(value_name = "FILE")]
+        path: String,
+    },
+    /// List the disks to fetch, by location, to retrieve the files meeting certain criteria
+    Plan {
+        /// The name of the collection the files belongs to
+        #[clap(long = "collection", short = 'c', value_name = "NAME")]
+        collection_name: String,
+        /// Minimum depth to search
+        #[clap(long = "min-depth", value_name = "INT")]
+        min_depth: Option<u32>,
+        /// Maximum depth to search
+        #[clap(long = "max-depth", value_name = "INT")]
+        max_depth: Option<u32>,
+        /// Regex of the name of the file
+        #[clap(long = "name", parse(try_from_str = parse_regex), value_name = "REGEX")]
+        name: Option<Regex>,
+        /// Regex for the full path of the file
+        #[clap(long = "path", parse(try_from_str = parse_regex), value_name = "REGEX")]
+        path: Option<Regex>,
+        /// Files and directories to search
+        #[clap(value_name = "FILE", min_values = 1)]
+        files: Vec<String>,
+    },
+    /// Move or rename a file or directory (similar to `mv`)
+    #[clap(name = "mv")]
+    Move {
+        /// The name of the collection the files belongs to
+        #[clap(long = "collection", short = 'c', value_name = "NAME")]
+        collection_name: String,
+        /// The current virtual path of the file or directory
+        #[clap(value_name = "OLD")]
+        src_path: String,
+        /// The new virtual path of the file or directory
+        #[clap(value_name = "NEW")]
+        dest_path: String,
+    },
     Path {
         /// The name of the collection the files belongs to
         #[clap(long = "collection", short = 'c', value_name = "NAME")]
@@ -409,15 +880,44 @@ impl FileCmd {
                 src_path,
                 dest_path,
                 move_file,
+                recursive,
+                include,
+                exclude,
+                symlinks,
+                strategy,
+                dedup,
+                verify,
             } => {
                 let collection = get_collection(manager.conn(), collection_name)?;
-                manager.add_file(
-                    collection.id(),
-                    partition_id.as_ref(),
-                    src_path,
-                    dest_path,
-                    *move_file,
-                )
+                let options = AddOptions {
+                    move_files: *move_file,
+                    dedup: *dedup,
+                    verify: *verify,
+                    strategy: *strategy,
+                };
+                if *recursive {
+                    let dir_options = AddDirOptions {
+                        include: GlobFilter::new(include.iter().map(|s| &**s))?,
+                        exclude: GlobFilter::new(exclude.iter().map(|s| &**s))?,
+                        symlinks: *symlinks,
+                    };
+                    manager.add_dir(
+                        collection.id(),
+                        partition_id.as_ref(),
+                        src_path,
+                        dest_path,
+                        &options,
+                        &dir_options,
+                    )
+                } else {
+                    manager.add_file(
+                        collection.id(),
+                        partition_id.as_ref(),
+                        src_path,
+                        dest_path,
+                        &options,
+                    )
+                }
             }
             Self::Find {
                 collection_name,
@@ -425,9 +925,32 @@ impl FileCmd {
                 max_depth,
                 name,
                 path,
+                in_archives,
+                mail_from,
+                subject,
                 files,
             } => {
                 let collection = get_collection(manager.conn(), collection_name)?;
+                if mail_from.is_some() || subject.is_some() {
+                    let messages = manager.find_mail(
+                        collection.id(),
+                        mail_from.as_ref(),
+                        subject.as_ref(),
+                        files.iter().map(|s| &**s),
+                    )?;
+                    return print_table(messages.with_title());
+                }
+                if *in_archives || files.iter().any(|f| f.contains("!/")) {
+                    let members = manager.find_archive_members(
+                        collection.id(),
+                        *min_depth,
+                        *max_depth,
+                        name.as_ref(),
+                        path.as_ref(),
+                        files.iter().map(|s| &**s),
+                    )?;
+                    return print_table(members.with_title());
+                }
                 for file in manager.find_files(
                     collection.id(),
                     *min_depth,
@@ -442,15 +965,48 @@ impl FileCmd {
                 }
                 Ok(())
             }
+            Self::Extract {
+                collection_name,
+                archive_path,
+                members,
+                dest_path,
+                allow_unverified,
+            } => {
+                let collection = get_collection(manager.conn(), collection_name)?;
+                manager.extract_members(
+                    collection.id(),
+                    archive_path,
+                    members.iter().map(|s| &**s),
+                    dest_path,
+                    *allow_unverified,
+                )
+            }
+            Self::Get {
+                collection_name,
+                recursive,
+                path,
+                dest_path,
+                allow_unverified,
+            } => {
+                let collection = get_collection(manager.conn(), collection_name)?;
+                manager.get_file(
+                    collection.id(),
+                    path,
+                    dest_path,
+                    *recursive,
+                    *allow_unverified,
+                )
+            }
             Self::Inspect {
                 collection_name,
                 path,
+                members,
             } => {
                 let collection = get_collection(manager.conn(), collection_name)?;
                 let path = path.to_str().ok_or_else(|| {
                     anyhow!("Path could not be made UTF-8: {}", path.to_string_lossy())
                 })?;
-                println!("{}", manager.inspect_file(collection.id(), path)?);
+                println!("{}", manager.inspect_file(collection.id(), path, *members)?);
                 Ok(())
             }
             Self::List {
@@ -466,6 +1022,43 @@ impl FileCmd {
                 }
                 Ok(())
             }
+            Self::Match { local_path } => print_table(manager.match_file(local_path)?.with_title()),
+            Self::Plan {
+                collection_name,
+                min_depth,
+                max_depth,
+                name,
+                path,
+                files,
+            } => {
+                let collection = get_collection(manager.conn(), collection_name)?;
+                let files = manager.find_files(
+                    collection.id(),
+                    *min_depth,
+                    *max_depth,
+                    name.as_ref(),
+                    path.as_ref(),
+                    files.iter().map(|s| &**s),
+                )?;
+                print!("{}", manager.retrieval_plan(files)?);
+                Ok(())
+            }
+            Self::Move {
+                collection_name,
+                src_path,
+                dest_path,
+            } => {
+                let collection = get_collection(manager.conn(), collection_name)?;
+                manager.move_file(collection.id(), src_path, dest_path)
+            }
+            Self::Remove {
+                collection_name,
+                recursive,
+                path,
+            } => {
+                let collection = get_collection(manager.conn(), collection_name)?;
+                manager.remove_file(collection.id(), path, *recursive)
+            }
             Self::Path {
                 collection_name,
                 file,
@@ -505,9 +1098,17 @@ impl LocationCmd {
 #[clap(disable_help_subcommand = true)]
 enum PartitionCmd {
     /// Add a new partition
+    #[clap(group(ArgGroup::new("source").required(true)))]
     Add {
         /// The path to to the partition (e.g., /dev/sdb1)
-        path: String,
+        #[clap(group = "source")]
+        path: Option<String>,
+        /// A directory to use as a partition instead of a block device (e.g., an NFS mount)
+        #[clap(long = "dir", value_name = "PATH", group = "source", requires = "disk")]
+        dir: Option<PathBuf>,
+        /// The label of the disk the directory belongs to
+        #[clap(long = "disk", value_name = "LABEL", requires = "dir")]
+        disk: Option<String>,
     },
     /// List all partitions
     #[clap(name = "ls")]
@@ -517,7 +1118,14 @@ enum PartitionCmd {
 impl PartitionCmd {
     fn run(&self, manager: &mut Manager) -> anyhow::Result<()> {
         match self {
-            Self::Add { path } => manager.add_partition(path),
+            Self::Add { path, dir, disk } => match (path, dir, disk) {
+                (Some(path), _, _) => manager.add_partition(path),
+                (_, Some(dir), Some(disk)) => {
+                    let disk = get_disk(manager.conn(), disk)?;
+                    manager.add_directory_partition(disk.id(), dir)
+                }
+                _ => unreachable!(), // enforced by the arg group
+            },
             Self::List => print_table(manager.list_partitions()?.with_title()),
         }
     }
@@ -537,4 +1145,50 @@ mod tests {
             x => panic!("Unexpected result: {:?}", x),
         }
     }
+
+    #[test]
+    fn cli_verify_requires_scope() {
+        match Cli::try_parse_from(["hoard", "verify"]) {
+            Err(err) if err.kind() == ErrorKind::MissingRequiredArgument => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        Cli::try_parse_from(["hoard", "verify", "--disk", "some-disk"]).unwrap();
+    }
+
+    #[test]
+    fn cli_find_mail_conflicts_with_file_filters() {
+        for filter in [
+            &["--name", "foo"][..],
+            &["--path", "foo"],
+            &["--min-depth", "1"],
+            &["--max-depth", "1"],
+            &["--in-archives"],
+        ] {
+            let mut args = vec!["hoard", "file", "find", "-c", "coll", "--subject", "hi"];
+            args.extend(filter);
+            match Cli::try_parse_from(args) {
+                Err(err) if err.kind() == ErrorKind::ArgumentConflict => (),
+                x => panic!("Unexpected result: {:?}", x),
+            }
+        }
+        Cli::try_parse_from(["hoard", "file", "find", "-c", "coll", "--mail-from", "me"]).unwrap();
+    }
+
+    #[test]
+    fn cli_partition_add_dir_requires_disk() {
+        match Cli::try_parse_from(["hoard", "partition", "add", "--dir", "/mnt/nas"]) {
+            Err(err) if err.kind() == ErrorKind::MissingRequiredArgument => (),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+        Cli::try_parse_from([
+            "hoard",
+            "partition",
+            "add",
+            "--dir",
+            "/mnt/nas",
+            "--disk",
+            "nas",
+        ])
+        .unwrap();
+    }
 }
@@ -1,3 +1,4 @@
+use crate::archive_utils::NestingLimits;
 use crate::hash_utils::HashAlgorithm;
 use std::fs::File;
 use std::path::Path;
@@ -7,6 +8,7 @@ use std::path::Path;
 pub struct Config {
     db: DbConfig,
     files: FileConfig,
+    archives: ArchiveConfig,
 }
 
 impl Config {
@@ -21,6 +23,10 @@ impl Config {
     pub fn files(&self) -> &FileConfig {
         &self.files
     }
+
+    pub fn archives(&self) -> &ArchiveConfig {
+        &self.archives
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -47,12 +53,27 @@ impl Default for DbConfig {
 #[serde(deny_unknown_fields, default)]
 pub struct FileConfig {
     hashes: Vec<HashAlgorithm>,
+    lookup_only: Vec<HashAlgorithm>,
+    verify_copies: bool,
 }
 
 impl FileConfig {
     pub fn hashes(&self) -> &[HashAlgorithm] {
         &self.hashes
     }
+
+    /// Algorithms that are only good for looking up files by hashes from other sources (e.g., old
+    /// leak indexes). Collisions can be made for these, so they are never used to decide that two
+    /// files have the same contents or that a copy is intact.
+    pub fn lookup_only(&self) -> &[HashAlgorithm] {
+        &self.lookup_only
+    }
+
+    /// Whether added files are always read back from the target's disk instead of the page cache
+    /// after they are copied
+    pub fn verify_copies(&self) -> bool {
+        self.verify_copies
+    }
 }
 
 impl Default for FileConfig {
@@ -63,6 +84,39 @@ impl Default for FileConfig {
                 HashAlgorithm::Sha512,
                 HashAlgorithm::Sha3_256,
             ],
+            lookup_only: vec![HashAlgorithm::Md5, HashAlgorithm::Crc32c],
+            verify_copies: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ArchiveConfig {
+    /// How many levels of archives inside of archives to list
+    max_nesting_depth: u32,
+    /// How many bytes can be read into memory and decompressed to list nested archives
+    max_nested_bytes: u64,
+    /// How many bytes a message in an mbox file can have to be listed
+    max_mbox_message_bytes: u64,
+}
+
+impl ArchiveConfig {
+    pub fn nesting_limits(&self) -> NestingLimits {
+        NestingLimits {
+            max_depth: self.max_nesting_depth,
+            max_bytes: self.max_nested_bytes,
+            max_message_bytes: self.max_mbox_message_bytes,
+        }
+    }
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            max_nesting_depth: 3,
+            max_nested_bytes: 512 * 1024 * 1024,
+            max_mbox_message_bytes: 256 * 1024 * 1024,
         }
     }
 }
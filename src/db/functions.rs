@@ -43,14 +43,14 @@ fn add_relative_depth(conn: &Connection) -> rusqlite::Result<()> {
             | FunctionFlags::SQLITE_DETERMINISTIC
             | FunctionFlags::SQLITE_DIRECTONLY,
         move |ctx| {
+            // archives count as directories, so `!/` (as in `/a.tar!/b.txt`) separates parts
+            // like `/` does
             let root_parts: Arc<Vec<String>> =
                 ctx.get_or_create_aux(0, |vr| -> Result<_, BoxError> {
-                    let mut string = vr.as_str()?;
+                    let string = vr.as_str()?.replace("!/", "/");
                     // we need to yank the trailing slash otherwise previxes break because the
                     // resulting vector has as the last element an empty string ("")
-                    if string.ends_with('/') {
-                        string = &string[0..string.len() - 1]
-                    }
+                    let string = string.strip_suffix('/').unwrap_or(&string);
                     Ok(string.split('/').map(|s| s.to_string()).collect())
                 })?;
             // this assumes that it doesn't end in a trailing slash which is correct for now
@@ -59,6 +59,7 @@ fn add_relative_depth(conn: &Connection) -> rusqlite::Result<()> {
                 .get_raw(1)
                 .as_str()
                 .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?
+                .replace("!/", "/")
                 .split('/')
                 .map(|s| s.to_string())
                 .collect::<Vec<_>>();
@@ -133,6 +134,10 @@ mod tests {
             (Some(1u64), "/foo", "/foo/bar"),
             (Some(2u64), "/foo", "/foo/bar/baz"),
             (Some(1u64), "/foo/bar/", "/foo/bar/baz"),
+            (Some(2u64), "/foo", "/foo/a.tar!/bar"),
+            (Some(1u64), "/foo/a.tar", "/foo/a.tar!/bar"),
+            (Some(1u64), "/foo/a.tar!/", "/foo/a.tar!/bar"),
+            (Some(2u64), "/foo/a.tar!/", "/foo/a.tar!/b.zip!/baz"),
         ];
         for (expected_depth, root, path) in cases {
             let found_depth: Option<u64> = stmt.query_row([root, path], |row| row.get(0)).unwrap();
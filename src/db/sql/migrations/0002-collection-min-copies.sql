@@ -0,0 +1,3 @@
+ALTER TABLE collections ADD COLUMN min_copies INTEGER NOT NULL DEFAULT 1
+    CHECK (min_copies >= 1)
+    CONSTRAINT ck_collections_min_copies;
@@ -0,0 +1,17 @@
+CREATE TABLE file_placement_checks (
+    id BINARY(16) NOT NULL
+        PRIMARY KEY CONSTRAINT pk_file_placement_checks
+        CHECK (length(id) = 16) CONSTRAINT ck_file_placement_checks_id,
+    partition_id BINARY(16) NOT NULL,
+    file_id BINARY(16) NOT NULL,
+    checked_date TEXT NOT NULL,
+    outcome TEXT NOT NULL
+        CHECK (outcome IN ('ok', 'mismatch', 'missing', 'size-differs', 'unverifiable'))
+        CONSTRAINT ck_file_placement_checks_outcome,
+    FOREIGN KEY (partition_id, file_id)
+        REFERENCES file_placements(partition_id, file_id)
+        CONSTRAINT fk_file_placement_checks_placement
+);
+
+CREATE INDEX ix_file_placement_checks_file_id_partition_id
+    ON file_placement_checks (file_id, partition_id);
@@ -0,0 +1,4 @@
+-- set for storage targets that are plain directories instead of block device partitions
+ALTER TABLE partitions ADD COLUMN directory TEXT
+    CHECK (directory IS NULL OR length(directory) > 0)
+    CONSTRAINT ck_partitions_directory;
@@ -0,0 +1,3 @@
+-- when a file was last placed on the partition. used by the round-robin placement strategy to
+-- pick the partition that has gone the longest without a new file.
+ALTER TABLE partitions ADD COLUMN last_placed_date TEXT;
@@ -0,0 +1,16 @@
+-- files under the virtual directory `prefix` should be kept on as few partitions as possible.
+-- a prefix of `/` applies to the whole collection.
+CREATE TABLE placement_affinities (
+    id BINARY(16) NOT NULL
+        PRIMARY KEY CONSTRAINT pk_placement_affinities
+        CHECK (length(id) = 16) CONSTRAINT ck_placement_affinities_id,
+    collection_id BINARY(16) NOT NULL,
+    prefix TEXT NOT NULL
+        CHECK (prefix LIKE '/%' AND prefix LIKE '%/')
+        CONSTRAINT ck_placement_affinities_prefix,
+    UNIQUE (collection_id, prefix)
+        CONSTRAINT uq_placement_affinities_collection_id_prefix,
+    FOREIGN KEY (collection_id)
+        REFERENCES collections(id)
+        CONSTRAINT fk_placement_affinities_collection_id
+);
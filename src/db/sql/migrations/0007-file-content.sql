@@ -0,0 +1,8 @@
+-- set when a file has the same content as another file and shares its physical copies instead of
+-- having placements of its own. the referenced file always has its own placements.
+ALTER TABLE files ADD COLUMN content_file_id BINARY(16)
+    CHECK (content_file_id IS NULL OR length(content_file_id) = 16)
+    CONSTRAINT ck_files_content_file_id
+    CONSTRAINT fk_files_content_file_id REFERENCES files(id);
+
+CREATE INDEX ix_files_content_file_id ON files (content_file_id);
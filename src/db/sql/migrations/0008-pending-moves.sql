@@ -0,0 +1,26 @@
+-- renames of files on partitions that weren't mounted when the file was moved. they are applied
+-- in the order they were queued the next time the partition is seen.
+CREATE TABLE pending_moves (
+    id BINARY(16) NOT NULL
+        PRIMARY KEY CONSTRAINT pk_pending_moves
+        CHECK (length(id) = 16) CONSTRAINT ck_pending_moves_id,
+    partition_id BINARY(16) NOT NULL,
+    collection_id BINARY(16) NOT NULL,
+    old_path TEXT NOT NULL
+        CHECK (old_path LIKE '/%') CONSTRAINT ck_pending_moves_old_path,
+    new_path TEXT NOT NULL
+        CHECK (new_path LIKE '/%') CONSTRAINT ck_pending_moves_new_path,
+    -- the order moves and deletions were queued in. it is shared with pending_deletions so that
+    -- operations queued within the same timestamp are still applied in order.
+    seq INTEGER NOT NULL,
+    created_date TEXT NOT NULL,
+    FOREIGN KEY (partition_id)
+        REFERENCES partitions(id)
+        CONSTRAINT fk_pending_moves_partition_id,
+    FOREIGN KEY (collection_id)
+        REFERENCES collections(id)
+        CONSTRAINT fk_pending_moves_collection_id
+);
+
+CREATE INDEX ix_pending_moves_partition_id ON pending_moves (partition_id);
+CREATE UNIQUE INDEX ix_pending_moves_seq ON pending_moves (seq);
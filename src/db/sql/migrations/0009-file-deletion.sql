@@ -0,0 +1,28 @@
+-- files in collections under legal hold cannot be removed
+ALTER TABLE collections ADD COLUMN legal_hold BOOLEAN NOT NULL DEFAULT 0
+    CHECK (legal_hold IN (0, 1))
+    CONSTRAINT ck_collections_legal_hold;
+
+-- copies of removed files on partitions that weren't mounted when the files were removed. they
+-- are deleted the next time the partition is seen, in order with any pending moves.
+CREATE TABLE pending_deletions (
+    id BINARY(16) NOT NULL
+        PRIMARY KEY CONSTRAINT pk_pending_deletions
+        CHECK (length(id) = 16) CONSTRAINT ck_pending_deletions_id,
+    partition_id BINARY(16) NOT NULL,
+    collection_id BINARY(16) NOT NULL,
+    path TEXT NOT NULL
+        CHECK (path LIKE '/%') CONSTRAINT ck_pending_deletions_path,
+    -- shares its sequence with pending_moves
+    seq INTEGER NOT NULL,
+    created_date TEXT NOT NULL,
+    FOREIGN KEY (partition_id)
+        REFERENCES partitions(id)
+        CONSTRAINT fk_pending_deletions_partition_id,
+    FOREIGN KEY (collection_id)
+        REFERENCES collections(id)
+        CONSTRAINT fk_pending_deletions_collection_id
+);
+
+CREATE INDEX ix_pending_deletions_partition_id ON pending_deletions (partition_id);
+CREATE UNIQUE INDEX ix_pending_deletions_seq ON pending_deletions (seq);
@@ -0,0 +1,15 @@
+-- member paths are stored relative to the root of the archive without a leading `./`, and the
+-- root itself isn't stored as a member. when a member was listed both with and without the `./`,
+-- only the one listed last is kept, which is what extracting the archive would leave and how
+-- archives are listed from now on.
+DELETE FROM file_archives WHERE path IN ('.', './');
+DELETE FROM file_archives
+WHERE EXISTS (
+    SELECT 1 FROM file_archives AS other
+    WHERE other.file_id = file_archives.file_id
+        AND other.rowid > file_archives.rowid
+        AND (CASE WHEN other.path LIKE './%' THEN substr(other.path, 3) ELSE other.path END)
+            = (CASE WHEN file_archives.path LIKE './%' THEN substr(file_archives.path, 3)
+                ELSE file_archives.path END)
+);
+UPDATE file_archives SET path = substr(path, 3) WHERE path LIKE './%';
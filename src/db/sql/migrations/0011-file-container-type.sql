@@ -0,0 +1,7 @@
+-- the archive or compression format detected from the leading bytes of the file when it was
+-- added. files added before this column existed are left NULL.
+ALTER TABLE files ADD COLUMN container_type TEXT
+    CHECK (container_type IS NULL OR length(container_type) > 0)
+    CONSTRAINT ck_files_container_type;
+
+CREATE INDEX ix_files_container_type ON files (container_type);
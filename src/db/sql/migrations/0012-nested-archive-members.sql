@@ -0,0 +1,8 @@
+-- members of archives nested in an archive point at the member that is the nested archive. their
+-- paths are the nested archive's path and their path in it joined by `!/`.
+ALTER TABLE file_archives ADD COLUMN parent_id BINARY(16)
+    CHECK (parent_id IS NULL OR length(parent_id) = 16)
+    CONSTRAINT ck_file_archives_parent_id
+    CONSTRAINT fk_file_archives_parent_id REFERENCES file_archives(id);
+
+CREATE INDEX ix_file_archives_parent_id ON file_archives (parent_id);
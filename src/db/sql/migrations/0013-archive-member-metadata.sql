@@ -0,0 +1,14 @@
+-- what the archive records about each member. members listed before these columns existed are
+-- left NULL.
+ALTER TABLE file_archives ADD COLUMN entry_type TEXT
+    CHECK (entry_type IS NULL OR length(entry_type) > 0)
+    CONSTRAINT ck_file_archives_entry_type;
+ALTER TABLE file_archives ADD COLUMN link_target TEXT;
+ALTER TABLE file_archives ADD COLUMN modified_date TEXT;
+ALTER TABLE file_archives ADD COLUMN compressed_size BIGINT
+    CHECK (compressed_size IS NULL OR compressed_size >= 0)
+    CONSTRAINT ck_file_archives_compressed_size;
+ALTER TABLE file_archives ADD COLUMN crc32 INTEGER
+    CHECK (crc32 IS NULL OR crc32 BETWEEN 0 AND 4294967295)
+    CONSTRAINT ck_file_archives_crc32;
+ALTER TABLE file_archives ADD COLUMN encrypted BOOLEAN;
@@ -0,0 +1,18 @@
+-- hashes of the contents of archive members, computed while the archive is listed, so that a
+-- single file can be matched against the archives it was shipped in.
+CREATE TABLE file_archive_hashes (
+    id BINARY(16) NOT NULL
+        PRIMARY KEY CONSTRAINT pk_file_archive_hashes
+        CHECK (length(id) = 16) CONSTRAINT ck_file_archive_hashes_id,
+    file_archive_id BINARY(16) NOT NULL,
+    hash_algorithm TEXT NOT NULL,
+    hash_value BINARY NOT NULL,
+    UNIQUE (file_archive_id, hash_algorithm)
+        CONSTRAINT uq_file_archive_hashes_file_archive_id_hash_algorithm,
+    FOREIGN KEY (file_archive_id)
+        REFERENCES file_archives(id)
+        CONSTRAINT fk_file_archive_hashes_file_archive_id
+);
+
+CREATE INDEX ix_file_archive_hashes_hash_value ON file_archive_hashes (hash_value);
+CREATE INDEX ix_file_hashes_hash_value ON file_hashes (hash_value);
@@ -0,0 +1,26 @@
+-- the headers of email messages, which are either files or members of archives like mbox files
+CREATE TABLE mail_messages (
+    id BINARY(16) NOT NULL
+        PRIMARY KEY CONSTRAINT pk_mail_messages
+        CHECK (length(id) = 16) CONSTRAINT ck_mail_messages_id,
+    file_id BINARY(16) NOT NULL,
+    -- NULL when the file itself is the message
+    file_archive_id BINARY(16)
+        CHECK (file_archive_id IS NULL OR length(file_archive_id) = 16)
+        CONSTRAINT ck_mail_messages_file_archive_id,
+    message_id TEXT,
+    sent_date TEXT,
+    mail_from TEXT,
+    mail_to TEXT,
+    subject TEXT,
+    FOREIGN KEY (file_id)
+        REFERENCES files(id)
+        CONSTRAINT fk_mail_messages_file_id,
+    FOREIGN KEY (file_archive_id)
+        REFERENCES file_archives(id)
+        CONSTRAINT fk_mail_messages_file_archive_id
+);
+
+CREATE INDEX ix_mail_messages_file_id ON mail_messages (file_id);
+CREATE INDEX ix_mail_messages_file_archive_id ON mail_messages (file_archive_id);
+CREATE INDEX ix_mail_messages_message_id ON mail_messages (message_id);
@@ -0,0 +1,25 @@
+-- copies and moves of added files on to partitions that are in flight. a row is committed before
+-- anything is written to the partition and removed once the file is at its final path and in the
+-- DB, so the rows left by an interrupted add are what `hoard recover` has to clean up.
+CREATE TABLE pending_placements (
+    id BINARY(16) NOT NULL
+        PRIMARY KEY CONSTRAINT pk_pending_placements
+        CHECK (length(id) = 16) CONSTRAINT ck_pending_placements_id,
+    partition_id BINARY(16) NOT NULL,
+    collection_id BINARY(16) NOT NULL,
+    path TEXT NOT NULL
+        CHECK (path LIKE '/%') CONSTRAINT ck_pending_placements_path,
+    -- the local file that is being moved, or NULL for copies
+    src_path TEXT,
+    -- the file once it's in the DB, or NULL until then. it's not a foreign key because the file
+    -- can be moved or removed before the placement is recovered
+    file_id BINARY(16)
+        CHECK (file_id IS NULL OR length(file_id) = 16) CONSTRAINT ck_pending_placements_file_id,
+    created_date TEXT NOT NULL,
+    FOREIGN KEY (partition_id)
+        REFERENCES partitions(id)
+        CONSTRAINT fk_pending_placements_partition_id,
+    FOREIGN KEY (collection_id)
+        REFERENCES collections(id)
+        CONSTRAINT fk_pending_placements_collection_id
+);
@@ -12,6 +12,10 @@ pub struct Collection {
     created_date: Timestamp,
     #[cfg_attr(feature = "cli", table(title = "Name"))]
     name: String,
+    #[cfg_attr(feature = "cli", table(title = "Min Copies"))]
+    min_copies: u32,
+    #[cfg_attr(feature = "cli", table(title = "Legal Hold"))]
+    legal_hold: bool,
 }
 
 impl Collection {
@@ -23,11 +27,22 @@ impl Collection {
         &self.name
     }
 
+    pub fn min_copies(&self) -> u32 {
+        self.min_copies
+    }
+
+    /// Whether files are blocked from being removed from the collection
+    pub fn legal_hold(&self) -> bool {
+        self.legal_hold
+    }
+
     fn star_mapper(row: &Row) -> rusqlite::Result<Self> {
         Ok(Self {
             id: row.get("id")?,
             name: row.get("name")?,
             created_date: row.get("created_date")?,
+            min_copies: row.get("min_copies")?,
+            legal_hold: row.get("legal_hold")?,
         })
     }
 
@@ -59,18 +74,53 @@ impl Collection {
             .collect::<Vec<anyhow::Result<Self>>>();
         rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
     }
+
+    pub fn set_min_copies<'b>(
+        tx: &Transaction<'b>,
+        id: &Uuid,
+        min_copies: u32,
+    ) -> anyhow::Result<()> {
+        if min_copies < 1 {
+            bail!("A collection must require at least 1 copy of each file")
+        }
+        match tx.execute(
+            "UPDATE collections SET min_copies = ? WHERE id = ?",
+            params![min_copies, id],
+        )? {
+            0 => bail!("No collection was found for ID {}", id.hyphenated()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn set_legal_hold<'b>(
+        tx: &Transaction<'b>,
+        id: &Uuid,
+        legal_hold: bool,
+    ) -> anyhow::Result<()> {
+        match tx.execute(
+            "UPDATE collections SET legal_hold = ? WHERE id = ?",
+            params![legal_hold, id],
+        )? {
+            0 => bail!("No collection was found for ID {}", id.hyphenated()),
+            _ => Ok(()),
+        }
+    }
 }
 
 pub struct NewCollection<'a> {
     pub name: &'a str,
+    pub min_copies: u32,
 }
 
 impl<'a> NewCollection<'a> {
     pub fn insert<'b>(&self, tx: &Transaction<'b>) -> anyhow::Result<Uuid> {
+        if self.min_copies < 1 {
+            bail!("A collection must require at least 1 copy of each file")
+        }
         let id = Uuid::new_v4();
         match tx.execute(
-            "INSERT INTO collections (id, name, created_date) VALUES (?, ?, ?)",
-            params![id.as_bytes(), self.name, Timestamp::now()],
+            "INSERT INTO collections (id, name, created_date, min_copies) VALUES (?, ?, ?, ?)",
+            params![id.as_bytes(), self.name, Timestamp::now(), self.min_copies],
         ) {
             Ok(_) => Ok(id),
             Err(ref e) if unique_violation(e, ["collections.name"]) => {
@@ -81,6 +131,87 @@ impl<'a> NewCollection<'a> {
     }
 }
 
+/// A virtual directory whose files should be kept together on as few partitions as possible.
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "cli", derive(Table))]
+pub struct PlacementAffinity {
+    #[cfg_attr(feature = "cli", table(skip = true))]
+    id: Uuid,
+    #[cfg_attr(feature = "cli", table(skip = true))]
+    collection_id: Uuid,
+    #[cfg_attr(feature = "cli", table(title = "Prefix"))]
+    prefix: String,
+}
+
+impl PlacementAffinity {
+    /// The virtual directory, always with a trailing `/`
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    fn star_mapper(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            collection_id: row.get("collection_id")?,
+            prefix: row.get("prefix")?,
+        })
+    }
+
+    pub fn for_collection(conn: &Connection, collection_id: &Uuid) -> anyhow::Result<Vec<Self>> {
+        let mut stmt = conn.prepare(
+            "SELECT * FROM placement_affinities WHERE collection_id = ? ORDER BY prefix",
+        )?;
+        let mut rows = stmt
+            .query_and_then([collection_id], Self::star_mapper)?
+            .map(|r| r.map_err(Into::into))
+            .collect::<Vec<anyhow::Result<Self>>>();
+        rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
+    }
+
+    pub fn delete<'b>(
+        tx: &Transaction<'b>,
+        collection_id: &Uuid,
+        prefix: &str,
+    ) -> anyhow::Result<()> {
+        match tx.execute(
+            "DELETE FROM placement_affinities WHERE collection_id = ? AND prefix = ?",
+            params![collection_id, prefix],
+        )? {
+            0 => bail!("No placement affinity was found for {prefix}"),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct NewPlacementAffinity<'a> {
+    pub collection_id: &'a Uuid,
+    pub prefix: &'a str,
+}
+
+impl<'a> NewPlacementAffinity<'a> {
+    pub fn insert<'b>(&self, tx: &Transaction<'b>) -> anyhow::Result<Uuid> {
+        let id = Uuid::new_v4();
+        match tx.execute(
+            "INSERT INTO placement_affinities (id, collection_id, prefix) VALUES (?, ?, ?)",
+            params![id.as_bytes(), self.collection_id, self.prefix],
+        ) {
+            Ok(_) => Ok(id),
+            Err(ref e)
+                if unique_violation(
+                    e,
+                    [
+                        "placement_affinities.collection_id",
+                        "placement_affinities.prefix",
+                    ],
+                ) =>
+            {
+                bail!("Placement affinity already exists: {}", self.prefix)
+            }
+            Err(e) => bail!("Unexpected DB error: {e:?}"),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -92,6 +223,7 @@ mod tests {
         let mut conn = fixtures::db();
         let new_col = NewCollection {
             name: "hella leaks",
+            min_copies: 1,
         };
         let id = auto_transaction(&mut conn, |tx| new_col.insert(tx)).unwrap();
         assert_eq!(
@@ -103,4 +235,65 @@ mod tests {
             vec![id]
         );
     }
+
+    #[test_log::test]
+    fn collection_set_min_copies() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        assert_eq!(coll.min_copies(), 1);
+        auto_transaction(&mut conn, |tx| Collection::set_min_copies(tx, coll.id(), 3)).unwrap();
+        let coll = Collection::for_id(&conn, coll.id()).unwrap().unwrap();
+        assert_eq!(coll.min_copies(), 3);
+    }
+
+    #[test_log::test]
+    fn collection_set_legal_hold() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        assert!(!coll.legal_hold());
+        auto_transaction(&mut conn, |tx| {
+            Collection::set_legal_hold(tx, coll.id(), true)
+        })
+        .unwrap();
+        let coll = Collection::for_id(&conn, coll.id()).unwrap().unwrap();
+        assert!(coll.legal_hold());
+    }
+
+    #[test_log::test]
+    fn collection_set_min_copies_zero() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let res = auto_transaction(&mut conn, |tx| Collection::set_min_copies(tx, coll.id(), 0));
+        assert!(res.is_err());
+    }
+
+    #[test_log::test]
+    fn placement_affinity_insert_fetch_delete() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let new_affinity = NewPlacementAffinity {
+            collection_id: coll.id(),
+            prefix: "/some-leak/",
+        };
+        auto_transaction(&mut conn, |tx| new_affinity.insert(tx)).unwrap();
+        assert!(auto_transaction(&mut conn, |tx| new_affinity.insert(tx)).is_err());
+
+        let affinities = PlacementAffinity::for_collection(&conn, coll.id()).unwrap();
+        assert_eq!(
+            affinities.iter().map(|a| a.prefix()).collect::<Vec<_>>(),
+            vec!["/some-leak/"]
+        );
+
+        auto_transaction(&mut conn, |tx| {
+            PlacementAffinity::delete(tx, coll.id(), "/some-leak/")
+        })
+        .unwrap();
+        assert!(PlacementAffinity::for_collection(&conn, coll.id())
+            .unwrap()
+            .is_empty());
+        assert!(auto_transaction(&mut conn, |tx| {
+            PlacementAffinity::delete(tx, coll.id(), "/some-leak/")
+        })
+        .is_err());
+    }
 }
@@ -1,8 +1,6 @@
 use crate::db::types::Timestamp;
 use crate::db::unique_violation;
 use crate::dev_utils;
-use rand::seq::SliceRandom;
-use rand::thread_rng;
 use rusqlite::types::Value;
 use rusqlite::{Connection, OptionalExtension, Row, Transaction};
 use std::rc::Rc;
@@ -22,6 +20,10 @@ impl Location {
         &self.id
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     fn star_mapper(row: &Row) -> rusqlite::Result<Self> {
         Ok(Self {
             id: row.get("id")?,
@@ -38,7 +40,6 @@ impl Location {
         rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
     }
 
-    #[cfg(test)]
     pub fn for_id(conn: &Connection, id: &Uuid) -> anyhow::Result<Option<Self>> {
         conn.query_row(
             "SELECT * FROM locations WHERE id = ?",
@@ -96,7 +97,6 @@ pub struct Disk {
 }
 
 impl Disk {
-    #[cfg(test)]
     pub fn id(&self) -> &Uuid {
         &self.id
     }
@@ -105,6 +105,10 @@ impl Disk {
         &self.label
     }
 
+    pub fn location_id(&self) -> &Uuid {
+        &self.location_id
+    }
+
     fn star_mapper(row: &Row) -> rusqlite::Result<Self> {
         Ok(Self {
             id: row.get("id")?,
@@ -142,6 +146,32 @@ impl Disk {
         .map_err(Into::into)
     }
 
+    /// The disks with a partition that has a copy of the file
+    pub fn for_file_id(conn: &Connection, file_id: &Uuid) -> anyhow::Result<Vec<Self>> {
+        let mut stmt = conn.prepare(concat!(
+            "SELECT DISTINCT d.* FROM disks AS d ",
+            "INNER JOIN partitions AS p ON p.disk_id = d.id ",
+            "INNER JOIN file_placements AS fp ON fp.partition_id = p.id ",
+            "WHERE fp.file_id = ? ",
+            "ORDER BY d.label",
+        ))?;
+        let mut rows = stmt
+            .query_and_then([file_id], Self::star_mapper)?
+            .map(|r| r.map_err(Into::into))
+            .collect::<Vec<anyhow::Result<Self>>>();
+        rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
+    }
+
+    pub fn for_label(conn: &Connection, label: &str) -> anyhow::Result<Option<Self>> {
+        conn.query_row(
+            "SELECT * FROM disks WHERE label = ?",
+            [label],
+            Self::star_mapper,
+        )
+        .optional()
+        .map_err(Into::into)
+    }
+
     pub fn for_id(conn: &Connection, id: &Uuid) -> anyhow::Result<Option<Self>> {
         conn.query_row(
             "SELECT * FROM disks WHERE id = ?",
@@ -207,8 +237,20 @@ pub struct Partition {
     // TODO hide this from the Table
     #[cfg_attr(feature = "cli", table(title = "UUID"))]
     uuid: String,
-    #[cfg_attr(feature = "cli", table(title = "Capacity (bytes)"))]
+    #[cfg_attr(feature = "cli", table(title = "Free Space (bytes)"))]
     capacity: u64,
+    #[cfg_attr(
+        feature = "cli",
+        table(title = "Directory", display_fn = "display_directory")
+    )]
+    directory: Option<String>,
+    #[cfg_attr(feature = "cli", table(skip = true))]
+    last_placed_date: Option<Timestamp>,
+}
+
+#[cfg(feature = "cli")]
+fn display_directory(directory: &Option<String>) -> &str {
+    directory.as_deref().unwrap_or("")
 }
 
 impl Partition {
@@ -216,16 +258,37 @@ impl Partition {
         &self.id
     }
 
+    pub fn disk_id(&self) -> &Uuid {
+        &self.disk_id
+    }
+
     pub fn uuid(&self) -> &str {
         &self.uuid
     }
 
+    /// The estimated free space on the partition in bytes
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// The directory of the storage target if it is not a block device partition
+    pub fn directory(&self) -> Option<&str> {
+        self.directory.as_deref()
+    }
+
+    /// When a file was last placed on the partition
+    pub fn last_placed_date(&self) -> Option<&Timestamp> {
+        self.last_placed_date.as_ref()
+    }
+
     fn star_mapper(row: &Row) -> rusqlite::Result<Self> {
         Ok(Self {
             id: row.get("id")?,
             disk_id: row.get("disk_id")?,
             uuid: row.get("uuid")?,
             capacity: row.get("capacity")?,
+            directory: row.get("directory")?,
+            last_placed_date: row.get("last_placed_date")?,
         })
     }
 
@@ -257,13 +320,38 @@ impl Partition {
         rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
     }
 
-    pub fn random(
-        conn: &Connection,
-        current_partition_uuids: &[&str],
-    ) -> anyhow::Result<Option<Self>> {
-        let choices = Self::current(conn, current_partition_uuids)?;
-        let mut rng = thread_rng();
-        Ok(choices.choose(&mut rng).cloned())
+    /// Set the free space of a partition to a freshly measured value.
+    pub fn refresh_capacity<'b>(
+        tx: &Transaction<'b>,
+        uuid: &str,
+        capacity: u64,
+    ) -> anyhow::Result<()> {
+        tx.execute(
+            "UPDATE partitions SET capacity = ? WHERE uuid = ?",
+            params![capacity, uuid],
+        )?;
+        Ok(())
+    }
+
+    /// Subtract the size of a newly placed file from the partition's free space.
+    pub fn record_placement<'b>(tx: &Transaction<'b>, id: &Uuid, size: u64) -> anyhow::Result<()> {
+        let sql = concat!(
+            "UPDATE partitions SET capacity = max(capacity - :size, 0), ",
+            "last_placed_date = :last_placed_date WHERE id = :id",
+        );
+        log::trace!("SQL:\n{}", sql);
+        let updated = tx.execute(
+            sql,
+            named_params! {
+                ":size": size,
+                ":last_placed_date": Timestamp::now(),
+                ":id": id,
+            },
+        )?;
+        if updated != 1 {
+            bail!("No partition was found for ID {}", id.hyphenated())
+        }
+        Ok(())
     }
 
     pub fn all(conn: &Connection) -> anyhow::Result<Vec<Self>> {
@@ -280,6 +368,7 @@ pub struct NewPartition<'a> {
     pub disk_id: &'a Uuid,
     pub uuid: &'a str,
     pub capacity: u64,
+    pub directory: Option<&'a str>,
 }
 
 impl<'a> NewPartition<'a> {
@@ -300,6 +389,7 @@ impl<'a> NewPartition<'a> {
             disk_id: &db_disk.id,
             uuid: partition.uuid(),
             capacity: partition.capacity(),
+            directory: None,
         }
         .insert(tx)
     }
@@ -307,8 +397,17 @@ impl<'a> NewPartition<'a> {
     pub fn insert<'b>(&self, tx: &Transaction<'b>) -> anyhow::Result<Uuid> {
         let id = Uuid::new_v4();
         match tx.execute(
-            "INSERT INTO partitions (id, disk_id, uuid, capacity) VALUES (?, ?, ?, ?)",
-            params![id.as_bytes(), self.disk_id, self.uuid, self.capacity],
+            concat!(
+                "INSERT INTO partitions (id, disk_id, uuid, capacity, directory) ",
+                "VALUES (?, ?, ?, ?, ?)",
+            ),
+            params![
+                id.as_bytes(),
+                self.disk_id,
+                self.uuid,
+                self.capacity,
+                self.directory
+            ],
         ) {
             Ok(_) => Ok(id),
             Err(ref e) if unique_violation(e, ["partitions.uuid"]) => {
@@ -369,6 +468,7 @@ mod tests {
             disk_id: &disk.id,
             uuid: "abc-123",
             capacity: 161,
+            directory: None,
         };
         let part_id = auto_transaction(&mut conn, |tx| new_part.insert(tx)).unwrap();
         assert_eq!(
@@ -397,4 +497,36 @@ mod tests {
         let current = Partition::current(&conn, &[partition.uuid()]).unwrap();
         assert_eq!(&current, &[partition]);
     }
+
+    #[test_log::test]
+    fn partition_record_placement() {
+        let mut conn = fixtures::db();
+        let loc = fixtures::location(&mut conn);
+        let disk = fixtures::disk(&mut conn, &loc);
+        let part = fixtures::partition(&mut conn, &disk);
+        assert!(part.last_placed_date().is_none());
+
+        auto_transaction(&mut conn, |tx| {
+            Partition::record_placement(tx, &part.id, 20)
+        })
+        .unwrap();
+        let updated = Partition::for_id(&conn, &part.id).unwrap().unwrap();
+        assert_eq!(updated.capacity(), part.capacity() - 20);
+        assert!(updated.last_placed_date().is_some());
+
+        // free space doesn't go negative
+        auto_transaction(&mut conn, |tx| {
+            Partition::record_placement(tx, &part.id, u32::MAX as u64)
+        })
+        .unwrap();
+        let updated = Partition::for_id(&conn, &part.id).unwrap().unwrap();
+        assert_eq!(updated.capacity(), 0);
+
+        auto_transaction(&mut conn, |tx| {
+            Partition::refresh_capacity(tx, &part.uuid, 9001)
+        })
+        .unwrap();
+        let updated = Partition::for_id(&conn, &part.id).unwrap().unwrap();
+        assert_eq!(updated.capacity(), 9001);
+    }
 }
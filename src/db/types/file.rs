@@ -1,8 +1,13 @@
+use crate::archive_utils::{ContainerType, EntryType};
 use crate::db::types::Timestamp;
 use crate::db::unique_violation;
+use crate::error::GenericError;
 use crate::hash_utils::HashAlgorithm;
 use regex::Regex;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Value, ValueRef};
 use rusqlite::{Connection, OptionalExtension, Row, ToSql, Transaction};
+use std::fmt;
+use std::rc::Rc;
 use uuid::Uuid;
 
 #[derive(Debug, PartialEq)]
@@ -12,6 +17,8 @@ pub struct File {
     path: String,
     created_date: Timestamp,
     size: u64,
+    content_file_id: Option<Uuid>,
+    container_type: Option<ContainerType>,
 }
 
 impl File {
@@ -19,6 +26,10 @@ impl File {
         &self.id
     }
 
+    pub fn collection_id(&self) -> &Uuid {
+        &self.collection_id
+    }
+
     pub fn path(&self) -> &str {
         &self.path
     }
@@ -27,6 +38,16 @@ impl File {
         self.size
     }
 
+    /// The file whose physical copies this file shares, if it was deduplicated
+    pub fn content_file_id(&self) -> Option<&Uuid> {
+        self.content_file_id.as_ref()
+    }
+
+    /// The archive or compression format detected when the file was added
+    pub fn container_type(&self) -> Option<ContainerType> {
+        self.container_type
+    }
+
     fn star_mapper(row: &Row) -> rusqlite::Result<Self> {
         Ok(Self {
             id: row.get("id")?,
@@ -34,10 +55,11 @@ impl File {
             path: row.get("path")?,
             created_date: row.get("created_date")?,
             size: row.get("size")?,
+            content_file_id: row.get("content_file_id")?,
+            container_type: row.get("container_type")?,
         })
     }
 
-    #[cfg(test)]
     pub fn for_id(conn: &Connection, id: &Uuid) -> anyhow::Result<Option<Self>> {
         conn.query_row(
             "SELECT * FROM files WHERE id = ?",
@@ -146,6 +168,304 @@ impl File {
             .collect::<Vec<anyhow::Result<Self>>>();
         rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
     }
+
+    /// The file that has the physical copies of this file's content. This is the file itself
+    /// unless it was deduplicated.
+    pub fn content_file(self, conn: &Connection) -> anyhow::Result<Self> {
+        match self.content_file_id {
+            Some(id) => Self::for_id(conn, &id)?
+                .ok_or_else(|| anyhow!("Content file not found: {}", id.hyphenated())),
+            None => Ok(self),
+        }
+    }
+
+    /// The files that share this file's physical copies.
+    pub fn referrers(conn: &Connection, id: &Uuid) -> anyhow::Result<Vec<Self>> {
+        let mut stmt =
+            conn.prepare("SELECT * FROM files WHERE content_file_id = ? ORDER BY path")?;
+        let mut rows = stmt
+            .query_and_then([id], Self::star_mapper)?
+            .map(|r| r.map_err(Into::into))
+            .collect::<Vec<anyhow::Result<Self>>>();
+        rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
+    }
+
+    /// A file with its own physical copies that has the given size and hash values. Only files
+    /// that have a hash for at least one of the algorithms that isn't in `lookup_only` and no
+    /// conflicting hashes match.
+    pub fn for_content(
+        conn: &Connection,
+        size: u64,
+        hashes: &[(HashAlgorithm, Vec<u8>)],
+        lookup_only: &[HashAlgorithm],
+    ) -> anyhow::Result<Option<Self>> {
+        if hashes.iter().all(|(a, _)| lookup_only.contains(a)) {
+            return Ok(None);
+        }
+        Ok(Self::with_content(conn, size, hashes, lookup_only)?
+            .into_iter()
+            .find(|f| f.content_file_id.is_none()))
+    }
+
+    /// All files in any collection that have the given size and hash values, oldest first. Files
+    /// match the same way as for [`File::for_content`], except that they can be found by
+    /// lookup-only hashes.
+    pub fn with_content(
+        conn: &Connection,
+        size: u64,
+        hashes: &[(HashAlgorithm, Vec<u8>)],
+        lookup_only: &[HashAlgorithm],
+    ) -> anyhow::Result<Vec<Self>> {
+        let (first_algorithm, first_value) = match trusted_first(hashes, lookup_only) {
+            Some(hash) => hash,
+            None => return Ok(Vec::new()),
+        };
+        let sql = concat!(
+            "SELECT f.* FROM files AS f ",
+            "INNER JOIN file_hashes AS h ON h.file_id = f.id ",
+            "WHERE f.size = :size ",
+            "  AND h.hash_algorithm = :hash_algorithm AND h.hash_value = :hash_value ",
+            "ORDER BY f.created_date",
+        );
+        log::trace!("SQL:\n{}", sql);
+
+        let mut stmt = conn.prepare(sql)?;
+        let candidates = stmt
+            .query_and_then(
+                named_params! {
+                    ":size": size,
+                    ":hash_algorithm": first_algorithm,
+                    ":hash_value": first_value,
+                },
+                Self::star_mapper,
+            )?
+            .collect::<rusqlite::Result<Vec<Self>>>()?;
+        let mut output = Vec::new();
+        for candidate in candidates {
+            let stored = FileHash::get_by_file_id(conn, candidate.id())?
+                .into_iter()
+                .map(|h| (h.hash_algorithm, h.hash_value))
+                .collect::<Vec<_>>();
+            if !hashes_conflict(&stored, hashes) {
+                output.push(candidate);
+            }
+        }
+        Ok(output)
+    }
+
+    /// Files with their own physical copies that are in the collection or that files in it were
+    /// deduplicated onto, and that have fewer placements than they need, along with their current
+    /// and needed number of placements. A file needs the highest `min_copies` of its own
+    /// collection and the collections of all of the files that share its content.
+    pub fn under_replicated(
+        conn: &Connection,
+        collection_id: &Uuid,
+    ) -> anyhow::Result<Vec<(Self, u32, u32)>> {
+        let sql = concat!(
+            "WITH required AS (",
+            "  SELECT coalesce(f.content_file_id, f.id) AS file_id, ",
+            "    max(c.min_copies) AS min_copies ",
+            "  FROM files AS f ",
+            "  INNER JOIN collections AS c ON c.id = f.collection_id ",
+            "  GROUP BY coalesce(f.content_file_id, f.id)",
+            ") ",
+            "SELECT f.*, count(fp.partition_id) AS copies, r.min_copies AS min_copies ",
+            "FROM files AS f ",
+            "INNER JOIN required AS r ON r.file_id = f.id ",
+            "LEFT OUTER JOIN file_placements AS fp ON fp.file_id = f.id ",
+            // deduplicated files are replicated through their content file
+            "WHERE f.content_file_id IS NULL ",
+            "  AND (f.collection_id = :collection_id OR EXISTS (",
+            "    SELECT 1 FROM files AS d ",
+            "    WHERE d.content_file_id = f.id AND d.collection_id = :collection_id",
+            "  )) ",
+            "GROUP BY f.id ",
+            "HAVING count(fp.partition_id) < r.min_copies ",
+            "ORDER BY f.collection_id, f.path",
+        );
+        log::trace!("SQL:\n{}", sql);
+
+        let mut stmt = conn.prepare(sql)?;
+        let mut rows = stmt
+            .query_and_then(named_params! {":collection_id": collection_id}, |row| {
+                Ok((
+                    Self::star_mapper(row)?,
+                    row.get("copies")?,
+                    row.get("min_copies")?,
+                ))
+            })?
+            .collect::<Vec<anyhow::Result<(Self, u32, u32)>>>();
+        rows.drain(..)
+            .collect::<anyhow::Result<Vec<(Self, u32, u32)>>>()
+    }
+
+    /// All files whose path starts with `prefix`, ordered by path.
+    pub fn with_prefix(
+        conn: &Connection,
+        collection_id: &Uuid,
+        prefix: &str,
+    ) -> anyhow::Result<Vec<Self>> {
+        let mut stmt = conn.prepare(concat!(
+            "SELECT * FROM files ",
+            "WHERE collection_id = :collection_id AND substr(path, 1, length(:prefix)) = :prefix ",
+            "ORDER BY path",
+        ))?;
+        let mut rows = stmt
+            .query_and_then(
+                named_params! {
+                    ":collection_id": collection_id,
+                    ":prefix": prefix,
+                },
+                Self::star_mapper,
+            )?
+            .map(|r| r.map_err(Into::into))
+            .collect::<Vec<anyhow::Result<Self>>>();
+        rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
+    }
+
+    pub fn set_path<'b>(tx: &Transaction<'b>, id: &Uuid, path: &str) -> anyhow::Result<()> {
+        match tx.execute("UPDATE files SET path = ? WHERE id = ?", params![path, id]) {
+            Ok(1) => Ok(()),
+            Ok(_) => bail!("File not found: {}", id.hyphenated()),
+            Err(ref e) if unique_violation(e, ["files.collection_id", "files.path"]) => {
+                bail!("That path already exists for the collection: {path}")
+            }
+            Err(e) => bail!("Unexpected DB error: {e:?}"),
+        }
+    }
+
+    /// Delete the file along with its placements, checks, hashes, archive listings, and mail
+    /// headers.
+    pub fn delete<'b>(tx: &Transaction<'b>, id: &Uuid) -> anyhow::Result<()> {
+        for sql in [
+            "DELETE FROM file_placement_checks WHERE file_id = ?",
+            "DELETE FROM file_placements WHERE file_id = ?",
+            "DELETE FROM file_hashes WHERE file_id = ?",
+            "DELETE FROM mail_messages WHERE file_id = ?",
+            concat!(
+                "DELETE FROM file_archive_hashes WHERE file_archive_id IN ",
+                "(SELECT id FROM file_archives WHERE file_id = ?)",
+            ),
+            "DELETE FROM file_archives WHERE file_id = ?",
+        ] {
+            tx.execute(sql, [id])?;
+        }
+        match tx.execute("DELETE FROM files WHERE id = ?", [id])? {
+            0 => bail!("File not found: {}", id.hyphenated()),
+            _ => Ok(()),
+        }
+    }
+
+    /// All files with a placement on one of the given partitions along with the ID of the
+    /// partition. Files with placements on multiple of the partitions are returned once per
+    /// partition.
+    pub fn placed_on_partitions(
+        conn: &Connection,
+        partition_ids: &[&Uuid],
+        collection_id: Option<&Uuid>,
+    ) -> anyhow::Result<Vec<(Self, Uuid)>> {
+        let mut sql = concat!(
+            "SELECT f.*, fp.partition_id AS partition_id FROM files AS f ",
+            "INNER JOIN file_placements AS fp ON fp.file_id = f.id ",
+            "WHERE fp.partition_id IN rarray(:partition_ids)",
+        )
+        .to_string();
+        let partition_ids = Rc::new(
+            partition_ids
+                .iter()
+                .map(|id| Value::Blob(id.as_bytes().to_vec()))
+                .collect::<Vec<_>>(),
+        );
+        let mut params: Vec<(&str, &dyn ToSql)> = vec![(":partition_ids", &partition_ids)];
+        if let Some(collection_id) = collection_id.as_ref() {
+            sql += " AND f.collection_id = :collection_id";
+            params.push((":collection_id", collection_id));
+        }
+        sql += " ORDER BY f.path";
+        log::trace!("SQL:\n{}", sql);
+
+        let mut stmt = conn.prepare(&sql)?;
+        let mut rows = stmt
+            .query_and_then(&*params, |row| {
+                Ok((Self::star_mapper(row)?, row.get("partition_id")?))
+            })?
+            .collect::<Vec<anyhow::Result<(Self, Uuid)>>>();
+        rows.drain(..)
+            .collect::<anyhow::Result<Vec<(Self, Uuid)>>>()
+    }
+}
+
+/// A file with the same content as at least one other file, in any collection.
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "cli", derive(Table))]
+pub struct DuplicateFile {
+    #[cfg_attr(feature = "cli", table(title = "Hash"))]
+    hash_value: String,
+    #[cfg_attr(feature = "cli", table(title = "Size (bytes)"))]
+    size: u64,
+    #[cfg_attr(feature = "cli", table(title = "Collection"))]
+    collection_name: String,
+    #[cfg_attr(feature = "cli", table(title = "Path"))]
+    path: String,
+    #[cfg_attr(feature = "cli", table(title = "Deduplicated"))]
+    deduplicated: bool,
+}
+
+impl DuplicateFile {
+    /// The hex-encoded hash value shared by the duplicates
+    pub fn hash_value(&self) -> &str {
+        &self.hash_value
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Whether the file shares another file's physical copies instead of having its own
+    pub fn deduplicated(&self) -> bool {
+        self.deduplicated
+    }
+
+    /// All files whose size and hash for the algorithm match another file's, grouped by hash.
+    pub fn all(conn: &Connection, hash_algorithm: &HashAlgorithm) -> anyhow::Result<Vec<Self>> {
+        let sql = concat!(
+            "SELECT h.hash_value AS hash_value, f.size AS size, c.name AS collection_name, ",
+            "  f.path AS path, f.content_file_id IS NOT NULL AS deduplicated ",
+            "FROM files AS f ",
+            "INNER JOIN file_hashes AS h ",
+            "  ON h.file_id = f.id AND h.hash_algorithm = :hash_algorithm ",
+            "INNER JOIN collections AS c ON c.id = f.collection_id ",
+            "WHERE (h.hash_value, f.size) IN (",
+            "  SELECT h2.hash_value, f2.size FROM files AS f2 ",
+            "  INNER JOIN file_hashes AS h2 ",
+            "    ON h2.file_id = f2.id AND h2.hash_algorithm = :hash_algorithm ",
+            "  GROUP BY h2.hash_value, f2.size HAVING count(*) > 1",
+            ") ",
+            "ORDER BY h.hash_value, c.name, f.path",
+        );
+        log::trace!("SQL:\n{}", sql);
+
+        let mut stmt = conn.prepare(sql)?;
+        let mut rows = stmt
+            .query_and_then(
+                named_params! { ":hash_algorithm": hash_algorithm },
+                |row| -> anyhow::Result<Self> {
+                    Ok(Self {
+                        hash_value: hex::encode(row.get::<_, Vec<u8>>("hash_value")?),
+                        size: row.get("size")?,
+                        collection_name: row.get("collection_name")?,
+                        path: row.get("path")?,
+                        deduplicated: row.get("deduplicated")?,
+                    })
+                },
+            )?
+            .collect::<Vec<anyhow::Result<Self>>>();
+        rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -153,6 +473,8 @@ pub struct NewFile<'a> {
     pub collection_id: &'a Uuid,
     pub path: &'a str,
     pub size: u64,
+    pub content_file_id: Option<&'a Uuid>,
+    pub container_type: Option<ContainerType>,
 }
 
 impl<'a> NewFile<'a> {
@@ -160,8 +482,10 @@ impl<'a> NewFile<'a> {
         let id = Uuid::new_v4();
         match tx.execute(
             concat!(
-                "INSERT INTO files (id, collection_id, path, size, created_date) ",
-                "VALUES (:id, :collection_id, :path, :size, :created_date)"
+                "INSERT INTO files ",
+                "(id, collection_id, path, size, created_date, content_file_id, container_type) ",
+                "VALUES (:id, :collection_id, :path, :size, :created_date, :content_file_id, ",
+                "        :container_type)"
             ),
             named_params! {
                 ":id": id.as_bytes(),
@@ -169,6 +493,8 @@ impl<'a> NewFile<'a> {
                 ":path": self.path,
                 ":size": self.size,
                 ":created_date": Timestamp::now(),
+                ":content_file_id": self.content_file_id,
+                ":container_type": self.container_type,
             },
         ) {
             Ok(_) => Ok(id),
@@ -212,6 +538,80 @@ impl FilePlacement {
     }
 }
 
+/// How many of the files under a virtual directory are on one partition.
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "cli", derive(Table))]
+pub struct PlacementSpread {
+    #[cfg_attr(feature = "cli", table(title = "Disk"))]
+    disk_label: String,
+    #[cfg_attr(feature = "cli", table(title = "Location"))]
+    location_name: String,
+    #[cfg_attr(feature = "cli", table(title = "Partition ID"))]
+    partition_id: Uuid,
+    #[cfg_attr(feature = "cli", table(title = "Files"))]
+    files: u64,
+    #[cfg_attr(feature = "cli", table(title = "Size (bytes)"))]
+    bytes: u64,
+}
+
+impl PlacementSpread {
+    pub fn disk_label(&self) -> &str {
+        &self.disk_label
+    }
+
+    pub fn partition_id(&self) -> &Uuid {
+        &self.partition_id
+    }
+
+    pub fn files(&self) -> u64 {
+        self.files
+    }
+
+    /// Placements of files whose path starts with `prefix` grouped by partition, with the
+    /// partitions holding the most files first.
+    pub fn for_prefix(
+        conn: &Connection,
+        collection_id: &Uuid,
+        prefix: &str,
+    ) -> anyhow::Result<Vec<Self>> {
+        let sql = concat!(
+            "SELECT d.label AS disk_label, l.name AS location_name, p.id AS partition_id, ",
+            "  count(*) AS files, sum(f.size) AS bytes ",
+            "FROM files AS f ",
+            "INNER JOIN file_placements AS fp ON fp.file_id = f.id ",
+            "INNER JOIN partitions AS p ON p.id = fp.partition_id ",
+            "INNER JOIN disks AS d ON d.id = p.disk_id ",
+            "INNER JOIN locations AS l ON l.id = d.location_id ",
+            "WHERE f.collection_id = :collection_id ",
+            "  AND substr(f.path, 1, length(:prefix)) = :prefix ",
+            "GROUP BY p.id ",
+            "ORDER BY files DESC, d.label",
+        );
+        log::trace!("SQL:\n{}", sql);
+
+        let mut stmt = conn.prepare(sql)?;
+        let mut rows = stmt
+            .query_and_then(
+                named_params! {
+                    ":collection_id": collection_id,
+                    ":prefix": prefix,
+                },
+                |row| -> rusqlite::Result<Self> {
+                    Ok(Self {
+                        disk_label: row.get("disk_label")?,
+                        location_name: row.get("location_name")?,
+                        partition_id: row.get("partition_id")?,
+                        files: row.get("files")?,
+                        bytes: row.get("bytes")?,
+                    })
+                },
+            )?
+            .map(|r| r.map_err(Into::into))
+            .collect::<Vec<anyhow::Result<Self>>>();
+        rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct NewFilePlacement<'a> {
     pub file_id: &'a Uuid,
@@ -242,6 +642,31 @@ impl<'a> NewFilePlacement<'a> {
     }
 }
 
+/// Whether any of the stored hashes has a different value than the given hash for the same
+/// algorithm
+fn hashes_conflict(
+    stored: &[(HashAlgorithm, Vec<u8>)],
+    hashes: &[(HashAlgorithm, Vec<u8>)],
+) -> bool {
+    hashes.iter().any(|(algorithm, value)| {
+        stored
+            .iter()
+            .any(|(a, v)| a == algorithm && v.as_slice() != value.as_slice())
+    })
+}
+
+/// The hash to find candidates by. Lookup-only hashes can find files that only have those, but
+/// a trusted hash is preferred when there is one.
+fn trusted_first<'a>(
+    hashes: &'a [(HashAlgorithm, Vec<u8>)],
+    lookup_only: &[HashAlgorithm],
+) -> Option<&'a (HashAlgorithm, Vec<u8>)> {
+    hashes
+        .iter()
+        .find(|(a, _)| !lookup_only.contains(a))
+        .or_else(|| hashes.first())
+}
+
 #[derive(Debug, PartialEq)]
 pub struct FileHash {
     file_id: Uuid,
@@ -254,6 +679,10 @@ impl FileHash {
         self.hash_algorithm
     }
 
+    pub fn hash_value(&self) -> &[u8] {
+        &self.hash_value
+    }
+
     pub fn hash_value_hex(&self) -> String {
         hex::encode(&self.hash_value)
     }
@@ -305,150 +734,1330 @@ impl<'a> NewFileHash<'a> {
     }
 }
 
+/// A member of an archive file.
 #[derive(Debug, PartialEq)]
-pub struct NewFileArchive<'a> {
-    pub file_id: &'a Uuid,
-    pub path: &'a str,
-    pub size: u64,
+pub struct FileArchive {
+    id: Uuid,
+    file_id: Uuid,
+    path: String,
+    size: u64,
+    entry_type: Option<EntryType>,
+    link_target: Option<String>,
+    modified_date: Option<Timestamp>,
+    compressed_size: Option<u64>,
+    crc32: Option<u32>,
+    encrypted: Option<bool>,
 }
 
-impl<'a> NewFileArchive<'a> {
-    pub fn insert<'b>(&self, tx: &Transaction<'b>) -> anyhow::Result<Uuid> {
-        let id = Uuid::new_v4();
-        match tx.execute(
-            "INSERT INTO file_archives (id, file_id, path, size) VALUES (?, ?, ?, ?)",
-            params![&id, &self.file_id, &self.path, &self.size],
-        ) {
-            Ok(_) => Ok(id),
-            Err(ref e) if unique_violation(e, ["file_archives.file_id", "file_archives.path"]) => {
-                bail!(
-                    "The archive with file ID {} already the path {}",
-                    self.file_id.hyphenated(),
-                    self.path,
-                )
-            }
-            Err(e) => bail!("Unexpected DB error: {e:?}"),
-        }
+impl FileArchive {
+    pub fn id(&self) -> &Uuid {
+        &self.id
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::db::auto_transaction;
-    use crate::db::types::Collection;
-    use crate::test_utils::fixtures;
-    use std::collections::HashSet;
+    /// The path of the member relative to the root of the archive
+    pub fn path(&self) -> &str {
+        &self.path
+    }
 
-    #[test_log::test]
-    fn file_insert_and_fetch() {
-        let mut conn = fixtures::db();
-        let coll = fixtures::collection(&mut conn);
-        let new_file = NewFile {
-            collection_id: coll.id(),
-            path: "/foo",
-            size: 123,
-        };
-        let file_id = auto_transaction(&mut conn, |tx| new_file.insert(tx)).unwrap();
-        assert_eq!(File::for_id(&conn, &file_id).unwrap().unwrap().id, file_id);
+    pub fn size(&self) -> u64 {
+        self.size
     }
 
-    #[test_log::test]
-    fn file_placement_insert() {
-        let mut conn = fixtures::db();
-        let coll = fixtures::collection(&mut conn);
-        let loc = fixtures::location(&mut conn);
-        let disk = fixtures::disk(&mut conn, &loc);
-        let partition = fixtures::partition(&mut conn, &disk);
-        let file = fixtures::file(&mut conn, &coll);
-        let new_placement = NewFilePlacement {
-            file_id: file.id(),
-            partition_id: partition.id(),
-        };
-        auto_transaction(&mut conn, |tx| new_placement.insert(tx)).unwrap();
+    /// These are `None` for members listed before the metadata was stored
+    pub fn entry_type(&self) -> Option<EntryType> {
+        self.entry_type
     }
 
-    #[test_log::test]
-    fn file_hash_insert() {
-        let mut conn = fixtures::db();
-        let coll = fixtures::collection(&mut conn);
-        let file = fixtures::file(&mut conn, &coll);
-        let new_hash = NewFileHash {
-            file_id: file.id(),
-            hash_algorithm: &HashAlgorithm::Sha256,
-            hash_value: b"1337",
-        };
-        auto_transaction(&mut conn, |tx| new_hash.insert(tx)).unwrap();
+    pub fn link_target(&self) -> Option<&str> {
+        self.link_target.as_deref()
     }
 
-    #[test_log::test]
-    fn file_archive_insert() {
-        let mut conn = fixtures::db();
-        let coll = fixtures::collection(&mut conn);
-        let file = fixtures::file(&mut conn, &coll);
-        let new_archive_file = NewFileArchive {
-            file_id: file.id(),
-            path: "./wat.txt",
-            size: 6969,
-        };
-        auto_transaction(&mut conn, |tx| new_archive_file.insert(tx)).unwrap();
+    /// When the member was last modified according to the archive
+    pub fn modified_date(&self) -> Option<&Timestamp> {
+        self.modified_date.as_ref()
     }
 
-    #[test]
-    fn file_ls_dir_simple() {
-        let mut conn = fixtures::db();
-        let coll = fixtures::collection(&mut conn);
-        let paths = hashset! {"/foo/bar", "/foo/.baz"};
-        auto_transaction::<'_, _, anyhow::Error, _>(&mut conn, |tx| {
-            for path in &paths {
-                NewFile {
-                    collection_id: coll.id(),
-                    path,
-                    size: 123,
-                }
-                .insert(tx)?;
-            }
-            Ok(())
-        })
-        .unwrap();
+    pub fn compressed_size(&self) -> Option<u64> {
+        self.compressed_size
+    }
 
-        let files = File::get_by_collection_and_directory(&conn, coll.id(), true, "/foo").unwrap();
-        let found_paths = files.iter().map(|f| f.path()).collect::<HashSet<_>>();
-        assert_eq!(found_paths, paths);
+    pub fn crc32(&self) -> Option<u32> {
+        self.crc32
     }
 
-    #[test]
-    fn file_ls_dir_hidden() {
-        let mut conn = fixtures::db();
-        let coll = fixtures::collection(&mut conn);
-        auto_transaction::<'_, _, anyhow::Error, _>(&mut conn, |tx| {
-            for path in &["/foo/bar", "/foo/.baz"] {
-                NewFile {
-                    collection_id: coll.id(),
-                    path,
-                    size: 123,
-                }
-                .insert(tx)?;
-            }
-            Ok(())
-        })
-        .unwrap();
+    pub fn encrypted(&self) -> Option<bool> {
+        self.encrypted
+    }
 
-        let files = File::get_by_collection_and_directory(&conn, coll.id(), false, "/foo").unwrap();
-        let found_paths = files.iter().map(|f| f.path()).collect::<Vec<_>>();
-        assert_eq!(found_paths, vec!["/foo/bar"]);
+    fn star_mapper(row: &Row) -> rusqlite::Result<Self> {
+        Self::prefixed_mapper(row, "")
     }
 
-    const FIND_IN_DIR_PATHS: &[&str] = &[
-        "/foo.txt",
-        "/foo/bar.txt",
-        "/foo/bar/baz.txt",
-        "/foo/bar/baz/quux.txt",
-        "/foo/bar/baz/quux/lol.txt",
-    ];
+    /// Map the columns whose names start with `prefix`
+    fn prefixed_mapper(row: &Row, prefix: &str) -> rusqlite::Result<Self> {
+        let col = |name: &str| format!("{prefix}{name}");
+        Ok(Self {
+            id: row.get(&*col("id"))?,
+            file_id: row.get(&*col("file_id"))?,
+            path: row.get(&*col("path"))?,
+            size: row.get(&*col("size"))?,
+            entry_type: row.get(&*col("entry_type"))?,
+            link_target: row.get(&*col("link_target"))?,
+            modified_date: row.get(&*col("modified_date"))?,
+            compressed_size: row.get(&*col("compressed_size"))?,
+            crc32: row.get(&*col("crc32"))?,
+            encrypted: row.get(&*col("encrypted"))?,
+        })
+    }
 
-    fn find_in_dir_test_cases() -> (Connection, Collection) {
-        let mut conn = fixtures::db();
+    pub fn get_by_file_id(conn: &Connection, file_id: &Uuid) -> anyhow::Result<Vec<Self>> {
+        let mut stmt =
+            conn.prepare("SELECT * FROM file_archives WHERE file_id = ? ORDER BY path")?;
+        let mut rows = stmt
+            .query_and_then([file_id], Self::star_mapper)?
+            .map(|r| r.map_err(Into::into))
+            .collect::<Vec<anyhow::Result<Self>>>();
+        rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
+    }
+
+    pub fn get_by_file_and_path(
+        conn: &Connection,
+        file_id: &Uuid,
+        path: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        conn.query_row(
+            "SELECT * FROM file_archives WHERE file_id = ? AND path = ?",
+            params![file_id, path],
+            Self::star_mapper,
+        )
+        .optional()
+        .map_err(Into::into)
+    }
+
+    /// The members of the archive that are directly in the directory `path` of the archive, like
+    /// [`File::get_by_collection_and_directory`]. An empty path is the root of the archive.
+    /// Directory members are included (e.g., `docs/`), as are members of nested archives when
+    /// the path is in one (e.g., `inner.zip!/`).
+    pub fn get_by_file_and_directory(
+        conn: &Connection,
+        file_id: &Uuid,
+        all: bool,
+        path: &str,
+    ) -> anyhow::Result<Vec<Self>> {
+        let mut re = regex::escape(path);
+        re.insert(0, '^');
+        if !re.ends_with('/') && !path.is_empty() {
+            re.push('/')
+        }
+        if !all {
+            re.push_str(r"[^\.]");
+        }
+        re.push_str("[^/]*/?$");
+
+        let mut stmt = conn.prepare(
+            "SELECT * FROM file_archives WHERE file_id = ? AND path REGEXP ? ORDER BY path",
+        )?;
+        let mut rows = stmt
+            .query_and_then(params![file_id, re], Self::star_mapper)?
+            .map(|r| r.map_err(Into::into))
+            .collect::<Vec<anyhow::Result<Self>>>();
+        rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
+    }
+
+    /// Members of the archives that are at or under `prefix`. A member's virtual path is the
+    /// archive's path and the member's path joined by `!/` (e.g., `/leak.tar!/docs/a.pdf`), and
+    /// the depth and path filters are applied to it the same way as to files.
+    /// The prefix can also be a directory in an archive (e.g., `/leak.tar!/docs/`), and depths
+    /// count archives as directories.
+    pub fn find_in_dir(
+        conn: &Connection,
+        collection_id: &Uuid,
+        prefix: &str,
+        min_depth: Option<u32>,
+        max_depth: Option<u32>,
+        name: Option<&Regex>,
+        path: Option<&Regex>,
+    ) -> anyhow::Result<Vec<(File, Self)>> {
+        let mut sql = concat!(
+            "SELECT f.*, a.id AS member_id, a.file_id AS member_file_id, a.path AS member_path, ",
+            "  a.size AS member_size, a.entry_type AS member_entry_type, ",
+            "  a.link_target AS member_link_target, a.modified_date AS member_modified_date, ",
+            "  a.compressed_size AS member_compressed_size, a.crc32 AS member_crc32, ",
+            "  a.encrypted AS member_encrypted ",
+            "FROM files AS f ",
+            "INNER JOIN file_archives AS a ON a.file_id = f.id ",
+            "WHERE f.collection_id = :collection_id ",
+            "  AND (f.path = :prefix OR f.path || '!/' || a.path = :prefix ",
+            "    OR substr(f.path || '!/' || a.path, 1, length(:dir)) = :dir)",
+        )
+        .to_string();
+        let dir = if prefix.ends_with('/') {
+            prefix.to_string()
+        } else {
+            format!("{prefix}/")
+        };
+        let mut params: Vec<(&str, &dyn ToSql)> = vec![
+            (":collection_id", collection_id),
+            (":prefix", &prefix),
+            (":dir", &dir),
+        ];
+
+        // need refs for lifetimes
+        let min_depth = min_depth.as_ref();
+        let max_depth = max_depth.as_ref();
+        let name = name.map(ToString::to_string);
+        let path = path.map(ToString::to_string);
+        let name = name.as_ref();
+        let path = path.as_ref();
+
+        if let Some(min) = min_depth {
+            sql += " AND relative_depth(:prefix, f.path || '!/' || a.path) >= :min_depth";
+            params.push((":min_depth", min));
+        }
+        if let Some(max) = max_depth {
+            sql += " AND relative_depth(:prefix, f.path || '!/' || a.path) <= :max_depth";
+            params.push((":max_depth", max));
+        }
+        if let Some(name) = name {
+            sql += " AND basename(a.path) REGEXP :name";
+            params.push((":name", name));
+        }
+        if let Some(path) = path {
+            sql += " AND (f.path || '!/' || a.path) REGEXP :path";
+            params.push((":path", path));
+        }
+        sql += " ORDER BY f.path, a.path";
+        log::trace!("SQL:\n{}", sql);
+
+        let mut stmt = conn.prepare(&sql)?;
+        let mut rows = stmt
+            .query_and_then(&*params, |row| -> anyhow::Result<(File, Self)> {
+                Ok((
+                    File::star_mapper(row)?,
+                    Self::prefixed_mapper(row, "member_")?,
+                ))
+            })?
+            .collect::<Vec<anyhow::Result<(File, Self)>>>();
+        rows.drain(..)
+            .collect::<anyhow::Result<Vec<(File, Self)>>>()
+    }
+
+    /// Members of archives in any collection that have the given size and hash values along with
+    /// the archive they are in. Members match the same way as files in [`File::with_content`].
+    pub fn with_content(
+        conn: &Connection,
+        size: u64,
+        hashes: &[(HashAlgorithm, Vec<u8>)],
+        lookup_only: &[HashAlgorithm],
+    ) -> anyhow::Result<Vec<(File, Self)>> {
+        let (first_algorithm, first_value) = match trusted_first(hashes, lookup_only) {
+            Some(hash) => hash,
+            None => return Ok(Vec::new()),
+        };
+        let sql = concat!(
+            "SELECT f.*, a.id AS member_id, a.file_id AS member_file_id, a.path AS member_path, ",
+            "  a.size AS member_size, a.entry_type AS member_entry_type, ",
+            "  a.link_target AS member_link_target, a.modified_date AS member_modified_date, ",
+            "  a.compressed_size AS member_compressed_size, a.crc32 AS member_crc32, ",
+            "  a.encrypted AS member_encrypted ",
+            "FROM file_archives AS a ",
+            "INNER JOIN files AS f ON f.id = a.file_id ",
+            "INNER JOIN file_archive_hashes AS h ON h.file_archive_id = a.id ",
+            "WHERE a.size = :size ",
+            "  AND h.hash_algorithm = :hash_algorithm AND h.hash_value = :hash_value ",
+            "ORDER BY f.path, a.path",
+        );
+        log::trace!("SQL:\n{}", sql);
+
+        let mut stmt = conn.prepare(sql)?;
+        let candidates = stmt
+            .query_and_then(
+                named_params! {
+                    ":size": size,
+                    ":hash_algorithm": first_algorithm,
+                    ":hash_value": first_value,
+                },
+                |row| -> rusqlite::Result<(File, Self)> {
+                    Ok((
+                        File::star_mapper(row)?,
+                        Self::prefixed_mapper(row, "member_")?,
+                    ))
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<(File, Self)>>>()?;
+        let mut output = Vec::new();
+        for (file, member) in candidates {
+            let stored = FileArchiveHash::get_by_file_archive_id(conn, &member.id)?
+                .into_iter()
+                .map(|h| (h.hash_algorithm, h.hash_value))
+                .collect::<Vec<_>>();
+            if !hashes_conflict(&stored, hashes) {
+                output.push((file, member));
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// A hash of the contents of an archive member.
+#[derive(Debug, PartialEq)]
+pub struct FileArchiveHash {
+    file_archive_id: Uuid,
+    hash_algorithm: HashAlgorithm,
+    hash_value: Vec<u8>,
+}
+
+impl FileArchiveHash {
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
+    pub fn hash_value(&self) -> &[u8] {
+        &self.hash_value
+    }
+
+    fn star_mapper(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            file_archive_id: row.get("file_archive_id")?,
+            hash_algorithm: row.get("hash_algorithm")?,
+            hash_value: row.get("hash_value")?,
+        })
+    }
+
+    pub fn get_by_file_archive_id(
+        conn: &Connection,
+        file_archive_id: &Uuid,
+    ) -> anyhow::Result<Vec<Self>> {
+        let mut stmt =
+            conn.prepare("SELECT * FROM file_archive_hashes WHERE file_archive_id = ?")?;
+        let mut rows = stmt
+            .query_and_then([file_archive_id], Self::star_mapper)?
+            .map(|r| r.map_err(Into::into))
+            .collect::<Vec<anyhow::Result<Self>>>();
+        rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NewFileArchiveHash<'a> {
+    pub file_archive_id: &'a Uuid,
+    pub hash_algorithm: &'a HashAlgorithm,
+    pub hash_value: &'a [u8],
+}
+
+impl<'a> NewFileArchiveHash<'a> {
+    pub fn insert<'b>(&self, tx: &Transaction<'b>) -> anyhow::Result<Uuid> {
+        let id = Uuid::new_v4();
+        match tx.execute(
+            concat!(
+                "INSERT INTO file_archive_hashes (id, file_archive_id, hash_algorithm, hash_value) ",
+                "VALUES (?, ?, ?, ?)",
+            ),
+            params![&id, &self.file_archive_id, &self.hash_algorithm, &self.hash_value],
+        ) {
+            Ok(_) => Ok(id),
+            Err(ref e)
+                if unique_violation(
+                    e,
+                    [
+                        "file_archive_hashes.file_archive_id",
+                        "file_archive_hashes.hash_algorithm",
+                    ],
+                ) =>
+            {
+                bail!(
+                    "The archive member with ID {} already has a hash with name {}",
+                    self.file_archive_id.hyphenated(),
+                    self.hash_algorithm,
+                )
+            }
+            Err(e) => bail!("Unexpected DB error: {e:?}"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NewFileArchive<'a> {
+    pub file_id: &'a Uuid,
+    pub path: &'a str,
+    pub size: u64,
+    /// The member of the same file that is the nested archive this member is in
+    pub parent_id: Option<&'a Uuid>,
+    pub entry_type: EntryType,
+    pub link_target: Option<&'a str>,
+    pub modified_date: Option<&'a Timestamp>,
+    pub compressed_size: Option<u64>,
+    pub crc32: Option<u32>,
+    pub encrypted: bool,
+}
+
+impl<'a> NewFileArchive<'a> {
+    pub fn insert<'b>(&self, tx: &Transaction<'b>) -> anyhow::Result<Uuid> {
+        let id = Uuid::new_v4();
+        match tx.execute(
+            concat!(
+                "INSERT INTO file_archives (id, file_id, path, size, parent_id, entry_type, ",
+                "  link_target, modified_date, compressed_size, crc32, encrypted) ",
+                "VALUES (:id, :file_id, :path, :size, :parent_id, :entry_type, :link_target, ",
+                "  :modified_date, :compressed_size, :crc32, :encrypted)"
+            ),
+            named_params! {
+                ":id": &id,
+                ":file_id": self.file_id,
+                ":path": self.path,
+                ":size": self.size,
+                ":parent_id": self.parent_id,
+                ":entry_type": self.entry_type,
+                ":link_target": self.link_target,
+                ":modified_date": self.modified_date,
+                ":compressed_size": self.compressed_size,
+                ":crc32": self.crc32,
+                ":encrypted": self.encrypted,
+            },
+        ) {
+            Ok(_) => Ok(id),
+            Err(ref e) if unique_violation(e, ["file_archives.file_id", "file_archives.path"]) => {
+                bail!(
+                    "The archive with file ID {} already the path {}",
+                    self.file_id.hyphenated(),
+                    self.path,
+                )
+            }
+            Err(e) => bail!("Unexpected DB error: {e:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckOutcome {
+    Ok,
+    Mismatch,
+    Missing,
+    SizeDiffers,
+    /// No trusted hashes are stored for the file
+    Unverifiable,
+}
+
+impl fmt::Display for CheckOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let val = match self {
+            Self::Ok => "ok",
+            Self::Mismatch => "mismatch",
+            Self::Missing => "missing",
+            Self::SizeDiffers => "size-differs",
+            Self::Unverifiable => "unverifiable",
+        };
+        write!(f, "{}", val)
+    }
+}
+
+impl TryFrom<&str> for CheckOutcome {
+    type Error = GenericError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "ok" => Ok(Self::Ok),
+            "mismatch" => Ok(Self::Mismatch),
+            "missing" => Ok(Self::Missing),
+            "size-differs" => Ok(Self::SizeDiffers),
+            "unverifiable" => Ok(Self::Unverifiable),
+            x => Err(GenericError::new(format!("Not a known check outcome: {x}"))),
+        }
+    }
+}
+
+impl ToSql for CheckOutcome {
+    #[inline]
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_string()))
+    }
+}
+
+impl FromSql for CheckOutcome {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Text(bytes) => ::std::str::from_utf8(bytes)
+                .map_err(|e| FromSqlError::Other(Box::new(e)))?
+                .try_into()
+                .map_err(|e| FromSqlError::Other(Box::new(e))),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FilePlacementCheck {
+    partition_id: Uuid,
+    file_id: Uuid,
+    checked_date: Timestamp,
+    outcome: CheckOutcome,
+}
+
+impl FilePlacementCheck {
+    pub fn partition_id(&self) -> &Uuid {
+        &self.partition_id
+    }
+
+    pub fn checked_date(&self) -> &Timestamp {
+        &self.checked_date
+    }
+
+    pub fn outcome(&self) -> CheckOutcome {
+        self.outcome
+    }
+
+    fn star_mapper(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            partition_id: row.get("partition_id")?,
+            file_id: row.get("file_id")?,
+            checked_date: row.get("checked_date")?,
+            outcome: row.get("outcome")?,
+        })
+    }
+
+    /// The most recent check of each of the file's placements.
+    pub fn latest_by_file_id(conn: &Connection, file_id: &Uuid) -> anyhow::Result<Vec<Self>> {
+        let sql = concat!(
+            "SELECT * FROM file_placement_checks AS c ",
+            "WHERE c.file_id = :file_id AND c.checked_date = (",
+            "  SELECT max(c2.checked_date) FROM file_placement_checks AS c2",
+            "  WHERE c2.file_id = c.file_id AND c2.partition_id = c.partition_id",
+            ")",
+        );
+        let mut stmt = conn.prepare(sql)?;
+        let mut rows = stmt
+            .query_and_then(named_params! {":file_id": file_id}, Self::star_mapper)?
+            .map(|r| r.map_err(Into::into))
+            .collect::<Vec<anyhow::Result<Self>>>();
+        rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NewFilePlacementCheck<'a> {
+    pub partition_id: &'a Uuid,
+    pub file_id: &'a Uuid,
+    pub outcome: CheckOutcome,
+}
+
+impl<'a> NewFilePlacementCheck<'a> {
+    pub fn insert<'b>(&self, tx: &Transaction<'b>) -> anyhow::Result<Uuid> {
+        let id = Uuid::new_v4();
+        match tx.execute(
+            concat!(
+                "INSERT INTO file_placement_checks ",
+                "(id, partition_id, file_id, checked_date, outcome) ",
+                "VALUES (?, ?, ?, ?, ?)",
+            ),
+            params![
+                &id,
+                &self.partition_id,
+                &self.file_id,
+                Timestamp::now(),
+                &self.outcome
+            ],
+        ) {
+            Ok(_) => Ok(id),
+            Err(e) => bail!("Unexpected DB error: {e:?}"),
+        }
+    }
+}
+
+/// A rename of a file on a partition that wasn't mounted when the file was moved.
+#[derive(Debug, PartialEq)]
+pub struct PendingMove {
+    id: Uuid,
+    partition_id: Uuid,
+    collection_id: Uuid,
+    old_path: String,
+    new_path: String,
+    seq: i64,
+}
+
+impl PendingMove {
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    pub fn collection_id(&self) -> &Uuid {
+        &self.collection_id
+    }
+
+    pub fn old_path(&self) -> &str {
+        &self.old_path
+    }
+
+    pub fn new_path(&self) -> &str {
+        &self.new_path
+    }
+
+    /// The position in the queue shared with [`PendingDeletion`]s
+    pub fn seq(&self) -> i64 {
+        self.seq
+    }
+
+    fn star_mapper(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            partition_id: row.get("partition_id")?,
+            collection_id: row.get("collection_id")?,
+            old_path: row.get("old_path")?,
+            new_path: row.get("new_path")?,
+            seq: row.get("seq")?,
+        })
+    }
+
+    /// The moves for a partition in the order they were queued.
+    pub fn for_partition(conn: &Connection, partition_id: &Uuid) -> anyhow::Result<Vec<Self>> {
+        let mut stmt =
+            conn.prepare("SELECT * FROM pending_moves WHERE partition_id = ? ORDER BY seq")?;
+        let mut rows = stmt
+            .query_and_then([partition_id], Self::star_mapper)?
+            .map(|r| r.map_err(Into::into))
+            .collect::<Vec<anyhow::Result<Self>>>();
+        rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
+    }
+
+    pub fn delete<'b>(tx: &Transaction<'b>, id: &Uuid) -> anyhow::Result<()> {
+        tx.execute("DELETE FROM pending_moves WHERE id = ?", [id])?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NewPendingMove<'a> {
+    pub partition_id: &'a Uuid,
+    pub collection_id: &'a Uuid,
+    pub old_path: &'a str,
+    pub new_path: &'a str,
+}
+
+impl<'a> NewPendingMove<'a> {
+    pub fn insert<'b>(&self, tx: &Transaction<'b>) -> anyhow::Result<Uuid> {
+        let id = Uuid::new_v4();
+        tx.execute(
+            concat!(
+                "INSERT INTO pending_moves ",
+                "(id, partition_id, collection_id, old_path, new_path, created_date, seq) ",
+                "VALUES (:id, :partition_id, :collection_id, :old_path, :new_path, :created_date, ",
+                // the queue is shared with pending deletions
+                "(SELECT coalesce(max(seq), 0) + 1 FROM ",
+                "(SELECT seq FROM pending_moves UNION ALL SELECT seq FROM pending_deletions)))",
+            ),
+            named_params! {
+                ":id": id.as_bytes(),
+                ":partition_id": self.partition_id,
+                ":collection_id": self.collection_id,
+                ":old_path": self.old_path,
+                ":new_path": self.new_path,
+                ":created_date": Timestamp::now(),
+            },
+        )?;
+        Ok(id)
+    }
+}
+
+/// A copy of a removed file on a partition that wasn't mounted when the file was removed.
+#[derive(Debug, PartialEq)]
+pub struct PendingDeletion {
+    id: Uuid,
+    partition_id: Uuid,
+    collection_id: Uuid,
+    path: String,
+    seq: i64,
+}
+
+impl PendingDeletion {
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    pub fn collection_id(&self) -> &Uuid {
+        &self.collection_id
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The position in the queue shared with [`PendingMove`]s
+    pub fn seq(&self) -> i64 {
+        self.seq
+    }
+
+    fn star_mapper(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            partition_id: row.get("partition_id")?,
+            collection_id: row.get("collection_id")?,
+            path: row.get("path")?,
+            seq: row.get("seq")?,
+        })
+    }
+
+    /// The deletions for a partition in the order they were queued.
+    pub fn for_partition(conn: &Connection, partition_id: &Uuid) -> anyhow::Result<Vec<Self>> {
+        let mut stmt =
+            conn.prepare("SELECT * FROM pending_deletions WHERE partition_id = ? ORDER BY seq")?;
+        let mut rows = stmt
+            .query_and_then([partition_id], Self::star_mapper)?
+            .map(|r| r.map_err(Into::into))
+            .collect::<Vec<anyhow::Result<Self>>>();
+        rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
+    }
+
+    pub fn delete<'b>(tx: &Transaction<'b>, id: &Uuid) -> anyhow::Result<()> {
+        tx.execute("DELETE FROM pending_deletions WHERE id = ?", [id])?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NewPendingDeletion<'a> {
+    pub partition_id: &'a Uuid,
+    pub collection_id: &'a Uuid,
+    pub path: &'a str,
+}
+
+impl<'a> NewPendingDeletion<'a> {
+    pub fn insert<'b>(&self, tx: &Transaction<'b>) -> anyhow::Result<Uuid> {
+        let id = Uuid::new_v4();
+        tx.execute(
+            concat!(
+                "INSERT INTO pending_deletions ",
+                "(id, partition_id, collection_id, path, created_date, seq) ",
+                "VALUES (:id, :partition_id, :collection_id, :path, :created_date, ",
+                // the queue is shared with pending moves
+                "(SELECT coalesce(max(seq), 0) + 1 FROM ",
+                "(SELECT seq FROM pending_moves UNION ALL SELECT seq FROM pending_deletions)))",
+            ),
+            named_params! {
+                ":id": id.as_bytes(),
+                ":partition_id": self.partition_id,
+                ":collection_id": self.collection_id,
+                ":path": self.path,
+                ":created_date": Timestamp::now(),
+            },
+        )?;
+        Ok(id)
+    }
+}
+
+/// A copy or move of an added file on to a partition that may have been interrupted.
+#[derive(Debug, PartialEq)]
+pub struct PendingPlacement {
+    id: Uuid,
+    partition_id: Uuid,
+    collection_id: Uuid,
+    path: String,
+    src_path: Option<String>,
+    file_id: Option<Uuid>,
+    created_date: Timestamp,
+}
+
+impl PendingPlacement {
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    pub fn partition_id(&self) -> &Uuid {
+        &self.partition_id
+    }
+
+    pub fn collection_id(&self) -> &Uuid {
+        &self.collection_id
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The local file that is being moved, or `None` if the file is being copied
+    pub fn src_path(&self) -> Option<&str> {
+        self.src_path.as_deref()
+    }
+
+    /// The file that was placed, once it's in the DB. It may have been moved or removed since.
+    pub fn file_id(&self) -> Option<&Uuid> {
+        self.file_id.as_ref()
+    }
+
+    fn star_mapper(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            partition_id: row.get("partition_id")?,
+            collection_id: row.get("collection_id")?,
+            path: row.get("path")?,
+            src_path: row.get("src_path")?,
+            file_id: row.get("file_id")?,
+            created_date: row.get("created_date")?,
+        })
+    }
+
+    /// All placements in the order they were started.
+    pub fn all(conn: &Connection) -> anyhow::Result<Vec<Self>> {
+        let mut stmt =
+            conn.prepare("SELECT * FROM pending_placements ORDER BY created_date, rowid")?;
+        let mut rows = stmt
+            .query_and_then([], Self::star_mapper)?
+            .map(|r| r.map_err(Into::into))
+            .collect::<Vec<anyhow::Result<Self>>>();
+        rows.drain(..).collect::<anyhow::Result<Vec<Self>>>()
+    }
+
+    /// Record the file in the same transaction that inserts it, so that the placement can be
+    /// finished by the file's ID even if its path changes.
+    pub fn set_file_id<'b>(tx: &Transaction<'b>, id: &Uuid, file_id: &Uuid) -> anyhow::Result<()> {
+        tx.execute(
+            "UPDATE pending_placements SET file_id = ? WHERE id = ?",
+            [file_id, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete<'b>(tx: &Transaction<'b>, id: &Uuid) -> anyhow::Result<()> {
+        tx.execute("DELETE FROM pending_placements WHERE id = ?", [id])?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NewPendingPlacement<'a> {
+    pub partition_id: &'a Uuid,
+    pub collection_id: &'a Uuid,
+    pub path: &'a str,
+    pub src_path: Option<&'a str>,
+}
+
+impl<'a> NewPendingPlacement<'a> {
+    pub fn insert<'b>(&self, tx: &Transaction<'b>) -> anyhow::Result<Uuid> {
+        let id = Uuid::new_v4();
+        tx.execute(
+            concat!(
+                "INSERT INTO pending_placements ",
+                "(id, partition_id, collection_id, path, src_path, created_date) ",
+                "VALUES (:id, :partition_id, :collection_id, :path, :src_path, :created_date)",
+            ),
+            named_params! {
+                ":id": id.as_bytes(),
+                ":partition_id": self.partition_id,
+                ":collection_id": self.collection_id,
+                ":path": self.path,
+                ":src_path": self.src_path,
+                ":created_date": Timestamp::now(),
+            },
+        )?;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::auto_transaction;
+    use crate::db::types::{Collection, NewCollection};
+    use crate::test_utils::fixtures;
+    use std::collections::HashSet;
+
+    const LOOKUP_ONLY: &[HashAlgorithm] = &[HashAlgorithm::Md5, HashAlgorithm::Crc32c];
+
+    #[test_log::test]
+    fn file_insert_and_fetch() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let new_file = NewFile {
+            collection_id: coll.id(),
+            path: "/foo",
+            size: 123,
+            content_file_id: None,
+            container_type: None,
+        };
+        let file_id = auto_transaction(&mut conn, |tx| new_file.insert(tx)).unwrap();
+        assert_eq!(File::for_id(&conn, &file_id).unwrap().unwrap().id, file_id);
+    }
+
+    #[test_log::test]
+    fn file_placement_insert() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let loc = fixtures::location(&mut conn);
+        let disk = fixtures::disk(&mut conn, &loc);
+        let partition = fixtures::partition(&mut conn, &disk);
+        let file = fixtures::file(&mut conn, &coll);
+        let new_placement = NewFilePlacement {
+            file_id: file.id(),
+            partition_id: partition.id(),
+        };
+        auto_transaction(&mut conn, |tx| new_placement.insert(tx)).unwrap();
+    }
+
+    #[test_log::test]
+    fn file_hash_insert() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let file = fixtures::file(&mut conn, &coll);
+        let new_hash = NewFileHash {
+            file_id: file.id(),
+            hash_algorithm: &HashAlgorithm::Sha256,
+            hash_value: b"1337",
+        };
+        auto_transaction(&mut conn, |tx| new_hash.insert(tx)).unwrap();
+    }
+
+    #[test_log::test]
+    fn file_archive_insert() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let file = fixtures::file(&mut conn, &coll);
+        let new_archive_file = NewFileArchive {
+            file_id: file.id(),
+            path: "./wat.txt",
+            size: 6969,
+            parent_id: None,
+            entry_type: EntryType::File,
+            link_target: None,
+            modified_date: None,
+            compressed_size: None,
+            crc32: None,
+            encrypted: false,
+        };
+        auto_transaction(&mut conn, |tx| new_archive_file.insert(tx)).unwrap();
+
+        auto_transaction(&mut conn, |tx| {
+            let parent_id = NewFileArchive {
+                file_id: file.id(),
+                path: "inner.zip",
+                size: 100,
+                parent_id: None,
+                entry_type: EntryType::File,
+                link_target: None,
+                modified_date: None,
+                compressed_size: None,
+                crc32: None,
+                encrypted: false,
+            }
+            .insert(tx)?;
+            NewFileArchive {
+                file_id: file.id(),
+                path: "inner.zip!/wat.txt",
+                size: 10,
+                parent_id: Some(&parent_id),
+                entry_type: EntryType::File,
+                link_target: None,
+                modified_date: None,
+                compressed_size: None,
+                crc32: Some(0xdeadbeef),
+                encrypted: false,
+            }
+            .insert(tx)
+        })
+        .unwrap();
+        assert_eq!(
+            FileArchive::get_by_file_id(&conn, file.id())
+                .unwrap()
+                .iter()
+                .map(|a| a.path())
+                .collect::<Vec<_>>(),
+            vec!["./wat.txt", "inner.zip", "inner.zip!/wat.txt"]
+        );
+        let members = FileArchive::get_by_file_id(&conn, file.id()).unwrap();
+        assert_eq!(members[2].entry_type(), Some(EntryType::File));
+        assert_eq!(members[2].crc32(), Some(0xdeadbeef));
+        assert_eq!(members[2].encrypted(), Some(false));
+    }
+
+    #[test_log::test]
+    fn file_archive_find_in_dir() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let file = fixtures::file(&mut conn, &coll);
+        auto_transaction(&mut conn, |tx| {
+            for path in ["a.pdf", "docs/b.pdf", "docs/c.txt"] {
+                NewFileArchive {
+                    file_id: file.id(),
+                    path,
+                    size: 10,
+                    parent_id: None,
+                    entry_type: EntryType::File,
+                    link_target: None,
+                    modified_date: None,
+                    compressed_size: None,
+                    crc32: None,
+                    encrypted: false,
+                }
+                .insert(tx)?;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .unwrap();
+
+        let members = |prefix, min_depth, max_depth, name: Option<&str>| {
+            let name = name.map(|n| Regex::new(n).unwrap());
+            FileArchive::find_in_dir(
+                &conn,
+                coll.id(),
+                prefix,
+                min_depth,
+                max_depth,
+                name.as_ref(),
+                None,
+            )
+            .unwrap()
+            .into_iter()
+            .map(|(f, a)| format!("{}!/{}", f.path(), a.path()))
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(members("/", None, None, Some(r"\.pdf$")).len(), 2);
+        assert_eq!(members(file.path(), None, None, None).len(), 3);
+        assert_eq!(
+            members("/", None, Some(2), None),
+            vec![format!("{}!/a.pdf", file.path())]
+        );
+        assert!(members("/other/", None, None, None).is_empty());
+        assert_eq!(
+            FileArchive::get_by_file_id(&conn, file.id()).unwrap().len(),
+            3
+        );
+
+        // searching in the archive
+        let docs = format!("{}!/docs/", file.path());
+        assert_eq!(members(&docs, None, None, None).len(), 2);
+        let root = format!("{}!/", file.path());
+        assert_eq!(
+            members(&root, None, Some(1), None),
+            vec![format!("{}!/a.pdf", file.path())]
+        );
+        assert_eq!(members(file.path(), Some(2), None, None).len(), 2);
+    }
+
+    #[test_log::test]
+    fn file_archive_get_by_file_and_directory() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let file = fixtures::file(&mut conn, &coll);
+        auto_transaction(&mut conn, |tx| {
+            for path in [
+                "a.pdf",
+                ".hidden",
+                "docs/",
+                "docs/b.pdf",
+                "inner.zip",
+                "inner.zip!/c.pdf",
+            ] {
+                NewFileArchive {
+                    file_id: file.id(),
+                    path,
+                    size: 10,
+                    parent_id: None,
+                    entry_type: EntryType::File,
+                    link_target: None,
+                    modified_date: None,
+                    compressed_size: None,
+                    crc32: None,
+                    encrypted: false,
+                }
+                .insert(tx)?;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .unwrap();
+
+        let paths = |all, dir| {
+            FileArchive::get_by_file_and_directory(&conn, file.id(), all, dir)
+                .unwrap()
+                .into_iter()
+                .map(|a| a.path)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(false, ""), vec!["a.pdf", "docs/", "inner.zip"]);
+        assert_eq!(paths(true, "").len(), 4);
+        assert_eq!(paths(false, "docs"), vec!["docs/b.pdf"]);
+        assert_eq!(paths(false, "inner.zip!/"), vec!["inner.zip!/c.pdf"]);
+        assert!(paths(false, "nope/").is_empty());
+        assert!(
+            FileArchive::get_by_file_and_path(&conn, file.id(), "docs/b.pdf")
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test_log::test]
+    fn under_replicated() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let loc = fixtures::location(&mut conn);
+        let disk = fixtures::disk(&mut conn, &loc);
+        let partition = fixtures::partition(&mut conn, &disk);
+        let (file, _, _) = fixtures::file_full(&mut conn, &partition, &coll);
+
+        assert_eq!(File::under_replicated(&conn, coll.id()).unwrap(), vec![]);
+
+        auto_transaction(&mut conn, |tx| Collection::set_min_copies(tx, coll.id(), 2)).unwrap();
+        let found = File::under_replicated(&conn, coll.id()).unwrap();
+        assert_eq!(found, vec![(file, 1, 2)]);
+    }
+
+    #[test_log::test]
+    fn under_replicated_deduplicated() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let loc = fixtures::location(&mut conn);
+        let disk = fixtures::disk(&mut conn, &loc);
+        let partition = fixtures::partition(&mut conn, &disk);
+        let (file, _, _) = fixtures::file_full(&mut conn, &partition, &coll);
+        let strict_id = auto_transaction(&mut conn, |tx| {
+            let id = NewCollection {
+                name: "strict",
+                min_copies: 3,
+            }
+            .insert(tx)?;
+            NewFile {
+                collection_id: &id,
+                path: "/copy.txt",
+                size: file.size(),
+                content_file_id: Some(file.id()),
+                container_type: None,
+            }
+            .insert(tx)?;
+            Ok::<_, anyhow::Error>(id)
+        })
+        .unwrap();
+
+        // the content needs as many copies as the strictest collection sharing it asks for
+        let found = File::under_replicated(&conn, &strict_id).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].0.id(), found[0].1, found[0].2), (file.id(), 1, 3));
+        let found = File::under_replicated(&conn, coll.id()).unwrap();
+        assert_eq!(found, vec![(file, 1, 3)]);
+        let other = auto_transaction(&mut conn, |tx| {
+            NewCollection {
+                name: "other",
+                min_copies: 1,
+            }
+            .insert(tx)
+        })
+        .unwrap();
+        assert!(File::under_replicated(&conn, &other).unwrap().is_empty());
+    }
+
+    #[test_log::test]
+    fn placed_on_partitions() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let loc = fixtures::location(&mut conn);
+        let disk = fixtures::disk(&mut conn, &loc);
+        let partition = fixtures::partition(&mut conn, &disk);
+        let (file, _, _) = fixtures::file_full(&mut conn, &partition, &coll);
+
+        let found = File::placed_on_partitions(&conn, &[partition.id()], None).unwrap();
+        assert_eq!(found, vec![(file, *partition.id())]);
+
+        let found =
+            File::placed_on_partitions(&conn, &[partition.id()], Some(&Uuid::new_v4())).unwrap();
+        assert_eq!(found, vec![]);
+    }
+
+    #[test_log::test]
+    fn placement_spread_for_prefix() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let loc = fixtures::location(&mut conn);
+        let disk = fixtures::disk(&mut conn, &loc);
+        let partition = fixtures::partition(&mut conn, &disk);
+        let (file, _, _) = fixtures::file_full(&mut conn, &partition, &coll);
+
+        let spread = PlacementSpread::for_prefix(&conn, coll.id(), "/").unwrap();
+        assert_eq!(spread.len(), 1);
+        assert_eq!(spread[0].partition_id(), partition.id());
+        assert_eq!(spread[0].disk_label(), disk.label());
+        assert_eq!(spread[0].files(), 1);
+        assert_eq!(spread[0].bytes, file.size());
+
+        let spread = PlacementSpread::for_prefix(&conn, coll.id(), "/foo/").unwrap();
+        assert!(spread.is_empty());
+    }
+
+    #[test_log::test]
+    fn file_set_path_and_pending_moves() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let loc = fixtures::location(&mut conn);
+        let disk = fixtures::disk(&mut conn, &loc);
+        let partition = fixtures::partition(&mut conn, &disk);
+        let (file, _, _) = fixtures::file_full(&mut conn, &partition, &coll);
+
+        auto_transaction(&mut conn, |tx| {
+            File::set_path(tx, file.id(), "/bar/foo.txt")?;
+            NewPendingMove {
+                partition_id: partition.id(),
+                collection_id: coll.id(),
+                old_path: "/foo.txt",
+                new_path: "/bar/foo.txt",
+            }
+            .insert(tx)?;
+            NewPendingMove {
+                partition_id: partition.id(),
+                collection_id: coll.id(),
+                old_path: "/bar/foo.txt",
+                new_path: "/baz.txt",
+            }
+            .insert(tx)
+        })
+        .unwrap();
+        let moved = File::with_prefix(&conn, coll.id(), "/bar/").unwrap();
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[0].path(), "/bar/foo.txt");
+        assert!(File::with_prefix(&conn, coll.id(), "/ba/")
+            .unwrap()
+            .is_empty());
+
+        // moves are returned in the order they were queued
+        let pending = PendingMove::for_partition(&conn, partition.id()).unwrap();
+        assert_eq!(
+            pending.iter().map(|p| p.new_path()).collect::<Vec<_>>(),
+            vec!["/bar/foo.txt", "/baz.txt"]
+        );
+        auto_transaction(&mut conn, |tx| PendingMove::delete(tx, pending[0].id())).unwrap();
+        assert_eq!(
+            PendingMove::for_partition(&conn, partition.id())
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // deletions are queued after the moves, even within the same timestamp
+        auto_transaction(&mut conn, |tx| {
+            NewPendingDeletion {
+                partition_id: partition.id(),
+                collection_id: coll.id(),
+                path: "/baz.txt",
+            }
+            .insert(tx)?;
+            NewPendingMove {
+                partition_id: partition.id(),
+                collection_id: coll.id(),
+                old_path: "/other.txt",
+                new_path: "/baz.txt",
+            }
+            .insert(tx)
+        })
+        .unwrap();
+        let deletions = PendingDeletion::for_partition(&conn, partition.id()).unwrap();
+        let moves = PendingMove::for_partition(&conn, partition.id()).unwrap();
+        assert!(moves[0].seq() < deletions[0].seq());
+        assert!(deletions[0].seq() < moves[1].seq());
+    }
+
+    #[test_log::test]
+    fn file_delete() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let loc = fixtures::location(&mut conn);
+        let disk = fixtures::disk(&mut conn, &loc);
+        let partition = fixtures::partition(&mut conn, &disk);
+        let (file, _, _) = fixtures::file_full(&mut conn, &partition, &coll);
+        let new_check = NewFilePlacementCheck {
+            partition_id: partition.id(),
+            file_id: file.id(),
+            outcome: CheckOutcome::Ok,
+        };
+        auto_transaction(&mut conn, |tx| new_check.insert(tx)).unwrap();
+
+        auto_transaction(&mut conn, |tx| File::delete(tx, file.id())).unwrap();
+        assert_eq!(File::for_id(&conn, file.id()).unwrap(), None);
+        assert!(FilePlacement::get_by_file_id(&conn, file.id())
+            .unwrap()
+            .is_empty());
+        assert!(FileHash::get_by_file_id(&conn, file.id())
+            .unwrap()
+            .is_empty());
+        assert!(auto_transaction(&mut conn, |tx| File::delete(tx, file.id())).is_err());
+    }
+
+    #[test_log::test]
+    fn file_placement_check_insert_and_fetch() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let loc = fixtures::location(&mut conn);
+        let disk = fixtures::disk(&mut conn, &loc);
+        let partition = fixtures::partition(&mut conn, &disk);
+        let (file, _, _) = fixtures::file_full(&mut conn, &partition, &coll);
+
+        for outcome in [CheckOutcome::Mismatch, CheckOutcome::Ok] {
+            let new_check = NewFilePlacementCheck {
+                partition_id: partition.id(),
+                file_id: file.id(),
+                outcome,
+            };
+            auto_transaction(&mut conn, |tx| new_check.insert(tx)).unwrap();
+            // timestamps have millisecond precision
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+
+        let checks = FilePlacementCheck::latest_by_file_id(&conn, file.id()).unwrap();
+        assert_eq!(checks.len(), 1);
+        assert_eq!(checks[0].outcome(), CheckOutcome::Ok);
+    }
+
+    #[test]
+    fn file_ls_dir_simple() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let paths = hashset! {"/foo/bar", "/foo/.baz"};
+        auto_transaction::<'_, _, anyhow::Error, _>(&mut conn, |tx| {
+            for path in &paths {
+                NewFile {
+                    collection_id: coll.id(),
+                    path,
+                    size: 123,
+                    content_file_id: None,
+                    container_type: None,
+                }
+                .insert(tx)?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        let files = File::get_by_collection_and_directory(&conn, coll.id(), true, "/foo").unwrap();
+        let found_paths = files.iter().map(|f| f.path()).collect::<HashSet<_>>();
+        assert_eq!(found_paths, paths);
+    }
+
+    #[test]
+    fn file_ls_dir_hidden() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        auto_transaction::<'_, _, anyhow::Error, _>(&mut conn, |tx| {
+            for path in &["/foo/bar", "/foo/.baz"] {
+                NewFile {
+                    collection_id: coll.id(),
+                    path,
+                    size: 123,
+                    content_file_id: None,
+                    container_type: None,
+                }
+                .insert(tx)?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        let files = File::get_by_collection_and_directory(&conn, coll.id(), false, "/foo").unwrap();
+        let found_paths = files.iter().map(|f| f.path()).collect::<Vec<_>>();
+        assert_eq!(found_paths, vec!["/foo/bar"]);
+    }
+
+    const FIND_IN_DIR_PATHS: &[&str] = &[
+        "/foo.txt",
+        "/foo/bar.txt",
+        "/foo/bar/baz.txt",
+        "/foo/bar/baz/quux.txt",
+        "/foo/bar/baz/quux/lol.txt",
+    ];
+
+    fn find_in_dir_test_cases() -> (Connection, Collection) {
+        let mut conn = fixtures::db();
         let coll = fixtures::collection(&mut conn);
 
         auto_transaction::<'_, _, anyhow::Error, _>(&mut conn, |tx| {
@@ -457,6 +2066,8 @@ mod tests {
                     collection_id: coll.id(),
                     path,
                     size: 123,
+                    content_file_id: None,
+                    container_type: None,
                 }
                 .insert(tx)?;
             }
@@ -524,4 +2135,116 @@ mod tests {
         let res = res.iter().map(|f| f.path()).collect::<HashSet<_>>();
         assert_eq!(res, FIND_IN_DIR_PATHS[2..].iter().copied().collect());
     }
+
+    #[test_log::test]
+    fn content_file_and_duplicates() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let loc = fixtures::location(&mut conn);
+        let disk = fixtures::disk(&mut conn, &loc);
+        let partition = fixtures::partition(&mut conn, &disk);
+        let (file, _, hashes) = fixtures::file_full(&mut conn, &partition, &coll);
+        let content = hashes
+            .iter()
+            .map(|h| (h.hash_algorithm(), h.hash_value().to_vec()))
+            .collect::<Vec<_>>();
+
+        let found = File::for_content(&conn, file.size(), &content, LOOKUP_ONLY).unwrap();
+        assert_eq!(found.as_ref(), Some(&file));
+        assert!(
+            File::for_content(&conn, file.size() + 1, &content, LOOKUP_ONLY)
+                .unwrap()
+                .is_none()
+        );
+        let conflicting = vec![(content[0].0, b"nope".to_vec())];
+        assert!(
+            File::for_content(&conn, file.size(), &conflicting, LOOKUP_ONLY)
+                .unwrap()
+                .is_none()
+        );
+
+        // lookup-only hashes find files but aren't enough to share their content
+        let md5 = (HashAlgorithm::Md5, b"md5".to_vec());
+        auto_transaction(&mut conn, |tx| {
+            NewFileHash {
+                file_id: file.id(),
+                hash_algorithm: &md5.0,
+                hash_value: &md5.1,
+            }
+            .insert(tx)
+        })
+        .unwrap();
+        let lookup_only = vec![md5.clone()];
+        assert_eq!(
+            File::with_content(&conn, file.size(), &lookup_only, LOOKUP_ONLY).unwrap(),
+            vec![File::for_id(&conn, file.id()).unwrap().unwrap()]
+        );
+        assert!(
+            File::for_content(&conn, file.size(), &lookup_only, LOOKUP_ONLY)
+                .unwrap()
+                .is_none()
+        );
+        let mixed = vec![md5, content[0].clone()];
+        let found = File::for_content(&conn, file.size(), &mixed, LOOKUP_ONLY).unwrap();
+        assert_eq!(found.as_ref(), Some(&file));
+        // and any algorithm can be configured as lookup-only
+        let excluded = [HashAlgorithm::Md5, content[0].0];
+        assert!(File::for_content(&conn, file.size(), &mixed, &excluded)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            File::with_content(&conn, file.size(), &mixed, &excluded)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let copy_id = auto_transaction(&mut conn, |tx| {
+            let id = NewFile {
+                collection_id: coll.id(),
+                path: "/copy.txt",
+                size: file.size(),
+                content_file_id: Some(file.id()),
+                container_type: None,
+            }
+            .insert(tx)?;
+            for (hash_algorithm, hash_value) in content.iter() {
+                NewFileHash {
+                    file_id: &id,
+                    hash_algorithm,
+                    hash_value,
+                }
+                .insert(tx)?;
+            }
+            Ok::<_, anyhow::Error>(id)
+        })
+        .unwrap();
+
+        assert_eq!(File::referrers(&conn, file.id()).unwrap().len(), 1);
+        let copy = File::for_id(&conn, &copy_id).unwrap().unwrap();
+        assert_eq!(copy.content_file(&conn).unwrap(), file);
+        // the copy is never returned as the physical file
+        let found = File::for_content(&conn, file.size(), &content, LOOKUP_ONLY).unwrap();
+        assert_eq!(found.as_ref(), Some(&file));
+        // and the copy doesn't count as under-replicated since it has no placements of its own
+        assert!(File::under_replicated(&conn, coll.id()).unwrap().is_empty());
+
+        let dupes = DuplicateFile::all(&conn, &content[0].0).unwrap();
+        assert_eq!(
+            dupes
+                .iter()
+                .map(|d| (d.path(), d.deduplicated()))
+                .collect::<Vec<_>>(),
+            vec![("/copy.txt", true), ("/foo.txt", false)]
+        );
+
+        // the content can't be deleted while it's referenced
+        let res = auto_transaction(&mut conn, |tx| {
+            tx.execute("DELETE FROM file_placements WHERE file_id = ?", [file.id()])?;
+            tx.execute("DELETE FROM file_hashes WHERE file_id = ?", [file.id()])?;
+            tx.execute("DELETE FROM files WHERE id = ?", [file.id()])?;
+            Ok::<_, anyhow::Error>(())
+        });
+        assert!(res.is_err());
+    }
 }
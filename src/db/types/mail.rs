@@ -0,0 +1,225 @@
+use crate::db::types::Timestamp;
+use regex::Regex;
+use rusqlite::{Connection, Row, ToSql, Transaction};
+use uuid::Uuid;
+
+/// The headers of an email message that is a file or a member of an archive.
+#[derive(Debug, PartialEq)]
+pub struct MailMessage {
+    id: Uuid,
+    file_id: Uuid,
+    file_archive_id: Option<Uuid>,
+    message_id: Option<String>,
+    sent_date: Option<Timestamp>,
+    mail_from: Option<String>,
+    mail_to: Option<String>,
+    subject: Option<String>,
+}
+
+impl MailMessage {
+    /// The file that is the message or the archive that has it
+    pub fn file_id(&self) -> &Uuid {
+        &self.file_id
+    }
+
+    pub fn sent_date(&self) -> Option<&Timestamp> {
+        self.sent_date.as_ref()
+    }
+
+    pub fn mail_from(&self) -> Option<&str> {
+        self.mail_from.as_deref()
+    }
+
+    pub fn subject(&self) -> Option<&str> {
+        self.subject.as_deref()
+    }
+
+    fn star_mapper(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            file_id: row.get("file_id")?,
+            file_archive_id: row.get("file_archive_id")?,
+            message_id: row.get("message_id")?,
+            sent_date: row.get("sent_date")?,
+            mail_from: row.get("mail_from")?,
+            mail_to: row.get("mail_to")?,
+            subject: row.get("subject")?,
+        })
+    }
+
+    /// Messages at or under `prefix` with the virtual path of each, which for members of
+    /// archives is the archive's path and the member's path joined by `!/`. The regexes are
+    /// matched against the `From` and `Subject` headers, and messages without the header don't
+    /// match.
+    pub fn find_in_dir(
+        conn: &Connection,
+        collection_id: &Uuid,
+        prefix: &str,
+        from: Option<&Regex>,
+        subject: Option<&Regex>,
+    ) -> anyhow::Result<Vec<(String, Self)>> {
+        let mut sql = concat!(
+            "SELECT m.*, f.path || coalesce('!/' || a.path, '') AS virtual_path ",
+            "FROM mail_messages AS m ",
+            "INNER JOIN files AS f ON f.id = m.file_id ",
+            "LEFT OUTER JOIN file_archives AS a ON a.id = m.file_archive_id ",
+            "WHERE f.collection_id = :collection_id ",
+            "  AND (f.path = :prefix ",
+            "    OR f.path || coalesce('!/' || a.path, '') = :prefix ",
+            "    OR substr(f.path || coalesce('!/' || a.path, ''), 1, length(:dir)) = :dir)",
+        )
+        .to_string();
+        let dir = if prefix.ends_with('/') {
+            prefix.to_string()
+        } else {
+            format!("{prefix}/")
+        };
+        let mut params: Vec<(&str, &dyn ToSql)> = vec![
+            (":collection_id", collection_id),
+            (":prefix", &prefix),
+            (":dir", &dir),
+        ];
+
+        // need refs for lifetimes
+        let from = from.map(ToString::to_string);
+        let subject = subject.map(ToString::to_string);
+        let from = from.as_ref();
+        let subject = subject.as_ref();
+
+        if let Some(from) = from {
+            sql += " AND m.mail_from IS NOT NULL AND m.mail_from REGEXP :from";
+            params.push((":from", from));
+        }
+        if let Some(subject) = subject {
+            sql += " AND m.subject IS NOT NULL AND m.subject REGEXP :subject";
+            params.push((":subject", subject));
+        }
+        sql += " ORDER BY m.sent_date, virtual_path";
+        log::trace!("SQL:\n{}", sql);
+
+        let mut stmt = conn.prepare(&sql)?;
+        let mut rows = stmt
+            .query_and_then(&*params, |row| -> anyhow::Result<(String, Self)> {
+                Ok((row.get("virtual_path")?, Self::star_mapper(row)?))
+            })?
+            .collect::<Vec<anyhow::Result<(String, Self)>>>();
+        rows.drain(..)
+            .collect::<anyhow::Result<Vec<(String, Self)>>>()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct NewMailMessage<'a> {
+    pub file_id: &'a Uuid,
+    /// `None` when the file itself is the message
+    pub file_archive_id: Option<&'a Uuid>,
+    pub message_id: Option<&'a str>,
+    pub sent_date: Option<&'a Timestamp>,
+    pub mail_from: Option<&'a str>,
+    pub mail_to: Option<&'a str>,
+    pub subject: Option<&'a str>,
+}
+
+impl<'a> NewMailMessage<'a> {
+    pub fn insert<'b>(&self, tx: &Transaction<'b>) -> anyhow::Result<Uuid> {
+        let id = Uuid::new_v4();
+        tx.execute(
+            concat!(
+                "INSERT INTO mail_messages (id, file_id, file_archive_id, message_id, sent_date, ",
+                "  mail_from, mail_to, subject) ",
+                "VALUES (:id, :file_id, :file_archive_id, :message_id, :sent_date, :mail_from, ",
+                "  :mail_to, :subject)"
+            ),
+            named_params! {
+                ":id": &id,
+                ":file_id": self.file_id,
+                ":file_archive_id": self.file_archive_id,
+                ":message_id": self.message_id,
+                ":sent_date": self.sent_date,
+                ":mail_from": self.mail_from,
+                ":mail_to": self.mail_to,
+                ":subject": self.subject,
+            },
+        )?;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive_utils::EntryType;
+    use crate::db::auto_transaction;
+    use crate::db::types::NewFileArchive;
+    use crate::test_utils::fixtures;
+
+    #[test_log::test]
+    fn find_in_dir() {
+        let mut conn = fixtures::db();
+        let coll = fixtures::collection(&mut conn);
+        let file = fixtures::file(&mut conn, &coll);
+        auto_transaction(&mut conn, |tx| {
+            let member_id = NewFileArchive {
+                file_id: file.id(),
+                path: "000001.eml",
+                size: 10,
+                parent_id: None,
+                entry_type: EntryType::File,
+                link_target: None,
+                modified_date: None,
+                compressed_size: None,
+                crc32: None,
+                encrypted: false,
+            }
+            .insert(tx)?;
+            for (file_archive_id, from, subject) in [
+                (Some(&member_id), "alice@example.com", "Hello"),
+                (None, "bob@example.com", "Re: Hello"),
+            ] {
+                NewMailMessage {
+                    file_id: file.id(),
+                    file_archive_id,
+                    message_id: None,
+                    sent_date: None,
+                    mail_from: Some(from),
+                    mail_to: None,
+                    subject: Some(subject),
+                }
+                .insert(tx)?;
+            }
+            NewMailMessage {
+                file_id: file.id(),
+                file_archive_id: None,
+                message_id: Some("1@example.com"),
+                sent_date: None,
+                mail_from: None,
+                mail_to: None,
+                subject: None,
+            }
+            .insert(tx)?;
+            Ok::<_, anyhow::Error>(())
+        })
+        .unwrap();
+
+        let paths = |prefix: &str, from: Option<&str>, subject: Option<&str>| {
+            let from = from.map(|r| Regex::new(r).unwrap());
+            let subject = subject.map(|r| Regex::new(r).unwrap());
+            let mut paths =
+                MailMessage::find_in_dir(&conn, coll.id(), prefix, from.as_ref(), subject.as_ref())
+                    .unwrap()
+                    .into_iter()
+                    .map(|(path, _)| path)
+                    .collect::<Vec<_>>();
+            paths.sort();
+            paths
+        };
+        assert_eq!(
+            paths("/", Some("alice"), None),
+            vec![format!("{}!/000001.eml", file.path())]
+        );
+        assert_eq!(paths("/", None, Some("^Re:")), vec![file.path()]);
+        assert_eq!(paths("/", None, None).len(), 3);
+        assert_eq!(paths(&format!("{}!/", file.path()), None, None).len(), 1);
+        assert!(paths("/other/", None, None).is_empty());
+    }
+}
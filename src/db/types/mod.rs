@@ -1,9 +1,11 @@
 mod collection;
 mod disk;
 mod file;
+mod mail;
 mod timestamp;
 
 pub use collection::*;
 pub use disk::*;
 pub use file::*;
+pub use mail::*;
 pub use timestamp::*;
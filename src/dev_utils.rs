@@ -3,6 +3,13 @@ use nix::NixPath;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
+/// The free space in bytes of the file system the path is on.
+pub fn free_space<P: ?Sized + NixPath>(path: &P) -> anyhow::Result<u64> {
+    let data = statfs(path)?;
+    let size: u64 = data.block_size().try_into()?;
+    Ok(size * data.blocks_free())
+}
+
 fn get_property(properties: &HashMap<String, String>, property: &str) -> anyhow::Result<String> {
     properties
         .get(property)
@@ -46,22 +53,17 @@ impl Partition {
         self.capacity
     }
 
-    fn get_capacity<P: ?Sized + NixPath>(path: &P) -> anyhow::Result<u64> {
-        let data = statfs(path)?;
-        let size: u64 = data.block_size().try_into()?;
-        Ok(size * data.blocks_free())
-    }
-
-    fn make<P: ?Sized + NixPath>(
-        path: &P,
-        properties: &HashMap<String, String>,
-    ) -> anyhow::Result<Self> {
+    fn make(properties: &HashMap<String, String>) -> anyhow::Result<Self> {
         let dev_name = get_property(properties, "DEVNAME")?;
+        let mount_point = block_utils::get_mountpoint(&dev_name)?
+            .ok_or_else(|| anyhow!("Device not mounted: {dev_name}"))?;
+        // the free space of the mounted file system, not of the file system holding the device
+        // node
+        let capacity = free_space(&mount_point)?;
         Ok(Self {
             uuid: get_property(properties, "ID_FS_UUID")?,
-            mount_point: block_utils::get_mountpoint(&dev_name)?
-                .ok_or_else(|| anyhow!("Device not mounted: {dev_name}"))?,
-            capacity: Self::get_capacity(path)?,
+            mount_point,
+            capacity,
         })
     }
 }
@@ -103,10 +105,7 @@ pub fn get_partition_for_path(path: impl AsRef<Path>) -> anyhow::Result<(Disk, P
     let properties = block_utils::get_block_dev_properties(path)?;
     match &*get_property(&properties, "DEVTYPE")? {
         "partition" => match block_utils::get_parent_devpath_from_path(path)? {
-            Some(parent) => Ok((
-                get_disk_for_path(parent)?,
-                Partition::make(path, &properties)?,
-            )),
+            Some(parent) => Ok((get_disk_for_path(parent)?, Partition::make(&properties)?)),
             None => Err(anyhow!(
                 "Unable to get parent disk for partition at path {}",
                 path.to_string_lossy()
@@ -133,17 +132,13 @@ pub fn get_partition_for_uuid(uuid: &str) -> anyhow::Result<Partition> {
         .ok_or_else(|| {
             anyhow!("Unable to find partition with UUID {uuid}. Is your disk plugged in?")
         })
-        .and_then(|(path, props)| Partition::make(path, &props))
+        .and_then(|(_, props)| Partition::make(&props))
 }
 
 pub fn get_all_partitions() -> anyhow::Result<Vec<Partition>> {
     Ok(block_utils::get_block_partitions()?
         .iter()
-        .flat_map(|path| {
-            block_utils::get_block_dev_properties(path)
-                .ok()
-                .map(|props| (path, props))
-        })
-        .flat_map(|(path, props)| Partition::make(path, &props).ok())
+        .flat_map(|path| block_utils::get_block_dev_properties(path).ok())
+        .flat_map(|props| Partition::make(&props).ok())
         .collect::<Vec<_>>())
 }
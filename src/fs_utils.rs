@@ -1,4 +1,10 @@
+use crate::error::GenericError;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+use std::fmt;
 use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
 use std::path::{Component, Path, PathBuf};
 
 /// Create all directories of the `suffix` if they don't exist. Assumes the suffix is a stripped
@@ -17,6 +23,21 @@ pub fn create_dirs_from(prefix: impl AsRef<Path>, suffix: impl AsRef<Path>) -> a
     Ok(())
 }
 
+/// Remove the directories above `path` that are empty, up to but not including `root`.
+pub fn remove_empty_dirs(root: impl AsRef<Path>, path: impl AsRef<Path>) -> anyhow::Result<()> {
+    let root = root.as_ref();
+    let mut dir = path.as_ref().parent();
+    while let Some(path) = dir {
+        if path == root || !path.starts_with(root) || fs::read_dir(path)?.next().is_some() {
+            break;
+        }
+        log::debug!("Removing empty directory: {}", path.to_string_lossy());
+        fs::remove_dir(path)?;
+        dir = path.parent();
+    }
+    Ok(())
+}
+
 pub fn canonical_path(in_path: impl AsRef<Path>) -> Result<PathBuf, String> {
     let in_path = PathBuf::from(in_path.as_ref());
     let mut out_path = PathBuf::new();
@@ -39,6 +60,20 @@ pub fn canonical_path(in_path: impl AsRef<Path>) -> Result<PathBuf, String> {
     Ok(out_path)
 }
 
+/// The canonical form of a virtual directory with a trailing `/` so that it can be used as a
+/// prefix for the paths of the files in it.
+pub fn dir_prefix(in_path: impl AsRef<Path>) -> Result<String, String> {
+    let path = canonical_path(in_path)?;
+    let mut prefix = path
+        .to_str()
+        .ok_or_else(|| format!("Path was not UTF-8: {}", path.to_string_lossy()))?
+        .to_string();
+    if !prefix.ends_with('/') {
+        prefix.push('/');
+    }
+    Ok(prefix)
+}
+
 /// Assuming a canonical, absolute path: remove the leading `/`
 pub fn strip_root(path: impl AsRef<Path>) -> PathBuf {
     let mut stripped_path = PathBuf::new();
@@ -50,6 +85,167 @@ pub fn strip_root(path: impl AsRef<Path>) -> PathBuf {
     stripped_path
 }
 
+/// Flush a buffered file and sync its contents to disk.
+pub fn sync_file(file: io::BufWriter<fs::File>) -> io::Result<()> {
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+/// Drop the cached pages of a file that was synced so that it's read from the disk again.
+pub fn drop_cache(file: &fs::File) -> anyhow::Result<()> {
+    posix_fadvise(
+        file.as_raw_fd(),
+        0,
+        0,
+        PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+    )?;
+    Ok(())
+}
+
+/// Sync the directory that has `path` so that a file created or renamed there survives a crash.
+pub fn sync_parent_dir(path: impl AsRef<Path>) -> io::Result<()> {
+    match path.as_ref().parent() {
+        Some(dir) => fs::File::open(dir)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+/// What to do with symlinks found while walking a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkMode {
+    Skip,
+    Follow,
+    Error,
+}
+
+impl fmt::Display for SymlinkMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let val = match self {
+            Self::Skip => "skip",
+            Self::Follow => "follow",
+            Self::Error => "error",
+        };
+        write!(f, "{}", val)
+    }
+}
+
+impl TryFrom<&str> for SymlinkMode {
+    type Error = GenericError;
+
+    fn try_from(value: &str) -> Result<Self, GenericError> {
+        match value {
+            "skip" => Ok(Self::Skip),
+            "follow" => Ok(Self::Follow),
+            "error" => Ok(Self::Error),
+            x => Err(GenericError::new(format!("Not a known symlink mode: {x}"))),
+        }
+    }
+}
+
+/// A set of globs matched against relative paths. Globs containing a `/` are matched against the
+/// whole path, and globs without one are matched against the file name only (like `rsync`).
+#[derive(Debug)]
+pub struct GlobFilter {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl GlobFilter {
+    pub fn new<'a>(globs: impl IntoIterator<Item = &'a str>) -> anyhow::Result<Self> {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+        for glob in globs {
+            let builder = if glob.contains('/') {
+                &mut paths
+            } else {
+                &mut names
+            };
+            builder.add(GlobBuilder::new(glob).literal_separator(true).build()?);
+        }
+        Ok(Self {
+            names: names.build()?,
+            paths: paths.build()?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.paths.is_empty()
+    }
+
+    pub fn is_match(&self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        self.paths.is_match(path)
+            || path
+                .file_name()
+                .map(|name| self.names.is_match(name))
+                .unwrap_or(false)
+    }
+}
+
+impl Default for GlobFilter {
+    fn default() -> Self {
+        Self {
+            names: GlobSet::empty(),
+            paths: GlobSet::empty(),
+        }
+    }
+}
+
+/// A reader that writes everything it reads from the inner reader to a writer.
+pub struct TeeReader<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: Read, W: Write> TeeReader<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self { reader, writer }
+    }
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.reader.read(buf)?;
+        self.writer.write_all(&buf[..bytes_read])?;
+        Ok(bytes_read)
+    }
+}
+
+/// A reader that remembers whether the inner reader failed, so that its errors can be told apart
+/// from the errors of whatever is parsing what it reads.
+pub struct TrackedReader<R> {
+    reader: R,
+    failed: bool,
+}
+
+impl<R> TrackedReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            failed: false,
+        }
+    }
+
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+}
+
+impl<R: Read> Read for TrackedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let res = self.reader.read(buf);
+        self.failed |= res.is_err();
+        res
+    }
+}
+
+impl<R: Seek> Seek for TrackedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let res = self.reader.seek(pos);
+        self.failed |= res.is_err();
+        res
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -64,4 +260,51 @@ mod tests {
         let expected = PathBuf::from("foo/bar");
         assert_eq!(strip_root(input), expected);
     }
+
+    #[test]
+    fn test_remove_empty_dirs() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("a/b/c")).unwrap();
+        fs::write(root.path().join("a/keep.txt"), b"keep").unwrap();
+        remove_empty_dirs(root.path(), root.path().join("a/b/c/gone.txt")).unwrap();
+        assert!(!root.path().join("a/b").exists());
+        assert!(root.path().join("a/keep.txt").exists());
+
+        fs::remove_file(root.path().join("a/keep.txt")).unwrap();
+        remove_empty_dirs(root.path(), root.path().join("a/keep.txt")).unwrap();
+        assert!(!root.path().join("a").exists());
+        assert!(root.path().exists());
+    }
+
+    #[test]
+    fn test_dir_prefix() {
+        assert_eq!(dir_prefix("/").unwrap(), "/");
+        assert_eq!(dir_prefix("/foo/bar").unwrap(), "/foo/bar/");
+        assert_eq!(dir_prefix("/foo/bar/").unwrap(), "/foo/bar/");
+        assert!(dir_prefix("foo").is_err());
+    }
+
+    #[test]
+    fn tee_reader() {
+        let mut out = Vec::new();
+        let mut read = Vec::new();
+        TeeReader::new(&b"some bytes"[..], &mut out)
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, b"some bytes");
+        assert_eq!(out, b"some bytes");
+    }
+
+    #[test]
+    fn glob_filter() {
+        let filter = GlobFilter::new(["*.pdf", "mail/**/*.eml"]).unwrap();
+        assert!(filter.is_match("a.pdf"));
+        assert!(filter.is_match("docs/2020/a.pdf"));
+        assert!(filter.is_match("mail/inbox/1.eml"));
+        assert!(!filter.is_match("other/inbox/1.eml"));
+        assert!(!filter.is_match("a.txt"));
+
+        assert!(GlobFilter::new([]).unwrap().is_empty());
+        assert!(GlobFilter::new(["a{"]).is_err());
+    }
 }
@@ -1,5 +1,6 @@
 use crate::error::GenericError;
 use digest::Digest;
+use md5::Md5;
 use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
 use rusqlite::ToSql;
 use sha1::Sha1;
@@ -7,7 +8,7 @@ use sha2::{Sha256, Sha384, Sha512};
 use sha3::{Sha3_256, Sha3_384, Sha3_512};
 use std::collections::HashMap;
 use std::fmt;
-use std::io::Read;
+use std::io::{self, Read, Write};
 use std::iter::ExactSizeIterator;
 
 pub fn make_hashes<'a, R: Read, I, II>(
@@ -26,26 +27,63 @@ where
         return Ok(HashMap::new());
     }
 
-    let mut hashes = HashMap::new();
-    for name in hash_algorithms {
-        hashes.insert(name, name.container());
-    }
-
+    let mut hasher = MultiHasher::new(hash_algorithms);
     let mut buf = vec![0u8; 4096];
     loop {
         let bytes_read = input.read(&mut buf)?;
         if bytes_read == 0 {
             break;
         }
-        for (_, container) in hashes.iter_mut() {
-            container.update(&buf[0..bytes_read]);
+        hasher.update(&buf[0..bytes_read]);
+    }
+    Ok(hasher.finalize().1)
+}
+
+/// Hashes everything written to it with several algorithms at once, so that the data can be
+/// hashed while it's read for something else.
+pub struct MultiHasher<'a> {
+    hashes: HashMap<&'a HashAlgorithm, HasherContainer>,
+    bytes_written: u64,
+}
+
+impl<'a> MultiHasher<'a> {
+    pub fn new(hash_algorithms: impl IntoIterator<Item = &'a HashAlgorithm>) -> Self {
+        Self {
+            hashes: hash_algorithms
+                .into_iter()
+                .map(|name| (name, name.container()))
+                .collect(),
+            bytes_written: 0,
         }
     }
 
-    Ok(hashes
-        .drain()
-        .map(|(name, container)| (name, container.finalize()))
-        .collect())
+    fn update(&mut self, bytes: &[u8]) {
+        for (_, container) in self.hashes.iter_mut() {
+            container.update(bytes);
+        }
+        self.bytes_written += bytes.len() as u64;
+    }
+
+    /// The number of bytes that were hashed and the hashes
+    pub fn finalize(mut self) -> (u64, HashMap<&'a HashAlgorithm, Vec<u8>>) {
+        let hashes = self
+            .hashes
+            .drain()
+            .map(|(name, container)| (name, container.finalize()))
+            .collect();
+        (self.bytes_written, hashes)
+    }
+}
+
+impl<'a> Write for MultiHasher<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
@@ -64,6 +102,12 @@ pub enum HashAlgorithm {
     Sha3_384,
     #[serde(rename = "sha3-512")]
     Sha3_512,
+    #[serde(rename = "blake3")]
+    Blake3,
+    #[serde(rename = "md5")]
+    Md5,
+    #[serde(rename = "crc32c")]
+    Crc32c,
 }
 
 impl HashAlgorithm {
@@ -76,6 +120,9 @@ impl HashAlgorithm {
             Self::Sha3_256 => HasherContainer::Sha3_256(Sha3_256::new()),
             Self::Sha3_384 => HasherContainer::Sha3_384(Sha3_384::new()),
             Self::Sha3_512 => HasherContainer::Sha3_512(Sha3_512::new()),
+            Self::Blake3 => HasherContainer::Blake3(Box::new(blake3::Hasher::new())),
+            Self::Md5 => HasherContainer::Md5(Md5::new()),
+            Self::Crc32c => HasherContainer::Crc32c(0),
         }
     }
 }
@@ -90,6 +137,9 @@ impl fmt::Display for HashAlgorithm {
             Self::Sha3_256 => "sha3-256".to_string(),
             Self::Sha3_384 => "sha3-384".to_string(),
             Self::Sha3_512 => "sha3-512".to_string(),
+            Self::Blake3 => "blake3".to_string(),
+            Self::Md5 => "md5".to_string(),
+            Self::Crc32c => "crc32c".to_string(),
         };
         write!(f, "{}", val)
     }
@@ -107,6 +157,9 @@ impl TryFrom<&str> for HashAlgorithm {
             "sha3-256" => Ok(Self::Sha3_256),
             "sha3-384" => Ok(Self::Sha3_384),
             "sha3-512" => Ok(Self::Sha3_512),
+            "blake3" => Ok(Self::Blake3),
+            "md5" => Ok(Self::Md5),
+            "crc32c" => Ok(Self::Crc32c),
             x => Err(GenericError::new(format!(
                 "Not a known hash algorithm: {x}"
             ))),
@@ -141,6 +194,10 @@ enum HasherContainer {
     Sha3_256(Sha3_256),
     Sha3_384(Sha3_384),
     Sha3_512(Sha3_512),
+    // boxed because the hasher is much larger than the others
+    Blake3(Box<blake3::Hasher>),
+    Md5(Md5),
+    Crc32c(u32),
 }
 
 impl HasherContainer {
@@ -153,6 +210,11 @@ impl HasherContainer {
             Self::Sha3_256(hasher) => hasher.update(bytes),
             Self::Sha3_384(hasher) => hasher.update(bytes),
             Self::Sha3_512(hasher) => hasher.update(bytes),
+            Self::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+            Self::Md5(hasher) => hasher.update(bytes),
+            Self::Crc32c(crc) => *crc = crc32c::crc32c_append(*crc, bytes),
         }
     }
 
@@ -165,6 +227,60 @@ impl HasherContainer {
             Self::Sha3_256(hasher) => Vec::from(hasher.finalize().as_slice()),
             Self::Sha3_384(hasher) => Vec::from(hasher.finalize().as_slice()),
             Self::Sha3_512(hasher) => Vec::from(hasher.finalize().as_slice()),
+            Self::Blake3(hasher) => Vec::from(hasher.finalize().as_bytes().as_slice()),
+            Self::Md5(hasher) => Vec::from(hasher.finalize().as_slice()),
+            Self::Crc32c(crc) => Vec::from(crc.to_be_bytes().as_slice()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_values() {
+        let algorithms = [
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Blake3,
+            HashAlgorithm::Md5,
+            HashAlgorithm::Crc32c,
+        ];
+        let hashes = make_hashes(&b"123456789"[..], &algorithms).unwrap();
+        let hex = |a: &HashAlgorithm| hex::encode(&hashes[a]);
+        assert_eq!(
+            hex(&HashAlgorithm::Sha256),
+            "15e2b0d3c33891ebb0f1ef609ec419420c20e320ce94c65fbc8c3312448eb225"
+        );
+        assert_eq!(
+            hex(&HashAlgorithm::Blake3),
+            "b7d65b48420d1033cb2595293263b6f72eabee20d55e699d0df1973b3c9deed1"
+        );
+        assert_eq!(hex(&HashAlgorithm::Md5), "25f9e794323b453885f5181f1b624d0b");
+        assert_eq!(hex(&HashAlgorithm::Crc32c), "e3069283");
+
+        let mut hasher = MultiHasher::new(&algorithms);
+        for chunk in [&b"1234"[..], b"", b"56789"] {
+            hasher.write_all(chunk).unwrap();
+        }
+        assert_eq!(hasher.finalize(), (9, hashes));
+    }
+
+    #[test]
+    fn names() {
+        for algorithm in [
+            HashAlgorithm::Sha1,
+            HashAlgorithm::Sha3_512,
+            HashAlgorithm::Blake3,
+            HashAlgorithm::Md5,
+            HashAlgorithm::Crc32c,
+        ] {
+            let name = algorithm.to_string();
+            assert_eq!(HashAlgorithm::try_from(name.as_str()).unwrap(), algorithm);
+            assert_eq!(
+                serde_yaml::from_str::<HashAlgorithm>(&name).unwrap(),
+                algorithm
+            );
         }
     }
 }
@@ -39,7 +39,11 @@ mod dev_utils;
 mod error;
 mod fs_utils;
 mod hash_utils;
+mod mail_utils;
 mod manager;
+mod placement;
+mod retrieval;
+mod storage;
 mod sync_db;
 #[cfg(test)]
 mod test_utils;
@@ -0,0 +1,453 @@
+use crate::db::types::Timestamp;
+use chrono::{DateTime, Utc};
+use std::cmp;
+use std::io::{self, BufRead, Read};
+use std::path::Path;
+
+/// Headers past this many bytes are not looked at
+pub const MAX_HEADER_SIZE: u64 = 64 * 1024;
+/// Lines of mbox files are read in pieces of at most this many bytes
+const MAX_LINE_SIZE: u64 = 64 * 1024;
+
+/// The headers of an email message that are indexed. Encoded words (RFC 2047) are decoded.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MailHeaders {
+    /// Without the surrounding `<>`
+    pub message_id: Option<String>,
+    pub date: Option<Timestamp>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub subject: Option<String>,
+}
+
+/// Whether a file is likely an email message going by its path: it ends with `.eml`, or it's in
+/// the `cur` or `new` directory of a Maildir.
+pub fn is_message_path(path: impl AsRef<Path>) -> bool {
+    let path = path.as_ref();
+    let is_eml = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.eq_ignore_ascii_case("eml"))
+        .unwrap_or(false);
+    let in_maildir = path
+        .parent()
+        .and_then(|dir| dir.file_name())
+        .map(|dir| dir == "cur" || dir == "new")
+        .unwrap_or(false);
+    is_eml || in_maildir
+}
+
+/// Whether the data looks like the start of an mbox file: a `From ` line with the sender and the
+/// date (e.g., `From a@example.com Tue Jul  1 10:52:37 2003`) followed by a header
+pub fn is_mbox(data: &[u8]) -> bool {
+    let mut lines = data.split(|b| *b == b'\n');
+    // unwrap ok because split always returns at least one piece
+    if !is_from_line(lines.next().unwrap()) {
+        return false;
+    }
+    let second_line = match lines.next() {
+        Some(line) => line,
+        None => return false,
+    };
+    match second_line.iter().position(|b| *b == b':') {
+        Some(i) => i > 0 && second_line[..i].iter().all(u8::is_ascii_graphic),
+        None => false,
+    }
+}
+
+/// Whether the line is the `From ` line of an mbox message, which has the date in the format of
+/// `asctime` after the sender. Some writers put a time zone before the year.
+fn is_from_line(line: &[u8]) -> bool {
+    const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let rest = match line.strip_prefix(b"From ").map(std::str::from_utf8) {
+        Some(Ok(rest)) => rest,
+        _ => return false,
+    };
+    let words = rest.split_whitespace().collect::<Vec<_>>();
+    let date = match words.split_first() {
+        Some((_sender, date)) if date.len() >= 5 => date,
+        _ => return false,
+    };
+    let is_number = |word: &str| !word.is_empty() && word.bytes().all(|b| b.is_ascii_digit());
+    DAYS.contains(&date[0])
+        && MONTHS.contains(&date[1])
+        && is_number(date[2])
+        && date[3].split(':').count() >= 2
+        && date[3].split(':').all(is_number)
+        && date[4..]
+            .iter()
+            .any(|word| word.len() == 4 && is_number(word))
+}
+
+/// Parse the headers at the start of a message. Returns `None` if it doesn't start with a header
+/// or has none of the indexed headers.
+pub fn parse_headers(data: &[u8]) -> Option<MailHeaders> {
+    let mut fields = Vec::<(String, String)>::new();
+    for line in data.split(|b| *b == b'\n') {
+        let line = String::from_utf8_lossy(line);
+        let line = line.strip_suffix('\r').unwrap_or(&line);
+        if line.is_empty() {
+            break;
+        }
+        match fields.last_mut() {
+            // folded lines continue the previous field
+            Some((_, value)) if line.starts_with([' ', '\t']) => {
+                value.push(' ');
+                value.push_str(line.trim_start());
+            }
+            _ => {
+                let (name, value) = line.split_once(':')?;
+                if name.is_empty() || !name.bytes().all(|b| b.is_ascii_graphic()) {
+                    return None;
+                }
+                fields.push((name.to_ascii_lowercase(), value.trim().to_string()));
+            }
+        }
+    }
+
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| decode_words(value))
+    };
+    let headers = MailHeaders {
+        message_id: field("message-id")
+            .map(|id| id.trim_start_matches('<').trim_end_matches('>').to_string()),
+        date: field("date").and_then(|date| parse_date(&date)),
+        from: field("from"),
+        to: field("to"),
+        subject: field("subject"),
+    };
+    if headers == MailHeaders::default() {
+        return None;
+    }
+    Some(headers)
+}
+
+/// Parse an RFC 2822 date, ignoring a trailing comment like `(UTC)`
+fn parse_date(date: &str) -> Option<Timestamp> {
+    let date = match date.find('(') {
+        Some(i) => date[..i].trim(),
+        None => date,
+    };
+    DateTime::parse_from_rfc2822(date)
+        .ok()
+        .map(|date| Timestamp::from(date.with_timezone(&Utc)))
+}
+
+/// Decode the encoded words (e.g., `=?UTF-8?B?w6lt?=`) in a header value. Words in unknown
+/// charsets are decoded as UTF-8, and words that can't be decoded are left as they are.
+fn decode_words(value: &str) -> String {
+    let mut output = String::new();
+    let mut rest = value;
+    let mut last_was_word = false;
+    while let Some(start) = rest.find("=?") {
+        let (between, word) = rest.split_at(start);
+        // `=?charset?encoding?text?=`
+        let parts = word[2..].split_once('?').and_then(|(charset, word)| {
+            let (encoding, word) = word.split_once('?')?;
+            let (text, _) = word.split_once("?=")?;
+            Some((charset, encoding, text))
+        });
+        let (charset, encoding, text) = match parts {
+            Some(parts) => parts,
+            None => break,
+        };
+        let word_len = charset.len() + encoding.len() + text.len() + 6;
+        let decoded = match encoding {
+            "B" | "b" => decode_base64(text),
+            "Q" | "q" => decode_q(text),
+            _ => None,
+        };
+
+        // whitespace between encoded words is not part of the text
+        if !(last_was_word && between.trim().is_empty()) {
+            output.push_str(between);
+        }
+        last_was_word = decoded.is_some();
+        match decoded {
+            Some(bytes)
+                if charset.eq_ignore_ascii_case("iso-8859-1")
+                    || charset.eq_ignore_ascii_case("latin1") =>
+            {
+                output.extend(bytes.iter().map(|b| *b as char))
+            }
+            Some(bytes) => output.push_str(&String::from_utf8_lossy(&bytes)),
+            None => output.push_str(&word[..word_len]),
+        }
+        rest = &word[word_len..];
+    }
+    output.push_str(rest);
+    output
+}
+
+fn decode_q(text: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut bytes = text.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'_' => output.push(b' '),
+            b'=' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                output.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b => output.push(b),
+        }
+    }
+    Some(output)
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for b in text.bytes().filter(|b| *b != b'=') {
+        let value = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(output)
+}
+
+/// The messages of an mbox file, without the `From ` lines that separate them. Lines starting
+/// with `From ` can't be in messages, so they always start a new one.
+///
+/// [`MboxMessages::next_message`] moves to the next message, which is then read through [`Read`].
+/// Nothing is buffered past the line being read, and long lines are read in pieces, so that
+/// neither long messages nor long lines have to fit in memory. Messages are cut off after
+/// `max_size` bytes.
+pub struct MboxMessages<R> {
+    reader: R,
+    max_size: u64,
+    /// The piece of a line that is being read, up to `pos`
+    line: Vec<u8>,
+    pos: usize,
+    /// Whether `line` is at the start of a line rather than the rest of a long line
+    starts_line: bool,
+    /// Whether `line` is the separator of the next message, which reading the previous one ended
+    /// at
+    at_separator: bool,
+    in_message: bool,
+    /// How many bytes of the current message have been read
+    size: u64,
+    truncated: bool,
+}
+
+impl<R: BufRead> MboxMessages<R> {
+    pub fn new(reader: R, max_size: u64) -> Self {
+        Self {
+            reader,
+            max_size,
+            line: Vec::new(),
+            pos: 0,
+            starts_line: true,
+            at_separator: false,
+            in_message: false,
+            size: 0,
+            truncated: false,
+        }
+    }
+
+    /// Skip the rest of the current message and move to the start of the next one. Returns
+    /// `false` when there are no more messages.
+    pub fn next_message(&mut self) -> io::Result<bool> {
+        if !self.at_separator {
+            loop {
+                self.read_piece()?;
+                if self.line.is_empty() {
+                    self.in_message = false;
+                    return Ok(false);
+                }
+                if self.is_separator() {
+                    break;
+                }
+            }
+        }
+        // the rest of a long separator isn't part of the message either
+        while !self.line.is_empty() && !self.line.ends_with(b"\n") {
+            self.read_piece()?;
+        }
+        self.pos = self.line.len();
+        self.at_separator = false;
+        self.in_message = true;
+        self.size = 0;
+        self.truncated = false;
+        Ok(true)
+    }
+
+    /// Whether the current message was cut off because it has more than `max_size` bytes
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    fn read_piece(&mut self) -> io::Result<()> {
+        self.starts_line = self.line.is_empty() || self.line.ends_with(b"\n");
+        self.line.clear();
+        self.pos = 0;
+        (&mut self.reader)
+            .take(MAX_LINE_SIZE)
+            .read_until(b'\n', &mut self.line)?;
+        Ok(())
+    }
+
+    fn is_separator(&self) -> bool {
+        self.starts_line && self.line.starts_with(b"From ")
+    }
+}
+
+impl<R: BufRead> Read for MboxMessages<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.in_message || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos == self.line.len() {
+            self.read_piece()?;
+            if self.line.is_empty() || self.is_separator() {
+                self.at_separator = !self.line.is_empty();
+                self.in_message = false;
+                return Ok(0);
+            }
+        }
+        if self.size == self.max_size {
+            self.truncated = true;
+            self.in_message = false;
+            return Ok(0);
+        }
+        let len = cmp::min(buf.len(), self.line.len() - self.pos);
+        let len = cmp::min(len as u64, self.max_size - self.size) as usize;
+        buf[..len].copy_from_slice(&self.line[self.pos..self.pos + len]);
+        self.pos += len;
+        self.size += len as u64;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers() {
+        let message = concat!(
+            "Message-ID: <123@example.com>\r\n",
+            "Date: Tue, 1 Jul 2003 10:52:37 +0200 (CEST)\r\n",
+            "From: =?UTF-8?B?w4ltaWxl?= <emile@example.com>\r\n",
+            "To: a@example.com,\r\n",
+            "  b@example.com\r\n",
+            "Subject: =?iso-8859-1?q?caf=E9?= =?UTF-8?Q?_au_lait?= today\r\n",
+            "\r\n",
+            "From: not a header\r\n",
+        );
+        let headers = parse_headers(message.as_bytes()).unwrap();
+        assert_eq!(headers.message_id.as_deref(), Some("123@example.com"));
+        assert_eq!(
+            headers.date.unwrap().to_string(),
+            "2003-07-01T08:52:37.000Z"
+        );
+        assert_eq!(headers.from.as_deref(), Some("Émile <emile@example.com>"));
+        assert_eq!(headers.to.as_deref(), Some("a@example.com, b@example.com"));
+        assert_eq!(headers.subject.as_deref(), Some("café au lait today"));
+
+        assert_eq!(parse_headers(b"just some text\n"), None);
+        assert_eq!(parse_headers(b"X-Other: 1\n\nbody"), None);
+    }
+
+    fn read_messages(mbox: &[u8], max_size: u64) -> Vec<(Vec<u8>, bool)> {
+        let mut messages = MboxMessages::new(mbox, max_size);
+        let mut read = Vec::new();
+        while messages.next_message().unwrap() {
+            let mut message = Vec::new();
+            messages.read_to_end(&mut message).unwrap();
+            read.push((message, messages.truncated()));
+        }
+        read
+    }
+
+    #[test]
+    fn mbox() {
+        let mbox = concat!(
+            "From a@example.com Tue Jul  1 10:52:37 2003\n",
+            "Subject: one\n",
+            "\n",
+            ">From the body\n",
+            "\n",
+            "From b@example.com Tue Jul  1 10:52:38 2003\n",
+            "Subject: two\n",
+        );
+        assert_eq!(
+            read_messages(mbox.as_bytes(), 1024),
+            vec![
+                (b"Subject: one\n\n>From the body\n\n".to_vec(), false),
+                (b"Subject: two\n".to_vec(), false)
+            ]
+        );
+
+        // messages are cut off at the maximum size
+        assert_eq!(
+            read_messages(mbox.as_bytes(), 13),
+            vec![
+                (b"Subject: one\n".to_vec(), true),
+                (b"Subject: two\n".to_vec(), false)
+            ]
+        );
+
+        // the rest of a message doesn't have to be read to get to the next one
+        let mut messages = MboxMessages::new(mbox.as_bytes(), 1024);
+        assert!(messages.next_message().unwrap());
+        assert!(messages.next_message().unwrap());
+        let mut message = Vec::new();
+        messages.read_to_end(&mut message).unwrap();
+        assert_eq!(message, b"Subject: two\n");
+        assert!(!messages.next_message().unwrap());
+    }
+
+    #[test]
+    fn mbox_long_lines() {
+        // a `From ` in the middle of a long line doesn't start a message
+        let mut mbox = b"From a@example.com Tue Jul  1 10:52:37 2003\n".to_vec();
+        mbox.extend_from_slice(&[b'x'; MAX_LINE_SIZE as usize]);
+        mbox.extend_from_slice(b"From the middle of a line\n");
+        mbox.extend_from_slice(b"From b@example.com Tue Jul  1 10:52:38 2003\nSubject: two\n");
+        let messages = read_messages(&mbox, u64::MAX);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].0.len(), MAX_LINE_SIZE as usize + 26);
+        assert!(messages[0].0.ends_with(b"xFrom the middle of a line\n"));
+        assert_eq!(messages[1].0, b"Subject: two\n");
+    }
+
+    #[test]
+    fn mbox_detection() {
+        assert!(is_mbox(
+            b"From a@example.com Tue Jul  1 10:52:37 2003\nSubject: hi\n"
+        ));
+        assert!(is_mbox(
+            b"From MAILER-DAEMON Fri Jul  8 12:08:34 +0000 2011\nSubject: hi\n"
+        ));
+        assert!(!is_mbox(b"From the desk of someone\n\nDear reader\n"));
+        assert!(!is_mbox(b"From x\nHeader: y\n"));
+        assert!(!is_mbox(b"Subject: hi\n"));
+    }
+
+    #[test]
+    fn message_paths() {
+        assert!(is_message_path("/mail/a.EML"));
+        assert!(is_message_path("/mail/Maildir/cur/1234.M1P2.host:2,S"));
+        assert!(!is_message_path("/mail/a.txt"));
+    }
+}
@@ -1,30 +1,155 @@
-use crate::archive_utils;
+use crate::archive_utils::{self, ArchiveEntry, ContainerType, Hashes};
 use crate::config::Config;
 use crate::db::init_connection;
 use crate::db::types::{
-    Collection, Disk, File, FileHash, FilePlacement, Location, NewCollection, NewDisk, NewFile,
-    NewFileArchive, NewFileHash, NewFilePlacement, NewLocation, NewPartition, Partition,
+    CheckOutcome, Collection, Disk, DuplicateFile, File, FileArchive, FileArchiveHash, FileHash,
+    FilePlacement, FilePlacementCheck, Location, MailMessage, NewCollection, NewDisk, NewFile,
+    NewFileArchive, NewFileArchiveHash, NewFileHash, NewFilePlacement, NewFilePlacementCheck,
+    NewLocation, NewMailMessage, NewPartition, NewPendingDeletion, NewPendingMove,
+    NewPendingPlacement, NewPlacementAffinity, Partition, PendingDeletion, PendingMove,
+    PendingPlacement, PlacementAffinity, PlacementSpread,
 };
 use crate::db::{auto_transaction, migrate};
-use crate::dev_utils::{self, get_disk_for_path, get_partition_for_path, get_partition_for_uuid};
-use crate::fs_utils::{canonical_path, create_dirs_from, strip_root};
-use crate::hash_utils::make_hashes;
+use crate::dev_utils::{get_disk_for_path, get_partition_for_path};
+use crate::fs_utils::{
+    canonical_path, create_dirs_from, dir_prefix, drop_cache, remove_empty_dirs, strip_root,
+    sync_file, sync_parent_dir, GlobFilter, SymlinkMode, TeeReader, TrackedReader,
+};
+use crate::hash_utils::{make_hashes, HashAlgorithm, MultiHasher};
+use crate::mail_utils::{self, MailHeaders};
+use crate::placement::{AffinityTracker, PlacementStrategy, Placer};
+use crate::retrieval::{DiskRef, RetrievalPlan, WantedFile};
+use crate::storage::{self, DirectoryTarget, StorageTarget};
 use crate::sync_db::sync_db;
 use regex::Regex;
 use rusqlite::types::Value;
-use rusqlite::Connection;
+use rusqlite::{Connection, Transaction};
 use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::fs;
+use std::io::{self, Read, Write};
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::Instant;
 use uuid::Uuid;
+use walkdir::WalkDir;
+
+/// The number of files inserted per transaction by [`Manager::add_dir`].
+pub const ADD_DIR_BATCH_SIZE: usize = 100;
 
 pub struct Manager {
     config: Config,
     conn: Connection,
 }
 
+/// The set of file placements to check with [`Manager::verify`].
+pub enum VerifyScope<'a> {
+    Collection(&'a Uuid),
+    Disk(&'a Uuid),
+    Partition(&'a Uuid),
+}
+
+/// How files are stored by [`Manager::add_file`] and [`Manager::add_dir`].
+pub struct AddOptions {
+    /// Move the files on to the target partitions instead of copying them
+    pub move_files: bool,
+    /// Point files at existing files with the same content instead of copying them again
+    pub dedup: bool,
+    /// Read copies back from the disks instead of the page cache when checking them against the
+    /// hashes of the sources, in addition to when the config says to
+    pub verify: bool,
+    pub strategy: PlacementStrategy,
+}
+
+impl Default for AddOptions {
+    fn default() -> Self {
+        Self {
+            move_files: false,
+            dedup: false,
+            verify: false,
+            strategy: PlacementStrategy::MostFree,
+        }
+    }
+}
+
+/// Filters for the files added by [`Manager::add_dir`].
+pub struct AddDirOptions {
+    pub include: GlobFilter,
+    pub exclude: GlobFilter,
+    pub symlinks: SymlinkMode,
+}
+
+impl Default for AddDirOptions {
+    fn default() -> Self {
+        Self {
+            include: GlobFilter::default(),
+            exclude: GlobFilter::default(),
+            symlinks: SymlinkMode::Skip,
+        }
+    }
+}
+
+/// The hashes and archive contents of a file that is about to be added.
+struct PreparedFile {
+    src_path: String,
+    dest_path: PathBuf,
+    size: u64,
+    hashes: Vec<(HashAlgorithm, Vec<u8>)>,
+    container_type: Option<ContainerType>,
+    archive_files: Vec<ArchiveEntry>,
+    /// The headers if the file is an email message
+    mail: Option<MailHeaders>,
+    /// Where the file will be stored, or `None` if it has the same content as a stored file
+    placement: Option<PreparedPlacement>,
+}
+
+/// Where [`Manager::add_file_prepare`] chose to put a file, with its entry in the journal of
+/// pending placements.
+struct PreparedPlacement {
+    pending_id: Uuid,
+    partition: Partition,
+    /// The final path of the file on the partition
+    path: PathBuf,
+    /// Where a copy is written to until the file is in the DB
+    temp_path: PathBuf,
+    /// Whether the file is copied to `temp_path` instead of moved to `path`
+    copied: bool,
+    /// Whether the copy is read back from the disk instead of the page cache when it's checked
+    /// before the file is stored
+    verify: bool,
+}
+
+/// A change to a partition that was queued while it wasn't mounted.
+enum PendingOperation {
+    Move(PendingMove),
+    Delete(PendingDeletion),
+}
+
+impl PendingOperation {
+    fn seq(&self) -> i64 {
+        match self {
+            Self::Move(pending) => pending.seq(),
+            Self::Delete(pending) => pending.seq(),
+        }
+    }
+}
+
+impl fmt::Display for PendingOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Move(pending) => write!(
+                f,
+                "move of {} to {}",
+                pending.old_path(),
+                pending.new_path()
+            ),
+            Self::Delete(pending) => write!(f, "deletion of {}", pending.path()),
+        }
+    }
+}
+
 impl Manager {
     pub fn new(config: Config, conn: Connection) -> Self {
         Self { config, conn }
@@ -84,18 +209,96 @@ impl Manager {
         Location::all(&self.conn)
     }
 
-    pub fn add_collection(&mut self, name: &str) -> anyhow::Result<()> {
+    pub fn add_collection(&mut self, name: &str, min_copies: u32) -> anyhow::Result<()> {
         auto_transaction(&mut self.conn, |tx| {
-            NewCollection { name }.insert(tx).map(|_| ())
+            NewCollection { name, min_copies }.insert(tx).map(|_| ())
         })?;
         log::info!("Collection added: {name}");
         Ok(())
     }
 
+    pub fn set_collection_min_copies(
+        &mut self,
+        collection_id: &Uuid,
+        min_copies: u32,
+    ) -> anyhow::Result<()> {
+        auto_transaction(&mut self.conn, |tx| {
+            Collection::set_min_copies(tx, collection_id, min_copies)
+        })?;
+        log::info!("Collection now requires {min_copies} copies of each file");
+        Ok(())
+    }
+
+    pub fn set_collection_legal_hold(
+        &mut self,
+        collection_id: &Uuid,
+        legal_hold: bool,
+    ) -> anyhow::Result<()> {
+        auto_transaction(&mut self.conn, |tx| {
+            Collection::set_legal_hold(tx, collection_id, legal_hold)
+        })?;
+        if legal_hold {
+            log::info!("Collection is now under legal hold");
+        } else {
+            log::info!("Collection is no longer under legal hold");
+        }
+        Ok(())
+    }
+
     pub fn list_collections(&self) -> anyhow::Result<Vec<Collection>> {
         Collection::all(&self.conn)
     }
 
+    /// Keep new files under the virtual directory on the partitions that already hold the most
+    /// files under it, only placing them elsewhere when those partitions are full.
+    pub fn add_placement_affinity(
+        &mut self,
+        collection_id: &Uuid,
+        dir: impl AsRef<Path>,
+    ) -> anyhow::Result<()> {
+        let prefix = dir_prefix(dir).map_err(|e| anyhow!("{}", e))?;
+        auto_transaction(&mut self.conn, |tx| {
+            NewPlacementAffinity {
+                collection_id,
+                prefix: &prefix,
+            }
+            .insert(tx)
+            .map(|_| ())
+        })?;
+        log::info!("Placement affinity added: {prefix}");
+        Ok(())
+    }
+
+    pub fn remove_placement_affinity(
+        &mut self,
+        collection_id: &Uuid,
+        dir: impl AsRef<Path>,
+    ) -> anyhow::Result<()> {
+        let prefix = dir_prefix(dir).map_err(|e| anyhow!("{}", e))?;
+        auto_transaction(&mut self.conn, |tx| {
+            PlacementAffinity::delete(tx, collection_id, &prefix)
+        })?;
+        log::info!("Placement affinity removed: {prefix}");
+        Ok(())
+    }
+
+    pub fn list_placement_affinities(
+        &self,
+        collection_id: &Uuid,
+    ) -> anyhow::Result<Vec<PlacementAffinity>> {
+        PlacementAffinity::for_collection(&self.conn, collection_id)
+    }
+
+    /// How the files under the virtual directory are currently spread across partitions.
+    pub fn placement_plan(
+        &self,
+        collection_id: &Uuid,
+        dir: impl AsRef<Path>,
+    ) -> anyhow::Result<Vec<PlacementSpread>> {
+        let prefix = dir_prefix(dir).map_err(|e| anyhow!("{}", e))?;
+        PlacementSpread::for_prefix(&self.conn, collection_id, &prefix)
+    }
+
     pub fn add_disk(
         &mut self,
         location_id: &Uuid,
@@ -103,10 +306,20 @@ impl Manager {
         label: &str,
     ) -> anyhow::Result<()> {
         let disk = get_disk_for_path(disk_path)?;
+        self.add_disk_with_serial_number(location_id, disk.serial_number(), label)
+    }
+
+    /// Add a disk that isn't a local block device (e.g., a NAS) using an arbitrary serial number.
+    pub fn add_disk_with_serial_number(
+        &mut self,
+        location_id: &Uuid,
+        serial_number: &str,
+        label: &str,
+    ) -> anyhow::Result<()> {
         auto_transaction(&mut self.conn, |tx| {
             NewDisk {
                 location_id,
-                serial_number: disk.serial_number(),
+                serial_number,
                 label,
             }
             .insert(tx)
@@ -129,6 +342,32 @@ impl Manager {
         Ok(())
     }
 
+    /// Add a directory as a storage target for the disk, writing a marker file to the directory
+    /// if it is not already set up as a target.
+    pub fn add_directory_partition(
+        &mut self,
+        disk_id: &Uuid,
+        dir_path: impl AsRef<Path>,
+    ) -> anyhow::Result<()> {
+        let dir_path = fs::canonicalize(dir_path)?;
+        let dir = dir_path
+            .to_str()
+            .ok_or_else(|| anyhow!("Path was not UTF-8: {}", dir_path.to_string_lossy()))?;
+        let target = DirectoryTarget::init(&dir_path)?;
+        auto_transaction(&mut self.conn, |tx| {
+            NewPartition {
+                disk_id,
+                uuid: target.uuid(),
+                capacity: target.capacity(),
+                directory: Some(dir),
+            }
+            .insert(tx)
+            .map(|_| ())
+        })?;
+        log::info!("Directory added as partition: {dir}");
+        Ok(())
+    }
+
     pub fn list_partitions(&self) -> anyhow::Result<Vec<Partition>> {
         Partition::all(&self.conn)
     }
@@ -139,36 +378,218 @@ impl Manager {
         partition_id: Option<&Uuid>,
         src_path: &str,
         dest_path: impl AsRef<Path>,
-        move_file: bool,
+        options: &AddOptions,
     ) -> anyhow::Result<()> {
         Self::add_file_check_src_path(src_path)?;
         let dest_path = dest_path.as_ref();
         self.add_file_check_dest_path(collection_id, dest_path)?;
 
-        let (db_part, part) = match partition_id {
-            Some(id) => match Partition::for_id(&self.conn, id)? {
-                Some(db_part) => {
-                    let part = get_partition_for_uuid(db_part.uuid())?;
-                    (db_part, part)
-                }
-                None => bail!("No partition was found for ID {}", id.hyphenated()),
-            },
-            None => self.random_partition()?,
-        };
-
-        let full_target_path = self.add_file_prep_target(&part, collection_id, dest_path)?;
-        self.add_file_do_insert(
+        let (mut placer, targets) =
+            self.placement_targets(collection_id, partition_id, options.strategy)?;
+        let prepared = self.add_file_prepare(
             collection_id,
-            db_part.id(),
+            &mut placer,
+            &targets,
             src_path,
             dest_path,
-            &full_target_path,
-            move_file,
+            options,
         )?;
+        let lookup_only = self.config.files().lookup_only();
+        let res = auto_transaction(&mut self.conn, |tx| {
+            Self::add_file_store(tx, collection_id, &prepared, options, lookup_only)
+        });
+        let placed = match res {
+            Ok(placed) => placed,
+            Err(e) => {
+                self.add_file_abort([&prepared]);
+                return Err(e);
+            }
+        };
+        self.add_file_finish(&prepared, placed)?;
+        if !placed && options.move_files {
+            fs::remove_file(src_path)?;
+        }
         log::info!("File added: {}", dest_path.to_string_lossy());
         Ok(())
     }
 
+    /// Recursively add all files in `src_dir` under the virtual directory `dest_dir`.
+    ///
+    /// Files are inserted in batches of [`ADD_DIR_BATCH_SIZE`]. Files that already exist in the
+    /// collection with the same size and hashes are assumed to have been added by an earlier,
+    /// interrupted run and are skipped. Moving files is refused while placements of an earlier
+    /// run still need to be recovered.
+    pub fn add_dir(
+        &mut self,
+        collection_id: &Uuid,
+        partition_id: Option<&Uuid>,
+        src_dir: impl AsRef<Path>,
+        dest_dir: impl AsRef<Path>,
+        options: &AddOptions,
+        dir_options: &AddDirOptions,
+    ) -> anyhow::Result<()> {
+        let src_dir = src_dir.as_ref();
+        if !src_dir.is_dir() {
+            bail!(
+                "Source path is not a directory: {}",
+                src_dir.to_string_lossy()
+            )
+        }
+        let dest_dir = canonical_path(dest_dir.as_ref()).map_err(|e| anyhow!("{}", e))?;
+        if options.move_files && !PendingPlacement::all(&self.conn)?.is_empty() {
+            bail!("Placements of an earlier run were interrupted. Run `hoard recover` before moving more files.")
+        }
+
+        // look up the targets once instead of once per file
+        let (mut placer, targets) =
+            self.placement_targets(collection_id, partition_id, options.strategy)?;
+
+        let (mut added, mut skipped) = (0, 0);
+        let mut batch = Vec::new();
+        for src_path in Self::add_dir_walk(src_dir, dir_options)? {
+            let dest_path = dest_dir.join(src_path.strip_prefix(src_dir)?);
+            let dest = dest_path
+                .to_str()
+                .ok_or_else(|| anyhow!("Path was not UTF-8: {}", dest_path.to_string_lossy()))?;
+
+            if let Some(file) = File::get_by_collection_and_path(&self.conn, collection_id, dest)? {
+                if file.size() == fs::metadata(&src_path)?.size() {
+                    let hashes = FileHash::get_by_file_id(&self.conn, file.id())?;
+                    match Self::check_hashes(
+                        &src_path,
+                        &hashes,
+                        self.config.files().lookup_only(),
+                    )? {
+                        CheckOutcome::Ok => {
+                            log::debug!("File was already added, skipping: {dest}");
+                            skipped += 1;
+                            continue;
+                        }
+                        CheckOutcome::Unverifiable => bail!(
+                            "File {} already exists in the collection without trusted hashes to compare it to",
+                            dest
+                        ),
+                        _ => {}
+                    }
+                }
+                bail!(
+                    "File {} already exists in the collection with different contents",
+                    dest
+                )
+            }
+            self.add_file_check_dest_path(collection_id, &dest_path)?;
+
+            batch.push((src_path, dest_path));
+            if batch.len() >= ADD_DIR_BATCH_SIZE {
+                added +=
+                    self.add_dir_batch(collection_id, &mut placer, &targets, &mut batch, options)?;
+            }
+        }
+        added += self.add_dir_batch(collection_id, &mut placer, &targets, &mut batch, options)?;
+
+        log::info!("Files added: {added}, already present: {skipped}");
+        Ok(())
+    }
+
+    /// All regular files under `src_dir` that pass the filters, sorted by path.
+    fn add_dir_walk(src_dir: &Path, options: &AddDirOptions) -> anyhow::Result<Vec<PathBuf>> {
+        let walker = WalkDir::new(src_dir)
+            .follow_links(options.symlinks == SymlinkMode::Follow)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| match entry.path().strip_prefix(src_dir) {
+                Ok(rel) if entry.depth() > 0 => !options.exclude.is_match(rel),
+                _ => true,
+            });
+
+        let mut files = Vec::new();
+        for entry in walker {
+            let entry = entry?;
+            let rel = entry.path().strip_prefix(src_dir)?;
+            let file_type = entry.file_type();
+            if file_type.is_symlink() {
+                match options.symlinks {
+                    SymlinkMode::Error => {
+                        bail!("Found symlink: {}", entry.path().to_string_lossy())
+                    }
+                    _ => {
+                        log::info!("Skipping symlink: {}", entry.path().to_string_lossy());
+                        continue;
+                    }
+                }
+            }
+            if file_type.is_dir() {
+                continue;
+            }
+            if !file_type.is_file() {
+                log::warn!("Skipping special file: {}", entry.path().to_string_lossy());
+                continue;
+            }
+            if !options.include.is_empty() && !options.include.is_match(rel) {
+                continue;
+            }
+            files.push(entry.into_path());
+        }
+        Ok(files)
+    }
+
+    /// Copy/move a batch of files to the targets and insert them in a single transaction. The
+    /// batch is emptied. If the transaction fails, the copies are removed and moved files are
+    /// moved back.
+    fn add_dir_batch(
+        &mut self,
+        collection_id: &Uuid,
+        placer: &mut Placer,
+        targets: &[StorageTarget],
+        batch: &mut Vec<(PathBuf, PathBuf)>,
+        options: &AddOptions,
+    ) -> anyhow::Result<usize> {
+        let mut prepared = Vec::with_capacity(batch.len());
+        for (src_path, dest_path) in batch.drain(..) {
+            let res = src_path
+                .to_str()
+                .ok_or_else(|| anyhow!("Path was not UTF-8: {}", src_path.to_string_lossy()))
+                .and_then(|src| {
+                    self.add_file_prepare(collection_id, placer, targets, src, &dest_path, options)
+                });
+            match res {
+                Ok(file) => prepared.push(file),
+                Err(e) => {
+                    self.add_file_abort(&prepared);
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut placed = Vec::with_capacity(prepared.len());
+        let lookup_only = self.config.files().lookup_only();
+        let res = auto_transaction(&mut self.conn, |tx| {
+            for file in prepared.iter() {
+                placed.push(Self::add_file_store(
+                    tx,
+                    collection_id,
+                    file,
+                    options,
+                    lookup_only,
+                )?);
+                log::debug!("File added: {}", file.dest_path.to_string_lossy());
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+        if let Err(e) = res {
+            self.add_file_abort(&prepared);
+            return Err(e);
+        }
+
+        for (file, placed) in prepared.iter().zip(placed) {
+            self.add_file_finish(file, placed)?;
+            if !placed && options.move_files {
+                fs::remove_file(&file.src_path)?;
+            }
+        }
+        Ok(prepared.len())
+    }
+
     fn add_file_check_src_path(src_path: &str) -> anyhow::Result<()> {
         if Path::new(src_path).is_dir() {
             bail!("Source path cannot be a directory: {src_path}")
@@ -210,8 +631,7 @@ impl Manager {
     }
 
     fn add_file_prep_target(
-        &self,
-        dev_partition: &dev_utils::Partition,
+        target: &StorageTarget,
         collection_id: &Uuid,
         dest_path: &Path,
     ) -> anyhow::Result<PathBuf> {
@@ -222,179 +642,1184 @@ impl Manager {
                 target_path.to_string_lossy()
             )
         })?;
-        create_dirs_from(&dev_partition.mount_point(), &target_dir)?;
-        Ok(dev_partition.mount_point().join(&target_path))
+        create_dirs_from(&target.mount_point(), &target_dir)?;
+        Ok(target.mount_point().join(&target_path))
     }
 
-    fn add_file_do_insert(
+    /// Read the parts of the source file that need to be stored in the DB. The file is read once
+    /// and at the same time hashed, listed if it's a [streamable](ContainerType::is_streamable)
+    /// archive, and copied to a temporary file on one of the targets. Other archives are read
+    /// again to list them.
+    ///
+    /// When deduplicating, files are only copied after they are read, and files that are
+    /// already stored aren't copied at all. Files that will be moved are renamed by
+    /// [`Manager::add_file_store`] instead. Either way, the placement is recorded in the journal
+    /// before anything is written to the target.
+    fn add_file_prepare(
         &mut self,
         collection_id: &Uuid,
-        partition_id: &Uuid,
+        placer: &mut Placer,
+        targets: &[StorageTarget],
         src_path: &str,
         dest_path: &Path,
-        full_target_path: &Path,
-        move_file: bool,
-    ) -> anyhow::Result<()> {
-        let hashes = make_hashes(fs::File::open(src_path)?, self.config.files().hashes())?;
-        let file_meta = fs::metadata(src_path)?;
+        options: &AddOptions,
+    ) -> anyhow::Result<PreparedFile> {
+        let mut src = fs::File::open(src_path)?;
+        let container_type = ContainerType::detect(&mut src)?;
+        let file_name = match container_type {
+            Some(_) => dest_path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| anyhow!("Path has no UTF-8 file name: {:?}", dest_path))?,
+            None => "",
+        };
+        let mail = if container_type.is_none() && mail_utils::is_message_path(dest_path) {
+            let mut header = Vec::new();
+            fs::File::open(src_path)?
+                .take(mail_utils::MAX_HEADER_SIZE)
+                .read_to_end(&mut header)?;
+            mail_utils::parse_headers(&header)
+        } else {
+            None
+        };
 
-        auto_transaction(&mut self.conn, |tx| {
-            let file_id = NewFile {
+        let (mut placement, out) = if options.move_files || options.dedup {
+            (None, None)
+        } else {
+            let size = src.metadata()?.size();
+            let (placement, out) = self.add_file_place(
                 collection_id,
-                path: dest_path
-                    .to_str()
-                    .ok_or_else(|| anyhow!("Path was not a UTF-8 string"))?,
-                size: file_meta.size(),
-            }
-            .insert(tx)?;
+                placer,
+                targets,
+                src_path,
+                dest_path,
+                size,
+                options,
+            )?;
+            (Some(placement), out)
+        };
 
-            NewFilePlacement {
-                partition_id,
-                file_id: &file_id,
+        let res = self.add_file_read(src, src_path, file_name, container_type, out);
+        let (size, hashes, archive_files) = match res {
+            Ok(read) => read,
+            Err(e) => {
+                if let Some(placement) = placement.as_ref() {
+                    self.add_file_unplace(placement, src_path);
+                }
+                return Err(e);
             }
-            .insert(tx)?;
+        };
+        // archives that can't be listed are stored like any other file
+        let (container_type, archive_files) = match archive_files {
+            Some(archive_files) => (container_type, archive_files),
+            None => (None, Vec::new()),
+        };
 
-            for (hash_algorithm, hash_value) in hashes.iter() {
-                NewFileHash {
-                    file_id: &file_id,
-                    hash_algorithm,
-                    hash_value,
+        let shared = options.dedup
+            && File::for_content(&self.conn, size, &hashes, self.config.files().lookup_only())?
+                .is_some();
+        if placement.is_none() && !shared {
+            let (new_placement, out) = self.add_file_place(
+                collection_id,
+                placer,
+                targets,
+                src_path,
+                dest_path,
+                size,
+                options,
+            )?;
+            if let Some(out) = out {
+                if let Err(e) = Self::add_file_copy(src_path, out, &hashes) {
+                    self.add_file_unplace(&new_placement, src_path);
+                    return Err(e);
                 }
-                .insert(tx)?;
             }
+            placement = Some(new_placement);
+        }
+        Ok(PreparedFile {
+            src_path: src_path.to_string(),
+            dest_path: dest_path.to_owned(),
+            size,
+            hashes,
+            container_type,
+            archive_files,
+            mail,
+            placement,
+        })
+    }
 
-            for (archive_path, size) in
-                archive_utils::list_files(src_path, fs::File::open(src_path)?)?
-            {
-                NewFileArchive {
-                    file_id: &file_id,
-                    path: &archive_path,
-                    size,
-                }
-                .insert(tx)?;
+    /// Choose where to put a file and record it in the journal of pending placements. Unless
+    /// the file will be moved, the temporary file it's copied to is created and returned.
+    #[allow(clippy::too_many_arguments)]
+    fn add_file_place(
+        &mut self,
+        collection_id: &Uuid,
+        placer: &mut Placer,
+        targets: &[StorageTarget],
+        src_path: &str,
+        dest_path: &Path,
+        size: u64,
+        options: &AddOptions,
+    ) -> anyhow::Result<(PreparedPlacement, Option<fs::File>)> {
+        let (partition, target) = Self::choose_target(placer, targets, dest_path, size)?;
+        let verify = options.verify || self.config.files().verify_copies();
+        self.journal_placement(
+            collection_id,
+            partition,
+            target,
+            src_path,
+            dest_path,
+            options.move_files,
+            verify,
+        )
+    }
+
+    /// Record a placement in the journal of pending placements before anything is written to
+    /// the target. Unless the file will be moved, the temporary file it's copied to is created
+    /// and returned.
+    #[allow(clippy::too_many_arguments)]
+    fn journal_placement(
+        &mut self,
+        collection_id: &Uuid,
+        partition: Partition,
+        target: &StorageTarget,
+        src_path: &str,
+        dest_path: &Path,
+        move_file: bool,
+        verify: bool,
+    ) -> anyhow::Result<(PreparedPlacement, Option<fs::File>)> {
+        let path = Self::add_file_prep_target(target, collection_id, dest_path)?;
+        let pending_id = auto_transaction(&mut self.conn, |tx| {
+            NewPendingPlacement {
+                partition_id: partition.id(),
+                collection_id,
+                path: dest_path
+                    .to_str()
+                    .ok_or_else(|| anyhow!("Path was not a UTF-8 string"))?,
+                src_path: move_file.then_some(src_path),
             }
+            .insert(tx)
+        })?;
+        let placement = PreparedPlacement {
+            temp_path: Self::temp_path(&path, &pending_id),
+            pending_id,
+            partition,
+            path,
+            copied: !move_file,
+            verify: !move_file && verify,
+        };
+        if move_file {
+            return Ok((placement, None));
+        }
 
-            // copy/move after insert to use the DB as a check against overwriting known files
-            // obviously this is bad for concurrent writes, but that's ok for now
-            let add_res = if move_file {
-                fs::rename(src_path, &full_target_path)
-            } else {
-                fs::copy(src_path, &full_target_path).map(|_| ())
-            };
-            match add_res {
-                Ok(_) => Ok(()),
-                Err(e) => Err(anyhow!(
+        let res = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&placement.temp_path)
+            .and_then(|out| {
+                out.set_permissions(fs::metadata(src_path)?.permissions())?;
+                Ok(out)
+            });
+        match res {
+            Ok(out) => Ok((placement, Some(out))),
+            Err(e) => {
+                self.add_file_unplace(&placement, src_path);
+                Err(anyhow!(
                     "Error copying {} to {}: {:?}",
                     src_path,
-                    full_target_path.to_string_lossy(),
+                    placement.temp_path.to_string_lossy(),
                     e
-                )),
+                ))
             }
-        })
+        }
     }
 
-    // TODO should probably move this out of the manager
-    pub fn path_on_partition(
-        collection_id: &Uuid,
-        dest_path: impl AsRef<Path>,
-    ) -> anyhow::Result<PathBuf> {
-        let root = PathBuf::from(format!("hoard/collections/{}", collection_id.hyphenated()));
-        let virt_path =
-            canonical_path(dest_path.as_ref().as_os_str()).map_err(|e| anyhow!("{}", e))?;
-        Ok(root.join(strip_root(virt_path)))
+    /// The path a copy is written to until its file is in the DB
+    fn temp_path(path: &Path, pending_id: &Uuid) -> PathBuf {
+        path.with_file_name(format!(".hoard-tmp-{}", pending_id.simple()))
     }
 
-    fn random_partition(&self) -> anyhow::Result<(Partition, dev_utils::Partition)> {
-        let mut partitions = dev_utils::get_all_partitions()?;
-        log::trace!("Current partitions: {:#?}", partitions);
-
-        let partition_ids = partitions.iter().map(|p| p.uuid()).collect::<Vec<_>>();
-        match Partition::random(&self.conn, &partition_ids)? {
-            Some(db_part) => {
-                let part = partitions
-                    .drain(..)
-                    .find(|p| p.uuid() == db_part.uuid())
-                    .unwrap();
-                Ok((db_part, part))
-            }
-            None => bail!(concat!(
-                "No currently mounted partitions were found in the DB. ",
-                "Try mounting one or adding one to the DB.",
-            ),),
+    /// Copy a file that was already read to its temporary file, checking that it still has the
+    /// hashes it was read with.
+    fn add_file_copy(src_path: &str, out: fs::File, hashes: &Hashes) -> anyhow::Result<()> {
+        let mut out = io::BufWriter::new(out);
+        let mut reader = TeeReader::new(fs::File::open(src_path)?, &mut out);
+        let found = make_hashes(&mut reader, hashes.iter().map(|(a, _)| a))?;
+        // nothing is read when there are no hashes
+        io::copy(&mut reader, &mut io::sink())?;
+        drop(reader);
+        sync_file(out)?;
+        if hashes.iter().any(|(a, v)| found.get(a) != Some(v)) {
+            bail!("{src_path} changed while it was being copied");
         }
+        Ok(())
     }
 
-    pub fn list_files<'a, I, II>(
+    /// Read the source file from start to end, hashing it and writing it to `out`, and list its
+    /// members if it's an archive. `out` is synced to disk. Returns the number of bytes read,
+    /// the hashes and the members.
+    fn add_file_read(
         &self,
-        collection_id: &Uuid,
-        files: II,
-        all: bool,
-    ) -> anyhow::Result<Vec<File>>
-    where
-        I: Iterator<Item = &'a str>,
-        II: IntoIterator<Item = &'a str, IntoIter = I>,
-    {
-        let mut output = Vec::new();
+        src: fs::File,
+        src_path: &str,
+        file_name: &str,
+        container_type: Option<ContainerType>,
+        out: Option<fs::File>,
+    ) -> anyhow::Result<(u64, Hashes, Option<Vec<ArchiveEntry>>)> {
+        let start = Instant::now();
+        let hash_algorithms = self.config.files().hashes();
+        let limits = self.config.archives().nesting_limits();
+        let mut hasher = MultiHasher::new(hash_algorithms);
+        let mut out = out.map(io::BufWriter::new);
+        let mut sink = io::sink();
+        let writer: &mut dyn Write = match out.as_mut() {
+            Some(out) => out,
+            None => &mut sink,
+        };
 
-        for file in files {
-            // if the path is a file
-            if !file.ends_with('/') {
-                if let Some(db_file) =
-                    File::get_by_collection_and_path(&self.conn, collection_id, file)?
-                {
-                    output.push(db_file);
-                    continue; // can't be both a file and a dir
-                }
+        let mut reader = TrackedReader::new(TeeReader::new(
+            TeeReader::new(io::BufReader::new(src), &mut hasher),
+            writer,
+        ));
+        let mut archive_files = match container_type {
+            Some(container_type) if container_type.is_streamable() => {
+                let res = archive_utils::list_files_stream(
+                    container_type,
+                    file_name,
+                    &mut reader,
+                    &limits,
+                    hash_algorithms,
+                );
+                Self::add_file_members(res, reader.failed(), src_path)?
             }
+            _ => Some(Vec::new()),
+        };
+        // the rest of the file is hashed and copied even if the lister didn't need it
+        io::copy(&mut reader, &mut io::sink())?;
+        drop(reader);
+        if let Some(out) = out {
+            sync_file(out)?;
+        }
+        let (size, hashes) = hasher.finalize();
+        let elapsed = start.elapsed().as_secs_f64();
+        log::info!(
+            "Read {} bytes of {} in {:.1}s ({:.1} MiB/s)",
+            size,
+            src_path,
+            elapsed,
+            size as f64 / 1024.0 / 1024.0 / elapsed.max(0.001),
+        );
 
-            // if the path is a dir
-            output.extend(File::get_by_collection_and_directory(
-                &self.conn,
-                collection_id,
-                all,
-                file,
-            )?);
+        if let Some(container_type) = container_type.filter(|c| !c.is_streamable()) {
+            let mut reader = TrackedReader::new(fs::File::open(src_path)?);
+            let res = archive_utils::list_files(
+                container_type,
+                file_name,
+                &mut reader,
+                &limits,
+                hash_algorithms,
+            );
+            archive_files = Self::add_file_members(res, reader.failed(), src_path)?;
         }
+        let mut hashes = hashes.into_iter().map(|(a, v)| (*a, v)).collect::<Vec<_>>();
+        hashes.sort_by_key(|(a, _)| a.to_string());
+        Ok((size, hashes, archive_files))
+    }
 
-        output.sort_by(|a, b| a.path().cmp(b.path()));
-        Ok(output)
+    /// The members of an archive, or `None` if it couldn't be listed (e.g., because it's corrupt).
+    /// Errors reading the file itself are returned since the file can't be added without it.
+    fn add_file_members(
+        res: anyhow::Result<Vec<ArchiveEntry>>,
+        read_failed: bool,
+        src_path: &str,
+    ) -> anyhow::Result<Option<Vec<ArchiveEntry>>> {
+        match res {
+            Ok(archive_files) => Ok(Some(archive_files)),
+            Err(e) if read_failed => Err(e),
+            Err(e) => {
+                log::warn!("Unable to list the members of {src_path}. Adding it without them: {e}");
+                Ok(None)
+            }
+        }
     }
 
-    pub fn find_files<'a, I, II>(
+    /// Undo the placements of files that weren't stored.
+    fn add_file_abort<'a>(&mut self, files: impl IntoIterator<Item = &'a PreparedFile>) {
+        for file in files {
+            if let Some(placement) = file.placement.as_ref() {
+                self.add_file_unplace(placement, &file.src_path);
+            }
+        }
+    }
+
+    /// Remove the copy of a file that wasn't stored, or move it back if it was moved, and take
+    /// its placement out of the journal. Errors are logged since this runs after another error.
+    fn add_file_unplace(&mut self, placement: &PreparedPlacement, src_path: &str) {
+        let src_path = (!placement.copied).then_some(src_path);
+        let res = Self::roll_back_placement(&placement.path, &placement.temp_path, src_path)
+            .and_then(|_| {
+                auto_transaction(&mut self.conn, |tx| {
+                    PendingPlacement::delete(tx, &placement.pending_id)
+                })
+            });
+        if let Err(e) = res {
+            log::error!(
+                "Unable to undo the placement of {}: {e}. Run `hoard recover` to try again.",
+                placement.path.to_string_lossy()
+            );
+        }
+    }
+
+    /// Remove the temporary file of a placement that didn't make it into the DB or, if the file
+    /// was being moved, move it back to `src_path`.
+    fn roll_back_placement(
+        path: &Path,
+        temp_path: &Path,
+        src_path: Option<&str>,
+    ) -> anyhow::Result<()> {
+        if temp_path.exists() {
+            log::info!("Removing {}", temp_path.to_string_lossy());
+            fs::remove_file(temp_path)?;
+        }
+        if let Some(src_path) = src_path {
+            if path.exists() && !Path::new(src_path).exists() {
+                log::info!("Moving {} back to {src_path}", path.to_string_lossy());
+                fs::rename(path, src_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Insert the file and record its placement or, when deduplicating, point it at a file with
+    /// the same content. Moved files are moved to their targets here, and copies are renamed by
+    /// [`Manager::add_file_finish`] once the transaction is committed. Returns whether the file
+    /// was placed.
+    fn add_file_store(
+        tx: &Transaction,
+        collection_id: &Uuid,
+        file: &PreparedFile,
+        options: &AddOptions,
+        lookup_only: &[HashAlgorithm],
+    ) -> anyhow::Result<bool> {
+        if options.dedup {
+            if let Some(content_file) = File::for_content(tx, file.size, &file.hashes, lookup_only)?
+            {
+                log::info!(
+                    "{} has the same content as {}. Not copying it again.",
+                    file.dest_path.to_string_lossy(),
+                    content_file.path()
+                );
+                Self::add_file_insert(tx, collection_id, file, Some(content_file.id()))?;
+                return Ok(false);
+            }
+        }
+
+        let placement = file.placement.as_ref().ok_or_else(|| {
+            anyhow!(
+                "No partition was chosen for {}",
+                file.dest_path.to_string_lossy()
+            )
+        })?;
+        let file_id = Self::add_file_insert(tx, collection_id, file, None)?;
+        NewFilePlacement {
+            partition_id: placement.partition.id(),
+            file_id: &file_id,
+        }
+        .insert(tx)?;
+        PendingPlacement::set_file_id(tx, &placement.pending_id, &file_id)?;
+        Partition::record_placement(tx, placement.partition.id(), file.size)?;
+
+        // move after insert to use the DB as a check against overwriting known files
+        // obviously this is bad for concurrent writes, but that's ok for now
+        if !placement.copied {
+            Self::add_file_move(&file.src_path, &placement.path)?;
+        } else {
+            Self::verify_copy(
+                &placement.temp_path,
+                file.size,
+                &file.hashes,
+                lookup_only,
+                placement.verify,
+            )
+            .map_err(|e| anyhow!("Copy of {} is bad: {e}", file.src_path))?;
+        }
+        Ok(true)
+    }
+
+    /// Read a copy back and check that it has the size and hashes of its source. Lookup-only
+    /// hashes are ignored, so only the size of a copy with none of the others is checked. With
+    /// `from_disk`, the copy is dropped from the page cache first so that what the disk has is
+    /// what's read.
+    fn verify_copy(
+        path: &Path,
+        size: u64,
+        hashes: &Hashes,
+        lookup_only: &[HashAlgorithm],
+        from_disk: bool,
+    ) -> anyhow::Result<()> {
+        let copy = fs::File::open(path)?;
+        if from_disk {
+            drop_cache(&copy)?;
+        }
+        let copy_size = copy.metadata()?.len();
+        if copy_size != size {
+            bail!("Expected {size} bytes but {copy_size} were written");
+        }
+        let trusted = hashes
+            .iter()
+            .filter(|(a, _)| !lookup_only.contains(a))
+            .collect::<Vec<_>>();
+        if trusted.is_empty() {
+            log::warn!(
+                "No trusted hashes to check {} with. Unable to verify its contents.",
+                path.to_string_lossy()
+            );
+            return Ok(());
+        }
+        let found = make_hashes(io::BufReader::new(copy), trusted.iter().map(|(a, _)| a))?;
+        for (hash_algorithm, hash_value) in trusted {
+            if found.get(hash_algorithm) != Some(hash_value) {
+                bail!("The {hash_algorithm} hash doesn't match the source's");
+            }
+        }
+        Ok(())
+    }
+
+    /// Rename the copy of a file that was stored to its final path, or remove it if the file
+    /// shares the content of another file added with it, and take the placement out of the
+    /// journal.
+    fn add_file_finish(&mut self, file: &PreparedFile, placed: bool) -> anyhow::Result<()> {
+        match file.placement.as_ref() {
+            Some(placement) => self.finish_placement(placement, placed, &file.src_path),
+            None => Ok(()),
+        }
+    }
+
+    /// Rename a copy to its final path once its placement is in the DB, or remove it if it
+    /// wasn't placed, and take the placement out of the journal.
+    fn finish_placement(
+        &mut self,
+        placement: &PreparedPlacement,
+        placed: bool,
+        src_path: &str,
+    ) -> anyhow::Result<()> {
+        if placement.copied {
+            let res = if placed {
+                fs::rename(&placement.temp_path, &placement.path)
+                    .and_then(|_| sync_parent_dir(&placement.path))
+            } else {
+                fs::remove_file(&placement.temp_path)
+            };
+            if let Err(e) = res {
+                bail!(
+                    "Unable to finish copying {} to {}: {e}. Run `hoard recover` to try again.",
+                    src_path,
+                    placement.path.to_string_lossy()
+                );
+            }
+        }
+        auto_transaction(&mut self.conn, |tx| {
+            PendingPlacement::delete(tx, &placement.pending_id)
+        })
+    }
+
+    fn add_file_insert(
+        tx: &Transaction,
+        collection_id: &Uuid,
+        file: &PreparedFile,
+        content_file_id: Option<&Uuid>,
+    ) -> anyhow::Result<Uuid> {
+        let file_id = NewFile {
+            collection_id,
+            path: file
+                .dest_path
+                .to_str()
+                .ok_or_else(|| anyhow!("Path was not a UTF-8 string"))?,
+            size: file.size,
+            content_file_id,
+            container_type: file.container_type,
+        }
+        .insert(tx)?;
+
+        for (hash_algorithm, hash_value) in file.hashes.iter() {
+            NewFileHash {
+                file_id: &file_id,
+                hash_algorithm,
+                hash_value,
+            }
+            .insert(tx)?;
+        }
+        if let Some(mail) = file.mail.as_ref() {
+            Self::insert_mail(tx, &file_id, None, mail)?;
+        }
+
+        // members of nested archives come after the archive that contains them
+        let mut member_ids = HashMap::new();
+        for entry in file.archive_files.iter() {
+            let parent_id = entry
+                .path
+                .rsplit_once("!/")
+                .and_then(|(parent, _)| member_ids.get(parent));
+            let id = NewFileArchive {
+                file_id: &file_id,
+                path: &entry.path,
+                size: entry.size,
+                parent_id,
+                entry_type: entry.entry_type,
+                link_target: entry.link_target.as_deref(),
+                modified_date: entry.modified.as_ref(),
+                compressed_size: entry.compressed_size,
+                crc32: entry.crc32,
+                encrypted: entry.encrypted,
+            }
+            .insert(tx)?;
+            for (hash_algorithm, hash_value) in entry.hashes.iter() {
+                NewFileArchiveHash {
+                    file_archive_id: &id,
+                    hash_algorithm,
+                    hash_value,
+                }
+                .insert(tx)?;
+            }
+            if let Some(mail) = entry.mail.as_ref() {
+                Self::insert_mail(tx, &file_id, Some(&id), mail)?;
+            }
+            member_ids.insert(entry.path.as_str(), id);
+        }
+        Ok(file_id)
+    }
+
+    fn insert_mail(
+        tx: &Transaction,
+        file_id: &Uuid,
+        file_archive_id: Option<&Uuid>,
+        mail: &MailHeaders,
+    ) -> anyhow::Result<()> {
+        NewMailMessage {
+            file_id,
+            file_archive_id,
+            message_id: mail.message_id.as_deref(),
+            sent_date: mail.date.as_ref(),
+            mail_from: mail.from.as_deref(),
+            mail_to: mail.to.as_deref(),
+            subject: mail.subject.as_deref(),
+        }
+        .insert(tx)?;
+        Ok(())
+    }
+
+    fn add_file_move(src_path: &str, full_target_path: &Path) -> anyhow::Result<()> {
+        match fs::rename(src_path, full_target_path) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(anyhow!(
+                "Error moving {} to {}: {:?}",
+                src_path,
+                full_target_path.to_string_lossy(),
+                e
+            )),
+        }
+    }
+
+    /// Finish or undo the placements of added files that were interrupted, e.g., by a crash.
+    /// Copies of files that made it into the DB are checked against the stored size and hashes
+    /// and renamed to the current paths of the files, which are found by their IDs in case they
+    /// were moved since. Anything written for files that didn't is removed or moved back, but
+    /// copies with the content of a file in the DB that has no other copies are kept, since they
+    /// may be the only copy of it. Placements on partitions that aren't mounted and copies that
+    /// don't match are left for later. This must not run while files are being added or
+    /// replicated.
+    pub fn recover(&mut self) -> anyhow::Result<()> {
+        let targets = self.mounted_targets()?;
+        self.prepare_targets(&targets)?;
+        let uuids = targets.iter().map(|p| p.uuid()).collect::<Vec<_>>();
+        let db_parts = Partition::current(&self.conn, &uuids)?;
+        let mut recovered = 0;
+        let mut skipped = 0;
+        for pending in PendingPlacement::all(&self.conn)? {
+            let db_part = match db_parts.iter().find(|p| p.id() == pending.partition_id()) {
+                Some(db_part) => db_part,
+                None => {
+                    skipped += 1;
+                    continue;
+                }
+            };
+            let target = Self::mounted_target(&targets, db_part);
+            let path = target.mount_point().join(Self::path_on_partition(
+                pending.collection_id(),
+                pending.path(),
+            )?);
+            let temp_path = Self::temp_path(&path, pending.id());
+
+            let mut placed_file = None;
+            if let Some(file) = pending
+                .file_id()
+                .map(|file_id| File::for_id(&self.conn, file_id))
+                .transpose()?
+                .flatten()
+            {
+                if FilePlacement::get_by_file_id(&self.conn, file.id())?
+                    .iter()
+                    .any(|p| p.partition_id() == pending.partition_id())
+                {
+                    placed_file = Some(file);
+                }
+            }
+            if let Some(file) = placed_file {
+                let file_path = Self::add_file_prep_target(
+                    target,
+                    file.collection_id(),
+                    Path::new(file.path()),
+                )?;
+                if temp_path.exists() {
+                    let outcome = if fs::metadata(&temp_path)?.len() != file.size() {
+                        CheckOutcome::SizeDiffers
+                    } else {
+                        Self::check_hashes(
+                            &temp_path,
+                            &FileHash::get_by_file_id(&self.conn, file.id())?,
+                            self.config.files().lookup_only(),
+                        )?
+                    };
+                    if !matches!(outcome, CheckOutcome::Ok | CheckOutcome::Unverifiable) {
+                        log::warn!(
+                            "Not renaming {}: it doesn't match the stored size and hashes of {} ({outcome:?}). Check it and remove it by hand.",
+                            temp_path.to_string_lossy(),
+                            file.path()
+                        );
+                        skipped += 1;
+                        continue;
+                    }
+                    log::info!(
+                        "Renaming {} to {}",
+                        temp_path.to_string_lossy(),
+                        file_path.to_string_lossy()
+                    );
+                    fs::rename(&temp_path, &file_path)?;
+                    sync_parent_dir(&file_path)?;
+                } else if !file_path.exists() {
+                    log::warn!(
+                        "{} is missing from partition {}",
+                        file.path(),
+                        pending.partition_id().hyphenated()
+                    );
+                }
+            } else if pending.file_id().is_some() && pending.src_path().is_some() {
+                // the move was committed, and the file was moved or removed since
+            } else {
+                if temp_path.exists() && self.is_only_copy(&temp_path)? {
+                    log::warn!(
+                        "Not removing {}: a file in the DB has its content and no other copies. Check it and remove it by hand.",
+                        temp_path.to_string_lossy()
+                    );
+                    skipped += 1;
+                    continue;
+                }
+                Self::roll_back_placement(&path, &temp_path, pending.src_path())?;
+            }
+            auto_transaction(&mut self.conn, |tx| {
+                PendingPlacement::delete(tx, pending.id())
+            })?;
+            recovered += 1;
+        }
+        log::info!("Recovered placements: {recovered}, left for later: {skipped}");
+        Ok(())
+    }
+
+    /// Whether any file in the DB has the size and hashes of the local file
+    fn is_only_copy(&self, path: &Path) -> anyhow::Result<bool> {
+        let src = fs::File::open(path)?;
+        let size = src.metadata()?.len();
+        let hashes = make_hashes(io::BufReader::new(src), self.config.files().hashes())?
+            .into_iter()
+            .map(|(a, v)| (*a, v))
+            .collect::<Vec<_>>();
+        for file in
+            File::with_content(&self.conn, size, &hashes, self.config.files().lookup_only())?
+        {
+            if file.content_file_id().is_none()
+                && FilePlacement::get_by_file_id(&self.conn, file.id())?.is_empty()
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // TODO should probably move this out of the manager
+    pub fn path_on_partition(
+        collection_id: &Uuid,
+        dest_path: impl AsRef<Path>,
+    ) -> anyhow::Result<PathBuf> {
+        let root = PathBuf::from(format!("hoard/collections/{}", collection_id.hyphenated()));
+        let virt_path =
+            canonical_path(dest_path.as_ref().as_os_str()).map_err(|e| anyhow!("{}", e))?;
+        Ok(root.join(strip_root(virt_path)))
+    }
+
+    /// All currently available storage targets.
+    fn mounted_targets(&self) -> anyhow::Result<Vec<StorageTarget>> {
+        storage::mounted_targets(&self.conn)
+    }
+
+    /// Refresh the free space of the mounted targets in the DB and apply the moves and deletions
+    /// queued for them. Commands that only read files don't do this, so they can't change anything
+    /// on the disks.
+    pub fn apply_pending(&mut self) -> anyhow::Result<()> {
+        let targets = self.mounted_targets()?;
+        self.prepare_targets(&targets)
+    }
+
+    /// Bring the targets up to date before files are written to them.
+    fn prepare_targets(&mut self, targets: &[StorageTarget]) -> anyhow::Result<()> {
+        self.refresh_capacities(targets)?;
+        self.apply_pending_operations(targets)
+    }
+
+    /// Warn if moves or deletions are still queued for the targets, because the copies on them
+    /// won't be at the paths in the DB until they're applied.
+    fn warn_pending_operations(&self, targets: &[StorageTarget]) -> anyhow::Result<()> {
+        let uuids = targets.iter().map(|p| p.uuid()).collect::<Vec<_>>();
+        let mut queued = 0;
+        for db_part in Partition::current(&self.conn, &uuids)? {
+            queued += PendingMove::for_partition(&self.conn, db_part.id())?.len();
+            queued += PendingDeletion::for_partition(&self.conn, db_part.id())?.len();
+        }
+        if queued > 0 {
+            log::warn!(
+                "{queued} moves and deletions are queued for the mounted partitions. Run `hoard apply` to apply them."
+            );
+        }
+        Ok(())
+    }
+
+    fn refresh_capacities(&mut self, targets: &[StorageTarget]) -> anyhow::Result<()> {
+        auto_transaction(&mut self.conn, |tx| {
+            for target in targets {
+                Partition::refresh_capacity(tx, target.uuid(), target.capacity())?;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+    }
+
+    /// The partitions new files can be placed on: either the given partition or all mounted
+    /// ones.
+    fn placement_targets(
+        &mut self,
+        collection_id: &Uuid,
+        partition_id: Option<&Uuid>,
+        strategy: PlacementStrategy,
+    ) -> anyhow::Result<(Placer, Vec<StorageTarget>)> {
+        let targets = match partition_id {
+            Some(id) => match Partition::for_id(&self.conn, id)? {
+                Some(db_part) => vec![storage::target_for_partition(&db_part)?],
+                None => bail!("No partition was found for ID {}", id.hyphenated()),
+            },
+            None => self.mounted_targets()?,
+        };
+        self.prepare_targets(&targets)?;
+
+        let interrupted = PendingPlacement::all(&self.conn)?.len();
+        if interrupted > 0 {
+            log::warn!(
+                "{interrupted} placements of added files were interrupted. Run `hoard recover` to finish or undo them."
+            );
+        }
+
+        let uuids = targets.iter().map(|p| p.uuid()).collect::<Vec<_>>();
+        let placer = Placer::new(strategy, Partition::current(&self.conn, &uuids)?)
+            .with_affinity(self.affinity_tracker(collection_id)?);
+        Ok((placer, targets))
+    }
+
+    fn affinity_tracker(&self, collection_id: &Uuid) -> anyhow::Result<AffinityTracker> {
+        let mut prefixes = Vec::new();
+        for affinity in PlacementAffinity::for_collection(&self.conn, collection_id)? {
+            let counts = PlacementSpread::for_prefix(&self.conn, collection_id, affinity.prefix())?
+                .iter()
+                .map(|s| (*s.partition_id(), s.files()))
+                .collect();
+            prefixes.push((affinity.prefix().to_string(), counts));
+        }
+        Ok(AffinityTracker::new(prefixes))
+    }
+
+    fn choose_target<'a>(
+        placer: &mut Placer,
+        targets: &'a [StorageTarget],
+        dest_path: &Path,
+        size: u64,
+    ) -> anyhow::Result<(Partition, &'a StorageTarget)> {
+        if placer.is_empty() {
+            bail!(concat!(
+                "No currently mounted partitions were found in the DB. ",
+                "Try mounting one or adding one to the DB.",
+            ))
+        }
+        let dest_path = dest_path
+            .to_str()
+            .ok_or_else(|| anyhow!("Path was not UTF-8: {}", dest_path.to_string_lossy()))?;
+        match placer.choose_for_path(dest_path, size) {
+            Some(db_part) => Ok((db_part.clone(), Self::mounted_target(targets, db_part))),
+            None => bail!("No mounted partition has enough free space for {size} bytes"),
+        }
+    }
+
+    /// Move a file, or all files under a directory, to a new virtual path. The files are renamed
+    /// on the mounted partitions that have copies of them, and the renames for unmounted
+    /// partitions are queued until the partitions are seen again.
+    pub fn move_file(
+        &mut self,
+        collection_id: &Uuid,
+        src_path: &str,
+        dest_path: &str,
+    ) -> anyhow::Result<()> {
+        let src_path = Self::canonical_str(src_path)?;
+        let dest_path = Self::canonical_str(dest_path)?;
+        let moves = match File::get_by_collection_and_path(&self.conn, collection_id, &src_path)? {
+            Some(file) => vec![(file, dest_path)],
+            None => {
+                let src_prefix = dir_prefix(&src_path).map_err(|e| anyhow!("{}", e))?;
+                let dest_prefix = dir_prefix(&dest_path).map_err(|e| anyhow!("{}", e))?;
+                if dest_prefix.starts_with(&src_prefix) {
+                    bail!("Cannot move a directory into itself: {src_path}")
+                }
+                let files = File::with_prefix(&self.conn, collection_id, &src_prefix)?;
+                if files.is_empty() {
+                    bail!("No file or directory was found at {src_path}")
+                }
+                files
+                    .into_iter()
+                    .map(|f| {
+                        let new_path = format!("{dest_prefix}{}", &f.path()[src_prefix.len()..]);
+                        (f, new_path)
+                    })
+                    .collect()
+            }
+        };
+
+        for (file, new_path) in moves.iter() {
+            if file.path() == new_path {
+                bail!("Source and destination are the same: {new_path}")
+            }
+            self.add_file_check_dest_path(collection_id, Path::new(new_path))?;
+            if let Some(other) =
+                File::with_prefix(&self.conn, collection_id, &format!("{new_path}/"))?.first()
+            {
+                bail!(
+                    "Path {:?} would collide with the directory of the existing file {:?}",
+                    new_path,
+                    other.path()
+                )
+            }
+        }
+
+        let queued = auto_transaction(&mut self.conn, |tx| {
+            let mut queued = 0;
+            for (file, new_path) in moves.iter() {
+                File::set_path(tx, file.id(), new_path)?;
+                // deduplicated files have no copies of their own to rename
+                if file.content_file_id().is_some() {
+                    continue;
+                }
+                for placement in FilePlacement::get_by_file_id(tx, file.id())? {
+                    NewPendingMove {
+                        partition_id: placement.partition_id(),
+                        collection_id,
+                        old_path: file.path(),
+                        new_path,
+                    }
+                    .insert(tx)?;
+                    queued += 1;
+                }
+            }
+            Ok::<_, anyhow::Error>(queued)
+        })?;
+        log::info!("Moved {} files with {} copies", moves.len(), queued);
+
+        // the renames are applied right away on the mounted partitions
+        self.apply_pending()
+    }
+
+    fn canonical_str(path: &str) -> anyhow::Result<String> {
+        let path = canonical_path(path).map_err(|e| anyhow!("{}", e))?;
+        path.to_str()
+            .map(ToString::to_string)
+            .ok_or_else(|| anyhow!("Path was not UTF-8: {}", path.to_string_lossy()))
+    }
+
+    /// Remove a file, or all files under a directory when `recursive` is set, and delete its
+    /// copies from the mounted partitions. Copies on unmounted partitions are deleted when the
+    /// partitions are seen again.
+    pub fn remove_file(
+        &mut self,
+        collection_id: &Uuid,
+        path: &str,
+        recursive: bool,
+    ) -> anyhow::Result<()> {
+        let coll = Collection::for_id(&self.conn, collection_id)?
+            .ok_or_else(|| anyhow!("Collection not found"))?;
+        if coll.legal_hold() {
+            bail!(
+                "Collection {} is under legal hold. No files can be removed from it.",
+                coll.name()
+            )
+        }
+
+        let path = Self::canonical_str(path)?;
+        let files = match File::get_by_collection_and_path(&self.conn, collection_id, &path)? {
+            Some(file) => vec![file],
+            None if recursive => {
+                let prefix = dir_prefix(&path).map_err(|e| anyhow!("{}", e))?;
+                let files = File::with_prefix(&self.conn, collection_id, &prefix)?;
+                if files.is_empty() {
+                    bail!("No file or directory was found at {path}")
+                }
+                files
+            }
+            None => bail!("No file was found at {path}. Directories require --recursive."),
+        };
+
+        // shared content can only be deleted once nothing else refers to it
+        let removed = files.iter().map(|f| f.id()).collect::<HashSet<_>>();
+        for file in files.iter() {
+            for referrer in File::referrers(&self.conn, file.id())? {
+                if !removed.contains(referrer.id()) {
+                    bail!(
+                        "{} cannot be removed because {} shares its content",
+                        file.path(),
+                        referrer.path()
+                    )
+                }
+            }
+        }
+
+        let queued = auto_transaction(&mut self.conn, |tx| {
+            let mut queued = 0;
+            // deduplicated files first because they refer to the files with copies
+            for file in files.iter().filter(|f| f.content_file_id().is_some()) {
+                File::delete(tx, file.id())?;
+            }
+            for file in files.iter().filter(|f| f.content_file_id().is_none()) {
+                for placement in FilePlacement::get_by_file_id(tx, file.id())? {
+                    NewPendingDeletion {
+                        partition_id: placement.partition_id(),
+                        collection_id,
+                        path: file.path(),
+                    }
+                    .insert(tx)?;
+                    queued += 1;
+                }
+                File::delete(tx, file.id())?;
+            }
+            Ok::<_, anyhow::Error>(queued)
+        })?;
+        log::info!("Removed {} files with {} copies", files.len(), queued);
+
+        // the copies are deleted right away from the mounted partitions
+        self.apply_pending()
+    }
+
+    /// Apply the moves and deletions that were queued while the targets weren't mounted.
+    fn apply_pending_operations(&mut self, targets: &[StorageTarget]) -> anyhow::Result<()> {
+        let uuids = targets.iter().map(|p| p.uuid()).collect::<Vec<_>>();
+        for db_part in Partition::current(&self.conn, &uuids)? {
+            let target = Self::mounted_target(targets, &db_part);
+            let mut pending = PendingMove::for_partition(&self.conn, db_part.id())?
+                .into_iter()
+                .map(PendingOperation::Move)
+                .chain(
+                    PendingDeletion::for_partition(&self.conn, db_part.id())?
+                        .into_iter()
+                        .map(PendingOperation::Delete),
+                )
+                .collect::<Vec<_>>();
+            pending.sort_by_key(PendingOperation::seq);
+
+            for op in pending {
+                let res = match &op {
+                    PendingOperation::Move(pending) => Self::apply_pending_move(target, pending),
+                    PendingOperation::Delete(pending) => {
+                        Self::apply_pending_deletion(target, pending)
+                    }
+                };
+                if let Err(e) = res {
+                    // later operations may depend on this one, so stop here and try again next time
+                    log::warn!(
+                        "Unable to apply {} on partition {}: {e}",
+                        op,
+                        db_part.id().hyphenated()
+                    );
+                    break;
+                }
+                auto_transaction(&mut self.conn, |tx| match &op {
+                    PendingOperation::Move(pending) => PendingMove::delete(tx, pending.id()),
+                    PendingOperation::Delete(pending) => PendingDeletion::delete(tx, pending.id()),
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_pending_deletion(
+        target: &StorageTarget,
+        pending: &PendingDeletion,
+    ) -> anyhow::Result<()> {
+        let path = target.mount_point().join(Self::path_on_partition(
+            pending.collection_id(),
+            pending.path(),
+        )?);
+        if !path.exists() {
+            log::debug!("Already deleted: {}", path.to_string_lossy());
+            return Ok(());
+        }
+        log::info!("Deleting {}", path.to_string_lossy());
+        fs::remove_file(&path)?;
+        let root = target
+            .mount_point()
+            .join(Self::path_on_partition(pending.collection_id(), "/")?);
+        remove_empty_dirs(&root, &path)?;
+        Ok(())
+    }
+
+    fn apply_pending_move(target: &StorageTarget, pending: &PendingMove) -> anyhow::Result<()> {
+        let old_path = target.mount_point().join(Self::path_on_partition(
+            pending.collection_id(),
+            pending.old_path(),
+        )?);
+        let new_path = target.mount_point().join(Self::path_on_partition(
+            pending.collection_id(),
+            pending.new_path(),
+        )?);
+        if !old_path.exists() && new_path.exists() {
+            log::debug!("Already moved: {}", new_path.to_string_lossy());
+            return Ok(());
+        }
+        if new_path.exists() {
+            bail!("Destination already exists: {}", new_path.to_string_lossy())
+        }
+
+        let new_path = Self::add_file_prep_target(
+            target,
+            pending.collection_id(),
+            Path::new(pending.new_path()),
+        )?;
+        log::info!(
+            "Moving {} to {}",
+            old_path.to_string_lossy(),
+            new_path.to_string_lossy()
+        );
+        fs::rename(&old_path, &new_path)?;
+        let root = target
+            .mount_point()
+            .join(Self::path_on_partition(pending.collection_id(), "/")?);
+        remove_empty_dirs(&root, &old_path)?;
+        Ok(())
+    }
+
+    /// List the files at or directly in the given paths. Archives can be listed like directories
+    /// by putting `!/` after their path (e.g., `/leak.tar!/docs/`).
+    pub fn list_files<'a, I, II>(
         &self,
         collection_id: &Uuid,
-        min_depth: Option<u32>,
-        max_depth: Option<u32>,
-        name: Option<&Regex>,
-        path: Option<&Regex>,
         files: II,
-    ) -> anyhow::Result<Vec<File>>
+        all: bool,
+    ) -> anyhow::Result<Vec<ListedFile>>
     where
         I: Iterator<Item = &'a str>,
         II: IntoIterator<Item = &'a str, IntoIter = I>,
     {
-        match (min_depth, max_depth) {
-            (Some(min), Some(max)) if min > max => {
-                bail!("Min depth ({min}) cannot be greater than max depth ({max})")
-            }
-            _ => (),
-        }
-
         let mut output = Vec::new();
 
-        for file_name in files {
+        for file in files {
+            if let Some((archive_path, member_path)) = file.split_once("!/") {
+                let archive = match File::get_by_collection_and_path(
+                    &self.conn,
+                    collection_id,
+                    archive_path,
+                )? {
+                    Some(archive) => archive,
+                    None => continue,
+                };
+                let mut members = Vec::new();
+                if !member_path.is_empty() && !member_path.ends_with('/') {
+                    members.extend(FileArchive::get_by_file_and_path(
+                        &self.conn,
+                        archive.id(),
+                        member_path,
+                    )?);
+                }
+                if members.is_empty() {
+                    members = FileArchive::get_by_file_and_directory(
+                        &self.conn,
+                        archive.id(),
+                        all,
+                        member_path,
+                    )?;
+                }
+                output.extend(members.into_iter().map(|member| ListedFile::Member {
+                    archive_path: archive.path().to_string(),
+                    member,
+                }));
+                continue;
+            }
+
             // if the path is a file
-            if !file_name.ends_with('/') {
+            if !file.ends_with('/') {
                 if let Some(db_file) =
-                    File::get_by_collection_and_path(&self.conn, collection_id, file_name)?
+                    File::get_by_collection_and_path(&self.conn, collection_id, file)?
                 {
-                    output.push(db_file);
-                    continue;
+                    output.push(ListedFile::File(db_file));
+                    continue; // can't be both a file and a dir
                 }
             }
 
-            let matches = File::find_in_dir(
-                &self.conn,
+            // if the path is a dir
+            output.extend(
+                File::get_by_collection_and_directory(&self.conn, collection_id, all, file)?
+                    .into_iter()
+                    .map(ListedFile::File),
+            );
+        }
+
+        output.sort_by_key(ListedFile::path);
+        Ok(output)
+    }
+
+    pub fn find_files<'a, I, II>(
+        &self,
+        collection_id: &Uuid,
+        min_depth: Option<u32>,
+        max_depth: Option<u32>,
+        name: Option<&Regex>,
+        path: Option<&Regex>,
+        files: II,
+    ) -> anyhow::Result<Vec<File>>
+    where
+        I: Iterator<Item = &'a str>,
+        II: IntoIterator<Item = &'a str, IntoIter = I>,
+    {
+        match (min_depth, max_depth) {
+            (Some(min), Some(max)) if min > max => {
+                bail!("Min depth ({min}) cannot be greater than max depth ({max})")
+            }
+            _ => (),
+        }
+
+        let mut output = Vec::new();
+
+        for file_name in files {
+            // if the path is a file
+            if !file_name.ends_with('/') {
+                if let Some(db_file) =
+                    File::get_by_collection_and_path(&self.conn, collection_id, file_name)?
+                {
+                    output.push(db_file);
+                    continue;
+                }
+            }
+
+            let matches = File::find_in_dir(
+                &self.conn,
                 collection_id,
                 file_name,
                 min_depth,
@@ -409,200 +1834,2594 @@ impl Manager {
         Ok(output)
     }
 
-    #[cfg(feature = "cli")]
-    pub fn inspect_file(&self, collection_id: &Uuid, path: &str) -> anyhow::Result<String> {
-        let coll = Collection::for_id(&self.conn, collection_id)?
-            .ok_or_else(|| anyhow!("Collection not found"))?;
-        let file = File::get_by_collection_and_path(&self.conn, collection_id, path)?
-            .ok_or_else(|| anyhow!("File not found"))?;
+    /// Like [`Manager::find_files`] but for the members of the archives under the given paths.
+    pub fn find_archive_members<'a, I, II>(
+        &self,
+        collection_id: &Uuid,
+        min_depth: Option<u32>,
+        max_depth: Option<u32>,
+        name: Option<&Regex>,
+        path: Option<&Regex>,
+        files: II,
+    ) -> anyhow::Result<Vec<ArchiveMember>>
+    where
+        I: Iterator<Item = &'a str>,
+        II: IntoIterator<Item = &'a str, IntoIter = I>,
+    {
+        match (min_depth, max_depth) {
+            (Some(min), Some(max)) if min > max => {
+                bail!("Min depth ({min}) cannot be greater than max depth ({max})")
+            }
+            _ => (),
+        }
+
+        let mut disk_labels = HashMap::new();
+        let mut output = Vec::new();
+        for file_name in files {
+            let matches = FileArchive::find_in_dir(
+                &self.conn,
+                collection_id,
+                file_name,
+                min_depth,
+                max_depth,
+                name,
+                path,
+            )?;
+            for (archive, member) in matches {
+                if !disk_labels.contains_key(archive.id()) {
+                    let content_id = *archive.content_file_id().unwrap_or_else(|| archive.id());
+                    let labels = Disk::for_file_id(&self.conn, &content_id)?
+                        .iter()
+                        .map(|d| d.label().to_string())
+                        .collect::<Vec<_>>();
+                    disk_labels.insert(*archive.id(), labels.join(", "));
+                }
+                output.push(ArchiveMember {
+                    archive_path: archive.path().to_string(),
+                    member_path: member.path().to_string(),
+                    size: member.size(),
+                    modified: member
+                        .modified_date()
+                        .map(ToString::to_string)
+                        .unwrap_or_default(),
+                    disks: disk_labels[archive.id()].clone(),
+                });
+            }
+        }
+
+        output.sort_by(|a, b| {
+            (&a.archive_path, &a.member_path).cmp(&(&b.archive_path, &b.member_path))
+        });
+        output.dedup();
+        Ok(output)
+    }
+
+    /// Email messages under the given paths whose `From` and `Subject` headers match the
+    /// regexes, and the disks that have copies of them. Messages are files in Maildirs or ending
+    /// in `.eml`, or members of mbox files.
+    pub fn find_mail<'a, I, II>(
+        &self,
+        collection_id: &Uuid,
+        from: Option<&Regex>,
+        subject: Option<&Regex>,
+        files: II,
+    ) -> anyhow::Result<Vec<MailMatch>>
+    where
+        I: Iterator<Item = &'a str>,
+        II: IntoIterator<Item = &'a str, IntoIter = I>,
+    {
+        let mut disk_labels = HashMap::new();
+        let mut output = Vec::new();
+        for file_name in files {
+            for (path, message) in
+                MailMessage::find_in_dir(&self.conn, collection_id, file_name, from, subject)?
+            {
+                if !disk_labels.contains_key(message.file_id()) {
+                    let file = File::for_id(&self.conn, message.file_id())?
+                        .ok_or_else(|| anyhow!("File not found"))?;
+                    let content_id = *file.content_file_id().unwrap_or_else(|| file.id());
+                    let labels = Disk::for_file_id(&self.conn, &content_id)?
+                        .iter()
+                        .map(|d| d.label().to_string())
+                        .collect::<Vec<_>>();
+                    disk_labels.insert(*message.file_id(), labels.join(", "));
+                }
+                output.push(MailMatch {
+                    path,
+                    date: message
+                        .sent_date()
+                        .map(ToString::to_string)
+                        .unwrap_or_default(),
+                    from: message.mail_from().unwrap_or_default().to_string(),
+                    subject: message.subject().unwrap_or_default().to_string(),
+                    disks: disk_labels[message.file_id()].clone(),
+                });
+            }
+        }
+
+        output.sort_by(|a, b| (&a.date, &a.path).cmp(&(&b.date, &b.path)));
+        output.dedup();
+        Ok(output)
+    }
+
+    /// Files and archive members in any collection with the same content as a local file. The
+    /// local file is hashed with the configured algorithms, so files and members hashed with none
+    /// of them can't match.
+    pub fn match_file(&self, local_path: impl AsRef<Path>) -> anyhow::Result<Vec<ContentMatch>> {
+        let local_path = local_path.as_ref();
+        let size = fs::metadata(local_path)?.size();
+        let hashes = make_hashes(fs::File::open(local_path)?, self.config.files().hashes())?
+            .into_iter()
+            .map(|(a, v)| (*a, v))
+            .collect::<Vec<_>>();
+        if hashes.is_empty() {
+            bail!("No hash algorithms are configured");
+        }
+
+        let mut collection_names = HashMap::new();
+        let mut disk_labels = HashMap::new();
+        let lookup_only = self.config.files().lookup_only();
+        let files = File::with_content(&self.conn, size, &hashes, lookup_only)?
+            .into_iter()
+            .map(|f| (f.path().to_string(), f))
+            .chain(
+                FileArchive::with_content(&self.conn, size, &hashes, lookup_only)?
+                    .into_iter()
+                    .map(|(f, m)| (format!("{}!/{}", f.path(), m.path()), f)),
+            )
+            .collect::<Vec<_>>();
+        let mut output = Vec::new();
+        for (path, file) in files {
+            if !collection_names.contains_key(file.collection_id()) {
+                let coll = Collection::for_id(&self.conn, file.collection_id())?
+                    .ok_or_else(|| anyhow!("Collection not found"))?;
+                collection_names.insert(*file.collection_id(), coll.name().to_string());
+            }
+            if !disk_labels.contains_key(file.id()) {
+                let content_id = *file.content_file_id().unwrap_or_else(|| file.id());
+                let labels = Disk::for_file_id(&self.conn, &content_id)?
+                    .iter()
+                    .map(|d| d.label().to_string())
+                    .collect::<Vec<_>>();
+                disk_labels.insert(*file.id(), labels.join(", "));
+            }
+            output.push(ContentMatch {
+                collection_name: collection_names[file.collection_id()].clone(),
+                path,
+                size,
+                disks: disk_labels[file.id()].clone(),
+            });
+        }
+
+        output.sort_by(|a, b| (&a.collection_name, &a.path).cmp(&(&b.collection_name, &b.path)));
+        Ok(output)
+    }
+
+    #[cfg(feature = "cli")]
+    pub fn inspect_file(
+        &self,
+        collection_id: &Uuid,
+        path: &str,
+        members: bool,
+    ) -> anyhow::Result<String> {
+        let coll = Collection::for_id(&self.conn, collection_id)?
+            .ok_or_else(|| anyhow!("Collection not found"))?;
+        let file = File::get_by_collection_and_path(&self.conn, collection_id, path)?
+            .ok_or_else(|| anyhow!("File not found"))?;
+        let content_file = match file.content_file_id() {
+            Some(id) => Some(
+                File::for_id(&self.conn, id)?
+                    .ok_or_else(|| anyhow!("Content file not found: {}", id.hyphenated()))?,
+            ),
+            None => None,
+        };
+        let physical_file = content_file.as_ref().unwrap_or(&file);
+        let shared_with = File::referrers(&self.conn, file.id())?
+            .iter()
+            .map(|f| f.path().to_string())
+            .collect();
+
+        let file_placements = FilePlacement::get_by_file_id(&self.conn, physical_file.id())?;
+        let mut placements = Vec::new();
+        for fp in file_placements {
+            let disk = Disk::for_partition_id(&self.conn, fp.partition_id())?.unwrap();
+            placements.push(disk.label().to_string())
+        }
+
+        let mut checks = Vec::new();
+        for check in FilePlacementCheck::latest_by_file_id(&self.conn, physical_file.id())? {
+            let disk = Disk::for_partition_id(&self.conn, check.partition_id())?.unwrap();
+            checks.push(CheckDisplay {
+                disk: disk.label().to_string(),
+                date: check.checked_date().to_string(),
+                outcome: check.outcome().to_string(),
+            })
+        }
+
+        let file_hashes = FileHash::get_by_file_id(&self.conn, file.id())?;
+        let mut hashes = Vec::new();
+        for fh in file_hashes {
+            hashes.push(HashDisplay {
+                algorithm: fh.hash_algorithm().to_string(),
+                value: fh.hash_value_hex(),
+            });
+        }
+
+        let mut member_displays = Vec::new();
+        if members {
+            for m in FileArchive::get_by_file_id(&self.conn, file.id())? {
+                let mut hashes = FileArchiveHash::get_by_file_archive_id(&self.conn, m.id())?
+                    .iter()
+                    .map(|h| HashDisplay {
+                        algorithm: h.hash_algorithm().to_string(),
+                        value: hex::encode(h.hash_value()),
+                    })
+                    .collect::<Vec<_>>();
+                hashes.sort_by(|a, b| a.algorithm.cmp(&b.algorithm));
+                member_displays.push(MemberDisplay {
+                    path: m.path().to_string(),
+                    size: m.size(),
+                    entry_type: m.entry_type().map(|t| t.to_string()),
+                    link_target: m.link_target().map(ToString::to_string),
+                    modified: m.modified_date().map(ToString::to_string),
+                    compressed_size: m.compressed_size(),
+                    crc32: m.crc32().map(|crc| format!("{:08x}", crc)),
+                    encrypted: m.encrypted().unwrap_or(false),
+                    hashes,
+                });
+            }
+        }
+
+        let disp = FileDisplay {
+            path: file.path().to_string(),
+            size: file.size(),
+            file_id: *file.id(),
+            collection: CollectionDisplay {
+                id: *collection_id,
+                name: coll.name().to_string(),
+            },
+            container_type: file.container_type().map(|ct| ct.to_string()),
+            content_of: match content_file {
+                Some(f) if f.collection_id() == collection_id => Some(f.path().to_string()),
+                Some(f) => {
+                    let other = Collection::for_id(&self.conn, f.collection_id())?
+                        .ok_or_else(|| anyhow!("Collection not found"))?;
+                    Some(format!("{} (collection {})", f.path(), other.name()))
+                }
+                None => None,
+            },
+            shared_with,
+            placements,
+            checks,
+            hashes,
+            members: member_displays,
+        };
+
+        Ok(serde_yaml::to_string(&disp)?)
+    }
+
+    /// Copy a file, or all files under a directory when `recursive` is set, out of the pool to a
+    /// local path. Each copy is checked against the stored hashes while it is copied, and other
+    /// mounted copies are tried if it doesn't match. Files without trusted hashes can't be
+    /// checked and are only copied if `allow_unverified` is set.
+    pub fn get_file(
+        &mut self,
+        collection_id: &Uuid,
+        path: &str,
+        local_dest: impl AsRef<Path>,
+        recursive: bool,
+        allow_unverified: bool,
+    ) -> anyhow::Result<()> {
+        let local_dest = local_dest.as_ref();
+        let path = Self::canonical_str(path)?;
+        let files = match File::get_by_collection_and_path(&self.conn, collection_id, &path)? {
+            Some(file) => {
+                // like `cp`, copy into the destination if it's a directory
+                let dest = match Path::new(file.path()).file_name() {
+                    Some(name) if local_dest.is_dir() => local_dest.join(name),
+                    _ => local_dest.to_owned(),
+                };
+                vec![(file, dest)]
+            }
+            None if recursive => {
+                let prefix = dir_prefix(&path).map_err(|e| anyhow!("{}", e))?;
+                let files = File::with_prefix(&self.conn, collection_id, &prefix)?;
+                if files.is_empty() {
+                    bail!("No file or directory was found at {path}")
+                }
+                files
+                    .into_iter()
+                    .map(|f| {
+                        let dest = local_dest.join(&f.path()[prefix.len()..]);
+                        (f, dest)
+                    })
+                    .collect()
+            }
+            None => bail!("No file was found at {path}. Directories require --recursive."),
+        };
+        for (_, dest) in files.iter() {
+            if dest.exists() {
+                bail!("Local path already exists: {}", dest.to_string_lossy())
+            }
+        }
+
+        let targets = self.mounted_targets()?;
+        self.warn_pending_operations(&targets)?;
+        let mut missing = 0;
+        let mut needed_disks = BTreeSet::new();
+        for (file, dest) in files {
+            let path = file.path().to_string();
+            let file = file.content_file(&self.conn)?;
+            let tried = self.get_file_copy(&targets, &file, &dest, allow_unverified)?;
+            if dest.exists() {
+                continue;
+            }
+
+            missing += 1;
+            log::warn!("No good mounted copy of {} was found", path);
+            needed_disks.extend(self.untried_disks(file.id(), &tried)?);
+        }
+
+        if missing > 0 {
+            if needed_disks.is_empty() {
+                bail!("{missing} files could not be copied and no other copies are known")
+            }
+            bail!(
+                "{} files could not be copied. Mount one of these disks and try again: {}",
+                missing,
+                needed_disks.into_iter().collect::<Vec<_>>().join(", ")
+            )
+        }
+        Ok(())
+    }
+
+    /// Extract members of an archive to `local_dest` without copying the whole archive. The
+    /// archive is read from a mounted copy, and each member is written to its path in the archive
+    /// under `local_dest`, with nested archives as directories. The extracted members are checked
+    /// against the stored sizes and hashes, and other copies are tried if they don't match.
+    /// Members without trusted hashes are only extracted if `allow_unverified` is set.
+    pub fn extract_members<'a>(
+        &mut self,
+        collection_id: &Uuid,
+        archive_path: &str,
+        members: impl IntoIterator<Item = &'a str>,
+        local_dest: impl AsRef<Path>,
+        allow_unverified: bool,
+    ) -> anyhow::Result<()> {
+        let local_dest = local_dest.as_ref();
+        let archive_path = Self::canonical_str(archive_path)?;
+        let archive = File::get_by_collection_and_path(&self.conn, collection_id, &archive_path)?
+            .ok_or_else(|| anyhow!("No file was found at {archive_path}"))?;
+        let container_type = archive
+            .container_type()
+            .ok_or_else(|| anyhow!("{archive_path} is not an archive"))?;
+
+        let mut dests = HashMap::new();
+        let mut expected = HashMap::new();
+        for member_path in members {
+            let member = FileArchive::get_by_file_and_path(&self.conn, archive.id(), member_path)?
+                .ok_or_else(|| anyhow!("{archive_path} has no member {member_path}"))?;
+            let dest = local_dest.join(strip_root(member_path.replace("!/", "/")));
+            if dest == local_dest {
+                bail!("Member path has no file name: {member_path}")
+            }
+            if dest.exists() {
+                bail!("Local path already exists: {}", dest.to_string_lossy())
+            }
+            let hashes = FileArchiveHash::get_by_file_archive_id(&self.conn, member.id())?
+                .into_iter()
+                .filter(|h| {
+                    !self
+                        .config
+                        .files()
+                        .lookup_only()
+                        .contains(&h.hash_algorithm())
+                })
+                .collect::<Vec<_>>();
+            if hashes.is_empty() {
+                if !allow_unverified {
+                    bail!(
+                        "No trusted hashes are stored for {}, so it can't be verified. Use --allow-unverified to extract it anyway.",
+                        member_path
+                    )
+                }
+                log::warn!(
+                    "No trusted hashes are stored for {}. Unable to verify its contents.",
+                    member_path
+                );
+            }
+            dests.insert(member_path.to_string(), dest);
+            expected.insert(member_path.to_string(), (member.size(), hashes));
+        }
+        let algorithms = expected
+            .values()
+            .flat_map(|(_, hashes)| hashes.iter().map(|h| h.hash_algorithm()))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        let file_name = archive_path
+            .rsplit('/')
+            .next()
+            .unwrap_or(&archive_path)
+            .to_string();
+        let archive = archive.content_file(&self.conn)?;
+        let targets = self.mounted_targets()?;
+        self.warn_pending_operations(&targets)?;
+        let placed = FilePlacement::get_by_file_id(&self.conn, archive.id())?
+            .iter()
+            .map(|p| *p.partition_id())
+            .collect::<HashSet<_>>();
+        let uuids = targets.iter().map(|p| p.uuid()).collect::<Vec<_>>();
+        let limits = self.config.archives().nesting_limits();
+
+        let mut tried = HashSet::new();
+        for db_part in Partition::current(&self.conn, &uuids)? {
+            if dests.is_empty() {
+                break;
+            }
+            if !placed.contains(db_part.id()) {
+                continue;
+            }
+            tried.insert(*db_part.id());
+            let target = Self::mounted_target(&targets, &db_part);
+            let src_path = target.mount_point().join(Self::path_on_partition(
+                archive.collection_id(),
+                archive.path(),
+            )?);
+
+            let res = fs::File::open(&src_path)
+                .map_err(Into::into)
+                .and_then(|src| {
+                    archive_utils::extract_files(
+                        container_type,
+                        &file_name,
+                        io::BufReader::new(src),
+                        &limits,
+                        &algorithms,
+                        &dests,
+                    )
+                });
+            let extracted = match res {
+                Ok(extracted) => extracted,
+                Err(e) => {
+                    log::warn!(
+                        "Unable to extract from {}: {e}. Trying another copy.",
+                        src_path.to_string_lossy()
+                    );
+                    Vec::new()
+                }
+            };
+            for entry in extracted {
+                let (size, hashes) = &expected[&entry.path];
+                let matches = entry.size == *size
+                    && hashes.iter().all(|h| {
+                        entry
+                            .hashes
+                            .iter()
+                            .any(|(a, v)| *a == h.hash_algorithm() && v == h.hash_value())
+                    });
+                if matches {
+                    dests.remove(&entry.path);
+                } else {
+                    log::warn!(
+                        "{} in {} did not match the stored size and hashes. Trying another copy.",
+                        entry.path,
+                        src_path.to_string_lossy()
+                    );
+                }
+            }
+            // anything left was not in this copy or did not match
+            for dest in dests.values() {
+                if dest.exists() {
+                    fs::remove_file(dest)?;
+                }
+            }
+        }
+
+        if !dests.is_empty() {
+            let mut missing = dests.into_keys().collect::<Vec<_>>();
+            missing.sort();
+            let needed_disks = self.untried_disks(archive.id(), &tried)?;
+            if needed_disks.is_empty() {
+                bail!(
+                    "These members could not be extracted and no other copies of {} are known: {}",
+                    archive_path,
+                    missing.join(", ")
+                )
+            }
+            bail!(
+                "These members could not be extracted: {}. Mount one of these disks and try again: {}",
+                missing.join(", "),
+                needed_disks.into_iter().collect::<Vec<_>>().join(", ")
+            )
+        }
+        Ok(())
+    }
+
+    /// The disks with copies of the file that are not on one of the `tried` partitions, with
+    /// their locations
+    fn untried_disks(
+        &self,
+        file_id: &Uuid,
+        tried: &HashSet<Uuid>,
+    ) -> anyhow::Result<BTreeSet<String>> {
+        let mut disks = BTreeSet::new();
+        for placement in FilePlacement::get_by_file_id(&self.conn, file_id)? {
+            if tried.contains(placement.partition_id()) {
+                continue;
+            }
+            let disk = Disk::for_partition_id(&self.conn, placement.partition_id())?
+                .ok_or_else(|| anyhow!("Disk for placement not found"))?;
+            let location = Location::for_id(&self.conn, disk.location_id())?
+                .ok_or_else(|| anyhow!("Location for disk not found"))?;
+            disks.insert(format!("{} (at {})", disk.label(), location.name()));
+        }
+        Ok(disks)
+    }
+
+    /// Try to copy a file from each mounted partition that has it until a copy matches the stored
+    /// hashes. Returns the IDs of the partitions that were tried.
+    fn get_file_copy(
+        &self,
+        targets: &[StorageTarget],
+        file: &File,
+        dest: &Path,
+        allow_unverified: bool,
+    ) -> anyhow::Result<HashSet<Uuid>> {
+        let expected = FileHash::get_by_file_id(&self.conn, file.id())?;
+        let lookup_only = self.config.files().lookup_only();
+        if !allow_unverified
+            && expected
+                .iter()
+                .all(|h| lookup_only.contains(&h.hash_algorithm()))
+        {
+            bail!(
+                "No trusted hashes are stored for {}, so its copies can't be verified. Use --allow-unverified to copy it anyway.",
+                file.path()
+            )
+        }
+        let placed = FilePlacement::get_by_file_id(&self.conn, file.id())?
+            .iter()
+            .map(|p| *p.partition_id())
+            .collect::<HashSet<_>>();
+        let uuids = targets.iter().map(|p| p.uuid()).collect::<Vec<_>>();
+
+        let mut tried = HashSet::new();
+        for db_part in Partition::current(&self.conn, &uuids)? {
+            if !placed.contains(db_part.id()) {
+                continue;
+            }
+            tried.insert(*db_part.id());
+            let target = Self::mounted_target(targets, &db_part);
+            let src_path = target
+                .mount_point()
+                .join(Self::path_on_partition(file.collection_id(), file.path())?);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            log::info!(
+                "Copying {} to {}",
+                src_path.to_string_lossy(),
+                dest.to_string_lossy()
+            );
+            let res = fs::File::open(&src_path).and_then(|src| {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(dest)
+                    .map(|out| (src, out))
+            });
+            let res = match res {
+                Ok((src, out)) => Self::read_check_hashes(
+                    TeeReader::new(src, out),
+                    &src_path,
+                    &expected,
+                    lookup_only,
+                ),
+                Err(e) => Err(e.into()),
+            };
+            match res {
+                Ok(CheckOutcome::Ok | CheckOutcome::Unverifiable) => return Ok(tried),
+                Ok(_) => log::warn!(
+                    "Hashes of {} did not match the stored hashes. Trying another copy.",
+                    src_path.to_string_lossy()
+                ),
+                Err(e) => log::warn!(
+                    "Unable to copy {}: {e}. Trying another copy.",
+                    src_path.to_string_lossy()
+                ),
+            }
+            if dest.exists() {
+                fs::remove_file(dest)?;
+            }
+        }
+        Ok(tried)
+    }
+
+    /// The disks to fetch to retrieve the files, grouped by location.
+    pub fn retrieval_plan(&self, files: Vec<File>) -> anyhow::Result<RetrievalPlan> {
+        let mut location_names = HashMap::new();
+        let mut wanted = Vec::new();
+        for file in files {
+            let path = file.path().to_string();
+            let file = file.content_file(&self.conn)?;
+            let mut disks = Vec::new();
+            for disk in Disk::for_file_id(&self.conn, file.id())? {
+                if !location_names.contains_key(disk.location_id()) {
+                    let location = Location::for_id(&self.conn, disk.location_id())?
+                        .ok_or_else(|| anyhow!("Location for disk not found"))?;
+                    location_names.insert(*disk.location_id(), location.name().to_string());
+                }
+                disks.push(DiskRef {
+                    id: *disk.id(),
+                    label: disk.label().to_string(),
+                    location_name: location_names[disk.location_id()].clone(),
+                });
+            }
+            wanted.push(WantedFile {
+                path,
+                size: file.size(),
+                disks,
+            });
+        }
+
+        let plan = RetrievalPlan::new(&wanted);
+        log::info!(
+            "{} files can be retrieved from {} disks",
+            wanted.len() - plan.unavailable().len(),
+            plan.disks().len()
+        );
+        Ok(plan)
+    }
+
+    pub fn file_mounted_path(
+        &mut self,
+        collection_id: &Uuid,
+        path: &str,
+    ) -> anyhow::Result<String> {
+        let current_targets = self.mounted_targets()?;
+        self.warn_pending_operations(&current_targets)?;
+        let uuids = Rc::new(
+            current_targets
+                .iter()
+                .map(|p| Value::Text(p.uuid().to_string()))
+                .collect::<Vec<_>>(),
+        );
+
+        let file = File::get_by_collection_and_path(&self.conn, collection_id, path)?
+            .ok_or_else(|| anyhow!("Path not found"))?
+            .content_file(&self.conn)?;
+
+        let sql = concat!(
+            "SELECT p.uuid FROM partitions AS p ",
+            "INNER JOIN file_placements AS fp ON fp.partition_id = p.id ",
+            "INNER JOIN files AS f ON f.id = fp.file_id ",
+            "WHERE p.uuid IN rarray(:uuids) AND f.id = :file_id",
+        );
+        log::trace!("SQL:\n{}", sql);
+
+        let params = named_params! {
+            ":uuids": uuids,
+            ":file_id": file.id(),
+        };
+        let mut stmt = self.conn.prepare(sql)?;
+        let db_part_uuids = stmt.query_and_then(params, |row| row.get::<_, String>(0))?;
+        for db_part_uuid in db_part_uuids {
+            let db_part_uuid = db_part_uuid?;
+            for target in &current_targets {
+                if target.uuid() == db_part_uuid {
+                    let target_path = Self::path_on_partition(file.collection_id(), file.path())?;
+                    let full_path = target.mount_point().join(&target_path);
+                    let full_path = full_path
+                        .to_str()
+                        .ok_or_else(|| anyhow!("Path was not utf-8"))?;
+                    return Ok(full_path.to_owned());
+                }
+            }
+        }
+        bail!("Could not find a mounted partition for that path.")
+    }
+
+    /// Files in all collections that have the same content as another file, using the first
+    /// configured hash algorithm that isn't lookup-only to compare them.
+    pub fn dedup_report(&self) -> anyhow::Result<Vec<DuplicateFile>> {
+        let hash_algorithm = self
+            .config
+            .files()
+            .hashes()
+            .iter()
+            .find(|a| !self.config.files().lookup_only().contains(a))
+            .ok_or_else(|| anyhow!("No hash algorithms that aren't lookup-only are configured"))?;
+        let duplicates = DuplicateFile::all(&self.conn, hash_algorithm)?;
+
+        // every group of duplicates needs one copy, and deduplicated files don't take up space
+        let mut seen = HashSet::new();
+        let mut reclaimable = 0;
+        for dup in duplicates.iter().filter(|d| !d.deduplicated()) {
+            if !seen.insert(dup.hash_value()) {
+                reclaimable += dup.size();
+            }
+        }
+        log::info!(
+            "Found {} files with duplicate content. {} bytes could be reclaimed by deduplication.",
+            duplicates.len(),
+            reclaimable
+        );
+        Ok(duplicates)
+    }
+
+    pub fn sync_db(&mut self, collection_id: &Uuid) -> anyhow::Result<()> {
+        // apply queued moves so that files are found at their current paths
+        self.apply_pending()?;
+        sync_db(self.config.files(), &mut self.conn, collection_id)
+    }
+
+    /// Copy files that have fewer placements than their collection's `min_copies` from one
+    /// mounted partition to other mounted partitions. The content that deduplicated files share
+    /// is given as many copies as the strictest of the collections sharing it asks for. New
+    /// copies are only placed on disks that don't already hold a copy of the file.
+    pub fn replicate(
+        &mut self,
+        collection_id: &Uuid,
+        strategy: PlacementStrategy,
+        verify: bool,
+    ) -> anyhow::Result<()> {
+        let coll = Collection::for_id(&self.conn, collection_id)?
+            .ok_or_else(|| anyhow!("Collection not found"))?;
+        let under_replicated = File::under_replicated(&self.conn, collection_id)?;
+        if under_replicated.is_empty() {
+            log::info!(
+                "All files have at least {} copies. Nothing to replicate.",
+                coll.min_copies()
+            );
+            return Ok(());
+        }
+        log::info!("Found {} under-replicated files", under_replicated.len());
+
+        let mounted_targets = self.mounted_targets()?;
+        self.prepare_targets(&mounted_targets)?;
+        let uuids = mounted_targets.iter().map(|p| p.uuid()).collect::<Vec<_>>();
+        let db_partitions = Partition::current(&self.conn, &uuids)?;
+        let mut placer = Placer::new(strategy, db_partitions.clone())
+            .with_affinity(self.affinity_tracker(collection_id)?);
+
+        let mut skipped_files = 0_u32;
+        for (file, copies, min_copies) in under_replicated {
+            let mut placed_partitions = HashSet::new();
+            let mut placed_disks = HashSet::new();
+            for placement in FilePlacement::get_by_file_id(&self.conn, file.id())? {
+                let db_part = Partition::for_id(&self.conn, placement.partition_id())?
+                    .ok_or_else(|| anyhow!("Partition for placement not found"))?;
+                placed_partitions.insert(*db_part.id());
+                placed_disks.insert(*db_part.disk_id());
+            }
+
+            let src_path = db_partitions
+                .iter()
+                .find(|p| placed_partitions.contains(p.id()))
+                .map(|db_part| -> anyhow::Result<PathBuf> {
+                    let target = Self::mounted_target(&mounted_targets, db_part);
+                    Ok(target
+                        .mount_point()
+                        .join(Self::path_on_partition(file.collection_id(), file.path())?))
+                })
+                .transpose()?;
+            let src_path = match src_path {
+                Some(path) => path,
+                None => {
+                    log::warn!("No copy of {} is currently mounted", file.path());
+                    skipped_files += 1;
+                    continue;
+                }
+            };
+
+            let hashes = FileHash::get_by_file_id(&self.conn, file.id())?;
+            for _ in copies..min_copies {
+                let db_part = match placer.choose_for_path_if(file.path(), file.size(), |p| {
+                    !placed_disks.contains(p.disk_id())
+                }) {
+                    Some(db_part) => db_part.clone(),
+                    None => {
+                        log::warn!(
+                            "No mounted partition with enough free space on a disk without a copy of {} was found",
+                            file.path()
+                        );
+                        skipped_files += 1;
+                        break;
+                    }
+                };
+                let target = Self::mounted_target(&mounted_targets, &db_part);
+                self.replicate_file(&file, &hashes, &src_path, &db_part, target, verify)?;
+                placed_disks.insert(*db_part.disk_id());
+            }
+        }
+
+        if skipped_files == 0 {
+            log::info!("Replication completed");
+            Ok(())
+        } else {
+            bail!(
+                concat!(
+                    "While replicating, {} files could not be given enough copies. ",
+                    "Try mounting more partitions.",
+                ),
+                skipped_files,
+            )
+        }
+    }
+
+    fn mounted_target<'a>(
+        mounted_targets: &'a [StorageTarget],
+        db_part: &Partition,
+    ) -> &'a StorageTarget {
+        // unwrap ok because the DB partitions were looked up from the mounted ones
+        mounted_targets
+            .iter()
+            .find(|p| p.uuid() == db_part.uuid())
+            .unwrap()
+    }
+
+    /// Copy a file to another partition the same way files are added: the copy is written to a
+    /// temporary file in the journal of pending placements, checked, and renamed once its
+    /// placement is in the DB. Anything written is removed if that fails.
+    fn replicate_file(
+        &mut self,
+        file: &File,
+        hashes: &[FileHash],
+        src_path: &Path,
+        db_part: &Partition,
+        target: &StorageTarget,
+        verify: bool,
+    ) -> anyhow::Result<()> {
+        let src_path = src_path
+            .to_str()
+            .ok_or_else(|| anyhow!("Path was not UTF-8: {}", src_path.to_string_lossy()))?;
+        let verify = verify || self.config.files().verify_copies();
+        let (placement, out) = self.journal_placement(
+            file.collection_id(),
+            db_part.clone(),
+            target,
+            src_path,
+            Path::new(file.path()),
+            false,
+            verify,
+        )?;
+        log::info!(
+            "Copying {} to {}",
+            src_path,
+            placement.path.to_string_lossy()
+        );
+        // copies of files without trusted hashes are made anyway, like they were added
+        let expected = hashes
+            .iter()
+            .map(|h| (h.hash_algorithm(), h.hash_value().to_vec()))
+            .collect::<Hashes>();
+        let res = out
+            .ok_or_else(|| anyhow!("No temporary file was created for {src_path}"))
+            .and_then(|out| Self::add_file_copy(src_path, out, &expected))
+            .and_then(|_| {
+                Self::verify_copy(
+                    &placement.temp_path,
+                    file.size(),
+                    &expected,
+                    self.config.files().lookup_only(),
+                    placement.verify,
+                )
+                .map_err(|e| anyhow!("Copy of {src_path} is bad: {e}"))
+            })
+            .and_then(|_| {
+                auto_transaction(&mut self.conn, |tx| {
+                    NewFilePlacement {
+                        partition_id: db_part.id(),
+                        file_id: file.id(),
+                    }
+                    .insert(tx)?;
+                    Partition::record_placement(tx, db_part.id(), file.size())?;
+                    PendingPlacement::set_file_id(tx, &placement.pending_id, file.id())
+                })
+            });
+        if let Err(e) = res {
+            self.add_file_unplace(&placement, src_path);
+            return Err(e);
+        }
+        self.finish_placement(&placement, true, src_path)
+    }
+
+    /// Re-read every placement on the currently mounted partitions in the scope, compare it to the
+    /// size and hashes in the DB, and record the outcome. Returns the results of the placements
+    /// that did not pass the check.
+    pub fn verify(&mut self, scope: VerifyScope<'_>) -> anyhow::Result<Vec<VerifyResult>> {
+        let mounted_targets = self.mounted_targets()?;
+        self.warn_pending_operations(&mounted_targets)?;
+        let uuids = mounted_targets.iter().map(|p| p.uuid()).collect::<Vec<_>>();
+        let db_partitions = Partition::current(&self.conn, &uuids)?
+            .into_iter()
+            .filter(|p| match scope {
+                VerifyScope::Collection(_) => true,
+                VerifyScope::Disk(id) => p.disk_id() == id,
+                VerifyScope::Partition(id) => p.id() == id,
+            })
+            .collect::<Vec<_>>();
+        if db_partitions.is_empty() {
+            bail!("No partitions matching the scope are currently mounted.")
+        }
+
+        let collection_id = match scope {
+            VerifyScope::Collection(id) => Some(id),
+            _ => None,
+        };
+        let partition_ids = db_partitions.iter().map(|p| p.id()).collect::<Vec<_>>();
+        let placements = File::placed_on_partitions(&self.conn, &partition_ids, collection_id)?;
+        log::info!("Verifying {} file placements", placements.len());
+
+        let mut checked = 0_u64;
+        let mut failures = Vec::new();
+        for (file, partition_id) in placements {
+            // unwrap ok because the placements were looked up from these partitions
+            let db_part = db_partitions
+                .iter()
+                .find(|p| p.id() == &partition_id)
+                .unwrap();
+            let target = Self::mounted_target(&mounted_targets, db_part);
+            let full_path = target
+                .mount_point()
+                .join(Self::path_on_partition(file.collection_id(), file.path())?);
+
+            log::debug!("Verifying {}", full_path.to_string_lossy());
+            let outcome = Self::check_placement(
+                &self.conn,
+                &file,
+                &full_path,
+                self.config.files().lookup_only(),
+            )?;
+            auto_transaction(&mut self.conn, |tx| {
+                NewFilePlacementCheck {
+                    partition_id: &partition_id,
+                    file_id: file.id(),
+                    outcome,
+                }
+                .insert(tx)
+            })?;
+            checked += 1;
+
+            if outcome != CheckOutcome::Ok {
+                let disk = Disk::for_partition_id(&self.conn, &partition_id)?.unwrap();
+                log::warn!(
+                    "Verification of {} on disk {} failed: {}",
+                    file.path(),
+                    disk.label(),
+                    outcome
+                );
+                failures.push(VerifyResult {
+                    path: file.path().to_string(),
+                    disk_label: disk.label().to_string(),
+                    outcome,
+                });
+            }
+        }
+
+        log::info!(
+            "Verified {} file placements. {} failed.",
+            checked,
+            failures.len()
+        );
+        Ok(failures)
+    }
+
+    fn check_placement(
+        conn: &Connection,
+        file: &File,
+        full_path: &Path,
+        lookup_only: &[HashAlgorithm],
+    ) -> anyhow::Result<CheckOutcome> {
+        let file_meta = match fs::metadata(full_path) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(CheckOutcome::Missing),
+            Err(e) => return Err(e.into()),
+        };
+        if file_meta.size() != file.size() {
+            return Ok(CheckOutcome::SizeDiffers);
+        }
+
+        let hashes = FileHash::get_by_file_id(conn, file.id())?;
+        Self::check_hashes(full_path, &hashes, lookup_only)
+    }
+
+    /// Re-hash the file at `path` and compare the results to the hashes stored in the DB.
+    fn check_hashes(
+        path: &Path,
+        expected: &[FileHash],
+        lookup_only: &[HashAlgorithm],
+    ) -> anyhow::Result<CheckOutcome> {
+        Self::read_check_hashes(fs::File::open(path)?, path, expected, lookup_only)
+    }
+
+    /// Lookup-only hashes are ignored, so files with only those are
+    /// [unverifiable](CheckOutcome::Unverifiable). The input is read to the end either way.
+    fn read_check_hashes(
+        mut input: impl Read,
+        path: &Path,
+        expected: &[FileHash],
+        lookup_only: &[HashAlgorithm],
+    ) -> anyhow::Result<CheckOutcome> {
+        let expected = expected
+            .iter()
+            .filter(|h| !lookup_only.contains(&h.hash_algorithm()))
+            .collect::<Vec<_>>();
+        if expected.is_empty() {
+            io::copy(&mut input, &mut io::sink())?;
+            log::warn!(
+                "No trusted hashes are stored for {}. Unable to verify its contents.",
+                path.to_string_lossy()
+            );
+            return Ok(CheckOutcome::Unverifiable);
+        }
+        let algorithms = expected
+            .iter()
+            .map(|h| h.hash_algorithm())
+            .collect::<Vec<_>>();
+        let found = make_hashes(input, &algorithms)?;
+        let matches = expected
+            .iter()
+            .all(|h| found.get(&h.hash_algorithm()).map(|v| v.as_slice()) == Some(h.hash_value()));
+        if matches {
+            Ok(CheckOutcome::Ok)
+        } else {
+            Ok(CheckOutcome::Mismatch)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct FileDisplay {
+    path: String,
+    size: u64,
+    file_id: Uuid,
+    collection: CollectionDisplay,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    container_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_of: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    shared_with: Vec<String>,
+    placements: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    checks: Vec<CheckDisplay>,
+    hashes: Vec<HashDisplay>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    members: Vec<MemberDisplay>,
+}
+
+#[derive(Serialize)]
+struct MemberDisplay {
+    path: String,
+    size: u64,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    entry_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link_target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compressed_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crc32: Option<String>,
+    #[serde(skip_serializing_if = "is_false")]
+    encrypted: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    hashes: Vec<HashDisplay>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+#[derive(Serialize)]
+struct CheckDisplay {
+    disk: String,
+    date: String,
+    outcome: String,
+}
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "cli", derive(Table))]
+pub struct VerifyResult {
+    #[cfg_attr(feature = "cli", table(title = "Path"))]
+    path: String,
+    #[cfg_attr(feature = "cli", table(title = "Disk"))]
+    disk_label: String,
+    #[cfg_attr(feature = "cli", table(title = "Outcome"))]
+    outcome: CheckOutcome,
+}
+
+impl VerifyResult {
+    pub fn outcome(&self) -> CheckOutcome {
+        self.outcome
+    }
+}
+
+/// A file or archive member listed by [`Manager::list_files`].
+#[derive(Debug, PartialEq)]
+pub enum ListedFile {
+    File(File),
+    Member {
+        archive_path: String,
+        member: FileArchive,
+    },
+}
+
+impl ListedFile {
+    /// The virtual path, which for members is the archive's path and the member's path joined
+    /// by `!/`
+    pub fn path(&self) -> String {
+        match self {
+            Self::File(file) => file.path().to_string(),
+            Self::Member {
+                archive_path,
+                member,
+            } => format!("{}!/{}", archive_path, member.path()),
+        }
+    }
+}
+
+/// A member of an archive found by [`Manager::find_archive_members`].
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "cli", derive(Table))]
+pub struct ArchiveMember {
+    #[cfg_attr(feature = "cli", table(title = "Archive"))]
+    archive_path: String,
+    #[cfg_attr(feature = "cli", table(title = "Member"))]
+    member_path: String,
+    #[cfg_attr(feature = "cli", table(title = "Size (bytes)"))]
+    size: u64,
+    #[cfg_attr(feature = "cli", table(title = "Modified"))]
+    modified: String,
+    #[cfg_attr(feature = "cli", table(title = "Disks"))]
+    disks: String,
+}
+
+impl ArchiveMember {
+    pub fn archive_path(&self) -> &str {
+        &self.archive_path
+    }
+
+    pub fn member_path(&self) -> &str {
+        &self.member_path
+    }
+}
+
+/// An email message found by [`Manager::find_mail`].
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "cli", derive(Table))]
+pub struct MailMatch {
+    #[cfg_attr(feature = "cli", table(title = "Path"))]
+    path: String,
+    #[cfg_attr(feature = "cli", table(title = "Date"))]
+    date: String,
+    #[cfg_attr(feature = "cli", table(title = "From"))]
+    from: String,
+    #[cfg_attr(feature = "cli", table(title = "Subject"))]
+    subject: String,
+    #[cfg_attr(feature = "cli", table(title = "Disks"))]
+    disks: String,
+}
+
+impl MailMatch {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+/// A file or archive member found by [`Manager::match_file`].
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "cli", derive(Table))]
+pub struct ContentMatch {
+    #[cfg_attr(feature = "cli", table(title = "Collection"))]
+    collection_name: String,
+    #[cfg_attr(feature = "cli", table(title = "Path"))]
+    path: String,
+    #[cfg_attr(feature = "cli", table(title = "Size (bytes)"))]
+    size: u64,
+    #[cfg_attr(feature = "cli", table(title = "Disks"))]
+    disks: String,
+}
+
+impl ContentMatch {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+#[derive(Serialize)]
+struct CollectionDisplay {
+    name: String,
+    id: Uuid,
+}
+
+#[derive(Serialize)]
+struct HashDisplay {
+    algorithm: String,
+    value: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::auto_transaction;
+    use crate::db::types::{
+        CheckOutcome, Collection, Disk, File, FileHash, NewPendingPlacement, PendingPlacement,
+    };
+    use crate::fs_utils::{GlobFilter, SymlinkMode};
+    use crate::hash_utils::{make_hashes, HashAlgorithm};
+    use crate::manager::{AddDirOptions, AddOptions, Manager, VerifyScope};
+    use crate::placement::PlacementStrategy;
+    use crate::test_utils::fixtures;
+    use regex::Regex;
+    use rusqlite::Connection;
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use tempfile::tempdir;
+    use uuid::Uuid;
+
+    #[test_log::test]
+    fn db_migrate() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut manager = Manager::new(fixtures::config(), conn);
+        manager.db_migrate().unwrap();
+    }
+
+    #[test_log::test]
+    fn db_vacuum() {
+        let manager = fixtures::manager();
+        manager.db_vacuum().unwrap();
+    }
+
+    #[test_log::test]
+    fn init() {
+        let td = tempdir().unwrap();
+        let inner_path = td.path().join("hoard"); // to force dir creation in `init`
+        let config_path = inner_path.join("config.yaml");
+        Manager::init(&config_path).unwrap();
+        assert!(config_path.exists());
+    }
+
+    #[test_log::test]
+    fn add_location() {
+        let mut manager = fixtures::manager();
+        manager.add_location("foo").unwrap();
+    }
+
+    #[test_log::test]
+    fn add_collection() {
+        let mut manager = fixtures::manager();
+        manager.add_collection("foo", 1).unwrap();
+    }
+
+    #[test_log::test]
+    fn set_collection_min_copies() {
+        let mut manager = fixtures::manager();
+        let coll = fixtures::collection(&mut manager.conn);
+        manager.set_collection_min_copies(coll.id(), 2).unwrap();
+    }
+
+    #[test_log::test]
+    fn list_collections() {
+        let mut manager = fixtures::manager();
+        let _ = fixtures::collection(&mut manager.conn);
+        let colls = manager.list_collections().unwrap();
+        assert_ne!(colls, vec![]);
+    }
+
+    #[test_log::test]
+    fn list_disks() {
+        let mut manager = fixtures::manager();
+        let loc = fixtures::location(&mut manager.conn);
+        let _ = fixtures::disk(&mut manager.conn, &loc);
+        let disks = manager.list_disks().unwrap();
+        assert_ne!(disks, vec![]);
+    }
+
+    #[test_log::test]
+    fn list_partitions() {
+        let mut manager = fixtures::manager();
+        let loc = fixtures::location(&mut manager.conn);
+        let disk = fixtures::disk(&mut manager.conn, &loc);
+        let _ = fixtures::partition(&mut manager.conn, &disk);
+        let parts = manager.list_partitions().unwrap();
+        assert_ne!(parts, vec![]);
+    }
+
+    #[test_log::test]
+    fn list_files() {
+        let mut manager = fixtures::manager();
+        let coll = fixtures::collection(&mut manager.conn);
+        let file = fixtures::file(&mut manager.conn, &coll);
+        let files = manager
+            .list_files(coll.id(), vec![file.path()], true)
+            .unwrap();
+        assert_ne!(files, vec![]);
+    }
+
+    #[test_log::test]
+    fn inspect_file() {
+        let mut manager = fixtures::manager();
+        let loc = fixtures::location(&mut manager.conn);
+        let disk = fixtures::disk(&mut manager.conn, &loc);
+        let partition = fixtures::partition(&mut manager.conn, &disk);
+        let coll = fixtures::collection(&mut manager.conn);
+        let (file, _, _) = fixtures::file_full(&mut manager.conn, &partition, &coll);
+        manager.inspect_file(coll.id(), file.path(), true).unwrap();
+    }
+
+    #[test_log::test]
+    fn check_placement() {
+        let mut manager = fixtures::manager();
+        let coll = fixtures::collection(&mut manager.conn);
+        let file = fixtures::file(&mut manager.conn, &coll);
+        let td = tempdir().unwrap();
+        let path = td.path().join("foo.txt");
+
+        let outcome = Manager::check_placement(&manager.conn, &file, &path, &[]).unwrap();
+        assert_eq!(outcome, CheckOutcome::Missing);
+
+        fs::write(&path, b"wat").unwrap();
+        let outcome = Manager::check_placement(&manager.conn, &file, &path, &[]).unwrap();
+        assert_eq!(outcome, CheckOutcome::SizeDiffers);
+    }
+
+    #[test_log::test]
+    fn sync_and_check_new_hash_algorithms() {
+        let mut manager = fixtures::manager();
+        manager.config = serde_yaml::from_str("files:\n  hashes: [sha2-256]").unwrap();
+        let (coll, _target_dir) = fixtures::directory_target(&mut manager);
+        let src_dir = tempdir().unwrap();
+        let src_path = src_dir.path().join("digits.txt");
+        fs::write(&src_path, b"123456789").unwrap();
+        manager
+            .add_file(
+                coll.id(),
+                None,
+                src_path.to_str().unwrap(),
+                "/digits.txt",
+                &AddOptions::default(),
+            )
+            .unwrap();
+
+        manager.config =
+            serde_yaml::from_str("files:\n  hashes: [sha2-256, blake3, md5, crc32c]").unwrap();
+        manager.sync_db(coll.id()).unwrap();
+        let file = File::get_by_collection_and_path(&manager.conn, coll.id(), "/digits.txt")
+            .unwrap()
+            .unwrap();
+        let hashes = FileHash::get_by_file_id(&manager.conn, file.id())
+            .unwrap()
+            .iter()
+            .map(|h| (h.hash_algorithm(), hex::encode(h.hash_value())))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(hashes.len(), 4);
+        assert_eq!(
+            hashes[&HashAlgorithm::Md5],
+            "25f9e794323b453885f5181f1b624d0b"
+        );
+        assert_eq!(hashes[&HashAlgorithm::Crc32c], "e3069283");
+
+        // wrong lookup-only hashes don't make a copy bad, but wrong trusted hashes do
+        let path = manager.file_mounted_path(coll.id(), "/digits.txt").unwrap();
+        let corrupt = |conn: &Connection, algorithm: &str| {
+            conn.execute(
+                "UPDATE file_hashes SET hash_value = x'00' WHERE hash_algorithm = ?",
+                [algorithm],
+            )
+            .unwrap();
+        };
+        corrupt(&manager.conn, "md5");
+        corrupt(&manager.conn, "crc32c");
+        let outcome = Manager::check_placement(
+            &manager.conn,
+            &file,
+            Path::new(&path),
+            manager.config.files().lookup_only(),
+        )
+        .unwrap();
+        assert_eq!(outcome, CheckOutcome::Ok);
+        corrupt(&manager.conn, "blake3");
+        let outcome = Manager::check_placement(
+            &manager.conn,
+            &file,
+            Path::new(&path),
+            manager.config.files().lookup_only(),
+        )
+        .unwrap();
+        assert_eq!(outcome, CheckOutcome::Mismatch);
+        // unless blake3 is configured as lookup-only too
+        manager.config = serde_yaml::from_str(
+            "files:\n  hashes: [sha2-256, blake3]\n  lookup_only: [md5, crc32c, blake3]",
+        )
+        .unwrap();
+        let outcome = Manager::check_placement(
+            &manager.conn,
+            &file,
+            Path::new(&path),
+            manager.config.files().lookup_only(),
+        )
+        .unwrap();
+        assert_eq!(outcome, CheckOutcome::Ok);
+
+        // with only lookup-only hashes left a copy can't be verified, and isn't served by default
+        manager
+            .conn
+            .execute(
+                "DELETE FROM file_hashes WHERE hash_algorithm IN ('sha2-256', 'blake3')",
+                [],
+            )
+            .unwrap();
+        let outcome = Manager::check_placement(
+            &manager.conn,
+            &file,
+            Path::new(&path),
+            manager.config.files().lookup_only(),
+        )
+        .unwrap();
+        assert_eq!(outcome, CheckOutcome::Unverifiable);
+        let dest = src_dir.path().join("got.txt");
+        assert!(manager
+            .get_file(coll.id(), "/digits.txt", &dest, false, false)
+            .is_err());
+        assert!(!dest.exists());
+        manager
+            .get_file(coll.id(), "/digits.txt", &dest, false, true)
+            .unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"123456789");
+    }
+
+    #[test_log::test]
+    fn sync_hashes_on_several_disks() {
+        let mut manager = fixtures::manager();
+        manager.config = serde_yaml::from_str("files:\n  hashes: [sha2-256]").unwrap();
+        let loc = fixtures::location(&mut manager.conn);
+        let disk = fixtures::disk(&mut manager.conn, &loc);
+        manager
+            .add_disk_with_serial_number(loc.id(), "other-serial", "other-disk")
+            .unwrap();
+        let other_disk = Disk::for_label(&manager.conn, "other-disk")
+            .unwrap()
+            .unwrap();
+        let coll = fixtures::collection(&mut manager.conn);
+        let target_dirs = [tempdir().unwrap(), tempdir().unwrap()];
+        manager
+            .add_directory_partition(disk.id(), target_dirs[0].path())
+            .unwrap();
+        manager
+            .add_directory_partition(other_disk.id(), target_dirs[1].path())
+            .unwrap();
+
+        let src_dir = tempdir().unwrap();
+        let options = AddOptions {
+            strategy: PlacementStrategy::RoundRobin,
+            ..Default::default()
+        };
+        let contents = (0..5).map(|i| format!("file {i}")).collect::<Vec<_>>();
+        for (i, content) in contents.iter().enumerate() {
+            let src_path = src_dir.path().join(format!("{i}.txt"));
+            fs::write(&src_path, content).unwrap();
+            manager
+                .add_file(
+                    coll.id(),
+                    None,
+                    src_path.to_str().unwrap(),
+                    format!("/{i}.txt"),
+                    &options,
+                )
+                .unwrap();
+        }
+
+        manager.config = serde_yaml::from_str("files:\n  hashes: [sha2-256, blake3]").unwrap();
+        manager.sync_db(coll.id()).unwrap();
+        for (i, content) in contents.iter().enumerate() {
+            let file =
+                File::get_by_collection_and_path(&manager.conn, coll.id(), &format!("/{i}.txt"))
+                    .unwrap()
+                    .unwrap();
+            let blake3 = FileHash::get_by_file_id(&manager.conn, file.id())
+                .unwrap()
+                .into_iter()
+                .find(|h| h.hash_algorithm() == HashAlgorithm::Blake3)
+                .unwrap();
+            assert_eq!(
+                blake3.hash_value(),
+                blake3::hash(content.as_bytes()).as_bytes()
+            );
+        }
+    }
+
+    #[test_log::test]
+    fn add_file_to_directory_partition() {
+        let mut manager = fixtures::manager();
+        let (coll, target_dir) = fixtures::directory_target(&mut manager);
+
+        let src_dir = tempdir().unwrap();
+        let src_path = src_dir.path().join("wat.txt");
+        fs::write(&src_path, b"wat").unwrap();
+        manager
+            .add_file(
+                coll.id(),
+                None,
+                src_path.to_str().unwrap(),
+                "/some-dir/wat.txt",
+                &AddOptions::default(),
+            )
+            .unwrap();
+
+        let mounted_path = manager
+            .file_mounted_path(coll.id(), "/some-dir/wat.txt")
+            .unwrap();
+        assert!(
+            mounted_path.starts_with(target_dir.path().canonicalize().unwrap().to_str().unwrap())
+        );
+        assert_eq!(fs::read(mounted_path).unwrap(), b"wat");
+    }
+
+    #[test_log::test]
+    fn add_file_single_pass() {
+        let mut manager = fixtures::manager();
+        let (coll, target_dir) = fixtures::directory_target(&mut manager);
+
+        // a tarball is listed while it's copied, and a zip is listed after
+        let src_dir = tempdir().unwrap();
+        let tar_path = src_dir.path().join("docs.tar");
+        fs::write(&tar_path, fixtures::tar(&[("a.txt", b"aaa")])).unwrap();
+        let zip_path = src_dir.path().join("docs.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&zip_path).unwrap());
+        zip.start_file("b.txt", Default::default()).unwrap();
+        zip.write_all(b"bbb").unwrap();
+        zip.finish().unwrap();
+        drop(zip);
+        for (src, dest) in [(&tar_path, "/docs.tar"), (&zip_path, "/docs.zip")] {
+            manager
+                .add_file(
+                    coll.id(),
+                    None,
+                    src.to_str().unwrap(),
+                    dest,
+                    &AddOptions::default(),
+                )
+                .unwrap();
+            let mounted_path = manager.file_mounted_path(coll.id(), dest).unwrap();
+            assert_eq!(fs::read(mounted_path).unwrap(), fs::read(src).unwrap());
+        }
+        let listed = manager
+            .list_files(coll.id(), ["/docs.tar!/", "/docs.zip!/"], false)
+            .unwrap()
+            .iter()
+            .map(|f| f.path())
+            .collect::<Vec<_>>();
+        assert_eq!(listed, vec!["/docs.tar!/a.txt", "/docs.zip!/b.txt"]);
+        let local_path = src_dir.path().join("b.txt");
+        fs::write(&local_path, "bbb").unwrap();
+        assert_eq!(manager.match_file(&local_path).unwrap().len(), 1);
+
+        // archives that can't be listed are added without their members
+        let broken: [(&str, &[u8]); 2] = [
+            ("broken.gz", b"\x1f\x8b\x08\x00 not really gzip"),
+            ("broken.7z", b"7z\xbc\xaf\x27\x1c not really 7z"),
+        ];
+        for (name, contents) in broken {
+            let src = src_dir.path().join(name);
+            fs::write(&src, contents).unwrap();
+            let dest = format!("/{}", name);
+            manager
+                .add_file(
+                    coll.id(),
+                    None,
+                    src.to_str().unwrap(),
+                    &dest,
+                    &AddOptions::default(),
+                )
+                .unwrap();
+            let inspected = manager.inspect_file(coll.id(), &dest, true).unwrap();
+            assert!(!inspected.contains("container_type"), "{}", inspected);
+            assert!(manager
+                .list_files(coll.id(), [format!("{}!/", dest).as_str()], false)
+                .unwrap()
+                .is_empty());
+        }
+        let on_disk = walkdir::WalkDir::new(target_dir.path().join("hoard"))
+            .into_iter()
+            .map(|e| e.unwrap())
+            .filter(|e| e.file_type().is_file())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .collect::<HashSet<_>>();
+        assert_eq!(
+            on_disk,
+            HashSet::from([
+                "docs.tar".to_string(),
+                "docs.zip".to_string(),
+                "broken.gz".to_string(),
+                "broken.7z".to_string()
+            ])
+        );
+        assert!(PendingPlacement::all(&manager.conn).unwrap().is_empty());
+    }
+
+    #[test_log::test]
+    fn add_file_verify() {
+        let mut manager = fixtures::manager();
+        let (coll, _target_dir) = fixtures::directory_target(&mut manager);
+        let options = AddOptions {
+            verify: true,
+            ..Default::default()
+        };
+
+        let src_dir = tempdir().unwrap();
+        let src_path = src_dir.path().join("a.txt");
+        fs::write(&src_path, "aaa").unwrap();
+        manager
+            .add_file(
+                coll.id(),
+                None,
+                src_path.to_str().unwrap(),
+                "/a.txt",
+                &options,
+            )
+            .unwrap();
+        let mounted_path = manager.file_mounted_path(coll.id(), "/a.txt").unwrap();
+        assert_eq!(fs::read_to_string(&mounted_path).unwrap(), "aaa");
+
+        // copies that don't match the source are caught
+        let hashes = vec![(
+            HashAlgorithm::Sha256,
+            make_hashes(&b"aaa"[..], [&HashAlgorithm::Sha256]).unwrap()[&HashAlgorithm::Sha256]
+                .clone(),
+        )];
+        let lookup_only = manager.config.files().lookup_only();
+        let verify = |size, lookup_only| {
+            Manager::verify_copy(Path::new(&mounted_path), size, &hashes, lookup_only, true)
+        };
+        assert!(verify(3, lookup_only).is_ok());
+        fs::write(&mounted_path, "aab").unwrap();
+        assert!(verify(3, lookup_only).is_err());
+        assert!(verify(4, lookup_only).is_err());
+        // hashes of algorithms configured as lookup-only aren't trusted to catch bad copies
+        assert!(verify(3, &[HashAlgorithm::Sha256]).is_ok());
+        assert!(verify(4, &[HashAlgorithm::Sha256]).is_err());
+    }
+
+    #[test_log::test]
+    fn recover_interrupted_adds() {
+        let mut manager = fixtures::manager();
+        let (coll, target_dir) = fixtures::directory_target(&mut manager);
+        let db_part = manager.list_partitions().unwrap().remove(0);
+        let src_dir = tempdir().unwrap();
+        let pending =
+            |manager: &mut Manager, path: &str, src_path: Option<&str>, file_id: Option<&Uuid>| {
+                auto_transaction(&mut manager.conn, |tx| {
+                    let id = NewPendingPlacement {
+                        partition_id: db_part.id(),
+                        collection_id: coll.id(),
+                        path,
+                        src_path,
+                    }
+                    .insert(tx)?;
+                    if let Some(file_id) = file_id {
+                        PendingPlacement::set_file_id(tx, &id, file_id)?;
+                    }
+                    Ok::<_, anyhow::Error>(id)
+                })
+                .unwrap()
+            };
+        let on_partition = |path: &str| {
+            target_dir
+                .path()
+                .join(Manager::path_on_partition(coll.id(), path).unwrap())
+        };
+
+        // a copy that was interrupted before its file was in the DB is removed
+        let copied = on_partition("/copied.txt");
+        fs::create_dir_all(copied.parent().unwrap()).unwrap();
+        let copied_id = pending(&mut manager, "/copied.txt", None, None);
+        fs::write(Manager::temp_path(&copied, &copied_id), "partial").unwrap();
+
+        // a copy of a file that is in the DB is renamed to its final path
+        let stored_src = src_dir.path().join("stored.txt");
+        fs::write(&stored_src, "stored").unwrap();
+        manager
+            .add_file(
+                coll.id(),
+                None,
+                stored_src.to_str().unwrap(),
+                "/stored.txt",
+                &AddOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(PendingPlacement::all(&manager.conn).unwrap().len(), 1);
+        let stored_file = File::get_by_collection_and_path(&manager.conn, coll.id(), "/stored.txt")
+            .unwrap()
+            .unwrap();
+        let stored = on_partition("/stored.txt");
+        let stored_id = pending(&mut manager, "/stored.txt", None, Some(stored_file.id()));
+        fs::rename(&stored, Manager::temp_path(&stored, &stored_id)).unwrap();
+
+        // a file that was moved before its transaction failed is moved back
+        let moved_src = src_dir.path().join("moved.txt");
+        let moved = on_partition("/moved.txt");
+        fs::write(&moved, "moved").unwrap();
+        pending(&mut manager, "/moved.txt", moved_src.to_str(), None);
+
+        manager.recover().unwrap();
+        assert!(PendingPlacement::all(&manager.conn).unwrap().is_empty());
+        assert_eq!(fs::read_to_string(&stored).unwrap(), "stored");
+        assert_eq!(fs::read_to_string(&moved_src).unwrap(), "moved");
+        let on_disk = fs::read_dir(stored.parent().unwrap())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(on_disk, vec!["stored.txt"]);
+    }
+
+    #[test_log::test]
+    fn recover_file_moved_after_crash() {
+        let mut manager = fixtures::manager();
+        let (coll, target_dir) = fixtures::directory_target(&mut manager);
+        let db_part = manager.list_partitions().unwrap().remove(0);
+        let on_partition = |path: &str| {
+            target_dir
+                .path()
+                .join(Manager::path_on_partition(coll.id(), path).unwrap())
+        };
+        let src_dir = tempdir().unwrap();
+        let src_path = src_dir.path().join("a.txt");
+        fs::write(&src_path, "aaa").unwrap();
+        manager
+            .add_file(
+                coll.id(),
+                None,
+                src_path.to_str().unwrap(),
+                "/a.txt",
+                &AddOptions::default(),
+            )
+            .unwrap();
+        let file = File::get_by_collection_and_path(&manager.conn, coll.id(), "/a.txt")
+            .unwrap()
+            .unwrap();
+
+        // the add stopped after its transaction, and the file was moved before the recovery
+        manager.move_file(coll.id(), "/a.txt", "/b/a.txt").unwrap();
+        let pending_id = auto_transaction(&mut manager.conn, |tx| {
+            let id = NewPendingPlacement {
+                partition_id: db_part.id(),
+                collection_id: coll.id(),
+                path: "/a.txt",
+                src_path: None,
+            }
+            .insert(tx)?;
+            PendingPlacement::set_file_id(tx, &id, file.id())?;
+            Ok::<_, anyhow::Error>(id)
+        })
+        .unwrap();
+        let temp_path = Manager::temp_path(&on_partition("/a.txt"), &pending_id);
+        fs::rename(on_partition("/b/a.txt"), &temp_path).unwrap();
+
+        manager.recover().unwrap();
+        assert!(PendingPlacement::all(&manager.conn).unwrap().is_empty());
+        assert!(!temp_path.exists());
+        assert_eq!(fs::read_to_string(on_partition("/b/a.txt")).unwrap(), "aaa");
+
+        // a copy that doesn't match its file isn't renamed
+        let file = File::get_by_collection_and_path(&manager.conn, coll.id(), "/b/a.txt")
+            .unwrap()
+            .unwrap();
+        let pending = |manager: &mut Manager, path: &str, file_id: Option<&Uuid>| {
+            auto_transaction(&mut manager.conn, |tx| {
+                let id = NewPendingPlacement {
+                    partition_id: db_part.id(),
+                    collection_id: coll.id(),
+                    path,
+                    src_path: None,
+                }
+                .insert(tx)?;
+                if let Some(file_id) = file_id {
+                    PendingPlacement::set_file_id(tx, &id, file_id)?;
+                }
+                Ok::<_, anyhow::Error>(id)
+            })
+            .unwrap()
+        };
+        let pending_id = pending(&mut manager, "/b/a.txt", Some(file.id()));
+        let bad_temp_path = Manager::temp_path(&on_partition("/b/a.txt"), &pending_id);
+        fs::write(&bad_temp_path, "aab").unwrap();
+        manager.recover().unwrap();
+        assert!(bad_temp_path.exists());
+        assert_eq!(fs::read_to_string(on_partition("/b/a.txt")).unwrap(), "aaa");
+        assert_eq!(PendingPlacement::all(&manager.conn).unwrap().len(), 1);
+        fs::remove_file(&bad_temp_path).unwrap();
+
+        // an interrupted copy of a file with other copies is removed
+        let pending_id = pending(&mut manager, "/c.txt", None);
+        let temp_path = Manager::temp_path(&on_partition("/c.txt"), &pending_id);
+        fs::write(&temp_path, "aaa").unwrap();
+        manager.recover().unwrap();
+        assert!(!temp_path.exists());
+        assert!(PendingPlacement::all(&manager.conn).unwrap().is_empty());
+
+        // but it's kept if it may be the only copy, e.g., after sync dropped the missing placement
+        manager
+            .conn
+            .execute("DELETE FROM file_placements WHERE file_id = ?", [file.id()])
+            .unwrap();
+        let pending_id = pending(&mut manager, "/c.txt", None);
+        let temp_path = Manager::temp_path(&on_partition("/c.txt"), &pending_id);
+        fs::write(&temp_path, "aaa").unwrap();
+        manager.recover().unwrap();
+        assert!(temp_path.exists());
+        assert_eq!(PendingPlacement::all(&manager.conn).unwrap().len(), 1);
+    }
+
+    #[test_log::test]
+    fn add_dir_filters_and_resumes() {
+        let mut manager = fixtures::manager();
+        let (coll, _target_dir) = fixtures::directory_target(&mut manager);
+
+        let src_dir = tempdir().unwrap();
+        fs::create_dir_all(src_dir.path().join("a/b")).unwrap();
+        fs::create_dir_all(src_dir.path().join("skip-me")).unwrap();
+        fs::write(src_dir.path().join("top.txt"), b"top").unwrap();
+        fs::write(src_dir.path().join("a/b/deep.txt"), b"deep").unwrap();
+        fs::write(src_dir.path().join("a/ignored.tmp"), b"tmp").unwrap();
+        fs::write(src_dir.path().join("skip-me/x.txt"), b"x").unwrap();
+        std::os::unix::fs::symlink("top.txt", src_dir.path().join("link.txt")).unwrap();
+
+        let options = AddDirOptions {
+            include: GlobFilter::new(["*.txt"]).unwrap(),
+            exclude: GlobFilter::new(["skip-me"]).unwrap(),
+            symlinks: SymlinkMode::Skip,
+        };
+        manager
+            .add_dir(
+                coll.id(),
+                None,
+                src_dir.path(),
+                "/dest",
+                &AddOptions::default(),
+                &options,
+            )
+            .unwrap();
+
+        let paths = manager
+            .find_files(coll.id(), None, None, None, None, ["/dest/"])
+            .unwrap()
+            .iter()
+            .map(|f| f.path().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/dest/a/b/deep.txt", "/dest/top.txt"]);
+        let mounted_path = manager
+            .file_mounted_path(coll.id(), "/dest/a/b/deep.txt")
+            .unwrap();
+        assert_eq!(fs::read(mounted_path).unwrap(), b"deep");
+
+        // a second run skips the files that were already added
+        fs::write(src_dir.path().join("new.txt"), b"new").unwrap();
+        manager
+            .add_dir(
+                coll.id(),
+                None,
+                src_dir.path(),
+                "/dest",
+                &AddOptions::default(),
+                &options,
+            )
+            .unwrap();
+        let files = manager
+            .find_files(coll.id(), None, None, None, None, ["/dest/"])
+            .unwrap();
+        assert_eq!(files.len(), 3);
+
+        // unless the file changed, even if its size is the same
+        fs::write(src_dir.path().join("top.txt"), b"pot").unwrap();
+        assert!(manager
+            .add_dir(
+                coll.id(),
+                None,
+                src_dir.path(),
+                "/dest",
+                &AddOptions::default(),
+                &options
+            )
+            .is_err());
+        fs::write(src_dir.path().join("top.txt"), b"top, but longer").unwrap();
+        assert!(manager
+            .add_dir(
+                coll.id(),
+                None,
+                src_dir.path(),
+                "/dest",
+                &AddOptions::default(),
+                &options
+            )
+            .is_err());
+
+        let options = AddDirOptions {
+            symlinks: SymlinkMode::Error,
+            ..options
+        };
+        assert!(manager
+            .add_dir(
+                coll.id(),
+                None,
+                src_dir.path(),
+                "/other",
+                &AddOptions::default(),
+                &options
+            )
+            .is_err());
+    }
 
-        let file_placements = FilePlacement::get_by_file_id(&self.conn, file.id())?;
-        let mut placements = Vec::new();
-        for fp in file_placements {
-            let disk = Disk::for_partition_id(&self.conn, fp.partition_id())?.unwrap();
-            placements.push(disk.label().to_string())
+    #[test_log::test]
+    fn add_dir_keeps_affinity_together() {
+        let mut manager = fixtures::manager();
+        let loc = fixtures::location(&mut manager.conn);
+        let disk = fixtures::disk(&mut manager.conn, &loc);
+        let coll = fixtures::collection(&mut manager.conn);
+        let target_dirs = [tempdir().unwrap(), tempdir().unwrap()];
+        for target_dir in target_dirs.iter() {
+            manager
+                .add_directory_partition(disk.id(), target_dir.path())
+                .unwrap();
         }
 
-        let file_hashes = FileHash::get_by_file_id(&self.conn, file.id())?;
-        let mut hashes = Vec::new();
-        for fh in file_hashes {
-            hashes.push(HashDisplay {
-                algorithm: fh.hash_algorithm().to_string(),
-                value: fh.hash_value_hex(),
-            });
+        let src_dir = tempdir().unwrap();
+        for name in ["a", "b", "c", "d"] {
+            fs::write(src_dir.path().join(name), name).unwrap();
         }
-
-        let disp = FileDisplay {
-            path: file.path().to_string(),
-            size: file.size(),
-            file_id: *file.id(),
-            collection: CollectionDisplay {
-                id: *collection_id,
-                name: coll.name().to_string(),
-            },
-            placements,
-            hashes,
+        let options = AddDirOptions::default();
+        let add_options = AddOptions {
+            strategy: PlacementStrategy::RoundRobin,
+            ..Default::default()
         };
 
-        Ok(serde_yaml::to_string(&disp)?)
-    }
+        manager
+            .add_placement_affinity(coll.id(), "/together")
+            .unwrap();
+        manager
+            .add_dir(
+                coll.id(),
+                None,
+                src_dir.path(),
+                "/together",
+                &add_options,
+                &options,
+            )
+            .unwrap();
+        let spread = manager.placement_plan(coll.id(), "/together").unwrap();
+        assert_eq!(spread.len(), 1);
+        assert_eq!(spread[0].files(), 4);
 
-    pub fn file_mounted_path(&self, collection_id: &Uuid, path: &str) -> anyhow::Result<String> {
-        let current_partitions = dev_utils::get_all_partitions()?;
-        let uuids = Rc::new(
-            current_partitions
-                .iter()
-                .map(|p| Value::Text(p.uuid().to_string()))
-                .collect::<Vec<_>>(),
+        manager
+            .add_dir(
+                coll.id(),
+                None,
+                src_dir.path(),
+                "/apart",
+                &add_options,
+                &options,
+            )
+            .unwrap();
+        assert_eq!(
+            manager.placement_plan(coll.id(), "/apart").unwrap().len(),
+            2
         );
+    }
 
-        let file = File::get_by_collection_and_path(&self.conn, collection_id, path)?
-            .ok_or_else(|| anyhow!("Path not found"))?;
-
-        let sql = concat!(
-            "SELECT p.uuid FROM partitions AS p ",
-            "INNER JOIN file_placements AS fp ON fp.partition_id = p.id ",
-            "INNER JOIN files AS f ON f.id = fp.file_id ",
-            "WHERE p.uuid IN rarray(:uuids) AND f.id = :file_id",
-        );
-        log::trace!("SQL:\n{}", sql);
+    #[test_log::test]
+    fn add_file_dedup() {
+        let mut manager = fixtures::manager();
+        let (coll, _target_dir) = fixtures::directory_target(&mut manager);
 
-        let params = named_params! {
-            ":uuids": uuids,
-            ":file_id": file.id(),
+        let src_dir = tempdir().unwrap();
+        let src_path = src_dir.path().join("wat.txt");
+        fs::write(&src_path, b"wat").unwrap();
+        let src_path = src_path.to_str().unwrap();
+        let options = AddOptions {
+            dedup: true,
+            ..Default::default()
         };
-        let mut stmt = self.conn.prepare(sql)?;
-        let db_part_uuids = stmt.query_and_then(params, |row| row.get::<_, String>(0))?;
-        for db_part_uuid in db_part_uuids {
-            let db_part_uuid = db_part_uuid?;
-            for dev_part in &current_partitions {
-                if dev_part.uuid() == db_part_uuid {
-                    let target_path = Self::path_on_partition(collection_id, path)?;
-                    let full_path = dev_part.mount_point().join(&target_path);
-                    let full_path = full_path
-                        .to_str()
-                        .ok_or_else(|| anyhow!("Path was not utf-8"))?;
-                    return Ok(full_path.to_owned());
-                }
-            }
-        }
-        bail!("Could not find a mounted partition for that path.")
-    }
+        manager
+            .add_file(coll.id(), None, src_path, "/original.txt", &options)
+            .unwrap();
+        manager
+            .add_file(coll.id(), None, src_path, "/renamed.txt", &options)
+            .unwrap();
 
-    pub fn sync_db(&mut self, collection_id: &Uuid) -> anyhow::Result<()> {
-        sync_db(self.config.files(), &mut self.conn, collection_id)
-    }
-}
+        let original = manager
+            .file_mounted_path(coll.id(), "/original.txt")
+            .unwrap();
+        let renamed = manager
+            .file_mounted_path(coll.id(), "/renamed.txt")
+            .unwrap();
+        assert_eq!(original, renamed);
+        let report = manager.dedup_report().unwrap();
+        assert_eq!(report.len(), 2);
 
-#[derive(Serialize)]
-struct FileDisplay {
-    path: String,
-    size: u64,
-    file_id: Uuid,
-    collection: CollectionDisplay,
-    placements: Vec<String>,
-    hashes: Vec<HashDisplay>,
-}
+        // without dedup the content is copied again
+        manager
+            .add_file(
+                coll.id(),
+                None,
+                src_path,
+                "/copied.txt",
+                &AddOptions::default(),
+            )
+            .unwrap();
+        let copied = manager.file_mounted_path(coll.id(), "/copied.txt").unwrap();
+        assert_ne!(original, copied);
+        assert_eq!(fs::read(copied).unwrap(), b"wat");
+    }
 
-#[derive(Serialize)]
-struct CollectionDisplay {
-    name: String,
-    id: Uuid,
-}
+    #[test_log::test]
+    fn move_file_queues_unmounted() {
+        let mut manager = fixtures::manager();
+        let (coll, target_dir) = fixtures::directory_target(&mut manager);
 
-#[derive(Serialize)]
-struct HashDisplay {
-    algorithm: String,
-    value: String,
-}
+        let src_dir = tempdir().unwrap();
+        fs::create_dir_all(src_dir.path().join("a")).unwrap();
+        fs::write(src_dir.path().join("a/one.txt"), b"one").unwrap();
+        fs::write(src_dir.path().join("a/two.txt"), b"two").unwrap();
+        let options = AddDirOptions::default();
+        manager
+            .add_dir(
+                coll.id(),
+                None,
+                src_dir.path(),
+                "/dir",
+                &AddOptions::default(),
+                &options,
+            )
+            .unwrap();
 
-#[cfg(test)]
-mod tests {
-    use crate::manager::Manager;
-    use crate::test_utils::fixtures;
-    use rusqlite::Connection;
-    use tempfile::tempdir;
+        // mounted partitions are renamed right away
+        manager
+            .move_file(coll.id(), "/dir/a/one.txt", "/renamed.txt")
+            .unwrap();
+        let path = manager
+            .file_mounted_path(coll.id(), "/renamed.txt")
+            .unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"one");
+        assert!(manager
+            .move_file(coll.id(), "/dir/a/two.txt", "/renamed.txt")
+            .is_err());
+        assert!(manager.move_file(coll.id(), "/dir", "/dir/nested").is_err());
 
-    #[test_log::test]
-    fn db_migrate() {
-        let conn = Connection::open_in_memory().unwrap();
-        let mut manager = Manager::new(fixtures::config(), conn);
-        manager.db_migrate().unwrap();
+        // unmounted partitions are renamed when the queue is applied after they're seen again
+        let marker = target_dir.path().join(".hoard-target");
+        let marker_contents = fs::read(&marker).unwrap();
+        fs::remove_file(&marker).unwrap();
+        manager.move_file(coll.id(), "/dir", "/other").unwrap();
+        assert!(manager
+            .file_mounted_path(coll.id(), "/other/a/two.txt")
+            .is_err());
+        fs::write(&marker, marker_contents).unwrap();
+        // looking a file up doesn't change anything on the disk
+        let path = manager
+            .file_mounted_path(coll.id(), "/other/a/two.txt")
+            .unwrap();
+        assert!(!Path::new(&path).exists());
+        manager.apply_pending().unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"two");
     }
 
     #[test_log::test]
-    fn db_vacuum() {
-        let manager = fixtures::manager();
-        manager.db_vacuum().unwrap();
+    fn remove_file() {
+        let mut manager = fixtures::manager();
+        let (coll, target_dir) = fixtures::directory_target(&mut manager);
+
+        let src_dir = tempdir().unwrap();
+        let src_path = src_dir.path().join("wat.txt");
+        fs::write(&src_path, b"wat").unwrap();
+        let src_path = src_path.to_str().unwrap();
+        let options = AddOptions {
+            dedup: true,
+            ..Default::default()
+        };
+        manager
+            .add_file(coll.id(), None, src_path, "/dir/a.txt", &options)
+            .unwrap();
+        manager
+            .add_file(coll.id(), None, src_path, "/dir/b.txt", &options)
+            .unwrap();
+        manager
+            .add_file(
+                coll.id(),
+                None,
+                src_path,
+                "/other.txt",
+                &AddOptions::default(),
+            )
+            .unwrap();
+        let copy_path = manager.file_mounted_path(coll.id(), "/dir/a.txt").unwrap();
+        let other_path = manager.file_mounted_path(coll.id(), "/other.txt").unwrap();
+
+        manager.set_collection_legal_hold(coll.id(), true).unwrap();
+        assert!(manager.remove_file(coll.id(), "/other.txt", false).is_err());
+        manager.set_collection_legal_hold(coll.id(), false).unwrap();
+
+        // the shared content can't be removed while another file uses it
+        assert!(manager.remove_file(coll.id(), "/dir/a.txt", false).is_err());
+        assert!(manager.remove_file(coll.id(), "/dir", false).is_err());
+        manager.remove_file(coll.id(), "/dir", true).unwrap();
+        assert!(!Path::new(&copy_path).exists());
+        // the emptied directory is removed too, but not the collection's root
+        let copy_dir = Path::new(&copy_path).parent().unwrap();
+        assert!(!copy_dir.exists());
+        assert!(copy_dir.parent().unwrap().exists());
+        assert!(manager.file_mounted_path(coll.id(), "/dir/b.txt").is_err());
+
+        // copies on unmounted partitions are deleted when they're seen again
+        let marker = target_dir.path().join(".hoard-target");
+        let marker_contents = fs::read(&marker).unwrap();
+        fs::remove_file(&marker).unwrap();
+        manager.remove_file(coll.id(), "/other.txt", false).unwrap();
+        assert!(Path::new(&other_path).exists());
+        fs::write(&marker, marker_contents).unwrap();
+        // verifying doesn't delete anything
+        manager.verify(VerifyScope::Collection(coll.id())).unwrap();
+        assert!(Path::new(&other_path).exists());
+        manager.apply_pending().unwrap();
+        assert!(!Path::new(&other_path).exists());
     }
 
     #[test_log::test]
-    fn init() {
-        let td = tempdir().unwrap();
-        let inner_path = td.path().join("hoard"); // to force dir creation in `init`
-        let config_path = inner_path.join("config.yaml");
-        Manager::init(&config_path).unwrap();
-        assert!(config_path.exists());
+    fn get_file_falls_back() {
+        let mut manager = fixtures::manager();
+        let loc = fixtures::location(&mut manager.conn);
+        let disk = fixtures::disk(&mut manager.conn, &loc);
+        manager
+            .add_disk_with_serial_number(loc.id(), "other-serial", "other-disk")
+            .unwrap();
+        let other_disk = Disk::for_label(&manager.conn, "other-disk")
+            .unwrap()
+            .unwrap();
+        let coll = fixtures::collection(&mut manager.conn);
+        manager.set_collection_min_copies(coll.id(), 2).unwrap();
+        let target_dirs = [tempdir().unwrap(), tempdir().unwrap()];
+        manager
+            .add_directory_partition(disk.id(), target_dirs[0].path())
+            .unwrap();
+        manager
+            .add_directory_partition(other_disk.id(), target_dirs[1].path())
+            .unwrap();
+
+        let src_dir = tempdir().unwrap();
+        fs::create_dir_all(src_dir.path().join("a")).unwrap();
+        fs::write(src_dir.path().join("a/wat.txt"), b"wat").unwrap();
+        manager
+            .add_file(
+                coll.id(),
+                None,
+                src_dir.path().join("a/wat.txt").to_str().unwrap(),
+                "/dir/wat.txt",
+                &AddOptions::default(),
+            )
+            .unwrap();
+        manager
+            .replicate(coll.id(), PlacementStrategy::MostFree, false)
+            .unwrap();
+        // the replica went through the journal and a temporary file like an added copy
+        assert!(PendingPlacement::all(&manager.conn).unwrap().is_empty());
+        for target_dir in target_dirs.iter() {
+            let dir = target_dir
+                .path()
+                .join(Manager::path_on_partition(coll.id(), "/dir").unwrap());
+            let on_disk = fs::read_dir(dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(on_disk, vec!["wat.txt"]);
+        }
+
+        // whichever copy is read first, a bad one is skipped
+        let dest_dir = tempdir().unwrap();
+        for (i, target_dir) in target_dirs.iter().enumerate() {
+            let copy_path = target_dir
+                .path()
+                .join(Manager::path_on_partition(coll.id(), "/dir/wat.txt").unwrap());
+            fs::write(&copy_path, b"bad").unwrap();
+            let dest = dest_dir.path().join(format!("{i}.txt"));
+            manager
+                .get_file(coll.id(), "/dir/wat.txt", &dest, false, false)
+                .unwrap();
+            assert_eq!(fs::read(&dest).unwrap(), b"wat");
+            fs::write(&copy_path, b"wat").unwrap();
+        }
+
+        manager
+            .get_file(coll.id(), "/dir", dest_dir.path().join("dir"), true, false)
+            .unwrap();
+        assert_eq!(
+            fs::read(dest_dir.path().join("dir/wat.txt")).unwrap(),
+            b"wat"
+        );
+        assert!(manager
+            .get_file(coll.id(), "/dir", dest_dir.path().join("dir"), true, false)
+            .is_err());
+
+        // the disks to fetch are named when nothing is mounted
+        for target_dir in target_dirs.iter() {
+            fs::remove_file(target_dir.path().join(".hoard-target")).unwrap();
+        }
+        let err = manager
+            .get_file(
+                coll.id(),
+                "/dir/wat.txt",
+                dest_dir.path().join("x.txt"),
+                false,
+                false,
+            )
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("other-disk"), "{}", err);
+        assert!(err.contains(disk.label()), "{}", err);
     }
 
     #[test_log::test]
-    fn add_location() {
+    fn retrieval_plan() {
         let mut manager = fixtures::manager();
-        manager.add_location("foo").unwrap();
+        let loc = fixtures::location(&mut manager.conn);
+        let disk = fixtures::disk(&mut manager.conn, &loc);
+        let partition = fixtures::partition(&mut manager.conn, &disk);
+        let coll = fixtures::collection(&mut manager.conn);
+        let (file, _, _) = fixtures::file_full(&mut manager.conn, &partition, &coll);
+
+        let plan = manager.retrieval_plan(vec![file]).unwrap();
+        assert_eq!(plan.disks().len(), 1);
+        assert_eq!(plan.disks()[0].disk_label(), disk.label());
+        assert_eq!(plan.disks()[0].location_name(), loc.name());
+        assert_eq!(plan.disks()[0].files(), ["/foo.txt"]);
     }
 
     #[test_log::test]
-    fn add_collection() {
+    fn find_archive_members() {
         let mut manager = fixtures::manager();
-        manager.add_collection("foo").unwrap();
+        let (coll, _target_dir) = fixtures::directory_target(&mut manager);
+
+        let src_dir = tempdir().unwrap();
+        let tar_path = src_dir.path().join("mail.tar");
+        let tar = fixtures::tar(&[
+            ("inbox/1.eml", b"one"),
+            ("inbox/2.eml", b"two"),
+            ("x.txt", b"x"),
+        ]);
+        fs::write(&tar_path, tar).unwrap();
+        manager
+            .add_file(
+                coll.id(),
+                None,
+                tar_path.to_str().unwrap(),
+                "/leak/mail.tar",
+                &AddOptions::default(),
+            )
+            .unwrap();
+
+        let name = Regex::new(r"\.eml$").unwrap();
+        let members = manager
+            .find_archive_members(coll.id(), None, None, Some(&name), None, ["/leak/"])
+            .unwrap();
+        assert_eq!(
+            members
+                .iter()
+                .map(|m| (m.archive_path(), m.member_path()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("/leak/mail.tar", "inbox/1.eml"),
+                ("/leak/mail.tar", "inbox/2.eml")
+            ]
+        );
+        assert_eq!(members[0].disks, "test-disk");
+
+        let inspected = manager
+            .inspect_file(coll.id(), "/leak/mail.tar", true)
+            .unwrap();
+        assert!(inspected.contains("inbox/2.eml"), "{}", inspected);
+        assert!(inspected.contains("container_type: tar\n"), "{}", inspected);
     }
 
     #[test_log::test]
-    fn list_collections() {
+    fn extract_members() {
         let mut manager = fixtures::manager();
-        let _ = fixtures::collection(&mut manager.conn);
-        let colls = manager.list_collections().unwrap();
-        assert_ne!(colls, vec![]);
+        let (coll, _target_dir) = fixtures::directory_target(&mut manager);
+
+        let src_dir = tempdir().unwrap();
+        let tar_path = src_dir.path().join("dump.tar");
+        let tar = fixtures::tar(&[("a.txt", b"wanted"), ("docs/b.txt", b"b")]);
+        fs::write(&tar_path, tar).unwrap();
+        manager
+            .add_file(
+                coll.id(),
+                None,
+                tar_path.to_str().unwrap(),
+                "/leak/dump.tar",
+                &AddOptions::default(),
+            )
+            .unwrap();
+
+        let dest_dir = tempdir().unwrap();
+        manager
+            .extract_members(
+                coll.id(),
+                "/leak/dump.tar",
+                ["a.txt", "docs/b.txt"],
+                dest_dir.path(),
+                false,
+            )
+            .unwrap();
+        assert_eq!(fs::read(dest_dir.path().join("a.txt")).unwrap(), b"wanted");
+        assert_eq!(fs::read(dest_dir.path().join("docs/b.txt")).unwrap(), b"b");
+
+        assert!(manager
+            .extract_members(
+                coll.id(),
+                "/leak/dump.tar",
+                ["nope.txt"],
+                dest_dir.path(),
+                false
+            )
+            .is_err());
+
+        // a copy whose member doesn't match the stored hashes
+        let stored_path = manager
+            .file_mounted_path(coll.id(), "/leak/dump.tar")
+            .unwrap();
+        let stored = fs::read(&stored_path).unwrap();
+        let pos = stored.windows(6).position(|w| w == b"wanted").unwrap();
+        let mut corrupted = stored.clone();
+        corrupted[pos..pos + 6].copy_from_slice(b"wanter");
+        fs::write(&stored_path, corrupted).unwrap();
+        let dest_dir = tempdir().unwrap();
+        let err = manager
+            .extract_members(
+                coll.id(),
+                "/leak/dump.tar",
+                ["a.txt"],
+                dest_dir.path(),
+                false,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("a.txt"), "{}", err);
+        assert!(!dest_dir.path().join("a.txt").exists());
     }
 
     #[test_log::test]
-    fn list_disks() {
+    fn list_archive_as_dir() {
         let mut manager = fixtures::manager();
-        let loc = fixtures::location(&mut manager.conn);
-        let _ = fixtures::disk(&mut manager.conn, &loc);
-        let disks = manager.list_disks().unwrap();
-        assert_ne!(disks, vec![]);
+        let (coll, _target_dir) = fixtures::directory_target(&mut manager);
+
+        let src_dir = tempdir().unwrap();
+        let tar_path = src_dir.path().join("dump.tar");
+        let tar = fixtures::tar(&[
+            ("a.txt", b"a"),
+            ("docs/b.txt", b"b"),
+            ("docs/c/d.txt", b"d"),
+        ]);
+        fs::write(&tar_path, tar).unwrap();
+        manager
+            .add_file(
+                coll.id(),
+                None,
+                tar_path.to_str().unwrap(),
+                "/leak/dump.tar",
+                &AddOptions::default(),
+            )
+            .unwrap();
+
+        let ls = |path| {
+            manager
+                .list_files(coll.id(), [path], false)
+                .unwrap()
+                .iter()
+                .map(|f| f.path())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ls("/leak/"), vec!["/leak/dump.tar"]);
+        assert_eq!(ls("/leak/dump.tar!/"), vec!["/leak/dump.tar!/a.txt"]);
+        assert_eq!(
+            ls("/leak/dump.tar!/docs"),
+            vec!["/leak/dump.tar!/docs/b.txt"]
+        );
+        assert_eq!(ls("/leak/dump.tar!/a.txt"), vec!["/leak/dump.tar!/a.txt"]);
+        assert!(ls("/leak/other.tar!/").is_empty());
+
+        let find = |path, max_depth| {
+            manager
+                .find_archive_members(coll.id(), None, max_depth, None, None, [path])
+                .unwrap()
+                .iter()
+                .map(|m| m.member_path().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(find("/leak/", Some(2)), vec!["a.txt"]);
+        assert_eq!(find("/leak/dump.tar", Some(2)), vec!["a.txt", "docs/b.txt"]);
+        assert_eq!(find("/leak/dump.tar!/docs/", Some(1)), vec!["docs/b.txt"]);
+        assert_eq!(find("/leak/dump.tar!/docs/", None).len(), 2);
     }
 
     #[test_log::test]
-    fn list_partitions() {
+    fn match_file() {
         let mut manager = fixtures::manager();
-        let loc = fixtures::location(&mut manager.conn);
-        let disk = fixtures::disk(&mut manager.conn, &loc);
-        let _ = fixtures::partition(&mut manager.conn, &disk);
-        let parts = manager.list_partitions().unwrap();
-        assert_ne!(parts, vec![]);
+        let (coll, _target_dir) = fixtures::directory_target(&mut manager);
+
+        let src_dir = tempdir().unwrap();
+        let tar_path = src_dir.path().join("docs.tar");
+        let tar = fixtures::tar(&[("a.txt", b"wanted"), ("b.txt", b"other")]);
+        fs::write(&tar_path, tar).unwrap();
+        let doc_path = src_dir.path().join("doc.txt");
+        fs::write(&doc_path, "wanted").unwrap();
+        for (src, dest) in [(&tar_path, "/docs.tar"), (&doc_path, "/loose/doc.txt")] {
+            manager
+                .add_file(
+                    coll.id(),
+                    None,
+                    src.to_str().unwrap(),
+                    dest,
+                    &AddOptions::default(),
+                )
+                .unwrap();
+        }
+
+        let local_path = src_dir.path().join("sent.txt");
+        fs::write(&local_path, "wanted").unwrap();
+        let matches = manager.match_file(&local_path).unwrap();
+        assert_eq!(
+            matches.iter().map(|m| m.path()).collect::<Vec<_>>(),
+            vec!["/docs.tar!/a.txt", "/loose/doc.txt"]
+        );
+        assert_eq!(matches[0].disks, "test-disk");
+
+        fs::write(&local_path, "wanter").unwrap();
+        assert!(manager.match_file(&local_path).unwrap().is_empty());
+
+        let inspected = manager.inspect_file(coll.id(), "/docs.tar", true).unwrap();
+        assert!(inspected.contains("algorithm: sha2-256"), "{}", inspected);
+
+        // member hashes go with the archive
+        manager.remove_file(coll.id(), "/docs.tar", false).unwrap();
+        fs::write(&local_path, "wanted").unwrap();
+        assert_eq!(manager.match_file(&local_path).unwrap().len(), 1);
     }
 
     #[test_log::test]
-    fn list_files() {
+    fn find_mail() {
         let mut manager = fixtures::manager();
-        let coll = fixtures::collection(&mut manager.conn);
-        let file = fixtures::file(&mut manager.conn, &coll);
-        let files = manager
-            .list_files(coll.id(), vec![file.path()], true)
+        let (coll, _target_dir) = fixtures::directory_target(&mut manager);
+
+        let src_dir = tempdir().unwrap();
+        let mbox_path = src_dir.path().join("inbox");
+        fs::write(
+            &mbox_path,
+            concat!(
+                "From alice@example.com Tue Jul  1 10:52:37 2003\n",
+                "From: Alice <alice@example.com>\n",
+                "Date: Tue, 1 Jul 2003 10:52:37 +0000\n",
+                "Subject: Invoice\n",
+                "\n",
+                "Attached.\n",
+                "\n",
+                "From bob@example.com Wed Jul  2 10:52:37 2003\n",
+                "From: Bob <bob@example.com>\n",
+                "Date: Wed, 2 Jul 2003 10:52:37 +0000\n",
+                "Subject: Re: Invoice\n",
+                "\n",
+                "Thanks.\n",
+            ),
+        )
+        .unwrap();
+        let maildir_path = src_dir.path().join("1057056757.M1P2.host:2,S");
+        fs::write(
+            &maildir_path,
+            "From: Carol <carol@example.com>\nSubject: Lunch\n\nNoon?\n",
+        )
+        .unwrap();
+        for (src, dest) in [
+            (&mbox_path, "/mail/inbox"),
+            (&maildir_path, "/mail/Maildir/cur/1057056757.M1P2.host:2,S"),
+        ] {
+            manager
+                .add_file(
+                    coll.id(),
+                    None,
+                    src.to_str().unwrap(),
+                    dest,
+                    &AddOptions::default(),
+                )
+                .unwrap();
+        }
+
+        let paths = |from: Option<&str>, subject: Option<&str>| {
+            let from = from.map(|r| Regex::new(r).unwrap());
+            let subject = subject.map(|r| Regex::new(r).unwrap());
+            manager
+                .find_mail(coll.id(), from.as_ref(), subject.as_ref(), ["/mail"])
+                .unwrap()
+                .iter()
+                .map(|m| m.path().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            paths(None, Some("Invoice")),
+            vec!["/mail/inbox!/000001.eml", "/mail/inbox!/000002.eml"]
+        );
+        assert_eq!(paths(Some("bob@"), None), vec!["/mail/inbox!/000002.eml"]);
+        assert_eq!(
+            paths(Some("(?i)carol"), Some("^Lunch$")),
+            vec!["/mail/Maildir/cur/1057056757.M1P2.host:2,S"]
+        );
+        assert!(paths(Some("dave"), None).is_empty());
+
+        let messages = manager
+            .find_mail(coll.id(), Some(&Regex::new("alice").unwrap()), None, ["/"])
             .unwrap();
-        assert_ne!(files, vec![]);
+        assert_eq!(messages[0].subject, "Invoice");
+        assert_eq!(messages[0].date, "2003-07-01T10:52:37.000Z");
+        assert_eq!(messages[0].disks, "test-disk");
+
+        let inspected = manager
+            .inspect_file(coll.id(), "/mail/inbox", true)
+            .unwrap();
+        assert!(inspected.contains("mbox"), "{}", inspected);
+
+        // messages go with their files
+        manager
+            .remove_file(coll.id(), "/mail/inbox", false)
+            .unwrap();
+        let messages = manager.find_mail(coll.id(), None, None, ["/"]).unwrap();
+        assert_eq!(messages.len(), 1);
     }
 
     #[test_log::test]
-    fn inspect_file() {
+    fn replicate_nothing_needed() {
         let mut manager = fixtures::manager();
         let loc = fixtures::location(&mut manager.conn);
         let disk = fixtures::disk(&mut manager.conn, &loc);
         let partition = fixtures::partition(&mut manager.conn, &disk);
         let coll = fixtures::collection(&mut manager.conn);
-        let (file, _, _) = fixtures::file_full(&mut manager.conn, &partition, &coll);
-        manager.inspect_file(coll.id(), file.path()).unwrap();
+        let _ = fixtures::file_full(&mut manager.conn, &partition, &coll);
+        manager
+            .replicate(coll.id(), PlacementStrategy::MostFree, false)
+            .unwrap();
+    }
+
+    #[test_log::test]
+    fn replicate_deduplicated_into_stricter_collection() {
+        let mut manager = fixtures::manager();
+        let (coll, _target_dir) = fixtures::directory_target(&mut manager);
+        manager.add_collection("strict", 3).unwrap();
+        let strict = Collection::for_name(&manager.conn, "strict")
+            .unwrap()
+            .unwrap();
+
+        let src_dir = tempdir().unwrap();
+        let src_path = src_dir.path().join("wat.txt");
+        fs::write(&src_path, b"wat").unwrap();
+        let src_path = src_path.to_str().unwrap();
+        let options = AddOptions {
+            dedup: true,
+            ..Default::default()
+        };
+        manager
+            .add_file(coll.id(), None, src_path, "/wat.txt", &options)
+            .unwrap();
+        manager
+            .replicate(coll.id(), PlacementStrategy::MostFree, false)
+            .unwrap();
+
+        // the content is owned by the lenient collection but has to meet the stricter one's
+        // min_copies, which the single disk can't
+        manager
+            .add_file(strict.id(), None, src_path, "/wat.txt", &options)
+            .unwrap();
+        let err = manager
+            .replicate(strict.id(), PlacementStrategy::MostFree, false)
+            .unwrap_err();
+        assert!(err.to_string().contains("could not be given enough copies"));
     }
 }
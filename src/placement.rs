@@ -0,0 +1,362 @@
+//! Choosing which partition a new file (or copy of a file) is placed on.
+
+use crate::db::types::Partition;
+use crate::error::GenericError;
+use std::fmt;
+use uuid::Uuid;
+
+/// How to pick among the mounted partitions that have room for a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlacementStrategy {
+    /// The partition with the most free space
+    MostFree,
+    /// The partition with the least free space, so that one partition is filled before the next
+    FillFirst,
+    /// The partition that least recently had a file placed on it
+    RoundRobin,
+}
+
+impl fmt::Display for PlacementStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let val = match self {
+            Self::MostFree => "most-free",
+            Self::FillFirst => "fill-first",
+            Self::RoundRobin => "round-robin",
+        };
+        write!(f, "{}", val)
+    }
+}
+
+impl TryFrom<&str> for PlacementStrategy {
+    type Error = GenericError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "most-free" => Ok(Self::MostFree),
+            "fill-first" => Ok(Self::FillFirst),
+            "round-robin" => Ok(Self::RoundRobin),
+            x => Err(GenericError::new(format!(
+                "Not a known placement strategy: {x}"
+            ))),
+        }
+    }
+}
+
+struct Candidate {
+    partition: Partition,
+    free: u64,
+    // lower was used less recently
+    rank: usize,
+}
+
+/// Chooses partitions for a series of files, keeping track of the space used by earlier choices
+/// so that many files can be placed before the DB is updated.
+pub struct Placer {
+    strategy: PlacementStrategy,
+    candidates: Vec<Candidate>,
+    next_rank: usize,
+    affinity: AffinityTracker,
+}
+
+impl Placer {
+    pub fn new(strategy: PlacementStrategy, mut partitions: Vec<Partition>) -> Self {
+        // never-used partitions first, then oldest. ties are broken by ID to be deterministic.
+        partitions.sort_by(|a, b| {
+            a.last_placed_date()
+                .cmp(&b.last_placed_date())
+                .then_with(|| a.id().cmp(b.id()))
+        });
+        let candidates = partitions
+            .drain(..)
+            .enumerate()
+            .map(|(rank, partition)| Candidate {
+                free: partition.capacity(),
+                partition,
+                rank,
+            })
+            .collect::<Vec<_>>();
+        Self {
+            strategy,
+            next_rank: candidates.len(),
+            candidates,
+            affinity: AffinityTracker::default(),
+        }
+    }
+
+    /// Keep files together according to the affinities when choosing with
+    /// [`Placer::choose_for_path_if`].
+    pub fn with_affinity(mut self, affinity: AffinityTracker) -> Self {
+        self.affinity = affinity;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    /// Choose a partition that can hold `size` bytes.
+    pub fn choose(&mut self, size: u64) -> Option<&Partition> {
+        self.choose_if(size, |_| true)
+    }
+
+    /// Choose a partition that can hold `size` bytes among those matching the filter.
+    pub fn choose_if<F>(&mut self, size: u64, filter: F) -> Option<&Partition>
+    where
+        F: Fn(&Partition) -> bool,
+    {
+        let fits = self
+            .candidates
+            .iter_mut()
+            .filter(|c| c.free >= size && filter(&c.partition));
+        let chosen = match self.strategy {
+            // `max_by_key` returns the last max, so reverse to keep the first
+            PlacementStrategy::MostFree => fits.rev().max_by_key(|c| c.free),
+            PlacementStrategy::FillFirst => fits.min_by_key(|c| c.free),
+            PlacementStrategy::RoundRobin => fits.min_by_key(|c| c.rank),
+        }?;
+
+        chosen.free -= size;
+        chosen.rank = self.next_rank;
+        self.next_rank += 1;
+        Some(&chosen.partition)
+    }
+
+    /// Choose a partition for the file at the virtual path. See [`Placer::choose_for_path_if`].
+    pub fn choose_for_path(&mut self, path: &str, size: u64) -> Option<&Partition> {
+        if self.affinity.prefix_for(path).is_none() {
+            return self.choose(size);
+        }
+        self.choose_for_path_if(path, size, |_| true)
+    }
+
+    /// Choose a partition for the file at the virtual path among those matching the filter,
+    /// preferring the partitions that hold the other files under the path's affinity prefix. If
+    /// none of them can hold `size` bytes, fall back to the strategy.
+    pub fn choose_for_path_if<F>(&mut self, path: &str, size: u64, filter: F) -> Option<&Partition>
+    where
+        F: Fn(&Partition) -> bool,
+    {
+        let preferred = self.affinity.preferred(path);
+        let preferred_idx = preferred.iter().find_map(|id| {
+            self.candidates
+                .iter()
+                .position(|c| c.partition.id() == id && c.free >= size && filter(&c.partition))
+        });
+        let id = match preferred_idx {
+            Some(idx) => {
+                let chosen = &mut self.candidates[idx];
+                chosen.free -= size;
+                chosen.rank = self.next_rank;
+                self.next_rank += 1;
+                *chosen.partition.id()
+            }
+            None => *self.choose_if(size, filter)?.id(),
+        };
+        if !preferred.is_empty() && !preferred.contains(&id) {
+            log::info!(
+                "Partitions with files under {} are full or excluded. Spilling {} to partition {}",
+                self.affinity.prefix_for(path).unwrap_or_default(),
+                path,
+                id.hyphenated()
+            );
+        }
+        self.affinity.record(path, &id);
+        self.candidates
+            .iter()
+            .find(|c| c.partition.id() == &id)
+            .map(|c| &c.partition)
+    }
+}
+
+/// Tracks which partitions hold the files under each virtual directory with a placement affinity
+/// so that new files can be kept together with them.
+#[derive(Debug, Default)]
+pub struct AffinityTracker {
+    // longest prefix first, and partitions with the most files first
+    prefixes: Vec<(String, Vec<(Uuid, u64)>)>,
+}
+
+impl AffinityTracker {
+    /// Create a tracker from prefixes (ending with `/`) and the number of files under them that
+    /// are on each partition.
+    pub fn new(mut prefixes: Vec<(String, Vec<(Uuid, u64)>)>) -> Self {
+        prefixes.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        for (_, counts) in prefixes.iter_mut() {
+            counts.sort_by(|(_, a), (_, b)| b.cmp(a));
+        }
+        Self { prefixes }
+    }
+
+    /// The longest prefix with an affinity that contains the path.
+    pub fn prefix_for(&self, path: &str) -> Option<&str> {
+        self.prefixes
+            .iter()
+            .find(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .map(|(prefix, _)| prefix.as_str())
+    }
+
+    /// The partitions that files under the same prefix as the path are on, most files first.
+    pub fn preferred(&self, path: &str) -> Vec<Uuid> {
+        self.prefixes
+            .iter()
+            .find(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .map(|(_, counts)| counts.iter().map(|(id, _)| *id).collect())
+            .unwrap_or_default()
+    }
+
+    /// Record that the file at the path was placed on the partition.
+    pub fn record(&mut self, path: &str, partition_id: &Uuid) {
+        let counts = match self
+            .prefixes
+            .iter_mut()
+            .find(|(prefix, _)| path.starts_with(prefix.as_str()))
+        {
+            Some((_, counts)) => counts,
+            None => return,
+        };
+        match counts.iter_mut().find(|(id, _)| id == partition_id) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((*partition_id, 1)),
+        }
+        counts.sort_by(|(_, a), (_, b)| b.cmp(a));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::auto_transaction;
+    use crate::db::types::NewPartition;
+    use crate::test_utils::fixtures;
+
+    fn partitions(capacities: &[u64]) -> Vec<Partition> {
+        let mut conn = fixtures::db();
+        let loc = fixtures::location(&mut conn);
+        let disk = fixtures::disk(&mut conn, &loc);
+        let mut ids = Vec::new();
+        for (i, capacity) in capacities.iter().enumerate() {
+            let uuid = format!("uuid-{i}");
+            ids.push(
+                auto_transaction(&mut conn, |tx| {
+                    NewPartition {
+                        disk_id: disk.id(),
+                        uuid: &uuid,
+                        capacity: *capacity,
+                        directory: None,
+                    }
+                    .insert(tx)
+                })
+                .unwrap(),
+            );
+        }
+        ids.iter()
+            .map(|id| Partition::for_id(&conn, id).unwrap().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn placement_strategy_round_trip() {
+        for strategy in [
+            PlacementStrategy::MostFree,
+            PlacementStrategy::FillFirst,
+            PlacementStrategy::RoundRobin,
+        ] {
+            assert_eq!(
+                PlacementStrategy::try_from(strategy.to_string().as_str()).unwrap(),
+                strategy
+            );
+        }
+        assert!(PlacementStrategy::try_from("random").is_err());
+    }
+
+    #[test_log::test]
+    fn most_free() {
+        let parts = partitions(&[100, 300, 200]);
+        let mut placer = Placer::new(PlacementStrategy::MostFree, parts.clone());
+        assert_eq!(placer.choose(150).unwrap().id(), parts[1].id());
+        // 150 left on the first choice, so the 200 one has the most free space now
+        assert_eq!(placer.choose(10).unwrap().id(), parts[2].id());
+    }
+
+    #[test_log::test]
+    fn fill_first() {
+        let parts = partitions(&[100, 300, 200]);
+        let mut placer = Placer::new(PlacementStrategy::FillFirst, parts.clone());
+        assert_eq!(placer.choose(50).unwrap().id(), parts[0].id());
+        assert_eq!(placer.choose(50).unwrap().id(), parts[0].id());
+        assert_eq!(placer.choose(50).unwrap().id(), parts[2].id());
+    }
+
+    #[test_log::test]
+    fn round_robin() {
+        let parts = partitions(&[100, 300, 200]);
+        let mut placer = Placer::new(PlacementStrategy::RoundRobin, parts.clone());
+        let mut chosen = (0..3)
+            .map(|_| *placer.choose(10).unwrap().id())
+            .collect::<Vec<_>>();
+        chosen.sort();
+        let mut expected = parts.iter().map(|p| *p.id()).collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(chosen, expected);
+        // the 100 byte partition is skipped when the file doesn't fit
+        let id = *placer.choose(150).unwrap().id();
+        assert_ne!(&id, parts[0].id());
+    }
+
+    #[test_log::test]
+    fn refuses_too_small() {
+        let parts = partitions(&[100, 200]);
+        let mut placer = Placer::new(PlacementStrategy::MostFree, parts.clone());
+        assert!(placer.choose(201).is_none());
+        assert!(placer.choose_if(150, |p| p.id() != parts[1].id()).is_none());
+        assert_eq!(placer.choose(200).unwrap().id(), parts[1].id());
+        assert!(placer.choose(101).is_none());
+    }
+
+    #[test_log::test]
+    fn preferred_partitions_before_strategy() {
+        let parts = partitions(&[100, 300, 200]);
+        let tracker = AffinityTracker::new(vec![("/".to_string(), vec![(*parts[0].id(), 1)])]);
+        let mut placer =
+            Placer::new(PlacementStrategy::MostFree, parts.clone()).with_affinity(tracker);
+        let chosen = placer.choose_for_path("/a", 60);
+        assert_eq!(chosen.unwrap().id(), parts[0].id());
+        // the preferred partition is full so this spills to the strategy's choice
+        let chosen = placer.choose_for_path("/b", 60);
+        assert_eq!(chosen.unwrap().id(), parts[1].id());
+    }
+
+    #[test]
+    fn affinity_tracker() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut tracker = AffinityTracker::new(vec![
+            ("/".to_string(), vec![(a, 1), (b, 5)]),
+            ("/leak/".to_string(), vec![]),
+        ]);
+        assert_eq!(tracker.prefix_for("/leak/x.txt"), Some("/leak/"));
+        assert_eq!(tracker.prefix_for("/other.txt"), Some("/"));
+        assert_eq!(tracker.preferred("/other.txt"), vec![b, a]);
+        assert!(tracker.preferred("/leak/x.txt").is_empty());
+
+        tracker.record("/leak/x.txt", &a);
+        assert_eq!(tracker.preferred("/leak/y.txt"), vec![a]);
+
+        assert!(AffinityTracker::default().preferred("/x").is_empty());
+    }
+
+    #[test_log::test]
+    fn choose_for_path_keeps_together() {
+        let parts = partitions(&[100, 300, 200]);
+        let tracker = AffinityTracker::new(vec![("/leak/".to_string(), vec![])]);
+        let mut placer =
+            Placer::new(PlacementStrategy::RoundRobin, parts.clone()).with_affinity(tracker);
+        let first = *placer.choose_for_path("/leak/a", 10).unwrap().id();
+        for name in ["/leak/b", "/leak/c"] {
+            let chosen = placer.choose_for_path(name, 10).unwrap();
+            assert_eq!(chosen.id(), &first);
+        }
+        // without an affinity round-robin moves on to another partition
+        let chosen = placer.choose_for_path("/other", 10).unwrap();
+        assert_ne!(chosen.id(), &first);
+    }
+}
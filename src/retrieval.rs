@@ -0,0 +1,264 @@
+//! Planning which disks need to be fetched to retrieve a selection of files.
+
+use std::collections::HashMap;
+use std::fmt;
+use uuid::Uuid;
+
+/// A disk that has a copy of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskRef {
+    pub id: Uuid,
+    pub label: String,
+    pub location_name: String,
+}
+
+/// A file to retrieve along with the disks that have copies of it.
+#[derive(Debug)]
+pub struct WantedFile {
+    pub path: String,
+    pub size: u64,
+    pub disks: Vec<DiskRef>,
+}
+
+/// The files that would be copied from one disk.
+#[derive(Debug, PartialEq)]
+pub struct DiskRetrieval {
+    disk: DiskRef,
+    files: Vec<String>,
+    bytes: u64,
+}
+
+impl DiskRetrieval {
+    pub fn disk_label(&self) -> &str {
+        &self.disk.label
+    }
+
+    pub fn location_name(&self) -> &str {
+        &self.disk.location_name
+    }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+/// The disks to fetch, ordered by location and label, and the files that have no copies.
+#[derive(Debug, PartialEq)]
+pub struct RetrievalPlan {
+    disks: Vec<DiskRetrieval>,
+    unavailable: Vec<String>,
+}
+
+impl RetrievalPlan {
+    /// Choose a small set of disks that together have copies of all the files. Disks are chosen
+    /// greedily by how many of the remaining files they have, and then disks whose files are all
+    /// on other chosen disks are dropped. Finding the smallest set is NP-hard, but this is close
+    /// to it for the ways files are spread across disks in practice.
+    pub fn new(files: &[WantedFile]) -> Self {
+        let unavailable = files
+            .iter()
+            .filter(|f| f.disks.is_empty())
+            .map(|f| f.path.clone())
+            .collect();
+
+        let mut chosen = Vec::<&DiskRef>::new();
+        let mut remaining = files
+            .iter()
+            .filter(|f| !f.disks.is_empty())
+            .collect::<Vec<_>>();
+        while !remaining.is_empty() {
+            let mut counts = HashMap::<&Uuid, (usize, u64, &DiskRef)>::new();
+            for file in remaining.iter() {
+                for disk in file.disks.iter() {
+                    let entry = counts.entry(&disk.id).or_insert((0, 0, disk));
+                    entry.0 += 1;
+                    entry.1 += file.size;
+                }
+            }
+            // unwrap ok because every remaining file has at least one disk
+            let (_, _, best) = counts
+                .into_values()
+                .max_by(|a, b| {
+                    a.0.cmp(&b.0)
+                        .then(a.1.cmp(&b.1))
+                        .then(b.2.label.cmp(&a.2.label))
+                })
+                .unwrap();
+            remaining.retain(|f| !f.disks.iter().any(|d| d.id == best.id));
+            chosen.push(best);
+        }
+
+        // a disk chosen early can end up redundant once the disks chosen after it are included
+        let mut kept = vec![true; chosen.len()];
+        for i in 0..chosen.len() {
+            let redundant = files.iter().all(|f| {
+                !f.disks.iter().any(|d| d.id == chosen[i].id)
+                    || chosen
+                        .iter()
+                        .enumerate()
+                        .any(|(j, c)| j != i && kept[j] && f.disks.iter().any(|d| d.id == c.id))
+            });
+            if redundant {
+                kept[i] = false;
+            }
+        }
+        let chosen = chosen
+            .into_iter()
+            .zip(kept)
+            .filter(|(_, kept)| *kept)
+            .map(|(disk, _)| disk)
+            .collect::<Vec<_>>();
+
+        // each file comes from the first chosen disk that has it
+        let mut disks = chosen
+            .iter()
+            .map(|disk| DiskRetrieval {
+                disk: (*disk).clone(),
+                files: Vec::new(),
+                bytes: 0,
+            })
+            .collect::<Vec<_>>();
+        for file in files.iter() {
+            if let Some(retrieval) = disks
+                .iter_mut()
+                .find(|r| file.disks.iter().any(|d| d.id == r.disk.id))
+            {
+                retrieval.files.push(file.path.clone());
+                retrieval.bytes += file.size;
+            }
+        }
+        disks.sort_by(|a, b| {
+            (&a.disk.location_name, &a.disk.label).cmp(&(&b.disk.location_name, &b.disk.label))
+        });
+
+        Self { disks, unavailable }
+    }
+
+    pub fn disks(&self) -> &[DiskRetrieval] {
+        &self.disks
+    }
+
+    /// Files that don't have a copy on any disk
+    pub fn unavailable(&self) -> &[String] {
+        &self.unavailable
+    }
+}
+
+impl fmt::Display for RetrievalPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut location = None;
+        for retrieval in self.disks.iter() {
+            if location != Some(retrieval.location_name()) {
+                location = Some(retrieval.location_name());
+                writeln!(f, "Location: {}", retrieval.location_name())?;
+            }
+            writeln!(
+                f,
+                "  [ ] {} ({} files, {} bytes)",
+                retrieval.disk_label(),
+                retrieval.files().len(),
+                retrieval.bytes()
+            )?;
+            for path in retrieval.files() {
+                writeln!(f, "        {}", path)?;
+            }
+        }
+        if !self.unavailable.is_empty() {
+            writeln!(f, "Not on any disk:")?;
+            for path in self.unavailable.iter() {
+                writeln!(f, "  {}", path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn disk(label: &str, location_name: &str) -> DiskRef {
+        DiskRef {
+            id: Uuid::new_v4(),
+            label: label.to_string(),
+            location_name: location_name.to_string(),
+        }
+    }
+
+    fn file(path: &str, disks: &[&DiskRef]) -> WantedFile {
+        WantedFile {
+            path: path.to_string(),
+            size: 10,
+            disks: disks.iter().map(|d| (*d).clone()).collect(),
+        }
+    }
+
+    #[test]
+    fn one_disk_has_everything() {
+        let a = disk("a", "home");
+        let b = disk("b", "home");
+        let plan =
+            RetrievalPlan::new(&[file("/1", &[&a, &b]), file("/2", &[&b]), file("/3", &[&b])]);
+        assert_eq!(plan.disks().len(), 1);
+        assert_eq!(plan.disks()[0].disk_label(), "b");
+        assert_eq!(plan.disks()[0].files(), ["/1", "/2", "/3"]);
+        assert_eq!(plan.disks()[0].bytes(), 30);
+        assert!(plan.unavailable().is_empty());
+    }
+
+    #[test]
+    fn grouped_by_location() {
+        let a = disk("a", "offsite");
+        let b = disk("b", "home");
+        let plan = RetrievalPlan::new(&[file("/1", &[&a]), file("/2", &[&b]), file("/3", &[])]);
+        assert_eq!(
+            plan.disks()
+                .iter()
+                .map(|r| (r.location_name(), r.disk_label()))
+                .collect::<Vec<_>>(),
+            vec![("home", "b"), ("offsite", "a")]
+        );
+        assert_eq!(plan.unavailable(), ["/3"]);
+        assert_eq!(
+            plan.to_string(),
+            concat!(
+                "Location: home\n",
+                "  [ ] b (1 files, 10 bytes)\n",
+                "        /2\n",
+                "Location: offsite\n",
+                "  [ ] a (1 files, 10 bytes)\n",
+                "        /1\n",
+                "Not on any disk:\n",
+                "  /3\n",
+            )
+        );
+    }
+
+    #[test]
+    fn redundant_disks_dropped() {
+        // greedy picks `x` first, but `y` and `z` are needed anyway and have all of its files
+        let x = disk("x", "home");
+        let y = disk("y", "home");
+        let z = disk("z", "home");
+        let plan = RetrievalPlan::new(&[
+            file("/1", &[&x, &y]),
+            file("/2", &[&x, &y]),
+            file("/3", &[&x, &z]),
+            file("/4", &[&x, &z]),
+            file("/5", &[&y]),
+            file("/6", &[&z]),
+        ]);
+        assert_eq!(
+            plan.disks()
+                .iter()
+                .map(|r| r.disk_label())
+                .collect::<Vec<_>>(),
+            vec!["y", "z"]
+        );
+        assert_eq!(plan.disks()[0].files(), ["/1", "/2", "/5"]);
+    }
+}
@@ -0,0 +1,222 @@
+//! Storage targets that files can be placed on.
+//!
+//! A storage target is either a partition on a block device that is found using `udev`, or a
+//! plain directory (e.g., an NFS/SMB share or bind mount) that is identified by a marker file
+//! containing the target's UUID.
+
+use crate::db::types::Partition;
+use crate::dev_utils;
+use rusqlite::Connection;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+#[derive(Debug)]
+pub enum StorageTarget {
+    Block(dev_utils::Partition),
+    Directory(DirectoryTarget),
+}
+
+impl StorageTarget {
+    pub fn uuid(&self) -> &str {
+        match self {
+            Self::Block(partition) => partition.uuid(),
+            Self::Directory(dir) => dir.uuid(),
+        }
+    }
+
+    pub fn mount_point(&self) -> &Path {
+        match self {
+            Self::Block(partition) => partition.mount_point(),
+            Self::Directory(dir) => dir.path(),
+        }
+    }
+
+    /// The free space on the target when it was looked up
+    pub fn capacity(&self) -> u64 {
+        match self {
+            Self::Block(partition) => partition.capacity(),
+            Self::Directory(dir) => dir.capacity(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DirectoryTarget {
+    uuid: String,
+    path: PathBuf,
+    capacity: u64,
+}
+
+impl DirectoryTarget {
+    const MARKER_FILE: &'static str = ".hoard-target";
+
+    pub fn uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Open a directory that was previously set up as a target.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let marker_path = path.join(Self::MARKER_FILE);
+        let uuid = match fs::read_to_string(&marker_path) {
+            Ok(contents) => contents.trim().to_string(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => bail!(
+                "Directory is not a storage target (missing {}): {}",
+                Self::MARKER_FILE,
+                path.to_string_lossy()
+            ),
+            Err(e) => return Err(e.into()),
+        };
+        if Uuid::parse_str(&uuid).is_err() {
+            bail!(
+                "Marker file did not contain a valid UUID: {}",
+                marker_path.to_string_lossy()
+            )
+        }
+
+        Ok(Self {
+            uuid,
+            path: path.to_owned(),
+            capacity: dev_utils::free_space(path)?,
+        })
+    }
+
+    /// Set up a directory as a target by writing the marker file if it doesn't already exist.
+    pub fn init(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if !path.is_dir() {
+            bail!("Path is not a directory: {}", path.to_string_lossy())
+        }
+
+        let marker_path = path.join(Self::MARKER_FILE);
+        if !marker_path.exists() {
+            log::info!("Writing marker file: {}", marker_path.to_string_lossy());
+            fs::write(&marker_path, format!("{}\n", Uuid::new_v4().hyphenated()))?;
+        }
+        Self::open(path)
+    }
+}
+
+/// All storage targets that are known to the DB and currently available.
+pub fn mounted_targets(conn: &Connection) -> anyhow::Result<Vec<StorageTarget>> {
+    let mut targets = match dev_utils::get_all_partitions() {
+        Ok(partitions) => partitions
+            .into_iter()
+            .map(StorageTarget::Block)
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            log::warn!("Unable to list block device partitions: {e}");
+            Vec::new()
+        }
+    };
+
+    for db_part in Partition::all(conn)? {
+        let dir = match db_part.directory() {
+            Some(dir) => dir,
+            None => continue,
+        };
+        match DirectoryTarget::open(dir) {
+            Ok(target) if target.uuid() == db_part.uuid() => {
+                targets.push(StorageTarget::Directory(target))
+            }
+            Ok(target) => log::warn!(
+                "Directory {} has target UUID {} but {} was expected",
+                dir,
+                target.uuid(),
+                db_part.uuid()
+            ),
+            Err(e) => log::debug!("Directory target not available: {e}"),
+        }
+    }
+
+    log::trace!("Current storage targets: {:#?}", targets);
+    Ok(targets)
+}
+
+/// Look up the storage target for a partition in the DB. Fails if it is not available.
+pub fn target_for_partition(db_part: &Partition) -> anyhow::Result<StorageTarget> {
+    match db_part.directory() {
+        Some(dir) => {
+            let target = DirectoryTarget::open(dir)?;
+            if target.uuid() != db_part.uuid() {
+                bail!(
+                    "Directory {} has target UUID {} but {} was expected",
+                    dir,
+                    target.uuid(),
+                    db_part.uuid()
+                )
+            }
+            Ok(StorageTarget::Directory(target))
+        }
+        None => Ok(StorageTarget::Block(dev_utils::get_partition_for_uuid(
+            db_part.uuid(),
+        )?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::auto_transaction;
+    use crate::db::types::NewPartition;
+    use crate::test_utils::fixtures;
+    use tempfile::tempdir;
+
+    #[test]
+    fn directory_target_init_and_open() {
+        let td = tempdir().unwrap();
+        let target = DirectoryTarget::init(td.path()).unwrap();
+        assert!(td.path().join(DirectoryTarget::MARKER_FILE).exists());
+
+        let reopened = DirectoryTarget::open(td.path()).unwrap();
+        assert_eq!(target.uuid(), reopened.uuid());
+
+        // init is idempotent
+        let reinit = DirectoryTarget::init(td.path()).unwrap();
+        assert_eq!(target.uuid(), reinit.uuid());
+    }
+
+    #[test]
+    fn directory_target_open_missing_marker() {
+        let td = tempdir().unwrap();
+        assert!(DirectoryTarget::open(td.path()).is_err());
+    }
+
+    #[test_log::test]
+    fn mounted_directory_targets() {
+        let mut conn = fixtures::db();
+        let loc = fixtures::location(&mut conn);
+        let disk = fixtures::disk(&mut conn, &loc);
+        let td = tempdir().unwrap();
+        let target = DirectoryTarget::init(td.path()).unwrap();
+        let dir = td.path().to_str().unwrap();
+
+        auto_transaction(&mut conn, |tx| {
+            NewPartition {
+                disk_id: disk.id(),
+                uuid: target.uuid(),
+                capacity: target.capacity(),
+                directory: Some(dir),
+            }
+            .insert(tx)
+        })
+        .unwrap();
+
+        let targets = mounted_targets(&conn).unwrap();
+        assert!(targets.iter().any(|t| t.uuid() == target.uuid()));
+
+        fs::remove_file(td.path().join(DirectoryTarget::MARKER_FILE)).unwrap();
+        let targets = mounted_targets(&conn).unwrap();
+        assert!(!targets.iter().any(|t| t.uuid() == target.uuid()));
+    }
+}
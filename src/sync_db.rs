@@ -1,17 +1,24 @@
+use crate::archive_utils::Hashes;
 use crate::config::FileConfig;
 use crate::db::auto_transaction;
-use crate::db::types::NewFileHash;
-use crate::dev_utils;
+use crate::db::types::{File, FileHash, NewFileHash, Partition};
 use crate::hash_utils::make_hashes;
 use crate::hash_utils::HashAlgorithm;
 use crate::manager::Manager;
+use crate::storage;
 use rusqlite::types::Value;
 use rusqlite::Connection;
 use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
 use uuid::Uuid;
 
+/// The number of files whose hashes are inserted per transaction while syncing
+const SYNC_HASHES_BATCH_SIZE: usize = 100;
+
 pub fn sync_db(
     file_config: &FileConfig,
     conn: &mut Connection,
@@ -45,7 +52,7 @@ fn is_sync_hash_needed(
         "  SELECT f.id FROM files AS f",
         "  LEFT OUTER JOIN file_hashes AS h",
         "  ON f.id = h.file_id AND h.hash_algorithm IN rarray(:algorithms)",
-        "  WHERE f.collection_id = :collection_id",
+        "  WHERE f.collection_id = :collection_id AND f.content_file_id IS NULL",
         "  GROUP BY f.id HAVING count(h.file_id) != :expected_count",
         ")",
     );
@@ -80,7 +87,7 @@ fn get_missing_hashes(
         "  SELECT f.id AS id, f.path AS path FROM files AS f",
         "  LEFT OUTER JOIN file_hashes AS h",
         "  ON f.id = h.file_id AND h.hash_algorithm IN rarray(:algorithms)",
-        "  WHERE f.collection_id = :collection_id",
+        "  WHERE f.collection_id = :collection_id AND f.content_file_id IS NULL",
         "  GROUP BY f.id, f.path",
         "  HAVING count(h.file_id) != :expected_count",
         ") AS f ",
@@ -131,6 +138,8 @@ fn get_missing_hashes(
     Ok(bucket)
 }
 
+/// Hash the files that are missing hashes. Each disk is read by its own worker so that
+/// different disks are read at the same time but each one only reads a file at a time.
 fn do_sync_hashes(
     conn: &mut Connection,
     algos: &[HashAlgorithm],
@@ -140,36 +149,35 @@ fn do_sync_hashes(
 
     let mut skipped_files = 0_u32;
     let mut skipped_partitions = HashSet::<Uuid>::new();
-    let mounted_partitions = dev_utils::get_all_partitions()?;
+    let mounted_targets = storage::mounted_targets(conn)?;
+    let uuids = mounted_targets.iter().map(|t| t.uuid()).collect::<Vec<_>>();
+    let disk_ids = Partition::current(conn, &uuids)?
+        .into_iter()
+        .map(|p| (p.uuid().to_string(), *p.disk_id()))
+        .collect::<HashMap<_, _>>();
 
+    // files on more than one mounted disk are read from the one with the fewest files queued
+    let mut jobs = HashMap::<Uuid, Vec<HashJob>>::new();
     for ((file_id, file_path), (partition_info, missing_algos)) in missing_hashes.data.iter() {
-        let part = mounted_partitions.iter().find(|dev_part| {
-            partition_info
-                .iter()
-                .any(|(_, p_uuid)| p_uuid == dev_part.uuid())
-        });
+        let part = mounted_targets
+            .iter()
+            .filter(|target| {
+                partition_info
+                    .iter()
+                    .any(|(_, p_uuid)| p_uuid == target.uuid())
+            })
+            .filter_map(|target| Some((target, *disk_ids.get(target.uuid())?)))
+            .min_by_key(|(_, disk_id)| jobs.get(disk_id).map(Vec::len).unwrap_or(0));
 
         match part {
-            Some(part) => {
-                log::info!(
-                    "Adding missing hashes for file ID {} at path {}",
-                    file_id,
-                    file_path
-                );
+            Some((part, disk_id)) => {
                 let path = Manager::path_on_partition(collection_id, file_path)?;
-                let full_path = part.mount_point().join(path);
-                let hashes = make_hashes(fs::File::open(full_path)?, missing_algos)?;
-                auto_transaction::<'_, _, anyhow::Error, _>(conn, |tx| {
-                    for (hash_algorithm, hash_value) in hashes.iter() {
-                        NewFileHash {
-                            file_id,
-                            hash_algorithm,
-                            hash_value,
-                        }
-                        .insert(tx)?;
-                    }
-                    Ok(())
-                })?;
+                jobs.entry(disk_id).or_default().push(HashJob {
+                    file_id: *file_id,
+                    file_path: file_path.clone(),
+                    full_path: part.mount_point().join(path),
+                    algorithms: missing_algos.iter().copied().collect(),
+                });
             }
             None => {
                 skipped_files += 1;
@@ -178,6 +186,63 @@ fn do_sync_hashes(
         }
     }
 
+    let total = jobs.values().map(Vec::len).sum::<usize>();
+    log::info!("Hashing {} files on {} disk(s)", total, jobs.len());
+    let (sender, receiver) = mpsc::channel();
+    let workers = jobs
+        .into_values()
+        .map(|disk_jobs| {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for job in disk_jobs {
+                    log::debug!(
+                        "Adding missing hashes for file ID {} at path {}",
+                        job.file_id,
+                        job.file_path
+                    );
+                    let res = fs::File::open(&job.full_path)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|file| make_hashes(file, &job.algorithms))
+                        .map(|hashes| hashes.into_iter().map(|(a, v)| (*a, v)).collect::<Hashes>());
+                    // the receiver is only gone if inserting failed, so there's no point going on
+                    if sender.send((job, res)).is_err() {
+                        break;
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(sender);
+
+    let mut done = 0;
+    let mut failed_files = 0_u32;
+    let mut batch = Vec::with_capacity(SYNC_HASHES_BATCH_SIZE);
+    for (job, res) in receiver {
+        match res {
+            Ok(hashes) => batch.push((job.file_id, hashes)),
+            Err(e) => {
+                log::error!("Unable to hash {}: {e}", job.full_path.to_string_lossy());
+                failed_files += 1;
+            }
+        }
+        done += 1;
+        if batch.len() >= SYNC_HASHES_BATCH_SIZE {
+            insert_hashes(conn, &batch)?;
+            batch.clear();
+            log::info!("Hashed {done} of {total} files");
+        }
+    }
+    insert_hashes(conn, &batch)?;
+    log::info!("Hashed {done} of {total} files");
+    for worker in workers {
+        if worker.join().is_err() {
+            bail!("A worker panicked while syncing hashes");
+        }
+    }
+
+    if failed_files > 0 {
+        bail!("While syncing hashes, {failed_files} files could not be read")
+    }
     if skipped_files == 0 {
         Ok(())
     } else {
@@ -192,6 +257,48 @@ fn do_sync_hashes(
     }
 }
 
+/// Insert the hashes of a batch of files in a single transaction. Files that share the content
+/// of one of the files get the same hashes.
+fn insert_hashes(conn: &mut Connection, batch: &[(Uuid, Hashes)]) -> anyhow::Result<()> {
+    auto_transaction(conn, |tx| {
+        for (file_id, hashes) in batch.iter() {
+            for (hash_algorithm, hash_value) in hashes.iter() {
+                NewFileHash {
+                    file_id,
+                    hash_algorithm,
+                    hash_value,
+                }
+                .insert(tx)?;
+            }
+            for referrer in File::referrers(tx, file_id)?.iter() {
+                let existing = FileHash::get_by_file_id(tx, referrer.id())?
+                    .into_iter()
+                    .map(|h| h.hash_algorithm())
+                    .collect::<HashSet<_>>();
+                for (hash_algorithm, hash_value) in hashes.iter() {
+                    if !existing.contains(hash_algorithm) {
+                        NewFileHash {
+                            file_id: referrer.id(),
+                            hash_algorithm,
+                            hash_value,
+                        }
+                        .insert(tx)?;
+                    }
+                }
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    })
+}
+
+/// A file to hash on one of the mounted partitions
+struct HashJob {
+    file_id: Uuid,
+    file_path: String,
+    full_path: PathBuf,
+    algorithms: Vec<HashAlgorithm>,
+}
+
 struct HashBucket {
     #[allow(clippy::type_complexity)]
     // (file_id, path) -> ([(partition_id, "uuid")], [hash_alg])
@@ -9,6 +9,7 @@ use crate::hash_utils::HashAlgorithm;
 use crate::manager::Manager;
 use rusqlite::Connection;
 use std::fs;
+use tempfile::TempDir;
 
 pub fn config() -> Config {
     serde_yaml::from_reader(fs::File::open("./dev-config.yaml").unwrap()).unwrap()
@@ -25,6 +26,31 @@ pub fn manager() -> Manager {
     Manager::new(config(), db())
 }
 
+/// A collection and a directory added as a partition to store files in. The directory is removed
+/// when the `TempDir` is dropped.
+pub fn directory_target(manager: &mut Manager) -> (Collection, TempDir) {
+    let loc = location(manager.conn());
+    let disk = disk(manager.conn(), &loc);
+    let coll = collection(manager.conn());
+    let target_dir = tempfile::tempdir().unwrap();
+    manager
+        .add_directory_partition(disk.id(), target_dir.path())
+        .unwrap();
+    (coll, target_dir)
+}
+
+/// A tarball with the members at the paths with the contents.
+pub fn tar(members: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, contents) in members {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_cksum();
+        builder.append_data(&mut header, path, *contents).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
 pub fn location(conn: &mut Connection) -> Location {
     let id = auto_transaction::<'_, _, anyhow::Error, _>(conn, |tx| {
         NewLocation {
@@ -55,6 +81,7 @@ pub fn partition(conn: &mut Connection, disk: &Disk) -> Partition {
             disk_id: disk.id(),
             uuid: "AFA-161-420-69",
             capacity: 420,
+            directory: None,
         }
         .insert(tx)
     })
@@ -66,6 +93,7 @@ pub fn collection(conn: &mut Connection) -> Collection {
     let id = auto_transaction::<'_, _, anyhow::Error, _>(conn, |tx| {
         NewCollection {
             name: "some-collection",
+            min_copies: 1,
         }
         .insert(tx)
     })
@@ -79,6 +107,8 @@ pub fn file(conn: &mut Connection, collection: &Collection) -> File {
             collection_id: collection.id(),
             path: "/foo.txt",
             size: 6969,
+            content_file_id: None,
+            container_type: None,
         }
         .insert(tx)
     })
@@ -115,6 +145,8 @@ pub fn file_full(
             collection_id: collection.id(),
             path: "/foo.txt",
             size: 6969,
+            content_file_id: None,
+            container_type: None,
         }
         .insert(tx)?;
 